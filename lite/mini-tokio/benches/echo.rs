@@ -0,0 +1,95 @@
+//! echo负载下对比epoll反应器和io_uring驱动：
+//! 服务端跑在单个分片上，多个阻塞客户端线程各自做若干次固定大小的往返，统计每秒往返次数。
+//! `cargo bench --bench echo --features io-uring`
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use mini_tokio::{spawn_local, MiniTokio};
+
+const CONNECTIONS: usize = 16;
+const ROUNDS: usize = 5000;
+const MESSAGE: usize = 64;
+
+fn epoll_server(addr: mpsc::Sender<SocketAddr>) {
+    MiniTokio::thread_per_core().shards(1).run(|| {
+        let addr = addr.clone();
+        async move {
+            let listener = mini_tokio::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            addr.send(listener.local_addr().unwrap()).unwrap();
+            for _ in 0..CONNECTIONS {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                spawn_local(async move {
+                    let mut buf = [0u8; MESSAGE];
+                    loop {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => stream.write_all(&buf[..n]).await.unwrap(),
+                        }
+                    }
+                });
+            }
+        }
+    });
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+fn uring_server(addr: mpsc::Sender<SocketAddr>) {
+    MiniTokio::thread_per_core().shards(1).run(|| {
+        let addr = addr.clone();
+        async move {
+            let listener = mini_tokio::uring::TcpListener::bind("127.0.0.1:0").unwrap();
+            addr.send(listener.local_addr().unwrap()).unwrap();
+            for _ in 0..CONNECTIONS {
+                let (stream, _) = listener.accept().await.unwrap();
+                spawn_local(async move {
+                    let mut buf = vec![0u8; MESSAGE];
+                    loop {
+                        let (n, mut data) = stream.read(buf).await;
+                        match n {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => {
+                                // 只回写读到的部分，写完再恢复成完整的读缓冲区
+                                data.truncate(n);
+                                let (res, mut data) = stream.write_all(data).await;
+                                res.unwrap();
+                                data.resize(MESSAGE, 0);
+                                buf = data;
+                            }
+                        }
+                    }
+                });
+            }
+        }
+    });
+}
+
+fn bench(name: &str, server: fn(mpsc::Sender<SocketAddr>)) {
+    let (sender, receiver) = mpsc::channel();
+    let server = thread::spawn(move || server(sender));
+    let addr = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CONNECTIONS).map(|_| thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+        let message = [7u8; MESSAGE];
+        let mut buf = [0u8; MESSAGE];
+        for _ in 0..ROUNDS {
+            stream.write_all(&message).unwrap();
+            stream.read_exact(&mut buf).unwrap();
+        }
+    })).collect();
+    clients.into_iter().for_each(|client| client.join().unwrap());
+    let elapsed = start.elapsed();
+    server.join().unwrap();
+    let total = CONNECTIONS * ROUNDS;
+    println!("{:<8} {} round trips in {:?}, {:.0} ops/s", name, total, elapsed, total as f64 / elapsed.as_secs_f64());
+}
+
+fn main() {
+    bench("epoll", epoll_server);
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    bench("io_uring", uring_server);
+}
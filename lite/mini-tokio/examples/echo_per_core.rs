@@ -0,0 +1,63 @@
+use std::cell::Cell;
+use std::net::SocketAddr;
+use socket2::{Domain, Protocol, Socket, Type};
+use mini_tokio::net::TcpListener;
+use mini_tokio::shard::{current_shard, submit};
+use mini_tokio::{spawn_local, MiniTokio};
+
+const ADDR: &str = "127.0.0.1:8190";
+
+thread_local! {
+    // 只在0号分片上使用，统计所有分片接受的连接总数
+    static TOTAL: Cell<usize> = const { Cell::new(0) };
+}
+
+/// 每个分片各自绑定同一个端口，由内核通过SO_REUSEPORT在分片之间分发连接，
+/// 分片之间不需要共享监听器
+fn bind_reuse_port(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// 可以用`nc 127.0.0.1 8190`连上去试试
+fn main() {
+    MiniTokio::thread_per_core().run(|| async {
+        let listener = bind_reuse_port(ADDR.parse().unwrap()).unwrap();
+        println!("shard {} listening on {}", current_shard(), ADDR);
+        loop {
+            let (mut stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    println!("accept error: {}", err);
+                    continue;
+                }
+            };
+            let shard = current_shard();
+            // 跨分片消息：把统计交给0号分片去做
+            submit(0, move || {
+                let total = TOTAL.with(|total| {
+                    total.set(total.get() + 1);
+                    total.get()
+                });
+                println!("shard {} accepted {}, total connections: {}", shard, peer, total);
+            });
+            spawn_local(async move {
+                let mut buffer = [0u8; 1024];
+                loop {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(size) => {
+                            if stream.write_all(&buffer[..size]).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    });
+}
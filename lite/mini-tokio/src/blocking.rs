@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// 默认最多同时存在的阻塞线程数
+const DEFAULT_MAX_THREADS: usize = 512;
+/// 默认空闲线程存活时间，超时后线程退出
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+struct State {
+    queue: VecDeque<Job>,
+    threads: usize,
+    idle: usize,
+    // 已经分配给空闲线程、但线程还没醒来的任务数
+    notified: usize,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    condvar: Condvar,
+    max_threads: usize,
+    keep_alive: Duration,
+}
+
+/// 弹性的阻塞线程池：有空闲线程就复用，没有就新建，直到max_threads，
+/// 超过上限的任务排队等待；线程空闲超过keep_alive后自动退出
+#[derive(Clone)]
+pub struct BlockingPool {
+    shared: Arc<Shared>,
+}
+
+impl BlockingPool {
+    pub fn new(max_threads: usize, keep_alive: Duration) -> Self {
+        BlockingPool {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    queue: VecDeque::new(),
+                    threads: 0,
+                    idle: 0,
+                    notified: 0,
+                }),
+                condvar: Condvar::new(),
+                max_threads: max_threads.max(1),
+                keep_alive,
+            }),
+        }
+    }
+
+    /// spawn_blocking使用的全局线程池
+    pub fn global() -> &'static BlockingPool {
+        static POOL: OnceLock<BlockingPool> = OnceLock::new();
+        POOL.get_or_init(|| BlockingPool::new(DEFAULT_MAX_THREADS, DEFAULT_KEEP_ALIVE))
+    }
+
+    /// 当前存活的线程数
+    pub fn threads(&self) -> usize {
+        self.shared.state.lock().unwrap().threads
+    }
+
+    pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T> where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+        let inner = Arc::new(Mutex::new(Inner { result: None, waker: None }));
+        let inner0 = inner.clone();
+        let job: Job = Box::new(move || {
+            // 任务panic不能带走工作线程，把panic交给等待的一方
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            let mut inner = inner0.lock().unwrap();
+            inner.result = Some(result);
+            if let Some(waker) = inner.waker.take() {
+                waker.wake();
+            }
+        });
+        let mut state = self.shared.state.lock().unwrap();
+        state.queue.push_back(job);
+        if state.idle > state.notified {
+            state.notified += 1;
+            self.shared.condvar.notify_one();
+        } else if state.threads < self.shared.max_threads {
+            state.threads += 1;
+            let shared = self.shared.clone();
+            thread::Builder::new()
+                .name("mini-tokio-blocking".to_string())
+                .spawn(move || shared.run())
+                .unwrap();
+        }
+        JoinHandle { inner }
+    }
+}
+
+impl Shared {
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            while let Some(job) = state.queue.pop_front() {
+                drop(state);
+                job();
+                state = self.state.lock().unwrap();
+            }
+            state.idle += 1;
+            let (guard, timeout) = self.condvar.wait_timeout(state, self.keep_alive).unwrap();
+            state = guard;
+            state.idle -= 1;
+            if state.notified > 0 {
+                state.notified -= 1;
+                continue;
+            }
+            if timeout.timed_out() && state.queue.is_empty() {
+                state.threads -= 1;
+                return;
+            }
+        }
+    }
+}
+
+struct Inner<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// spawn_blocking返回的句柄，await得到闭包的返回值，闭包panic时在await处继续panic
+pub struct JoinHandle<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.result.take() {
+            Some(Ok(val)) => Poll::Ready(val),
+            Some(Err(err)) => panic::resume_unwind(err),
+            None => {
+                match &inner.waker {
+                    Some(waker) if waker.will_wake(cx.waker()) => {}
+                    _ => inner.waker = Some(cx.waker().clone()),
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// 在阻塞线程池上执行闭包，避免文件I/O、大量计算之类的阻塞操作卡住执行器线程
+pub fn spawn_blocking<F, T>(f: F) -> JoinHandle<T> where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    BlockingPool::global().spawn(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use futures::executor::block_on;
+    use crate::blocking::{spawn_blocking, BlockingPool};
+
+    #[test]
+    fn test_spawn_blocking() {
+        let ans = block_on(spawn_blocking(|| (1..=100).sum::<u64>()));
+        assert_eq!(ans, 5050);
+    }
+
+    #[test]
+    fn test_max_threads_and_keep_alive() {
+        let pool = BlockingPool::new(2, Duration::from_millis(50));
+        let start = Instant::now();
+        let handles: Vec<_> = (0..4).map(|i| pool.spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            i
+        })).collect();
+        assert_eq!(pool.threads(), 2);
+        let ans: Vec<_> = handles.into_iter().map(block_on).collect();
+        assert_eq!(ans, vec![0, 1, 2, 3]);
+        // 只有两个线程，四个任务至少要跑两轮
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(pool.threads(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "boom")]
+    fn test_panic() {
+        block_on(spawn_blocking(|| panic!("boom")))
+    }
+}
@@ -0,0 +1,51 @@
+//! 基于阻塞线程池的异步文件操作，接口和std::fs保持一致
+
+use std::fs::Metadata;
+use std::io;
+use std::path::Path;
+use crate::blocking::spawn_blocking;
+
+pub async fn read(path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+    let path = path.as_ref().to_owned();
+    spawn_blocking(move || std::fs::read(path)).await
+}
+
+pub async fn read_to_string(path: impl AsRef<Path>) -> io::Result<String> {
+    let path = path.as_ref().to_owned();
+    spawn_blocking(move || std::fs::read_to_string(path)).await
+}
+
+pub async fn write(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let path = path.as_ref().to_owned();
+    let contents = contents.as_ref().to_owned();
+    spawn_blocking(move || std::fs::write(path, contents)).await
+}
+
+pub async fn metadata(path: impl AsRef<Path>) -> io::Result<Metadata> {
+    let path = path.as_ref().to_owned();
+    spawn_blocking(move || std::fs::metadata(path)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::{fs, MiniTokio};
+
+    #[test]
+    fn test_read_write() {
+        let path = std::env::temp_dir().join(format!("mini-tokio-fs-{}", std::process::id()));
+        let ans = Arc::new(Mutex::new(None));
+        let ans0 = ans.clone();
+        MiniTokio::thread_per_core().shards(1).pin(false).run(|| {
+            let (path, ans) = (path.clone(), ans0.clone());
+            async move {
+                fs::write(&path, "hello mini-tokio").await.unwrap();
+                let len = fs::metadata(&path).await.unwrap().len();
+                let content = fs::read_to_string(&path).await.unwrap();
+                *ans.lock().unwrap() = Some((len, content));
+            }
+        });
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(*ans.lock().unwrap(), Some((16, "hello mini-tokio".to_string())));
+    }
+}
@@ -0,0 +1,16 @@
+pub mod blocking;
+pub mod fs;
+pub mod net;
+mod reactor;
+mod runtime;
+pub mod shard;
+pub mod sim;
+mod task;
+pub mod time;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
+
+pub use blocking::spawn_blocking;
+pub use runtime::MiniTokio;
+pub use shard::{spawn_local, ThreadPerCore};
+pub use time::Delay;
@@ -0,0 +1,98 @@
+use std::future::poll_fn;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use mio::{Interest, Token};
+use crate::reactor::Reactor;
+
+/// 异步TCP监听器，依赖分片线程上的反应器，只能在thread-per-core运行时中使用
+pub struct TcpListener {
+    inner: mio::net::TcpListener,
+    token: Token,
+}
+
+impl TcpListener {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<TcpListener> {
+        TcpListener::from_std(std::net::TcpListener::bind(addr)?)
+    }
+
+    /// 从已经绑定好的标准库监听器创建，比如设置了SO_REUSEPORT的socket
+    pub fn from_std(listener: std::net::TcpListener) -> io::Result<TcpListener> {
+        listener.set_nonblocking(true)?;
+        let mut inner = mio::net::TcpListener::from_std(listener);
+        let token = Reactor::current().register(&mut inner, Interest::READABLE)?;
+        Ok(TcpListener { inner, token })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let reactor = Reactor::current();
+        let (stream, addr) = poll_fn(|cx| {
+            reactor.poll_io(self.token, Interest::READABLE, cx, || self.inner.accept())
+        }).await?;
+        Ok((TcpStream::new(stream)?, addr))
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        if let Some(reactor) = Reactor::try_current() {
+            reactor.deregister(&mut self.inner, self.token);
+        }
+    }
+}
+
+pub struct TcpStream {
+    inner: mio::net::TcpStream,
+    token: Token,
+}
+
+impl TcpStream {
+    fn new(mut inner: mio::net::TcpStream) -> io::Result<TcpStream> {
+        let token = Reactor::current().register(&mut inner, Interest::READABLE | Interest::WRITABLE)?;
+        Ok(TcpStream { inner, token })
+    }
+
+    pub fn from_std(stream: std::net::TcpStream) -> io::Result<TcpStream> {
+        stream.set_nonblocking(true)?;
+        TcpStream::new(mio::net::TcpStream::from_std(stream))
+    }
+
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let reactor = Reactor::current();
+        poll_fn(|cx| {
+            reactor.poll_io(self.token, Interest::READABLE, cx, || self.inner.read(buf))
+        }).await
+    }
+
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let reactor = Reactor::current();
+        poll_fn(|cx| {
+            reactor.poll_io(self.token, Interest::WRITABLE, cx, || self.inner.write(buf))
+        }).await
+    }
+
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf).await? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        if let Some(reactor) = Reactor::try_current() {
+            reactor.deregister(&mut self.inner, self.token);
+        }
+    }
+}
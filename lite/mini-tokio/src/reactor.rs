@@ -0,0 +1,127 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use mio::event::Source;
+use mio::{Events, Interest, Token};
+
+/// mio::Waker使用的token，用来把阻塞在epoll上的分片线程叫醒
+pub(crate) const WAKE_TOKEN: Token = Token(usize::MAX);
+
+#[derive(Default)]
+struct Registration {
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+/// 基于epoll(mio)的I/O反应器，每个分片线程一个，只在本线程内使用
+pub(crate) struct Reactor {
+    poll: RefCell<mio::Poll>,
+    events: RefCell<Events>,
+    registrations: RefCell<HashMap<Token, Registration>>,
+    next_token: Cell<usize>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Rc<Reactor>>> = const { RefCell::new(None) };
+}
+
+impl Reactor {
+    pub(crate) fn new(poll: mio::Poll) -> Self {
+        Reactor {
+            poll: RefCell::new(poll),
+            events: RefCell::new(Events::with_capacity(1024)),
+            registrations: RefCell::new(HashMap::new()),
+            next_token: Cell::new(0),
+        }
+    }
+
+    pub(crate) fn set_current(reactor: Option<Rc<Reactor>>) {
+        CURRENT.with(|curr| *curr.borrow_mut() = reactor);
+    }
+
+    /// 当前线程的反应器，只有thread-per-core的分片线程上才有
+    pub(crate) fn current() -> Rc<Reactor> {
+        Reactor::try_current().expect("mini-tokio net types must be used on a thread-per-core shard")
+    }
+
+    pub(crate) fn try_current() -> Option<Rc<Reactor>> {
+        CURRENT.try_with(|curr| curr.borrow().clone()).ok().flatten()
+    }
+
+    pub(crate) fn register(&self, source: &mut impl Source, interest: Interest) -> io::Result<Token> {
+        let token = Token(self.next_token.get() + 1);
+        self.next_token.set(token.0);
+        self.poll.borrow().registry().register(source, token, interest)?;
+        self.registrations.borrow_mut().insert(token, Registration::default());
+        Ok(token)
+    }
+
+    pub(crate) fn deregister(&self, source: &mut impl Source, token: Token) {
+        let _ = self.poll.borrow().registry().deregister(source);
+        self.registrations.borrow_mut().remove(&token);
+    }
+
+    #[cfg(all(test, target_os = "linux", feature = "io-uring"))]
+    pub(crate) fn is_registered(&self, token: Token) -> bool {
+        self.registrations.borrow().contains_key(&token)
+    }
+
+    /// 先直接尝试I/O操作，返回WouldBlock时才登记waker，等epoll通知就绪后再重试。
+    /// 反应器只在任务poll的间隙处理事件，所以先尝试后登记不会丢失通知
+    pub(crate) fn poll_io<T>(
+        &self,
+        token: Token,
+        interest: Interest,
+        cx: &mut Context<'_>,
+        mut op: impl FnMut() -> io::Result<T>,
+    ) -> Poll<io::Result<T>> {
+        match op() {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                let mut registrations = self.registrations.borrow_mut();
+                let registration = registrations.entry(token).or_default();
+                let slot = if interest.is_readable() {
+                    &mut registration.reader
+                } else {
+                    &mut registration.writer
+                };
+                match slot {
+                    Some(waker) if waker.will_wake(cx.waker()) => {}
+                    _ => *slot = Some(cx.waker().clone()),
+                }
+                Poll::Pending
+            }
+            ans => Poll::Ready(ans),
+        }
+    }
+
+    /// 等待I/O事件，最多等待timeout，None表示一直等到有事件或被叫醒
+    pub(crate) fn turn(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let mut events = self.events.borrow_mut();
+        if let Err(err) = self.poll.borrow_mut().poll(&mut events, timeout) {
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        let mut registrations = self.registrations.borrow_mut();
+        let mut wakers = Vec::new();
+        for event in events.iter() {
+            if event.token() == WAKE_TOKEN {
+                continue;
+            }
+            if let Some(registration) = registrations.get_mut(&event.token()) {
+                if event.is_readable() || event.is_read_closed() || event.is_error() {
+                    wakers.extend(registration.reader.take());
+                }
+                if event.is_writable() || event.is_write_closed() || event.is_error() {
+                    wakers.extend(registration.writer.take());
+                }
+            }
+        }
+        drop(registrations);
+        wakers.into_iter().for_each(Waker::wake);
+        Ok(())
+    }
+}
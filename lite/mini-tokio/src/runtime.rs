@@ -0,0 +1,120 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::sync::Arc;
+use crossbeam::channel;
+use crate::shard::ThreadPerCore;
+use crate::sim::Simulation;
+use crate::task::Task;
+
+thread_local! {
+    // 当前线程正在运行的模拟器，Delay通过它判断应该走虚拟时钟还是真实时钟
+    static SIMULATION: RefCell<Option<Arc<Simulation>>> = const { RefCell::new(None) };
+}
+
+/// 获取当前线程上正在运行的模拟器，非模拟模式下返回None
+pub(crate) fn current_simulation() -> Option<Arc<Simulation>> {
+    SIMULATION.with(|sim| sim.borrow().clone())
+}
+
+/// 在run期间把模拟器挂到线程上，退出(包括panic)时卸下
+struct EnterGuard;
+
+impl EnterGuard {
+    fn enter(sim: Arc<Simulation>) -> Self {
+        SIMULATION.with(|curr| *curr.borrow_mut() = Some(sim));
+        EnterGuard
+    }
+}
+
+impl Drop for EnterGuard {
+    fn drop(&mut self) {
+        SIMULATION.with(|curr| {
+            if let Some(sim) = curr.borrow_mut().take() {
+                if std::thread::panicking() {
+                    // 失败的运行可以通过相同的种子精确重放
+                    eprintln!("simulation failed, replay with {}={}", crate::sim::SEED_ENV, sim.seed());
+                }
+            }
+        });
+    }
+}
+
+pub struct MiniTokio {
+    head: channel::Receiver<Arc<Task>>,
+    tail: channel::Sender<Arc<Task>>,
+    simulation: Option<Arc<Simulation>>,
+}
+
+impl MiniTokio {
+    pub fn new() -> MiniTokio {
+        let (sender, receiver) = channel::unbounded();
+        MiniTokio {
+            head: receiver,
+            tail: sender,
+            simulation: None,
+        }
+    }
+
+    /// 创建一个单线程的确定性模拟运行时：
+    /// Delay不再真实睡眠而是推进虚拟时钟，任务的调度顺序由种子决定，
+    /// 所以同一个种子总能得到完全相同的执行过程
+    pub fn simulation(seed: u64) -> MiniTokio {
+        let mut mini_tokio = MiniTokio::new();
+        mini_tokio.simulation = Some(Arc::new(Simulation::new(seed)));
+        mini_tokio
+    }
+
+    /// 种子从环境变量读取，没有设置的话随机生成一个并打印出来，方便重放
+    pub fn simulation_from_env() -> MiniTokio {
+        MiniTokio::simulation(crate::sim::seed_from_env())
+    }
+
+    /// thread-per-core风格的运行时，每个CPU一个绑核的分片，详见ThreadPerCore
+    pub fn thread_per_core() -> ThreadPerCore {
+        ThreadPerCore::new()
+    }
+
+    pub fn run(&self) {
+        match &self.simulation {
+            Some(sim) => self.run_simulation(sim.clone()),
+            None => {
+                // 从任务队列拉取任务并执行
+                while let Ok(task) = self.head.recv() {
+                    task.poll();
+                }
+            }
+        }
+    }
+
+    /// 模拟模式下，没有就绪任务时直接把时钟拨到下一个定时器的截止时间，
+    /// 既没有就绪任务也没有定时器时说明所有任务都已完成(或永远不会被唤醒)，退出
+    fn run_simulation(&self, sim: Arc<Simulation>) {
+        let _guard = EnterGuard::enter(sim.clone());
+        let mut ready = Vec::new();
+        loop {
+            while let Ok(task) = self.head.try_recv() {
+                ready.push(task);
+            }
+            if ready.is_empty() {
+                if sim.advance() {
+                    continue;
+                }
+                break;
+            }
+            // 从就绪任务中按种子挑一个执行，以此模拟不同的调度顺序
+            let idx = sim.next_index(ready.len());
+            let task = ready.swap_remove(idx);
+            task.poll();
+        }
+    }
+
+    pub fn spawn<F>(&self, future: F) where F: Future<Output = ()> + Send + 'static {
+        Task::spawn(future, &self.tail)
+    }
+}
+
+impl Default for MiniTokio {
+    fn default() -> Self {
+        MiniTokio::new()
+    }
+}
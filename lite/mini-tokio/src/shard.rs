@@ -0,0 +1,296 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::Context;
+use std::thread;
+use std::time::Duration;
+use crossbeam::channel;
+use futures::channel::oneshot;
+use futures::task::{self, ArcWake};
+use crate::reactor::{Reactor, WAKE_TOKEN};
+
+type Job = Box<dyn FnOnce() + Send>;
+type LocalFuture = Pin<Box<dyn Future<Output = ()>>>;
+
+/// 其他线程(包括其他分片)访问某个分片的入口：投递闭包、唤醒任务都通过它完成
+#[derive(Clone)]
+struct Remote {
+    inbox: channel::Sender<Job>,
+    woken: channel::Sender<usize>,
+    waker: Arc<mio::Waker>,
+}
+
+impl Remote {
+    fn wake(&self) {
+        let _ = self.waker.wake();
+    }
+}
+
+/// 本地任务的waker只记录任务id，唤醒时把id投递回所属分片的就绪队列。
+/// 这样任务本身可以是!Send的，而waker依然可以跨线程传递
+struct TaskWaker {
+    id: usize,
+    remote: Remote,
+}
+
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        if arc_self.remote.woken.send(arc_self.id).is_ok() {
+            arc_self.remote.wake();
+        }
+    }
+}
+
+/// 每个分片一个的单线程执行器，任务只会在创建它的线程上被poll
+struct Executor {
+    id: usize,
+    shards: Arc<Vec<Remote>>,
+    tasks: RefCell<HashMap<usize, LocalFuture>>,
+    next_id: Cell<usize>,
+    woken: channel::Receiver<usize>,
+    inbox: channel::Receiver<Job>,
+    reactor: Rc<Reactor>,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    uring: Option<Rc<crate::uring::Driver>>,
+}
+
+thread_local! {
+    static EXECUTOR: RefCell<Option<Rc<Executor>>> = const { RefCell::new(None) };
+}
+
+fn current() -> Rc<Executor> {
+    EXECUTOR.with(|curr| curr.borrow().clone())
+        .expect("must be called on a thread-per-core shard")
+}
+
+impl Executor {
+    fn spawn(&self, future: LocalFuture) {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.tasks.borrow_mut().insert(id, future);
+        let _ = self.shards[self.id].woken.send(id);
+    }
+
+    fn poll_task(&self, id: usize) {
+        // 先从表里取出来，poll期间任务可能会spawn_local新的任务
+        let future = self.tasks.borrow_mut().remove(&id);
+        if let Some(mut future) = future {
+            let waker = task::waker(Arc::new(TaskWaker {
+                id,
+                remote: self.shards[self.id].clone(),
+            }));
+            let mut context = Context::from_waker(&waker);
+            if future.as_mut().poll(&mut context).is_pending() {
+                self.tasks.borrow_mut().insert(id, future);
+            }
+        }
+    }
+
+    /// 执行投递来的闭包和就绪的任务，都没有时阻塞在epoll上，所有任务完成后退出
+    fn run(&self) {
+        loop {
+            while let Ok(job) = self.inbox.try_recv() {
+                job();
+            }
+            let mut progressed = false;
+            while let Ok(id) = self.woken.try_recv() {
+                self.poll_task(id);
+                progressed = true;
+            }
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            if let Some(uring) = &self.uring {
+                // 提交攒下的操作，顺便收割已经完成的
+                progressed |= uring.turn();
+            }
+            if self.tasks.borrow().is_empty() && self.inbox.is_empty() {
+                break;
+            }
+            let timeout = if progressed || !self.woken.is_empty() || !self.inbox.is_empty() {
+                Some(Duration::ZERO)
+            } else {
+                None
+            };
+            self.reactor.turn(timeout).unwrap();
+        }
+    }
+}
+
+/// thread-per-core风格的运行时：每个CPU一个绑核的分片线程，各自拥有执行器和epoll反应器，
+/// 分片之间不共享任务队列，只能通过消息传递通信
+pub struct ThreadPerCore {
+    shards: usize,
+    pin: bool,
+}
+
+impl ThreadPerCore {
+    pub(crate) fn new() -> Self {
+        let shards = core_affinity::get_core_ids().map(|ids| ids.len()).unwrap_or(1).max(1);
+        ThreadPerCore { shards, pin: true }
+    }
+
+    /// 指定分片数量，默认等于CPU数量
+    pub fn shards(mut self, shards: usize) -> Self {
+        self.shards = shards.max(1);
+        self
+    }
+
+    /// 是否把分片线程绑定到CPU上，默认绑定
+    pub fn pin(mut self, pin: bool) -> Self {
+        self.pin = pin;
+        self
+    }
+
+    /// 在每个分片上执行init返回的Future，阻塞直到所有分片上的任务都完成
+    pub fn run<F, Fut>(self, init: F) where F: Fn() -> Fut + Sync, Fut: Future<Output = ()> + 'static {
+        let mut polls = Vec::with_capacity(self.shards);
+        let mut remotes = Vec::with_capacity(self.shards);
+        let mut receivers = Vec::with_capacity(self.shards);
+        for _ in 0..self.shards {
+            let poll = mio::Poll::new().unwrap();
+            let waker = Arc::new(mio::Waker::new(poll.registry(), WAKE_TOKEN).unwrap());
+            let (inbox_tx, inbox_rx) = channel::unbounded();
+            let (woken_tx, woken_rx) = channel::unbounded();
+            polls.push(poll);
+            remotes.push(Remote { inbox: inbox_tx, woken: woken_tx, waker });
+            receivers.push((inbox_rx, woken_rx));
+        }
+        let shards = Arc::new(remotes);
+        let core_ids = if self.pin { core_affinity::get_core_ids().unwrap_or_default() } else { Vec::new() };
+        let init = &init;
+        thread::scope(|scope| {
+            for (id, (poll, (inbox, woken))) in polls.into_iter().zip(receivers).enumerate() {
+                let shards = shards.clone();
+                let core_id = core_ids.get(id % core_ids.len().max(1)).copied();
+                thread::Builder::new()
+                    .name(format!("mini-tokio-shard-{}", id))
+                    .spawn_scoped(scope, move || {
+                        if let Some(core_id) = core_id {
+                            core_affinity::set_for_current(core_id);
+                        }
+                        let reactor = Rc::new(Reactor::new(poll));
+                        let executor = Rc::new(Executor {
+                            id,
+                            shards,
+                            tasks: RefCell::new(HashMap::new()),
+                            next_id: Cell::new(0),
+                            woken,
+                            inbox,
+                            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+                            uring: crate::uring::Driver::install(&reactor),
+                            reactor: reactor.clone(),
+                        });
+                        Reactor::set_current(Some(reactor));
+                        EXECUTOR.with(|curr| *curr.borrow_mut() = Some(executor.clone()));
+                        executor.spawn(Box::pin(init()));
+                        executor.run();
+                        // 先丢掉执行器(以及还没完成的任务)，再卸下反应器
+                        EXECUTOR.with(|curr| curr.borrow_mut().take());
+                        drop(executor);
+                        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+                        crate::uring::Driver::uninstall();
+                        Reactor::set_current(None);
+                    })
+                    .unwrap();
+            }
+        });
+    }
+}
+
+/// 在当前分片上创建一个任务，任务不需要是Send的，并且永远不会离开这个分片
+pub fn spawn_local<F>(future: F) where F: Future<Output = ()> + 'static {
+    current().spawn(Box::pin(future));
+}
+
+/// 当前分片的编号
+pub fn current_shard() -> usize {
+    current().id
+}
+
+/// 分片总数
+pub fn shard_count() -> usize {
+    current().shards.len()
+}
+
+/// 把闭包投递到目标分片上执行，闭包里可以访问目标分片的线程局部状态或者spawn_local
+pub fn submit<F>(shard: usize, f: F) where F: FnOnce() + Send + 'static {
+    let remote = &current().shards[shard];
+    if remote.inbox.send(Box::new(f)).is_ok() {
+        remote.wake();
+    }
+}
+
+/// 在目标分片上执行闭包并等待结果，目标分片已经退出时返回None
+pub async fn run_on<F, T>(shard: usize, f: F) -> Option<T> where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    let (sender, receiver) = oneshot::channel();
+    submit(shard, move || {
+        let _ = sender.send(f());
+    });
+    receiver.await.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::net::{TcpListener, TcpStream};
+    use crate::shard::{current_shard, run_on, shard_count, spawn_local};
+    use crate::time::sleep;
+    use crate::MiniTokio;
+
+    #[test]
+    fn test_spawn_local_and_message() {
+        let sum = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicUsize::new(0));
+        MiniTokio::thread_per_core().shards(3).pin(false).run(|| {
+            let (sum, done) = (sum.clone(), done.clone());
+            async move {
+                // Rc不是Send的，只能用spawn_local
+                let counter = Rc::new(Cell::new(0));
+                for _ in 0..4 {
+                    let counter = counter.clone();
+                    spawn_local(async move {
+                        sleep(Duration::from_millis(10)).await;
+                        counter.set(counter.get() + 1);
+                    });
+                }
+                sleep(Duration::from_millis(50)).await;
+                assert_eq!(counter.get(), 4);
+                // 向下一个分片询问它的编号
+                let next = (current_shard() + 1) % shard_count();
+                let ans = run_on(next, current_shard).await.unwrap();
+                sum.fetch_add(ans, Ordering::SeqCst);
+                // 等所有分片都拿到结果再退出，否则消息可能发给已经退出的分片
+                done.fetch_add(1, Ordering::SeqCst);
+                while done.load(Ordering::SeqCst) < 3 {
+                    sleep(Duration::from_millis(5)).await;
+                }
+            }
+        });
+        assert_eq!(sum.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_echo() {
+        MiniTokio::thread_per_core().shards(1).pin(false).run(|| async {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            spawn_local(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 64];
+                let n = stream.read(&mut buf).await.unwrap();
+                stream.write_all(&buf[..n]).await.unwrap();
+            });
+            let mut stream = TcpStream::from_std(std::net::TcpStream::connect(addr).unwrap()).unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 64];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"hello");
+        });
+    }
+}
@@ -0,0 +1,127 @@
+use std::sync::Mutex;
+use std::task::Waker;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use crate::time::Timers;
+
+/// 重放时使用的环境变量
+pub const SEED_ENV: &str = "MINI_TOKIO_SEED";
+
+pub fn seed_from_env() -> u64 {
+    if let Some(seed) = std::env::var(SEED_ENV).ok().and_then(|s| s.parse().ok()) {
+        return seed;
+    }
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+    println!("simulation seed: {}", seed);
+    seed
+}
+
+/// 确定性模拟需要的全部状态：种子、伪随机数生成器、虚拟时钟和定时器表
+pub(crate) struct Simulation {
+    seed: u64,
+    rng: Mutex<u64>,
+    now: Mutex<Instant>,
+    timers: Mutex<Timers>,
+}
+
+impl Simulation {
+    pub(crate) fn new(seed: u64) -> Self {
+        Simulation {
+            seed,
+            // xorshift的状态不能为0
+            rng: Mutex::new(seed ^ 0x9E37_79B9_7F4A_7C15 | 1),
+            now: Mutex::new(Instant::now()),
+            timers: Mutex::new(Timers::default()),
+        }
+    }
+
+    pub(crate) fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    /// xorshift64*，足够用来打乱调度顺序，而且不需要引入额外的依赖
+    fn next_u64(&self) -> u64 {
+        let mut state = self.rng.lock().unwrap();
+        let mut x = *state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        *state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub(crate) fn next_index(&self, len: usize) -> usize {
+        (self.next_u64() % len as u64) as usize
+    }
+
+    pub(crate) fn timers(&self) -> &Mutex<Timers> {
+        &self.timers
+    }
+
+    /// 把虚拟时钟推进到最早的那个截止时间，并唤醒所有到期的定时器，
+    /// 没有定时器可以推进时返回false
+    pub(crate) fn advance(&self) -> bool {
+        let mut timers = self.timers.lock().unwrap();
+        let when = match timers.next_deadline() {
+            Some(when) => when,
+            None => return false,
+        };
+        {
+            let mut now = self.now.lock().unwrap();
+            if *now < when {
+                *now = when;
+            }
+        }
+        let expired = timers.take_expired(when);
+        drop(timers);
+        expired.into_iter().for_each(Waker::wake);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use crate::time::{now, Delay};
+    use crate::MiniTokio;
+
+    fn trace(seed: u64) -> Vec<String> {
+        let mini_tokio = MiniTokio::simulation(seed);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..8 {
+            let log = log.clone();
+            mini_tokio.spawn(async move {
+                for round in 0..3 {
+                    log.lock().unwrap().push(format!("task{}-{}", i, round));
+                    Delay::new(now() + Duration::from_millis(10)).await;
+                }
+            });
+        }
+        mini_tokio.run();
+        let ans = log.lock().unwrap().clone();
+        ans
+    }
+
+    #[test]
+    fn test_replay() {
+        assert_eq!(trace(42), trace(42));
+        assert_ne!(trace(1), trace(2));
+    }
+
+    #[test]
+    fn test_virtual_time() {
+        let mini_tokio = MiniTokio::simulation(7);
+        let start = Instant::now();
+        mini_tokio.spawn(async {
+            let begin = now();
+            Delay::new(begin + Duration::from_secs(3600)).await;
+            assert_eq!(now() - begin, Duration::from_secs(3600));
+        });
+        mini_tokio.run();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,48 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::Context;
+use crossbeam::channel;
+use futures::task;
+use futures::task::ArcWake;
+
+pub(crate) struct Task {
+    // 任务完成后置为None，之后即使再被唤醒也不会重复poll已经完成的Future
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    tail: channel::Sender<Arc<Task>>,
+}
+
+impl Task {
+    fn execute(self: &Arc<Task>) {
+        // 唤醒waker的最终实现，就是把它添加到任务队列中等待推进
+        let _ = self.tail.send(self.clone());
+    }
+
+    pub(crate) fn poll(self: Arc<Task>) {
+        // 根据ArcWaker创建一个waker
+        let waker = task::waker(self.clone());
+        // 创建对应的上下文，或者可以理解成一个waker包装器
+        let mut context = Context::from_waker(&waker);
+        let mut slot = self.future.try_lock().unwrap();
+        if let Some(future) = slot.as_mut() {
+            // 触发Future的poll
+            if future.as_mut().poll(&mut context).is_ready() {
+                *slot = None;
+            }
+        }
+    }
+
+    pub(crate) fn spawn<F>(future: F, tail: &channel::Sender<Arc<Task>>) where F: Future<Output = ()> + Send + 'static {
+        let task = Arc::new(Task{
+            future: Mutex::new(Some(Box::pin(future))),
+            tail: tail.clone(),
+        });
+        task.execute();
+    }
+}
+
+impl ArcWake for Task {
+    fn wake_by_ref(arc_self: &Arc<Task>) {
+        arc_self.execute()
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::runtime::current_simulation;
+use crate::sim::Simulation;
+
+/// 当前时间，模拟模式下返回虚拟时钟
+pub fn now() -> Instant {
+    match current_simulation() {
+        Some(sim) => sim.now(),
+        None => Instant::now(),
+    }
+}
+
+pub fn sleep(duration: Duration) -> Delay {
+    Delay::new(now() + duration)
+}
+
+/// 定时器表，按(截止时间, 定时器id)排序，相同截止时间的定时器按注册顺序触发。
+/// 真实时钟的驱动线程和模拟器共用这一个结构
+#[derive(Default)]
+pub(crate) struct Timers {
+    entries: BTreeMap<(Instant, u64), Waker>,
+}
+
+impl Timers {
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.entries.keys().next().map(|(when, _)| *when)
+    }
+
+    /// 取出所有截止时间不晚于now的定时器
+    pub(crate) fn take_expired(&mut self, now: Instant) -> Vec<Waker> {
+        let rest = self.entries.split_off(&(now, u64::MAX));
+        let expired = std::mem::replace(&mut self.entries, rest);
+        expired.into_values().collect()
+    }
+}
+
+/// 真实时钟下所有Delay共享一个驱动线程，线程睡到最早的截止时间，唤醒到期的定时器
+struct Driver {
+    timers: Mutex<Timers>,
+    condvar: Condvar,
+}
+
+impl Driver {
+    fn get() -> &'static Driver {
+        static DRIVER: OnceLock<&'static Driver> = OnceLock::new();
+        DRIVER.get_or_init(|| {
+            let driver: &'static Driver = Box::leak(Box::new(Driver {
+                timers: Mutex::new(Timers::default()),
+                condvar: Condvar::new(),
+            }));
+            thread::Builder::new()
+                .name("mini-tokio-timer".to_string())
+                .spawn(move || driver.run())
+                .unwrap();
+            driver
+        })
+    }
+
+    fn run(&self) {
+        let mut timers = self.timers.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let expired = timers.take_expired(now);
+            if !expired.is_empty() {
+                // 唤醒时不持有锁，避免和poll中的注册互相等待
+                drop(timers);
+                expired.into_iter().for_each(Waker::wake);
+                timers = self.timers.lock().unwrap();
+                continue;
+            }
+            timers = match timers.next_deadline() {
+                Some(when) => self.condvar.wait_timeout(timers, when - now).unwrap().0,
+                None => self.condvar.wait(timers).unwrap(),
+            };
+        }
+    }
+}
+
+/// Delay注册在哪个定时器表上
+enum Handle {
+    Real(&'static Driver),
+    Sim(Arc<Simulation>),
+}
+
+impl Handle {
+    fn current() -> Handle {
+        match current_simulation() {
+            Some(sim) => Handle::Sim(sim),
+            None => Handle::Real(Driver::get()),
+        }
+    }
+
+    fn now(&self) -> Instant {
+        match self {
+            Handle::Real(_) => Instant::now(),
+            Handle::Sim(sim) => sim.now(),
+        }
+    }
+
+    fn timers(&self) -> &Mutex<Timers> {
+        match self {
+            Handle::Real(driver) => &driver.timers,
+            Handle::Sim(sim) => sim.timers(),
+        }
+    }
+
+    /// 新的截止时间比驱动线程正在等的更早时，需要叫醒它重新计算等待时间
+    fn notify(&self) {
+        if let Handle::Real(driver) = self {
+            driver.condvar.notify_one();
+        }
+    }
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// 只有在截止时间到达时才会被唤醒；被drop时会从定时器表中注销，不会留下后台线程或悬挂的waker
+pub struct Delay {
+    when: Instant,
+    id: u64,
+    handle: Option<Handle>,
+    // 当前登记在定时器表中的截止时间，None表示没有登记
+    registered: Option<Instant>,
+}
+
+impl Delay {
+    pub fn new(deadline: Instant) -> Self {
+        Delay {
+            when: deadline,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            handle: None,
+            registered: None,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.when
+    }
+
+    /// 修改截止时间，已经登记的定时器会原地挪到新的位置，保留原来的waker
+    pub fn reset(&mut self, deadline: Instant) {
+        self.when = deadline;
+        let (handle, registered) = match (&self.handle, self.registered) {
+            (Some(handle), Some(registered)) => (handle, registered),
+            _ => return,
+        };
+        let mut timers = handle.timers().lock().unwrap();
+        if let Some(waker) = timers.entries.remove(&(registered, self.id)) {
+            timers.entries.insert((deadline, self.id), waker);
+            self.registered = Some(deadline);
+        } else {
+            // 旧的定时器已经触发过了，等下一次poll再登记
+            self.registered = None;
+        }
+        drop(timers);
+        if deadline < registered {
+            handle.notify();
+        }
+    }
+
+    fn deregister(&mut self) {
+        if let (Some(handle), Some(registered)) = (&self.handle, self.registered.take()) {
+            handle.timers().lock().unwrap().entries.remove(&(registered, self.id));
+        }
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let handle = this.handle.get_or_insert_with(Handle::current);
+        if handle.now() >= this.when {
+            this.deregister();
+            return Poll::Ready(());
+        }
+        let mut timers = handle.timers().lock().unwrap();
+        let key = (this.when, this.id);
+        match timers.entries.get_mut(&key) {
+            // 已经登记过，只在waker变化时更新，因为Future可能在多个线程之间转移
+            Some(waker) => {
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+            }
+            None => {
+                let earliest = timers.next_deadline().is_none_or(|next| this.when < next);
+                timers.entries.insert(key, cx.waker().clone());
+                this.registered = Some(this.when);
+                drop(timers);
+                if earliest {
+                    handle.notify();
+                }
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Delay {
+    fn drop(&mut self) {
+        self.deregister();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use futures::task::{self, ArcWake};
+    use crate::time::Delay;
+
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl ArcWake for Counter {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn poll(delay: &mut Delay, counter: &Arc<Counter>) -> Poll<()> {
+        let waker = task::waker(counter.clone());
+        Pin::new(delay).poll(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn test_drop_before_fire() {
+        let counter = Arc::new(Counter::default());
+        let mut delay = Delay::new(Instant::now() + Duration::from_millis(50));
+        assert!(poll(&mut delay, &counter).is_pending());
+        drop(delay);
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_repeated_reset() {
+        let counter = Arc::new(Counter::default());
+        let start = Instant::now();
+        let mut delay = Delay::new(start + Duration::from_millis(20));
+        assert!(poll(&mut delay, &counter).is_pending());
+        for i in 1..=10 {
+            delay.reset(start + Duration::from_millis(20 + i * 10));
+        }
+        // 往前拨也要能正确唤醒
+        delay.reset(start + Duration::from_millis(100));
+        while counter.0.load(Ordering::SeqCst) == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(poll(&mut delay, &counter).is_ready());
+        thread::sleep(Duration::from_millis(150));
+        // 之前的截止时间都已经注销，只会被唤醒一次
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_reset_after_ready() {
+        let counter = Arc::new(Counter::default());
+        let mut delay = Delay::new(Instant::now());
+        assert!(poll(&mut delay, &counter).is_ready());
+        delay.reset(Instant::now() + Duration::from_millis(30));
+        assert!(poll(&mut delay, &counter).is_pending());
+        while counter.0.load(Ordering::SeqCst) == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(poll(&mut delay, &counter).is_ready());
+    }
+}
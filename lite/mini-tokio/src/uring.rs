@@ -0,0 +1,412 @@
+//! 基于io_uring的完成式I/O。操作提交到分片线程上的ring，完成后通过任务原本的waker唤醒；
+//! ring的fd注册在epoll反应器上，分片空闲阻塞时同样能被完成事件叫醒。
+//! 读写接口拿走缓冲区的所有权，操作完成后再连同结果一起还回来，
+//! 因为内核可能在Future被drop之后仍然在访问缓冲区
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use io_uring::{opcode, squeue, types, IoUring};
+use mio::unix::SourceFd;
+use mio::{Interest, Token};
+use crate::reactor::Reactor;
+
+const RING_ENTRIES: u32 = 256;
+/// 关闭驱动时提交的取消操作用这个user_data，和操作的下标区分开
+const CANCEL: u64 = u64::MAX;
+
+enum Lifecycle {
+    Waiting(Option<Waker>),
+    Completed(i32),
+    // Future已经被drop，但内核还没完成，缓冲区先寄存在这里直到完成
+    Ignored(#[allow(dead_code)] Box<dyn std::any::Any>),
+}
+
+pub(crate) struct Driver {
+    ring: RefCell<IoUring>,
+    // ring的fd在反应器上的注册，drop时注销
+    reactor: Rc<Reactor>,
+    token: Token,
+    ops: RefCell<Vec<Option<Lifecycle>>>,
+    free: RefCell<Vec<usize>>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Rc<Driver>>> = const { RefCell::new(None) };
+}
+
+impl Driver {
+    /// 在当前分片上创建ring并注册到反应器，内核不支持io_uring时返回None
+    pub(crate) fn install(reactor: &Rc<Reactor>) -> Option<Rc<Driver>> {
+        let ring = IoUring::new(RING_ENTRIES).ok()?;
+        let fd = ring.as_raw_fd();
+        let token = reactor.register(&mut SourceFd(&fd), Interest::READABLE).ok()?;
+        let driver = Rc::new(Driver {
+            ring: RefCell::new(ring),
+            reactor: reactor.clone(),
+            token,
+            ops: RefCell::new(Vec::new()),
+            free: RefCell::new(Vec::new()),
+        });
+        CURRENT.with(|curr| *curr.borrow_mut() = Some(driver.clone()));
+        Some(driver)
+    }
+
+    /// 分片退出时调用，最后一个引用释放时Drop会等内核放弃所有还在进行的操作
+    pub(crate) fn uninstall() {
+        CURRENT.with(|curr| curr.borrow_mut().take());
+    }
+
+    fn current() -> io::Result<Rc<Driver>> {
+        CURRENT.try_with(|curr| curr.borrow().clone()).ok().flatten()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "io_uring is not available on this thread"))
+    }
+
+    fn push(&self, entry: squeue::Entry) -> io::Result<usize> {
+        let index = match self.free.borrow_mut().pop() {
+            Some(index) => index,
+            None => {
+                let mut ops = self.ops.borrow_mut();
+                ops.push(None);
+                ops.len() - 1
+            }
+        };
+        let entry = entry.user_data(index as u64);
+        let mut ring = self.ring.borrow_mut();
+        // 提交队列满了先交给内核腾出位置
+        while unsafe { ring.submission().push(&entry) }.is_err() {
+            // 提交失败时操作没有进入队列，下标还回去
+            if let Err(err) = ring.submit() {
+                self.free.borrow_mut().push(index);
+                return Err(err);
+            }
+        }
+        self.ops.borrow_mut()[index] = Some(Lifecycle::Waiting(None));
+        Ok(index)
+    }
+
+    /// 把提交队列交给内核并收割完成队列，唤醒了任务时返回true
+    pub(crate) fn turn(&self) -> bool {
+        let mut ring = self.ring.borrow_mut();
+        let _ = ring.submit();
+        let mut ops = self.ops.borrow_mut();
+        let mut wakers = Vec::new();
+        for cqe in ring.completion() {
+            let index = cqe.user_data() as usize;
+            match ops[index].take() {
+                Some(Lifecycle::Waiting(waker)) => {
+                    ops[index] = Some(Lifecycle::Completed(cqe.result()));
+                    wakers.extend(waker);
+                }
+                // 没人等的操作直接释放，缓冲区随之drop
+                _ => self.free.borrow_mut().push(index),
+            }
+        }
+        drop(ops);
+        drop(ring);
+        let woke = !wakers.is_empty();
+        wakers.into_iter().for_each(Waker::wake);
+        woke
+    }
+}
+
+impl Drop for Driver {
+    /// 被Future丢下的操作内核可能还在写它的缓冲区，先全部取消并等它们的完成事件到达，再释放缓冲区和ring
+    fn drop(&mut self) {
+        let fd = self.ring.get_mut().as_raw_fd();
+        self.reactor.deregister(&mut SourceFd(&fd), self.token);
+        let ring = self.ring.get_mut();
+        let ops = self.ops.get_mut();
+        let mut pending = 0;
+        for (index, op) in ops.iter().enumerate() {
+            if matches!(op, Some(Lifecycle::Ignored(_))) {
+                let cancel = opcode::AsyncCancel::new(index as u64).build().user_data(CANCEL);
+                while unsafe { ring.submission().push(&cancel) }.is_err() {
+                    if ring.submit().is_err() {
+                        break;
+                    }
+                }
+                pending += 1;
+            }
+        }
+        while pending > 0 {
+            match ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                // 等不到完成事件就不能释放缓冲区，只能泄漏掉
+                Err(_) => {
+                    std::mem::forget(std::mem::take(ops));
+                    return;
+                }
+            }
+            for cqe in ring.completion() {
+                let index = cqe.user_data();
+                if index != CANCEL && ops[index as usize].take().is_some() {
+                    pending -= 1;
+                }
+            }
+        }
+    }
+}
+
+/// 一个正在进行的io_uring操作，data是内核会访问的缓冲区，在操作完成前由Op或驱动持有
+struct Op<T: 'static> {
+    driver: Rc<Driver>,
+    index: Option<usize>,
+    data: Option<Box<T>>,
+}
+
+impl<T: 'static> Op<T> {
+    fn submit(data: T, build: impl FnOnce(&mut T) -> squeue::Entry) -> io::Result<Op<T>> {
+        let driver = Driver::current()?;
+        let mut data = Box::new(data);
+        let index = driver.push(build(&mut data))?;
+        Ok(Op { driver, index: Some(index), data: Some(data) })
+    }
+}
+
+impl<T: 'static> Future for Op<T> {
+    type Output = (io::Result<u32>, T);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let index = this.index.expect("polled after completion");
+        let mut ops = this.driver.ops.borrow_mut();
+        match &mut ops[index] {
+            Some(Lifecycle::Completed(result)) => {
+                let result = *result;
+                ops[index] = None;
+                drop(ops);
+                this.driver.free.borrow_mut().push(index);
+                this.index = None;
+                let ans = if result < 0 {
+                    Err(io::Error::from_raw_os_error(-result))
+                } else {
+                    Ok(result as u32)
+                };
+                Poll::Ready((ans, *this.data.take().unwrap()))
+            }
+            Some(Lifecycle::Waiting(waker)) => {
+                match waker {
+                    Some(waker) if waker.will_wake(cx.waker()) => {}
+                    _ => *waker = Some(cx.waker().clone()),
+                }
+                Poll::Pending
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<T: 'static> Drop for Op<T> {
+    fn drop(&mut self) {
+        let Some(index) = self.index else {
+            return;
+        };
+        let mut ops = self.driver.ops.borrow_mut();
+        match ops[index] {
+            // 完成事件已经收割过了，不会再来，直接释放
+            Some(Lifecycle::Completed(_)) => {
+                ops[index] = None;
+                self.driver.free.borrow_mut().push(index);
+            }
+            _ => {
+                let data: Box<dyn std::any::Any> = self.data.take().unwrap();
+                ops[index] = Some(Lifecycle::Ignored(data));
+            }
+        }
+    }
+}
+
+/// 通过io_uring accept的TCP监听器，只能在开启了io-uring特性的thread-per-core分片上使用
+pub struct TcpListener {
+    inner: std::net::TcpListener,
+}
+
+impl TcpListener {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<TcpListener> {
+        Ok(TcpListener::from_std(std::net::TcpListener::bind(addr)?))
+    }
+
+    pub fn from_std(listener: std::net::TcpListener) -> TcpListener {
+        TcpListener { inner: listener }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let fd = self.inner.as_raw_fd();
+        let op = Op::submit((), |_| {
+            opcode::Accept::new(types::Fd(fd), std::ptr::null_mut(), std::ptr::null_mut()).build()
+        })?;
+        let fd = op.await.0? as RawFd;
+        let stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+        let addr = stream.peer_addr()?;
+        Ok((TcpStream { inner: stream }, addr))
+    }
+}
+
+pub struct TcpStream {
+    inner: std::net::TcpStream,
+}
+
+impl TcpStream {
+    pub fn from_std(stream: std::net::TcpStream) -> TcpStream {
+        TcpStream { inner: stream }
+    }
+
+    /// 读到buf的已初始化部分(0..len)，返回读到的字节数和缓冲区本身
+    pub async fn read(&self, mut buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) {
+        let fd = self.inner.as_raw_fd();
+        let len = buf.len() as u32;
+        let op = match Op::submit(std::mem::take(&mut buf), |buf| {
+            opcode::Read::new(types::Fd(fd), buf.as_mut_ptr(), len).build()
+        }) {
+            Ok(op) => op,
+            Err(err) => return (Err(err), buf),
+        };
+        let (ans, buf) = op.await;
+        (ans.map(|n| n as usize), buf)
+    }
+
+    pub async fn write(&self, buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) {
+        self.write_at(buf, 0).await
+    }
+
+    /// 把buf全部写完再还回来
+    pub async fn write_all(&self, mut buf: Vec<u8>) -> (io::Result<()>, Vec<u8>) {
+        let mut written = 0;
+        while written < buf.len() {
+            let (ans, rest) = self.write_at(buf, written).await;
+            buf = rest;
+            match ans {
+                Ok(0) => return (Err(io::ErrorKind::WriteZero.into()), buf),
+                Ok(n) => written += n,
+                Err(err) => return (Err(err), buf),
+            }
+        }
+        (Ok(()), buf)
+    }
+
+    /// 写buf[offset..]
+    async fn write_at(&self, mut buf: Vec<u8>, offset: usize) -> (io::Result<usize>, Vec<u8>) {
+        let fd = self.inner.as_raw_fd();
+        let len = (buf.len() - offset) as u32;
+        let op = match Op::submit(std::mem::take(&mut buf), |buf| {
+            opcode::Write::new(types::Fd(fd), buf[offset..].as_ptr(), len).build()
+        }) {
+            Ok(op) => op,
+            Err(err) => return (Err(err), buf),
+        };
+        let (ans, buf) = op.await;
+        (ans.map(|n| n as usize), buf)
+    }
+}
+
+/// 通过io_uring的超时操作睡眠，不经过定时器线程
+pub async fn sleep(duration: Duration) -> io::Result<()> {
+    let timespec = types::Timespec::new().sec(duration.as_secs()).nsec(duration.subsec_nanos());
+    let op = Op::submit(timespec, |timespec| opcode::Timeout::new(timespec as *const _).build())?;
+    match op.await.0 {
+        // 超时到期时返回的是-ETIME
+        Err(err) if err.raw_os_error() == Some(libc::ETIME) => Ok(()),
+        ans => ans.map(|_| ()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use crate::uring::{sleep, Driver, Lifecycle, Op, TcpListener};
+    use crate::reactor::Reactor;
+    use crate::{spawn_local, MiniTokio};
+
+    #[test]
+    fn test_echo_and_timeout() {
+        let ans = Arc::new(Mutex::new(Vec::new()));
+        let ans0 = ans.clone();
+        MiniTokio::thread_per_core().shards(1).pin(false).run(|| {
+            let ans = ans0.clone();
+            async move {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = listener.local_addr().unwrap();
+                let client = std::thread::spawn(move || {
+                    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+                    stream.write_all(b"ping").unwrap();
+                    let mut buf = [0u8; 4];
+                    stream.read_exact(&mut buf).unwrap();
+                    buf
+                });
+                let (stream, _) = listener.accept().await.unwrap();
+                spawn_local(async move {
+                    let (n, buf) = stream.read(vec![0u8; 64]).await;
+                    let n = n.unwrap();
+                    let (res, _) = stream.write_all(buf[..n].to_vec()).await;
+                    res.unwrap();
+                });
+                let start = Instant::now();
+                sleep(Duration::from_millis(30)).await.unwrap();
+                assert!(start.elapsed() >= Duration::from_millis(30));
+                // 不能直接join阻塞分片，回显任务还要在这个分片上执行
+                while !client.is_finished() {
+                    sleep(Duration::from_millis(5)).await.unwrap();
+                }
+                ans.lock().unwrap().extend_from_slice(&client.join().unwrap());
+            }
+        });
+        assert_eq!(&*ans.lock().unwrap(), b"ping");
+    }
+
+    #[test]
+    fn test_drop_op() {
+        MiniTokio::thread_per_core().shards(1).pin(false).run(|| async {
+            let driver = Driver::current().unwrap();
+            // 完成之后还没被poll就drop，下标要回收
+            let timespec = io_uring::types::Timespec::new().nsec(1_000_000);
+            let op = Op::submit(timespec, |timespec| io_uring::opcode::Timeout::new(timespec as *const _).build()).unwrap();
+            let index = op.index.unwrap();
+            while !matches!(driver.ops.borrow()[index], Some(Lifecycle::Completed(_))) {
+                sleep(Duration::from_millis(5)).await.unwrap();
+            }
+            drop(op);
+            assert!(driver.ops.borrow()[index].is_none());
+            assert!(driver.free.borrow().contains(&index));
+
+            // 没完成就drop的读操作在分片退出时被取消，不会卡住也不会释放内核还在用的缓冲区
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let stream = crate::uring::TcpStream::from_std(listener.accept().unwrap().0);
+            let mut read = Box::pin(stream.read(vec![0u8; 64]));
+            assert!(futures::poll!(&mut read).is_pending());
+            drop(read);
+            sleep(Duration::from_millis(5)).await.unwrap();
+            assert!(driver.ops.borrow().iter().any(|op| matches!(op, Some(Lifecycle::Ignored(_)))));
+        });
+    }
+
+    #[test]
+    fn test_deregister() {
+        let reactor = Rc::new(Reactor::new(mio::Poll::new().unwrap()));
+        let driver = match Driver::install(&reactor) {
+            Some(driver) => driver,
+            // 内核不支持io_uring
+            None => return,
+        };
+        let token = driver.token;
+        assert!(reactor.is_registered(token));
+        Driver::uninstall();
+        drop(driver);
+        assert!(!reactor.is_registered(token));
+    }
+}
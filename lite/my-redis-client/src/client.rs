@@ -0,0 +1,190 @@
+//! 基于resp的最小客户端，替代原来用的mini_redis::client
+
+use std::sync::Arc;
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use resp::{Connection, Frame, Protocol};
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// S是底层的连接，明文连接是TcpStream，TLS连接是TlsStream<TcpStream>
+pub struct Client<S = TcpStream> {
+    connection: Connection<S>,
+}
+
+pub async fn connect(addr: impl ToSocketAddrs) -> Result<Client> {
+    let socket = TcpStream::connect(addr).await?;
+    Ok(Client { connection: Connection::new(socket) })
+}
+
+/// TLS连接的参数，文件都是PEM格式
+pub struct TlsOptions {
+    /// 校验服务端证书用的CA
+    pub ca_cert_file: String,
+    /// 服务端要求客户端证书(双向TLS)时出示的证书链和私钥
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    /// 校验服务端证书时用的名字，一般是服务端的域名
+    pub server_name: String,
+}
+
+pub async fn connect_tls(addr: impl ToSocketAddrs, options: &TlsOptions) -> Result<Client<TlsStream<TcpStream>>> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(&options.ca_cert_file)? {
+        roots.add(cert?)?;
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let config = match (&options.cert_file, &options.key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            let certs = CertificateDer::pem_file_iter(cert_file)?.collect::<std::result::Result<Vec<_>, _>>()?;
+            builder.with_client_auth_cert(certs, PrivateKeyDer::from_pem_file(key_file)?)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err("cert_file and key_file must be given together".into()),
+    };
+    let server_name = ServerName::try_from(options.server_name.clone())?;
+    let socket = TcpStream::connect(addr).await?;
+    let stream = TlsConnector::from(Arc::new(config)).connect(server_name, socket).await?;
+    Ok(Client { connection: Connection::new(stream) })
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    /// 协商协议版本，返回服务端信息
+    pub async fn hello(&mut self, protocol: Protocol) -> Result<Frame> {
+        let version = if protocol == Protocol::Resp3 { "3" } else { "2" };
+        let info = self.request(&["HELLO", version]).await?;
+        self.connection.set_protocol(protocol);
+        Ok(info)
+    }
+
+    pub async fn get(&mut self, key: &str) -> Result<Option<Bytes>> {
+        match self.request(&["GET", key]).await? {
+            Frame::Bulk(val) => Ok(Some(val)),
+            Frame::Null => Ok(None),
+            frame => Err(format!("unexpected response {:?}", frame).into()),
+        }
+    }
+
+    pub async fn set(&mut self, key: &str, val: Bytes) -> Result<()> {
+        let cmd = Frame::Array(vec![bulk("SET"), bulk(key), Frame::Bulk(val)]);
+        match self.send(&cmd).await? {
+            Frame::Simple(ok) if ok == "OK" => Ok(()),
+            frame => Err(format!("unexpected response {:?}", frame).into()),
+        }
+    }
+
+    async fn request(&mut self, args: &[&str]) -> Result<Frame> {
+        self.send(&Frame::Array(args.iter().map(|arg| bulk(arg)).collect())).await
+    }
+
+    /// 发送命令并等待回复，错误回复转换成Err
+    async fn send(&mut self, cmd: &Frame) -> Result<Frame> {
+        self.connection.write_frame(cmd).await?;
+        loop {
+            match self.connection.read_frame().await? {
+                // RESP3下服务端可能随时推送消息，不是这条命令的回复
+                Some(Frame::Push(_)) => continue,
+                Some(Frame::Attribute(_, frame)) => return unwrap_error(*frame),
+                Some(frame) => return unwrap_error(frame),
+                None => return Err("connection reset by server".into()),
+            }
+        }
+    }
+}
+
+fn bulk(s: &str) -> Frame {
+    Frame::Bulk(Bytes::from(s.to_string()))
+}
+
+fn unwrap_error(frame: Frame) -> Result<Frame> {
+    match frame {
+        Frame::Error(msg) => Err(msg.into()),
+        frame => Ok(frame),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use bytes::Bytes;
+    use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+    use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+    use resp::{Connection, Frame};
+    use crate::client::{connect_tls, TlsOptions};
+
+    fn write(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("my-redis-client-tls-{}-{}.pem", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// 用测试时生成的CA签发服务端和客户端证书，服务端要求客户端证书，对每条命令都回复"v"
+    #[tokio::test]
+    async fn test_connect_tls() {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+        let issue = |name: &str, usage: ExtendedKeyUsagePurpose| {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            params.extended_key_usages = vec![usage];
+            (params.signed_by(&key, &ca, &ca_key).unwrap().pem(), key.serialize_pem())
+        };
+        let (server_cert, server_key) = issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        let (client_cert, client_key) = issue("client", ExtendedKeyUsagePurpose::ClientAuth);
+
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_slice(ca.pem().as_bytes()).unwrap()).unwrap();
+        let config = ServerConfig::builder()
+            .with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build().unwrap())
+            .with_single_cert(
+                vec![CertificateDer::from_pem_slice(server_cert.as_bytes()).unwrap()],
+                PrivateKeyDer::from_pem_slice(server_key.as_bytes()).unwrap(),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut connection = Connection::new(acceptor.accept(socket).await?);
+                    while connection.read_frame().await?.is_some() {
+                        connection.write_frame(&Frame::Bulk(Bytes::from("v"))).await?;
+                    }
+                    Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+                });
+            }
+        });
+
+        let mut options = TlsOptions {
+            ca_cert_file: write("ca", &ca.pem()).to_string_lossy().to_string(),
+            cert_file: Some(write("cert", &client_cert).to_string_lossy().to_string()),
+            key_file: Some(write("key", &client_key).to_string_lossy().to_string()),
+            server_name: "localhost".to_string(),
+        };
+        let mut client = connect_tls(addr, &options).await.unwrap();
+        assert_eq!(client.get("k").await.unwrap(), Some(Bytes::from("v")));
+        // 证书上的名字对不上时握手失败
+        options.server_name = "example.com".to_string();
+        assert!(connect_tls(addr, &options).await.is_err());
+        for path in [&options.ca_cert_file, options.cert_file.as_ref().unwrap(), options.key_file.as_ref().unwrap()] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
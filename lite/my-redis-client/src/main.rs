@@ -1,11 +1,13 @@
 use bytes::Bytes;
-use mini_redis::client;
-use mini_redis::client::Client;
-use mini_redis::Result;
+use resp::Protocol;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use crate::client::{Client, Result, TlsOptions};
 use crate::Cmd::{Get, Set};
 
+mod client;
+
 type Response<T> = oneshot::Sender<Result<T>>;
 
 enum Cmd {
@@ -21,12 +23,43 @@ enum Cmd {
     }
 }
 
+const ADDR: &str = "127.0.0.1:16379";
+
+/// 用法：my-redis-client [--tls --cacert ca.pem [--cert client.pem --key client.key] [--sni localhost]]
 #[tokio::main]
 async fn main() -> Result<()> {
+    match parse_tls_options(std::env::args().skip(1))? {
+        Some(options) => run(client::connect_tls(ADDR, &options).await?, client::connect_tls(ADDR, &options).await?).await,
+        None => run(client::connect(ADDR).await?, client::connect(ADDR).await?).await,
+    }
+}
+
+/// 没有--tls时返回None
+fn parse_tls_options(mut args: impl Iterator<Item = String>) -> Result<Option<TlsOptions>> {
+    let mut tls = false;
+    let mut options = TlsOptions { ca_cert_file: String::new(), cert_file: None, key_file: None, server_name: "localhost".to_string() };
+    while let Some(arg) = args.next() {
+        if arg == "--tls" {
+            tls = true;
+            continue;
+        }
+        let value = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+        match arg.as_str() {
+            "--cacert" => options.ca_cert_file = value,
+            "--cert" => options.cert_file = Some(value),
+            "--key" => options.key_file = Some(value),
+            "--sni" => options.server_name = value,
+            _ => return Err(format!("unexpected argument '{}'", arg).into()),
+        }
+    }
+    Ok(if tls { Some(options) } else { None })
+}
+
+async fn run<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(mut client1: Client<S>, client2: Client<S>) -> Result<()> {
     let (sender1, receiver1) = mpsc::channel(64);
     let (sender2, receiver2) = mpsc::channel(64);
-    let mut client1 = client::connect("127.0.0.1:16379").await.unwrap();
-    let mut client2 = client::connect("127.0.0.1:16379").await.unwrap();
+    // 一个连接用RESP3，一个保持RESP2
+    client1.hello(Protocol::Resp3).await.unwrap();
     let send_manager1 = tokio::spawn(async move {
         send_process(receiver1, client1).await
     });
@@ -40,7 +73,8 @@ async fn main() -> Result<()> {
             key: "key1".to_string(),
             response: resp_sender,
         };
-        sender11.send(cmd).await;
+        // 接收端一直活到send_process结束，发送不会失败
+        let _ = sender11.send(cmd).await;
         // 包装的有点多，所以，解包装也有点多
         let ans = resp_receiver.await.unwrap().unwrap();
         println!("{:?}", ans)
@@ -53,9 +87,9 @@ async fn main() -> Result<()> {
             val: Bytes::from("aaa".to_string()),
             response: resp_sender,
         };
-        sender12.send(cmd).await;
-        let ans = resp_receiver.await.unwrap().unwrap();
-        println!("{:?}", ans)
+        let _ = sender12.send(cmd).await;
+        resp_receiver.await.unwrap().unwrap();
+        println!("OK")
     });
     let sender21 = sender2.clone();
     let operation3 = tokio::spawn(async move {
@@ -64,7 +98,7 @@ async fn main() -> Result<()> {
             key: "key2".to_string(),
             response: resp_sender,
         };
-        sender21.send(cmd).await;
+        let _ = sender21.send(cmd).await;
         let ans = resp_receiver.await.unwrap().unwrap();
         println!("{:?}", ans)
     });
@@ -76,9 +110,9 @@ async fn main() -> Result<()> {
             val: Bytes::from("bbb".to_string()),
             response: resp_sender,
         };
-        sender22.send(cmd).await;
-        let ans = resp_receiver.await.unwrap().unwrap();
-        println!("{:?}", ans)
+        let _ = sender22.send(cmd).await;
+        resp_receiver.await.unwrap().unwrap();
+        println!("OK")
     });
     send_manager1.await.unwrap();
     send_manager2.await.unwrap();
@@ -90,17 +124,17 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn send_process(mut receiver: mpsc::Receiver<Cmd>, mut client: Client) {
+async fn send_process<S: AsyncRead + AsyncWrite + Unpin>(mut receiver: mpsc::Receiver<Cmd>, mut client: Client<S>) {
     while let Some(cmd) = receiver.recv().await {
         match cmd {
             Get {key, response} => {
                 if let Some(resp) = client.get(key.as_str()).await.unwrap() {
-                    response.send(Ok(resp));
+                    let _ = response.send(Ok(resp));
                 }
             }
             Set {key, val, response} => {
                 client.set(key.as_str(), val.clone()).await.unwrap();
-                response.send(Ok(()));
+                let _ = response.send(Ok(()));
             }
         }
     }
@@ -0,0 +1,99 @@
+//! 多客户端并发压测：启动编译好的服务端进程，分别用不同的分片数和工作线程数，
+//! 让一批客户端同时对随机的键做SET/GET，统计每秒完成的命令数，观察吞吐随核数的变化。
+//! `cargo bench --bench throughput`
+
+use std::net::TcpListener;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+
+const CLIENTS: usize = 64;
+const KEYS: usize = 10000;
+const DURATION: Duration = Duration::from_secs(2);
+
+struct ServerProcess(Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+async fn start_server(shards: usize, threads: usize) -> (ServerProcess, String) {
+    // 先占一个空闲端口再释放，交给服务端进程使用
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let child = Command::new(env!("CARGO_BIN_EXE_my-redis-server"))
+        .args(["--port", &port.to_string(), "--shards", &shards.to_string()])
+        // tokio的运行时会读这个环境变量决定工作线程数
+        .env("TOKIO_WORKER_THREADS", threads.to_string())
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let server = ServerProcess(child);
+    let addr = format!("127.0.0.1:{}", port);
+    for _ in 0..100 {
+        if tokio::net::TcpStream::connect(&addr).await.is_ok() {
+            return (server, addr);
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("server did not start on {}", addr);
+}
+
+async fn bench(shards: usize, threads: usize) -> f64 {
+    let (_server, addr) = start_server(shards, threads).await;
+    let stop = Arc::new(AtomicBool::new(false));
+    let ops = Arc::new(AtomicU64::new(0));
+    let clients: Vec<_> = (0..CLIENTS).map(|id| {
+        let (addr, stop, ops) = (addr.clone(), stop.clone(), ops.clone());
+        tokio::spawn(async move {
+            let mut client = mini_redis::client::connect(&addr).await.unwrap();
+            let value = Bytes::from_static(b"value");
+            let mut seed = id as u64 + 1;
+            while !stop.load(Ordering::Relaxed) {
+                // xorshift，够用了
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                let key = format!("key:{}", seed as usize % KEYS);
+                if seed.is_multiple_of(2) {
+                    client.set(&key, value.clone()).await.unwrap();
+                } else {
+                    client.get(&key).await.unwrap();
+                }
+                ops.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+    }).collect();
+    let start = Instant::now();
+    tokio::time::sleep(DURATION).await;
+    stop.store(true, Ordering::Relaxed);
+    let elapsed = start.elapsed();
+    let total = ops.load(Ordering::Relaxed);
+    for client in clients {
+        client.await.unwrap();
+    }
+    total as f64 / elapsed.as_secs_f64()
+}
+
+#[tokio::main]
+async fn main() {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut threads = vec![1];
+    while threads.last().unwrap() * 2 <= cores {
+        threads.push(threads.last().unwrap() * 2);
+    }
+    if *threads.last().unwrap() != cores {
+        threads.push(cores);
+    }
+    println!("{} clients, {} cores", CLIENTS, cores);
+    for shards in [1, 16] {
+        for &n in &threads {
+            let throughput = bench(shards, n).await;
+            println!("shards: {:>2}, threads: {:>2}, {:>10.0} ops/s", shards, n, throughput);
+        }
+    }
+}
@@ -0,0 +1,487 @@
+//! 访问控制：每个连接以某个用户的身份执行命令，用户规定了能执行哪些命令、能访问哪些键和频道。
+//! 规则的写法和Redis的ACL SETUSER一致：on/off、>password、<password、#hash、!hash、nopass、resetpass、
+//! +command、-command、+@category、-@category、allcommands、nocommands、~pattern、allkeys、resetkeys、
+//! &pattern、allchannels、resetchannels、reset。密码只保存SHA256。
+//!
+//! ACL文件每行是`user <name> <rules...>`，空行和#开头的行忽略，ACL SAVE按ACL LIST的格式写回去。
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+use std::sync::RwLock;
+use bytes::Bytes;
+use resp::Frame;
+use sha2::{Digest, Sha256};
+use crate::cmd::{self, Command, CommandSpec, NO_AUTH};
+use crate::glob;
+
+/// 命令类别，CommandSpec里只写数据类型和read、fast这些，write和slow由WRITE标志和FAST推出来
+pub const KEYSPACE: u32 = 1;
+pub const READ: u32 = 1 << 1;
+pub const WRITE: u32 = 1 << 2;
+pub const STRING: u32 = 1 << 3;
+pub const LIST: u32 = 1 << 4;
+pub const HASH: u32 = 1 << 5;
+pub const SET: u32 = 1 << 6;
+pub const SORTEDSET: u32 = 1 << 7;
+pub const PUBSUB: u32 = 1 << 8;
+pub const ADMIN: u32 = 1 << 9;
+pub const FAST: u32 = 1 << 10;
+pub const SLOW: u32 = 1 << 11;
+pub const DANGEROUS: u32 = 1 << 12;
+pub const CONNECTION: u32 = 1 << 13;
+pub const TRANSACTION: u32 = 1 << 14;
+pub const SCRIPTING: u32 = 1 << 15;
+
+/// ACL CAT按这个顺序列出
+pub const CATEGORIES: &[(&str, u32)] = &[
+    ("keyspace", KEYSPACE),
+    ("read", READ),
+    ("write", WRITE),
+    ("string", STRING),
+    ("list", LIST),
+    ("hash", HASH),
+    ("set", SET),
+    ("sortedset", SORTEDSET),
+    ("pubsub", PUBSUB),
+    ("admin", ADMIN),
+    ("fast", FAST),
+    ("slow", SLOW),
+    ("dangerous", DANGEROUS),
+    ("connection", CONNECTION),
+    ("transaction", TRANSACTION),
+    ("scripting", SCRIPTING),
+];
+
+/// 命令所属的全部类别
+pub fn categories(spec: &CommandSpec) -> u32 {
+    let mut categories = spec.categories;
+    if spec.has(cmd::WRITE) {
+        categories |= WRITE;
+    }
+    if categories & FAST == 0 {
+        categories |= SLOW;
+    }
+    categories
+}
+
+pub fn category(name: &str) -> Option<u32> {
+    CATEGORIES.iter().find(|(category, _)| category.eq_ignore_ascii_case(name)).map(|(_, bit)| *bit)
+}
+
+pub fn noauth() -> Frame {
+    Frame::error("NOAUTH Authentication required.")
+}
+
+pub fn wrongpass() -> Frame {
+    Frame::error("WRONGPASS invalid username-password pair or user is disabled.")
+}
+
+fn hash_password(password: &[u8]) -> String {
+    Sha256::digest(password).iter().fold(String::with_capacity(64), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct User {
+    pub name: String,
+    enabled: bool,
+    nopass: bool,
+    // 密码的SHA256，十六进制小写
+    passwords: BTreeSet<String>,
+    // 按命令规则依次算出来的可以执行的命令
+    commands: HashSet<&'static str>,
+    // 生效的命令规则，ACL LIST原样输出，+@all和-@all会清掉之前的规则
+    command_rules: Vec<String>,
+    keys: Vec<Bytes>,
+    channels: Vec<Bytes>,
+}
+
+impl User {
+    /// 新用户：关闭、没有密码、不能执行任何命令、不能访问任何键和频道
+    pub fn new(name: &str) -> User {
+        User {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: BTreeSet::new(),
+            commands: HashSet::new(),
+            command_rules: vec!["-@all".to_string()],
+            keys: Vec::new(),
+            channels: Vec::new(),
+        }
+    }
+
+    /// 默认用户：不需要密码，可以执行所有命令
+    fn default_user() -> User {
+        let mut user = User::new("default");
+        for rule in ["on", "nopass", "allkeys", "allchannels", "allcommands"] {
+            user.apply(rule.as_bytes()).unwrap();
+        }
+        user
+    }
+
+    /// 应用一条规则，出错时返回错误原因，用户可能已经被改了一部分，调用方要在副本上操作
+    pub fn apply(&mut self, rule: &[u8]) -> Result<(), String> {
+        let text = String::from_utf8_lossy(rule);
+        match text.to_ascii_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.keys = vec![Bytes::from_static(b"*")],
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => self.channels = vec![Bytes::from_static(b"*")],
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => self.command_rule("+@all")?,
+            "nocommands" => self.command_rule("-@all")?,
+            "reset" => *self = User::new(&self.name),
+            _ => match rule.first() {
+                Some(b'>') => {
+                    self.passwords.insert(hash_password(&rule[1..]));
+                    self.nopass = false;
+                }
+                Some(b'<') => {
+                    if !self.passwords.remove(&hash_password(&rule[1..])) {
+                        return Err("The password you are trying to remove from the user does not exist".to_string());
+                    }
+                }
+                Some(b'#') | Some(b'!') => {
+                    let hash = &text[1..];
+                    if hash.len() != 64 || !hash.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f')) {
+                        return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters".to_string());
+                    }
+                    if rule[0] == b'#' {
+                        self.passwords.insert(hash.to_string());
+                        self.nopass = false;
+                    } else if !self.passwords.remove(hash) {
+                        return Err("The password you are trying to remove from the user does not exist".to_string());
+                    }
+                }
+                Some(b'~') => add_pattern(&mut self.keys, &rule[1..]),
+                Some(b'&') => add_pattern(&mut self.channels, &rule[1..]),
+                Some(b'+') | Some(b'-') => self.command_rule(&text)?,
+                _ => return Err("Syntax error".to_string()),
+            },
+        }
+        Ok(())
+    }
+
+    fn command_rule(&mut self, rule: &str) -> Result<(), String> {
+        let (allow, name) = (rule.starts_with('+'), rule[1..].to_ascii_lowercase());
+        let bits = match name.strip_prefix('@') {
+            Some("all") => u32::MAX,
+            Some(name) => category(name).ok_or_else(|| "Unknown command category".to_string())?,
+            None => 0,
+        };
+        let specs: Vec<&CommandSpec> = match bits {
+            0 => vec![cmd::lookup(&name).ok_or_else(|| "Unknown command".to_string())?],
+            bits => cmd::commands().filter(|spec| categories(spec) & bits != 0).collect(),
+        };
+        for spec in specs {
+            if allow {
+                self.commands.insert(spec.name);
+            } else {
+                self.commands.remove(spec.name);
+            }
+        }
+        if bits == u32::MAX {
+            self.command_rules.clear();
+        }
+        self.command_rules.push(format!("{}{}", &rule[..1], name));
+        Ok(())
+    }
+
+    fn authenticate(&self, password: &[u8]) -> bool {
+        self.enabled && (self.nopass || self.passwords.contains(&hash_password(password)))
+    }
+
+    /// 检查能不能执行这条命令，cmd的参数个数可能不对，不能假设参数存在
+    pub fn check(&self, spec: &CommandSpec, cmd: &Command) -> Result<(), Frame> {
+        if !self.commands.contains(spec.name) {
+            return Err(Frame::error(format!("NOPERM User {} has no permissions to run the '{}' command", self.name, spec.name)));
+        }
+        if !command_keys(spec, cmd).all(|key| self.keys.iter().any(|pattern| glob::matches(pattern, key))) {
+            return Err(Frame::error("NOPERM No permissions to access a key"));
+        }
+        let allowed = match spec.name {
+            "publish" => cmd.args.iter().take(1).all(|channel| self.channels.iter().any(|pattern| glob::matches(pattern, channel))),
+            "subscribe" => cmd.args.iter().all(|channel| self.channels.iter().any(|pattern| glob::matches(pattern, channel))),
+            // 模式本身不能当成频道去匹配，只能是允许的模式之一
+            "psubscribe" => cmd.args.iter().all(|channel| self.channels.iter().any(|pattern| &pattern[..] == b"*" || pattern == channel)),
+            _ => true,
+        };
+        if !allowed {
+            return Err(Frame::error("NOPERM No permissions to access a channel"));
+        }
+        Ok(())
+    }
+
+    fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    /// ACL GETUSER的回复
+    pub fn info(&self) -> Frame {
+        let bulk = |s: String| Frame::Bulk(Bytes::from(s));
+        let patterns = |prefix: char, patterns: &[Bytes]| {
+            patterns.iter().map(|pattern| format!("{}{}", prefix, String::from_utf8_lossy(pattern))).collect::<Vec<_>>().join(" ")
+        };
+        Frame::Map(vec![
+            (bulk("flags".to_string()), Frame::Array(self.flags().into_iter().map(|flag| bulk(flag.to_string())).collect())),
+            (bulk("passwords".to_string()), Frame::Array(self.passwords.iter().cloned().map(bulk).collect())),
+            (bulk("commands".to_string()), bulk(self.command_rules.join(" "))),
+            (bulk("keys".to_string()), bulk(patterns('~', &self.keys))),
+            (bulk("channels".to_string()), bulk(patterns('&', &self.channels))),
+        ])
+    }
+
+    /// ACL LIST里的一行，重新应用这些规则可以得到同样的用户
+    pub fn describe(&self) -> String {
+        let mut rules = vec!["user".to_string(), self.name.clone()];
+        rules.extend(self.flags().into_iter().map(String::from));
+        rules.extend(self.passwords.iter().map(|hash| format!("#{}", hash)));
+        rules.extend(self.keys.iter().map(|pattern| format!("~{}", String::from_utf8_lossy(pattern))));
+        if self.channels.is_empty() {
+            rules.push("resetchannels".to_string());
+        }
+        rules.extend(self.channels.iter().map(|pattern| format!("&{}", String::from_utf8_lossy(pattern))));
+        rules.extend(self.command_rules.iter().cloned());
+        rules.join(" ")
+    }
+}
+
+/// *会覆盖其他所有模式
+fn add_pattern(patterns: &mut Vec<Bytes>, pattern: &[u8]) {
+    if pattern == b"*" {
+        *patterns = vec![Bytes::from_static(b"*")];
+    } else if !patterns.iter().any(|existing| existing == pattern) {
+        patterns.push(Bytes::copy_from_slice(pattern));
+    }
+}
+
+/// 命令访问的键，MIGRATE的键不在KeySpec里，单独处理
+fn command_keys<'a>(spec: &CommandSpec, cmd: &'a Command) -> Box<dyn Iterator<Item = &'a Bytes> + 'a> {
+    if spec.name != "migrate" {
+        return Box::new(spec.keys.keys(&cmd.args));
+    }
+    let keys = cmd.args.iter().position(|arg| cmd::eq_ignore_case(arg, "keys")).map_or(&[][..], |idx| &cmd.args[idx + 1..]);
+    Box::new(cmd.args.get(2).filter(|key| !key.is_empty()).into_iter().chain(keys))
+}
+
+/// 所有用户，ACL SETUSER、DELUSER、LOAD都是整体替换，检查权限时拿读锁
+pub struct Acl {
+    users: RwLock<BTreeMap<String, User>>,
+}
+
+impl Acl {
+    /// 只有默认用户，requirepass不为空时默认用户需要这个密码
+    pub fn new(requirepass: &str) -> Acl {
+        let acl = Acl { users: RwLock::new(BTreeMap::from([("default".to_string(), User::default_user())])) };
+        acl.set_requirepass(requirepass);
+        acl
+    }
+
+    /// CONFIG SET requirepass：替换默认用户的所有密码，空字符串表示不需要密码
+    pub fn set_requirepass(&self, password: &str) {
+        let mut users = self.users.write().unwrap();
+        let user = users.get_mut("default").unwrap();
+        user.apply(b"resetpass").unwrap();
+        if password.is_empty() {
+            user.apply(b"nopass").unwrap();
+        } else {
+            user.apply(format!(">{}", password).as_bytes()).unwrap();
+        }
+    }
+
+    /// 新连接是不是可以不认证就以默认用户的身份执行命令
+    pub fn default_nopass(&self) -> bool {
+        let users = self.users.read().unwrap();
+        users.get("default").is_some_and(|user| user.enabled && user.nopass)
+    }
+
+    pub fn authenticate(&self, name: &str, password: &[u8]) -> bool {
+        self.users.read().unwrap().get(name).is_some_and(|user| user.authenticate(password))
+    }
+
+    /// 用户不存在(比如刚被删掉)时什么都不能执行
+    pub fn check(&self, name: &str, spec: &CommandSpec, cmd: &Command) -> Result<(), Frame> {
+        match self.users.read().unwrap().get(name) {
+            Some(user) => user.check(spec, cmd),
+            None => Err(Frame::error(format!("NOPERM User {} has no permissions to run the '{}' command", name, spec.name))),
+        }
+    }
+
+    /// ACL SETUSER，用户不存在时新建。所有规则都合法才生效
+    pub fn set_user(&self, name: &str, rules: &[Bytes]) -> Result<(), Frame> {
+        let mut users = self.users.write().unwrap();
+        let mut user = users.get(name).cloned().unwrap_or_else(|| User::new(name));
+        for rule in rules {
+            user.apply(rule).map_err(|err| Frame::error(format!("ERR Error in ACL SETUSER modifier '{}': {}", String::from_utf8_lossy(rule), err)))?;
+        }
+        users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    /// ACL DELUSER，返回删掉的用户名
+    pub fn delete_users(&self, names: &[Bytes]) -> Result<Vec<String>, Frame> {
+        if names.iter().any(|name| &name[..] == b"default") {
+            return Err(Frame::error("ERR The 'default' user cannot be removed"));
+        }
+        let mut users = self.users.write().unwrap();
+        Ok(names.iter().filter_map(|name| users.remove(&*String::from_utf8_lossy(name)).map(|user| user.name)).collect())
+    }
+
+    pub fn user(&self, name: &str) -> Option<User> {
+        self.users.read().unwrap().get(name).cloned()
+    }
+
+    pub fn usernames(&self) -> Vec<String> {
+        self.users.read().unwrap().keys().cloned().collect()
+    }
+
+    /// ACL LIST
+    pub fn list(&self) -> Vec<String> {
+        self.users.read().unwrap().values().map(User::describe).collect()
+    }
+
+    /// 读ACL文件，整个文件都合法才替换所有用户。文件里没有默认用户时默认用户恢复成初始状态
+    pub fn load(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut users = BTreeMap::new();
+        for (line_no, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+            let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line_no + 1, msg));
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (name, rules) = match words[..] {
+                [] => continue,
+                [first, ..] if first.starts_with('#') => continue,
+                ["user", name, ref rules @ ..] => (name, rules),
+                _ => return Err(invalid("should start with user keyword followed by the username".to_string())),
+            };
+            if users.contains_key(name) {
+                return Err(invalid(format!("duplicate user '{}' found", name)));
+            }
+            let mut user = User::new(name);
+            for rule in rules {
+                user.apply(rule.as_bytes()).map_err(|err| invalid(format!("{}. Rule: '{}'", err, rule)))?;
+            }
+            users.insert(name.to_string(), user);
+        }
+        users.entry("default".to_string()).or_insert_with(User::default_user);
+        *self.users.write().unwrap() = users;
+        Ok(())
+    }
+
+    /// ACL SAVE，先写临时文件再改名
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension(format!("tmp-{}", std::process::id()));
+        let content: String = self.list().into_iter().map(|line| line + "\n").collect();
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+/// MONITOR和慢查询日志里不显示这些命令的参数，里面可能有密码
+pub fn hides_args(name: &str) -> bool {
+    matches!(name, "auth" | "hello" | "acl" | "migrate" | "config")
+}
+
+/// 执行命令之前的检查：没有认证的连接只能执行AUTH、HELLO和QUIT，认证过的按用户的权限检查。
+/// 参数个数不对时也要先检查，不能让格式错误的命令绕过认证；认证过的连接执行不存在的命令时交给后面报错
+pub fn authorize(acl: &Acl, user: Option<&str>, cmd: &Command) -> Result<(), Frame> {
+    let spec = cmd::lookup(&cmd.name);
+    if spec.is_some_and(|spec| spec.has(NO_AUTH)) {
+        return Ok(());
+    }
+    match (user, spec) {
+        (None, _) => Err(noauth()),
+        (Some(user), Some(spec)) => acl.check(user, spec, cmd),
+        (Some(_), None) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use resp::Frame;
+    use crate::acl::Acl;
+    use crate::acl::{authorize, noauth};
+    use crate::cmd::{lookup, Command};
+
+    fn command(line: &str) -> Command {
+        let parts = line.split_whitespace().map(|s| Frame::Bulk(Bytes::from(s.to_string()))).collect();
+        Command::from_frame(Frame::Array(parts)).unwrap()
+    }
+
+    fn rules(line: &str) -> Vec<Bytes> {
+        line.split_whitespace().map(|s| Bytes::from(s.to_string())).collect()
+    }
+
+    fn check(acl: &Acl, user: &str, line: &str) -> Result<(), Frame> {
+        let cmd = command(line);
+        acl.check(user, lookup(&cmd.name).unwrap(), &cmd)
+    }
+
+    #[test]
+    fn test_permissions() {
+        let acl = Acl::new("secret");
+        assert!(!acl.default_nopass());
+        assert!(acl.authenticate("default", b"secret"));
+        assert!(!acl.authenticate("default", b"wrong"));
+
+        acl.set_user("alice", &rules("on >pw ~cache:* &news +@read +set -@dangerous")).unwrap();
+        assert!(acl.authenticate("alice", b"pw"));
+        assert_eq!(check(&acl, "alice", "GET cache:1"), Ok(()));
+        assert_eq!(check(&acl, "alice", "SET cache:1 v"), Ok(()));
+        assert_eq!(check(&acl, "alice", "DEL cache:1"), Err(Frame::error("NOPERM User alice has no permissions to run the 'del' command")));
+        assert_eq!(check(&acl, "alice", "MGET cache:1 other"), Err(Frame::error("NOPERM No permissions to access a key")));
+        // KEYS是@read，同时也是@dangerous
+        assert!(check(&acl, "alice", "KEYS *").is_err());
+        acl.set_user("alice", &rules("+publish")).unwrap();
+        assert_eq!(check(&acl, "alice", "PUBLISH news hi"), Ok(()));
+        assert_eq!(check(&acl, "alice", "PUBLISH sports hi"), Err(Frame::error("NOPERM No permissions to access a channel")));
+        // CLUSTER和CLIENT会影响其他节点和连接，属于@admin和@dangerous
+        acl.set_user("carol", &rules("on >pw +@connection -@dangerous")).unwrap();
+        assert_eq!(check(&acl, "carol", "PING"), Ok(()));
+        assert!(check(&acl, "carol", "CLIENT KILL ID 1").is_err());
+        acl.set_user("carol", &rules("+@all -@admin")).unwrap();
+        assert!(check(&acl, "carol", "CLUSTER RESET").is_err());
+
+        // 出错时整条SETUSER都不生效
+        assert!(acl.set_user("alice", &rules("off +nosuchcommand")).is_err());
+        assert!(acl.authenticate("alice", b"pw"));
+        assert_eq!(
+            acl.user("alice").unwrap().describe(),
+            "user alice on #30c952fab122c3f9759f02a6d95c3758b246b4fee239957b2d4fee46e26170c4 ~cache:* &news -@all +@read +set -@dangerous +publish"
+        );
+        assert_eq!(acl.delete_users(&rules("alice bob")), Ok(vec!["alice".to_string()]));
+        assert!(acl.delete_users(&rules("default")).is_err());
+    }
+
+    #[test]
+    fn test_authorize() {
+        let acl = Acl::new("secret");
+        // 参数个数不对的命令同样需要认证
+        for line in ["MONITOR x", "PSYNC ? -1 x", "REPLICAOF", "CLIENT", "GET", "NOSUCHCOMMAND"] {
+            assert_eq!(authorize(&acl, None, &command(line)), Err(noauth()), "{}", line);
+        }
+        assert_eq!(authorize(&acl, None, &command("AUTH a b c")), Ok(()));
+        acl.set_user("reader", &rules("on >pw allkeys +@read")).unwrap();
+        assert!(authorize(&acl, Some("reader"), &command("PSYNC x")).is_err());
+        assert!(authorize(&acl, Some("reader"), &command("PUBLISH")).is_err());
+        assert_eq!(authorize(&acl, Some("reader"), &command("GET")), Ok(()));
+    }
+}
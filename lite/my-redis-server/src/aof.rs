@@ -0,0 +1,410 @@
+//! 追加写日志：每条执行成功的写命令按RESP格式追加到文件末尾，启动时按顺序重放恢复数据。
+//! 相对的过期时间(EXPIRE、SET EX之类)在命令后面额外记一条PEXPIREAT，重放时不会被延长。
+//! 命令所在的数据库和上一条不同时先记一条SELECT。
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use bytes::{Bytes, BytesMut};
+use resp::{Frame, Protocol};
+use crate::cmd::{self, Command, Handler};
+use crate::db::{to_unix_millis, Db};
+use crate::server::Server;
+use crate::value::Value;
+
+/// 重写时集合类型每条命令最多带这么多个元素，避免生成特别大的命令
+const ITEMS_PER_COMMAND: usize = 64;
+
+/// appendfsync策略
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fsync {
+    /// 每条命令都fsync，最安全也最慢
+    Always,
+    /// 后台每秒fsync一次，最多丢一秒的数据
+    EverySec,
+    /// 交给操作系统决定什么时候落盘
+    No,
+}
+
+impl Fsync {
+    pub fn parse(s: &str) -> Option<Fsync> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Some(Fsync::Always),
+            "everysec" => Some(Fsync::EverySec),
+            "no" => Some(Fsync::No),
+            _ => None,
+        }
+    }
+}
+
+struct State {
+    file: File,
+    // 重写期间新产生的命令，重写完成后追加到新文件末尾
+    rewrite_buffer: Option<Vec<u8>>,
+    // 最后一条SELECT选择的数据库，None表示下一条命令前必须先SELECT
+    selected: Option<usize>,
+}
+
+pub struct Aof {
+    pub path: PathBuf,
+    // 可以用CONFIG SET appendfsync在运行时修改
+    policy: Mutex<Fsync>,
+    state: Mutex<State>,
+    rewriting: AtomicBool,
+}
+
+impl Aof {
+    pub fn open(path: impl Into<PathBuf>, fsync: Fsync) -> io::Result<Aof> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Aof {
+            path,
+            policy: Mutex::new(fsync),
+            state: Mutex::new(State { file, rewrite_buffer: None, selected: None }),
+            rewriting: AtomicBool::new(false),
+        })
+    }
+
+    /// 追加数据库index上的若干条命令
+    pub fn append(&self, index: usize, frames: &[Frame]) -> io::Result<()> {
+        let mut buf = BytesMut::new();
+        let mut state = self.state.lock().unwrap();
+        if state.selected != Some(index) {
+            select_frame(index).encode(&mut buf, Protocol::Resp2);
+            state.selected = Some(index);
+        }
+        frames.iter().for_each(|frame| frame.encode(&mut buf, Protocol::Resp2));
+        if let Some(rewrite_buffer) = state.rewrite_buffer.as_mut() {
+            rewrite_buffer.extend_from_slice(&buf);
+        }
+        state.file.write_all(&buf)?;
+        if self.policy() == Fsync::Always {
+            state.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    pub fn fsync(&self) -> io::Result<()> {
+        self.state.lock().unwrap().file.sync_data()
+    }
+
+    pub fn policy(&self) -> Fsync {
+        *self.policy.lock().unwrap()
+    }
+
+    pub fn set_policy(&self, fsync: Fsync) {
+        *self.policy.lock().unwrap() = fsync;
+    }
+
+    /// 是否正在后台重写，INFO persistence使用
+    pub fn is_rewriting(&self) -> bool {
+        self.rewriting.load(Ordering::Acquire)
+    }
+
+    /// 重写结束：把重写期间积累的命令接到新文件后面，再替换掉旧文件
+    fn finish_rewrite(&self, data: &[u8]) -> io::Result<()> {
+        let tmp = self.path.with_extension(format!("rewrite-{}", std::process::id()));
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        let mut state = self.state.lock().unwrap();
+        let res = (|| {
+            file.write_all(state.rewrite_buffer.as_deref().unwrap_or_default())?;
+            file.sync_all()?;
+            std::fs::rename(&tmp, &self.path)?;
+            OpenOptions::new().append(true).open(&self.path)
+        })();
+        state.rewrite_buffer = None;
+        match res {
+            Ok(file) => {
+                state.file = file;
+                Ok(())
+            }
+            Err(err) => {
+                let _ = std::fs::remove_file(&tmp);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// 把一条命令转成RESP数组
+pub fn command_frame(name: &str, args: &[Bytes]) -> Frame {
+    let mut parts = Vec::with_capacity(args.len() + 1);
+    parts.push(Frame::Bulk(Bytes::copy_from_slice(name.as_bytes())));
+    parts.extend(args.iter().cloned().map(Frame::Bulk));
+    Frame::Array(parts)
+}
+
+fn pexpireat(key: &Bytes, when: Instant) -> Frame {
+    command_frame("pexpireat", &[key.clone(), Bytes::from(to_unix_millis(when).to_string())])
+}
+
+/// 写命令在AOF和复制流里的形式：带相对过期时间的命令后面补一条PEXPIREAT，重放时过期时间不会顺延
+pub fn log_frames(cmd: &Command, db: &mut Db) -> Vec<Frame> {
+    let mut frames = vec![command_frame(&cmd.name, &cmd.args)];
+    if matches!(cmd.name.as_str(), "expire" | "pexpire" | "set" | "setex" | "psetex" | "restore" | "restore-asking") {
+        if let Some(Some(when)) = db.expires_at(&cmd.args[0]) {
+            frames.push(pexpireat(&cmd.args[0], when));
+        }
+    }
+    frames
+}
+
+/// SELECT index
+pub fn select_frame(index: usize) -> Frame {
+    command_frame("select", &[Bytes::from(index.to_string())])
+}
+
+/// 记录执行成功的写命令，调用时还持有命令涉及的分片锁，日志顺序和执行顺序一致
+pub fn feed(aof: &Aof, index: usize, frames: &[Frame]) {
+    if let Err(err) = aof.append(index, frames) {
+        warning!("Error writing to the AOF file: {}", err);
+    }
+}
+
+/// 后台任务：appendfsync为everysec时每秒fsync一次
+pub async fn fsync_every_second(aof: Arc<Aof>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        if aof.policy() != Fsync::EverySec {
+            continue;
+        }
+        let aof = aof.clone();
+        let res = tokio::task::spawn_blocking(move || aof.fsync()).await;
+        if let Ok(Err(err)) = res {
+            warning!("Error syncing the AOF file: {}", err);
+        }
+    }
+}
+
+/// 启动时重放日志，返回重放的命令数。末尾不完整的命令(比如写到一半时崩溃)会被截掉，
+/// 中间出现无法解析的内容时返回错误
+pub fn load(server: &Server, path: impl AsRef<Path>) -> io::Result<usize> {
+    let path = path.as_ref();
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let total = data.len();
+    let mut buffer = BytesMut::from(&data[..]);
+    let (mut count, mut index) = (0, 0);
+    loop {
+        let frame = match resp::parse(&mut buffer) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad command at offset {}: {}", total - buffer.len(), err))),
+        };
+        let cmd = Command::from_frame(frame).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad command in append only file"))?;
+        if cmd.name == "select" {
+            index = cmd.args.first()
+                .and_then(|arg| cmd::parse_db_index(arg, server.db.databases()).ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad SELECT in append only file"))?;
+            continue;
+        }
+        // 不经过execute：日志里的数据必须全部加载，不检查maxmemory也不淘汰，从库也不拒绝写命令
+        let reply = match cmd::check(&cmd) {
+            Ok(spec) => {
+                let mut db = match spec.handler {
+                    Handler::Db(_) => server.db.lock(index, spec.keys.keys(&cmd.args)),
+                    _ => server.db.lock(index, []),
+                };
+                cmd::call(server, spec, &cmd, &mut db)
+            }
+            Err(err) => err,
+        };
+        if let Frame::Error(err) = reply {
+            warning!("Error replaying '{}' from the AOF file: {}", cmd.name, err);
+        }
+        count += 1;
+    }
+    if !buffer.is_empty() {
+        let valid = total - buffer.len();
+        warning!("AOF file has a truncated command at the end, truncating from {} to {} bytes", total, valid);
+        OpenOptions::new().write(true).open(path)?.set_len(valid as u64)?;
+    }
+    Ok(count)
+}
+
+/// BGREWRITEAOF：根据当前键空间生成最短的命令序列，在后台线程里写新文件
+pub fn rewrite(server: &Server) -> Result<(), Frame> {
+    let aof = server.aof.clone().ok_or_else(|| Frame::error("ERR append only file is not enabled"))?;
+    if aof.rewriting.swap(true, Ordering::AcqRel) {
+        return Err(Frame::error("ERR Background append only file rewriting already in progress"));
+    }
+    // 持有所有分片锁时开始缓冲，快照之后的写命令都会进入重写缓冲区，不重不漏
+    // 新文件的最后一个数据库和缓冲区里的命令不一定相同，缓冲区要从SELECT开始
+    let snapshot = server.db.snapshot_with(|| {
+        let mut state = aof.state.lock().unwrap();
+        state.rewrite_buffer = Some(Vec::new());
+        state.selected = None;
+    });
+    std::thread::spawn(move || {
+        let mut buf = BytesMut::new();
+        for (index, entries) in snapshot.iter().enumerate().filter(|(_, entries)| !entries.is_empty()) {
+            select_frame(index).encode(&mut buf, Protocol::Resp2);
+            for (key, value, expires_at) in entries {
+                for frame in rebuild(key, value) {
+                    frame.encode(&mut buf, Protocol::Resp2);
+                }
+                if let Some(when) = expires_at {
+                    pexpireat(key, *when).encode(&mut buf, Protocol::Resp2);
+                }
+            }
+        }
+        match aof.finish_rewrite(&buf) {
+            Ok(()) => notice!("Background AOF rewrite finished successfully"),
+            Err(err) => warning!("Background AOF rewrite error: {}", err),
+        }
+        aof.rewriting.store(false, Ordering::Release);
+    });
+    Ok(())
+}
+
+/// 重新构造出这个值的写命令
+fn rebuild(key: &Bytes, value: &Value) -> Vec<Frame> {
+    let batches = |name: &str, items: Vec<Bytes>, per_item: usize| -> Vec<Frame> {
+        items.chunks(ITEMS_PER_COMMAND * per_item)
+            .map(|chunk| {
+                let mut args = vec![key.clone()];
+                args.extend_from_slice(chunk);
+                command_frame(name, &args)
+            })
+            .collect()
+    };
+    match value {
+        Value::String(val) => vec![command_frame("set", &[key.clone(), val.clone()])],
+        Value::List(list) => batches("rpush", list.iter().cloned().collect(), 1),
+        Value::Hash(hash) => batches("hset", hash.iter().flat_map(|(field, val)| [field.clone(), val.clone()]).collect(), 2),
+        Value::Set(set) => batches("sadd", set.iter().cloned().collect(), 1),
+        Value::ZSet(zset) => batches("zadd", zset.iter().flat_map(|(member, score)| [Bytes::from(score.to_string()), member.clone()]).collect(), 2),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use bytes::Bytes;
+    use resp::Frame;
+    use crate::aof::{load, Aof, Fsync};
+    use crate::cmd::{execute, Command};
+    use crate::config::Config;
+    use crate::evict::Policy;
+    use crate::server::Server;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("my-redis-{}-{}.aof", name, std::process::id()))
+    }
+
+    fn run(server: &Server, line: &str) -> Frame {
+        let parts = line.split_whitespace().map(|s| Frame::Bulk(Bytes::from(s.to_string()))).collect();
+        execute(server, 0, &Command::from_frame(Frame::Array(parts)).unwrap())
+    }
+
+    fn server_with_aof(path: &std::path::Path) -> Server {
+        let mut server = Server::with_shards(4);
+        server.aof = Some(Arc::new(Aof::open(path, Fsync::Always).unwrap()));
+        server
+    }
+
+    fn bulk(s: &str) -> Frame {
+        Frame::Bulk(Bytes::from(s.to_string()))
+    }
+
+    #[test]
+    fn test_replay() {
+        let path = temp_path("replay");
+        let _ = std::fs::remove_file(&path);
+        let server = server_with_aof(&path);
+        run(&server, "SET a 1");
+        run(&server, "INCRBY a 10");
+        run(&server, "RPUSH l x y z");
+        run(&server, "LPOP l");
+        run(&server, "SET t v EX 100");
+        run(&server, "GET a");
+        // 失败的和什么也没改的写命令不记录
+        run(&server, "INCR l");
+        run(&server, "DEL missing");
+        run(&server, "SET a 5 NX");
+        run(&server, "LPOP missing");
+        // 别的数据库上的命令前面先记一条SELECT
+        execute(&server, 3, &Command::from_frame(Frame::Array(vec![bulk("SET"), bulk("a"), bulk("3")])).unwrap());
+
+        let replayed = Server::with_shards(2);
+        assert_eq!(load(&replayed, &path).unwrap(), 7);
+        assert_eq!(run(&replayed, "GET a"), bulk("11"));
+        assert_eq!(execute(&replayed, 3, &Command::from_frame(Frame::Array(vec![bulk("GET"), bulk("a")])).unwrap()), bulk("3"));
+        assert_eq!(run(&replayed, "LRANGE l 0 -1"), Frame::Array(vec![bulk("y"), bulk("z")]));
+        assert!(matches!(run(&replayed, "TTL t"), Frame::Integer(ttl) if ttl > 90 && ttl <= 100));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_over_maxmemory() {
+        let path = temp_path("maxmemory");
+        let _ = std::fs::remove_file(&path);
+        let server = server_with_aof(&path);
+        let value = "x".repeat(1000);
+        for idx in 0..200 {
+            run(&server, &format!("SET key:{} {}", idx, value));
+        }
+        // 日志里的数据比maxmemory多，重放时既不拒绝也不淘汰
+        for policy in [Policy::NoEviction, Policy::AllKeysLru] {
+            let replayed = Server::with_config(Config { shards: 4, maxmemory: 64 * 1024, maxmemory_policy: policy, ..Config::default() });
+            assert_eq!(load(&replayed, &path).unwrap(), 200);
+            assert_eq!(replayed.db.len(0), 200);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_tail() {
+        let path = temp_path("truncated");
+        let valid = "*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+        std::fs::write(&path, format!("{}*3\r\n$3\r\nSET\r\n$1\r\nk", valid)).unwrap();
+        let server = Server::with_shards(1);
+        assert_eq!(load(&server, &path).unwrap(), 1);
+        assert_eq!(run(&server, "GET k"), bulk("v"));
+        assert_eq!(std::fs::read(&path).unwrap(), valid.as_bytes());
+        // 中间出现垃圾数据时报错
+        std::fs::write(&path, format!("{}!garbage\r\n{}", valid, valid)).unwrap();
+        assert!(load(&Server::with_shards(1), &path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let path = temp_path("rewrite");
+        let _ = std::fs::remove_file(&path);
+        let server = server_with_aof(&path);
+        for _ in 0..100 {
+            run(&server, "INCR counter");
+        }
+        run(&server, "ZADD z 1.5 a 2 b");
+        run(&server, "HSET h f v");
+        run(&server, "PEXPIRE h 100000");
+        let before = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(run(&server, "BGREWRITEAOF"), Frame::Simple("Background append only file rewriting started".to_string()));
+        let start = Instant::now();
+        while server.aof.as_ref().unwrap().rewriting.load(Ordering::Acquire) {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // 重写之后的命令继续追加到新文件
+        run(&server, "INCR counter");
+        assert!(std::fs::metadata(&path).unwrap().len() < before);
+
+        let replayed = Server::with_shards(2);
+        load(&replayed, &path).unwrap();
+        assert_eq!(run(&replayed, "GET counter"), bulk("101"));
+        assert_eq!(run(&replayed, "ZSCORE z a"), Frame::Double(1.5));
+        assert!(matches!(run(&replayed, "PTTL h"), Frame::Integer(ttl) if ttl > 90000));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,913 @@
+//! 集群模式：和Redis Cluster一样把键空间分成16384个哈希槽，键的槽位是CRC16(键) mod 16384，
+//! 键里有`{...}`时只对花括号里的部分求哈希，相关的键可以放进同一个槽里一起操作。
+//! 每个节点负责一部分槽，收到不归自己负责的键时回复`MOVED <slot> <ip>:<port>`，客户端据此更新路由表。
+//!
+//! 迁移槽时目标节点标记为IMPORTING，源节点标记为MIGRATING：键还在源节点上时照常处理，
+//! 已经用MIGRATE搬走的键回复`ASK`，让客户端带着ASKING去目标节点上问一次。
+//! 全部搬完之后用`CLUSTER SETSLOT <slot> NODE <id>`把槽交给目标节点。
+//!
+//! 没有Redis那样单独的集群总线：每个节点定期通过普通的命令端口向已知的节点发送`CLUSTER MEET`介绍自己，
+//! 再用`CLUSTER NODES`拉取对方的视图。只相信对方关于它自己负责哪些槽的说法，
+//! 同一个槽有冲突时配置纪元(config epoch)大的一方胜出。不做故障检测和故障转移。
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use tokio::net::TcpStream;
+use resp::{Connection, Frame};
+use crate::aof::{self, command_frame};
+use crate::cmd::{self, eq_ignore_case, parse_i64, syntax_error, Command, Reply, ASKING};
+use crate::db::Keyspace;
+use crate::replication;
+use crate::rdb;
+use crate::server::Server;
+
+pub const SLOTS: usize = 16384;
+/// 每隔这么久和所有已知节点交换一次视图
+const GOSSIP_PERIOD: Duration = Duration::from_millis(500);
+const GOSSIP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// CRC16/XMODEM，和Redis Cluster用的是同一个
+const CRC16_TABLE: [u16; 256] = {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, byte| (crc << 8) ^ CRC16_TABLE[((crc >> 8) as u8 ^ *byte) as usize])
+}
+
+/// 键所在的槽。有`{`并且后面有`}`、中间不为空时只对中间的部分求哈希
+pub fn key_slot(key: &[u8]) -> u16 {
+    let tag = key.iter().position(|b| *b == b'{').and_then(|start| {
+        let len = key[start + 1..].iter().position(|b| *b == b'}')?;
+        (len > 0).then(|| &key[start + 1..start + 1 + len])
+    });
+    crc16(tag.unwrap_or(key)) % SLOTS as u16
+}
+
+struct Node {
+    id: String,
+    ip: String,
+    port: u16,
+    epoch: u64,
+    // 最近一次交换视图是否成功
+    connected: bool,
+}
+
+impl Node {
+    fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+}
+
+/// CLUSTER NODES里的一行
+struct NodeInfo {
+    id: String,
+    ip: String,
+    port: u16,
+    myself: bool,
+    epoch: u64,
+    slots: Vec<(u16, u16)>,
+    // 只有自己那一行有，格式是[slot->-id]和[slot-<-id]
+    migrating: Vec<(u16, String)>,
+    importing: Vec<(u16, String)>,
+}
+
+struct State {
+    // 自己在nodes里的下标
+    myself: usize,
+    current_epoch: u64,
+    nodes: Vec<Node>,
+    // 每个槽由nodes里的哪个节点负责
+    slots: Vec<Option<usize>>,
+    migrating: BTreeMap<u16, usize>,
+    importing: BTreeMap<u16, usize>,
+    // MEET了但是还没交换过视图、不知道ID的节点
+    meet: Vec<(String, u16)>,
+}
+
+impl State {
+    fn new(id: String) -> State {
+        State {
+            myself: 0,
+            current_epoch: 0,
+            nodes: vec![Node { id, ip: String::new(), port: 0, epoch: 0, connected: true }],
+            slots: vec![None; SLOTS],
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+            meet: Vec::new(),
+        }
+    }
+
+    /// 加载集群配置文件，格式和CLUSTER NODES一样，最后一行是`vars currentEpoch <epoch> ...`
+    fn parse(content: &str) -> Result<State, String> {
+        let mut current_epoch = 0;
+        let mut infos = Vec::new();
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields[0] == "vars" {
+                let epoch = fields.iter().position(|field| *field == "currentEpoch").and_then(|idx| fields.get(idx + 1));
+                current_epoch = epoch.and_then(|epoch| epoch.parse().ok()).ok_or("invalid vars line")?;
+            } else {
+                infos.push(parse_node(line).ok_or_else(|| format!("invalid node line '{}'", line))?);
+            }
+        }
+        let me = infos.iter().find(|info| info.myself).ok_or("no myself node")?;
+        let mut state = State::new(me.id.clone());
+        state.current_epoch = current_epoch;
+        for info in &infos {
+            let idx = match state.index(&info.id) {
+                Some(idx) => idx,
+                None => state.add_node(&info.id, &info.ip, info.port),
+            };
+            state.nodes[idx].epoch = info.epoch;
+            for &(start, end) in &info.slots {
+                state.slots[start as usize..=end as usize].fill(Some(idx));
+            }
+        }
+        for info in infos.iter().filter(|info| info.myself) {
+            for (slot, id) in &info.migrating {
+                let idx = state.index(id).ok_or("unknown node in migrating slot")?;
+                state.migrating.insert(*slot, idx);
+            }
+            for (slot, id) in &info.importing {
+                let idx = state.index(id).ok_or("unknown node in importing slot")?;
+                state.importing.insert(*slot, idx);
+            }
+        }
+        Ok(state)
+    }
+
+    fn index(&self, id: &str) -> Option<usize> {
+        self.nodes.iter().position(|node| node.id == id)
+    }
+
+    fn add_node(&mut self, id: &str, ip: &str, port: u16) -> usize {
+        self.nodes.push(Node { id: id.to_string(), ip: ip.to_string(), port, epoch: 0, connected: false });
+        self.nodes.len() - 1
+    }
+
+    fn node_index(&self, id: &Bytes) -> Result<usize, Frame> {
+        self.index(&String::from_utf8_lossy(id))
+            .ok_or_else(|| Frame::error(format!("ERR Unknown node {}", String::from_utf8_lossy(id))))
+    }
+
+    /// 某个节点负责的槽，合并成连续的区间
+    fn ranges(&self, idx: usize) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = Vec::new();
+        for slot in (0..SLOTS).filter(|slot| self.slots[*slot] == Some(idx)) {
+            match ranges.last_mut() {
+                Some((_, end)) if *end as usize + 1 == slot => *end = slot as u16,
+                _ => ranges.push((slot as u16, slot as u16)),
+            }
+        }
+        ranges
+    }
+
+    fn nodes_text(&self) -> String {
+        let mut text = String::new();
+        for (idx, node) in self.nodes.iter().enumerate() {
+            let flags = if idx == self.myself { "myself,master" } else { "master" };
+            let link = if node.connected { "connected" } else { "disconnected" };
+            text.push_str(&format!("{} {}@0 {} - 0 0 {} {}", node.id, node.addr(), flags, node.epoch, link));
+            for (start, end) in self.ranges(idx) {
+                if start == end {
+                    text.push_str(&format!(" {}", start));
+                } else {
+                    text.push_str(&format!(" {}-{}", start, end));
+                }
+            }
+            if idx == self.myself {
+                for (slot, target) in &self.migrating {
+                    text.push_str(&format!(" [{}->-{}]", slot, self.nodes[*target].id));
+                }
+                for (slot, source) in &self.importing {
+                    text.push_str(&format!(" [{}-<-{}]", slot, self.nodes[*source].id));
+                }
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    fn redirect(&self, kind: &str, slot: u16, idx: usize) -> Frame {
+        Frame::error(format!("{} {} {}", kind, slot, self.nodes[idx].addr()))
+    }
+}
+
+fn parse_node(line: &str) -> Option<NodeInfo> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 8 {
+        return None;
+    }
+    let addr = fields[1].split('@').next()?;
+    let (ip, port) = addr.rsplit_once(':')?;
+    let mut info = NodeInfo {
+        id: fields[0].to_string(),
+        ip: ip.to_string(),
+        port: port.parse().ok()?,
+        myself: fields[2].split(',').any(|flag| flag == "myself"),
+        epoch: fields[6].parse().ok()?,
+        slots: Vec::new(),
+        migrating: Vec::new(),
+        importing: Vec::new(),
+    };
+    for field in &fields[8..] {
+        if let Some(entry) = field.strip_prefix('[').and_then(|field| field.strip_suffix(']')) {
+            if let Some((slot, id)) = entry.split_once("->-") {
+                info.migrating.push((slot.parse().ok()?, id.to_string()));
+            } else if let Some((slot, id)) = entry.split_once("-<-") {
+                info.importing.push((slot.parse().ok()?, id.to_string()));
+            }
+            continue;
+        }
+        let (start, end) = field.split_once('-').unwrap_or((field, field));
+        let (start, end): (u16, u16) = (start.parse().ok()?, end.parse().ok()?);
+        if start > end || end as usize >= SLOTS {
+            return None;
+        }
+        info.slots.push((start, end));
+    }
+    Some(info)
+}
+
+/// CLUSTER SETSLOT的几种操作
+pub enum SetSlot {
+    Importing(Bytes),
+    Migrating(Bytes),
+    Stable,
+    Node(Bytes),
+}
+
+/// 集群状态，每次修改都写回集群配置文件，重启后节点ID和槽的分配不变
+pub struct Cluster {
+    state: Mutex<State>,
+    path: PathBuf,
+}
+
+impl Cluster {
+    /// 加载集群配置文件，不存在时生成新的节点ID。ip和port是其他节点和客户端访问自己用的地址
+    pub fn open(path: impl Into<PathBuf>, ip: &str, port: u16) -> io::Result<Cluster> {
+        let path = path.into();
+        let mut state = match std::fs::read_to_string(&path) {
+            // 节点ID和复制ID的格式一样
+            Err(err) if err.kind() == io::ErrorKind::NotFound => State::new(replication::new_replid()),
+            Err(err) => return Err(err),
+            Ok(content) => State::parse(&content)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), err)))?,
+        };
+        let me = state.myself;
+        state.nodes[me].ip = ip.to_string();
+        state.nodes[me].port = port;
+        let cluster = Cluster { state: Mutex::new(state), path };
+        cluster.write(&cluster.state.lock().unwrap())?;
+        Ok(cluster)
+    }
+
+    fn write(&self, state: &State) -> io::Result<()> {
+        let content = format!("{}vars currentEpoch {} lastVoteEpoch 0\n", state.nodes_text(), state.current_epoch);
+        let tmp = self.path.with_extension(format!("tmp-{}", std::process::id()));
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(&tmp, &self.path)
+    }
+
+    /// 修改之后调用，写文件失败只记日志，内存里的状态照样生效
+    fn save(&self, state: &State) {
+        if let Err(err) = self.write(state) {
+            warning!("Can't save the cluster config file {}: {}", self.path.display(), err);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn myid(&self) -> String {
+        let state = self.lock();
+        state.nodes[state.myself].id.clone()
+    }
+
+    pub fn nodes(&self) -> String {
+        self.lock().nodes_text()
+    }
+
+    /// CLUSTER INFO
+    pub fn info(&self) -> String {
+        let state = self.lock();
+        let assigned = state.slots.iter().filter(|owner| owner.is_some()).count();
+        let size = (0..state.nodes.len()).filter(|idx| state.slots.contains(&Some(*idx))).count();
+        format!(
+            "cluster_enabled:1\r\ncluster_state:{}\r\ncluster_slots_assigned:{}\r\ncluster_slots_ok:{}\r\n\
+             cluster_known_nodes:{}\r\ncluster_size:{}\r\ncluster_current_epoch:{}\r\ncluster_my_epoch:{}\r\n",
+            if assigned == SLOTS { "ok" } else { "fail" }, assigned, assigned,
+            state.nodes.len(), size, state.current_epoch, state.nodes[state.myself].epoch,
+        )
+    }
+
+    /// CLUSTER SLOTS：每个连续区间一项，[起始槽, 结束槽, [ip, 端口, 节点ID]]
+    pub fn slots(&self) -> Frame {
+        let state = self.lock();
+        let mut ranges: Vec<(u16, u16, usize)> = (0..state.nodes.len())
+            .flat_map(|idx| state.ranges(idx).into_iter().map(move |(start, end)| (start, end, idx)))
+            .collect();
+        ranges.sort_unstable();
+        Frame::Array(ranges.into_iter().map(|(start, end, idx)| {
+            let node = &state.nodes[idx];
+            Frame::Array(vec![
+                Frame::Integer(start as i64),
+                Frame::Integer(end as i64),
+                Frame::Array(vec![
+                    Frame::Bulk(Bytes::from(node.ip.clone())),
+                    Frame::Integer(node.port as i64),
+                    Frame::Bulk(Bytes::from(node.id.clone())),
+                ]),
+            ])
+        }).collect())
+    }
+
+    /// CLUSTER MEET，真正的握手在下一轮交换视图时进行
+    pub fn meet(&self, ip: String, port: u16) {
+        let mut state = self.lock();
+        let known = state.nodes.iter().any(|node| node.ip == ip && node.port == port);
+        if !known && !state.meet.contains(&(ip.clone(), port)) {
+            state.meet.push((ip, port));
+        }
+    }
+
+    pub fn add_slots(&self, slots: &[u16]) -> Result<(), Frame> {
+        let mut state = self.lock();
+        if let Some(slot) = slots.iter().find(|slot| state.slots[**slot as usize].is_some()) {
+            return Err(Frame::error(format!("ERR Slot {} is already busy", slot)));
+        }
+        let me = state.myself;
+        for slot in slots {
+            state.slots[*slot as usize] = Some(me);
+        }
+        self.save(&state);
+        Ok(())
+    }
+
+    pub fn del_slots(&self, slots: &[u16]) -> Result<(), Frame> {
+        let mut state = self.lock();
+        if let Some(slot) = slots.iter().find(|slot| state.slots[**slot as usize].is_none()) {
+            return Err(Frame::error(format!("ERR Slot {} is already unassigned", slot)));
+        }
+        for slot in slots {
+            state.slots[*slot as usize] = None;
+            state.migrating.remove(slot);
+            state.importing.remove(slot);
+        }
+        self.save(&state);
+        Ok(())
+    }
+
+    /// CLUSTER SETSLOT，keyspace用来检查交出去的槽里是不是还有键
+    pub fn set_slot(&self, keyspace: &Keyspace, slot: u16, action: SetSlot) -> Result<(), Frame> {
+        let mut state = self.lock();
+        let me = state.myself;
+        let owner = state.slots[slot as usize];
+        match action {
+            SetSlot::Migrating(id) => {
+                if owner != Some(me) {
+                    return Err(Frame::error(format!("ERR I'm not the owner of hash slot {}", slot)));
+                }
+                let target = state.node_index(&id)?;
+                if target == me {
+                    return Err(Frame::error("ERR Can't MIGRATE to myself"));
+                }
+                state.migrating.insert(slot, target);
+            }
+            SetSlot::Importing(id) => {
+                if owner == Some(me) {
+                    return Err(Frame::error(format!("ERR I'm already the owner of hash slot {}", slot)));
+                }
+                let source = state.node_index(&id)?;
+                if source == me {
+                    return Err(Frame::error("ERR Can't IMPORT from myself"));
+                }
+                state.importing.insert(slot, source);
+            }
+            SetSlot::Stable => {
+                state.migrating.remove(&slot);
+                state.importing.remove(&slot);
+            }
+            SetSlot::Node(id) => {
+                let target = state.node_index(&id)?;
+                if owner == Some(me) && target != me && keyspace.count_keys_in_slot(slot) > 0 {
+                    return Err(Frame::error(format!(
+                        "ERR Can't assign hashslot {} to a different node while I still hold keys for this hash slot.", slot)));
+                }
+                if target != me {
+                    state.migrating.remove(&slot);
+                }
+                // 导入完成：和Redis一样不经过协商直接增大自己的配置纪元，交换视图时别的节点就会认可新的归属
+                if target == me && state.importing.remove(&slot).is_some() {
+                    state.current_epoch += 1;
+                    state.nodes[me].epoch = state.current_epoch;
+                    notice!("Configuration epoch bumped to {} after importing slot {}", state.current_epoch, slot);
+                }
+                state.slots[slot as usize] = Some(target);
+            }
+        }
+        self.save(&state);
+        Ok(())
+    }
+
+    /// 检查一条命令的键是不是都由本节点负责，asking表示客户端前一条命令是ASKING
+    pub fn check_keys(&self, keyspace: &Keyspace, keys: &[&Bytes], asking: bool) -> Result<(), Frame> {
+        let slot = match keys.first() {
+            Some(key) => key_slot(key),
+            None => return Ok(()),
+        };
+        if keys.iter().any(|key| key_slot(key) != slot) {
+            return Err(Frame::error("CROSSSLOT Keys in request don't hash to the same slot"));
+        }
+        let ask = {
+            let state = self.lock();
+            match state.slots[slot as usize] {
+                None => return Err(Frame::error(format!("CLUSTERDOWN Hash slot {} not served", slot))),
+                Some(owner) if owner != state.myself => {
+                    if asking && state.importing.contains_key(&slot) {
+                        return Ok(());
+                    }
+                    return Err(state.redirect("MOVED", slot, owner));
+                }
+                Some(_) => match state.migrating.get(&slot) {
+                    Some(target) => state.redirect("ASK", slot, *target),
+                    None => return Ok(()),
+                },
+            }
+        };
+        // 迁移中的槽：键都还在就照常执行，都已经搬走了就让客户端去目标节点问。
+        // 不持有锁到执行，检查之后被MIGRATE搬走的键会被当成不存在
+        let mut db = keyspace.lock(0, keys.iter().copied());
+        match keys.iter().filter(|key| !db.contains_key(key)).count() {
+            0 => Ok(()),
+            missing if missing == keys.len() => Err(ask),
+            _ => Err(Frame::error("TRYAGAIN Multiple keys request during rehashing of slot")),
+        }
+    }
+
+    /// 需要交换视图的节点：已知的其他节点和MEET了还没握手的节点
+    fn peers(&self) -> Vec<(String, u16)> {
+        let state = self.lock();
+        state.nodes.iter().enumerate()
+            .filter(|(idx, _)| *idx != state.myself)
+            .map(|(_, node)| (node.ip.clone(), node.port))
+            .chain(state.meet.iter().cloned())
+            .collect()
+    }
+
+    fn announce(&self) -> (String, u16) {
+        let state = self.lock();
+        let me = &state.nodes[state.myself];
+        (me.ip.clone(), me.port)
+    }
+
+    fn set_connected(&self, ip: &str, port: u16, connected: bool) {
+        let mut state = self.lock();
+        for node in state.nodes.iter_mut().filter(|node| node.ip == ip && node.port == port) {
+            node.connected = connected;
+        }
+    }
+
+    /// 合并从ip:port拉取的CLUSTER NODES：认识新的节点，按配置纪元更新对方负责的槽
+    fn merge(&self, ip: &str, port: u16, text: &str) -> Result<(), String> {
+        let infos: Vec<NodeInfo> = text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| parse_node(line).ok_or_else(|| format!("invalid node line '{}'", line)))
+            .collect::<Result<_, _>>()?;
+        let mut state = self.lock();
+        let mut changed = false;
+        state.meet.retain(|(meet_ip, meet_port)| !(meet_ip == ip && *meet_port == port));
+        let me = state.myself;
+        for info in &infos {
+            if info.id == state.nodes[me].id {
+                continue;
+            }
+            let idx = match state.index(&info.id) {
+                Some(idx) => idx,
+                None => {
+                    notice!("Discovered cluster node {} at {}:{}", info.id, info.ip, info.port);
+                    changed = true;
+                    state.add_node(&info.id, &info.ip, info.port)
+                }
+            };
+            if !info.myself {
+                continue;
+            }
+            // 对方关于自己的说法：地址以实际连上的为准
+            if state.nodes[idx].ip != ip || state.nodes[idx].port != port {
+                state.nodes[idx].ip = ip.to_string();
+                state.nodes[idx].port = port;
+                changed = true;
+            }
+            if info.epoch > state.nodes[idx].epoch {
+                state.nodes[idx].epoch = info.epoch;
+                changed = true;
+            }
+            if info.epoch > state.current_epoch {
+                state.current_epoch = info.epoch;
+                changed = true;
+            }
+            for slot in info.slots.iter().flat_map(|&(start, end)| start..=end) {
+                let claimed = match state.slots[slot as usize] {
+                    None => true,
+                    Some(owner) => owner != idx && state.nodes[owner].epoch < info.epoch,
+                };
+                if claimed {
+                    if state.slots[slot as usize] == Some(me) {
+                        notice!("Slot {} is now served by {} with a newer config epoch", slot, info.id);
+                        state.migrating.remove(&slot);
+                    }
+                    state.slots[slot as usize] = Some(idx);
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            self.save(&state);
+        }
+        Ok(())
+    }
+}
+
+/// 路由检查，process在执行命令之前调用，没有开启集群模式时什么也不做
+pub fn route(server: &Server, cmd: &Command, asking: bool) -> Result<(), Frame> {
+    let (cluster, spec) = match (&server.cluster, cmd::lookup(&cmd.name)) {
+        (Some(cluster), Some(spec)) => (cluster, spec),
+        _ => return Ok(()),
+    };
+    // 集群模式下只有0号数据库
+    if matches!(spec.name, "move" | "swapdb") {
+        return Err(Frame::error(format!("ERR {} is not allowed in cluster mode", spec.name.to_uppercase())));
+    }
+    let keys: Vec<&Bytes> = spec.keys.keys(&cmd.args).collect();
+    cluster.check_keys(&server.db, &keys, asking || spec.has(ASKING))
+}
+
+/// 后台任务：定期向已知的节点介绍自己并拉取它们的视图。连接一直保持，出错时下一轮重连
+pub async fn gossip(server: Arc<Server>) {
+    let cluster = match &server.cluster {
+        Some(cluster) => cluster,
+        None => return,
+    };
+    let mut links: HashMap<(String, u16), Connection> = HashMap::new();
+    let mut interval = tokio::time::interval(GOSSIP_PERIOD);
+    loop {
+        interval.tick().await;
+        let auth = server.config.read().unwrap().auth_args();
+        for (ip, port) in cluster.peers() {
+            let result = exchange(cluster, &mut links, &auth, &ip, port).await;
+            if let Err(err) = &result {
+                verbose!("Can't exchange cluster state with {}:{}: {}", ip, port, err);
+                links.remove(&(ip.clone(), port));
+            }
+            cluster.set_connected(&ip, port, result.is_ok());
+        }
+    }
+}
+
+/// auth为空时不认证，否则是新建连接之后发送的AUTH的参数
+async fn exchange(cluster: &Cluster, links: &mut HashMap<(String, u16), Connection>, auth: &[String], ip: &str, port: u16) -> resp::Result<()> {
+    let key = (ip.to_string(), port);
+    if !links.contains_key(&key) {
+        let stream = tokio::time::timeout(GOSSIP_TIMEOUT, TcpStream::connect((ip, port))).await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timeout"))??;
+        let mut connection = Connection::new(stream);
+        if !auth.is_empty() {
+            let parts: Vec<Bytes> = std::iter::once("auth".to_string()).chain(auth.iter().cloned()).map(Bytes::from).collect();
+            request(&mut connection, &parts).await?;
+        }
+        links.insert(key.clone(), connection);
+    }
+    let connection = links.get_mut(&key).unwrap();
+    let (my_ip, my_port) = cluster.announce();
+    request(connection, &[Bytes::from("cluster"), Bytes::from("meet"), Bytes::from(my_ip), Bytes::from(my_port.to_string())]).await?;
+    let nodes = match request(connection, &[Bytes::from("cluster"), Bytes::from("nodes")]).await? {
+        Frame::Bulk(nodes) | Frame::Verbatim(_, nodes) => nodes,
+        other => return Err(io::Error::other(format!("unexpected reply to CLUSTER NODES: {:?}", other)).into()),
+    };
+    cluster.merge(ip, port, &String::from_utf8_lossy(&nodes)).map_err(io::Error::other)?;
+    Ok(())
+}
+
+/// 发一条命令并在GOSSIP_TIMEOUT内等待回复，对方回复错误时返回Err
+async fn request(connection: &mut Connection, parts: &[Bytes]) -> resp::Result<Frame> {
+    connection.write_frame(&command_frame(&String::from_utf8_lossy(&parts[0]), &parts[1..])).await?;
+    match tokio::time::timeout(GOSSIP_TIMEOUT, connection.read_frame()).await {
+        Ok(Ok(Some(Frame::Error(err)))) => Err(io::Error::other(err).into()),
+        Ok(Ok(Some(frame))) => Ok(frame),
+        Ok(Ok(None)) => Err(io::Error::from(io::ErrorKind::ConnectionReset).into()),
+        Ok(Err(err)) => Err(err),
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timeout waiting for reply").into()),
+    }
+}
+
+/// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE] [AUTH password | AUTH2 username password] [KEYS key [key ...]]，
+/// 由process调用。
+/// 用RESTORE-ASKING把连接当前数据库index里的键发到目标节点，成功之后删除本地的键。搬运期间不持有分片锁，
+/// 用WATCH的版本号检查键有没有被修改过，被修改过的键留在本地，可以用REPLACE重新迁移
+pub async fn migrate(server: &Server, index: usize, args: &[Bytes]) -> Frame {
+    migrate_keys(server, index, args).await.unwrap_or_else(|err| err)
+}
+
+async fn migrate_keys(server: &Server, index: usize, args: &[Bytes]) -> Reply {
+    let host = String::from_utf8_lossy(&args[0]).to_string();
+    let port: u16 = std::str::from_utf8(&args[1]).ok().and_then(|port| port.parse().ok())
+        .ok_or_else(|| Frame::error("ERR Invalid port"))?;
+    // 目标节点的数据库个数由它自己检查
+    let destination = usize::try_from(parse_i64(&args[3])?).map_err(|_| Frame::error("ERR DB index is out of range"))?;
+    let timeout = match parse_i64(&args[4])? {
+        millis if millis <= 0 => Duration::from_secs(1),
+        millis => Duration::from_millis(millis as u64),
+    };
+    let (mut copy, mut replace) = (false, false);
+    // 目标节点开启了认证时先发送AUTH
+    let mut auth = Vec::new();
+    let mut keys = Vec::new();
+    if !args[2].is_empty() {
+        keys.push(args[2].clone());
+    }
+    let mut options = args[5..].iter();
+    while let Some(option) = options.next() {
+        if eq_ignore_case(option, "copy") {
+            copy = true;
+        } else if eq_ignore_case(option, "replace") {
+            replace = true;
+        } else if eq_ignore_case(option, "auth") {
+            auth = options.next().cloned().into_iter().collect();
+            if auth.is_empty() {
+                return Err(syntax_error());
+            }
+        } else if eq_ignore_case(option, "auth2") {
+            auth = options.by_ref().take(2).cloned().collect();
+            if auth.len() != 2 {
+                return Err(syntax_error());
+            }
+        } else if eq_ignore_case(option, "keys") {
+            if !args[2].is_empty() {
+                return Err(Frame::error("ERR When using MIGRATE KEYS option, the key argument must be set to the empty string"));
+            }
+            keys.extend(options.by_ref().cloned());
+        } else {
+            return Err(syntax_error());
+        }
+    }
+    // 先记下版本号再导出，和DUMP一样不带过期时间，剩余的毫秒数作为RESTORE的参数
+    let mut dumped = Vec::new();
+    {
+        let mut db = server.db.lock(index, &keys);
+        for key in &keys {
+            let version = db.watch(key);
+            let ttl = match db.expires_at(key) {
+                None => continue,
+                Some(None) => 0,
+                Some(Some(when)) => when.saturating_duration_since(Instant::now()).as_millis().max(1) as i64,
+            };
+            let value = db.get(key).unwrap().clone();
+            dumped.push((key.clone(), version, ttl, rdb::encode_entries(&[(key.clone(), value, None)])));
+        }
+    }
+    let result = send_keys(&host, port, &auth, destination, timeout, &dumped, replace).await;
+    let mut db = server.db.lock(index, &keys);
+    let mut modified = false;
+    if let Ok(restored) = &result {
+        let mut deleted = Vec::new();
+        for ((key, version, _, _), reply) in dumped.iter().zip(restored) {
+            if reply.is_err() || copy {
+                continue;
+            }
+            if db.version(key) == *version {
+                db.remove(key);
+                deleted.push(key.clone());
+            } else {
+                modified = true;
+            }
+        }
+        if !deleted.is_empty() {
+            server.rdb.record_change(deleted.len() as u64);
+            server.propagate(index, &[command_frame("del", &deleted)]);
+        }
+    }
+    for key in &keys {
+        db.unwatch(key);
+    }
+    match result {
+        _ if dumped.is_empty() => Ok(Frame::Simple("NOKEY".to_string())),
+        Err(err) => Err(err),
+        Ok(restored) => match restored.into_iter().find_map(Result::err) {
+            Some(err) => Err(Frame::error(format!("ERR Target instance replied with error: {}", err))),
+            None if modified => Err(Frame::error("ERR Some keys were modified during migration and were kept, retry with REPLACE")),
+            None => Ok(Frame::ok()),
+        },
+    }
+}
+
+/// 把导出的键用RESTORE-ASKING发到目标节点的数据库destination，返回目标节点对每个键的回复。auth为空时不认证
+async fn send_keys(host: &str, port: u16, auth: &[Bytes], destination: usize, timeout: Duration, dumped: &[(Bytes, u64, i64, Vec<u8>)], replace: bool) -> Result<Vec<Result<(), String>>, Frame> {
+    if dumped.is_empty() {
+        return Ok(Vec::new());
+    }
+    let stream = match tokio::time::timeout(timeout, TcpStream::connect((host, port))).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(err)) => return Err(io_error(&err)),
+        Err(_) => return Err(io_error(&"connect timeout")),
+    };
+    let mut connection = Connection::new(stream);
+    if !auth.is_empty() {
+        connection.write_frame(&command_frame("auth", auth)).await.map_err(|err| io_error(&err))?;
+        if let Err(err) = read_reply(&mut connection, timeout).await? {
+            return Err(Frame::error(format!("ERR Target instance replied with error: {}", err)));
+        }
+    }
+    if destination != 0 {
+        connection.write_frame(&aof::select_frame(destination)).await.map_err(|err| io_error(&err))?;
+        if let Err(err) = read_reply(&mut connection, timeout).await? {
+            return Err(Frame::error(format!("ERR Target instance replied with error: {}", err)));
+        }
+    }
+    for (key, _, ttl, payload) in dumped {
+        let mut args = vec![key.clone(), Bytes::from(ttl.to_string()), Bytes::from(payload.clone())];
+        if replace {
+            args.push(Bytes::from("REPLACE"));
+        }
+        connection.write_frame(&command_frame("restore-asking", &args)).await.map_err(|err| io_error(&err))?;
+    }
+    let mut restored = Vec::with_capacity(dumped.len());
+    for _ in dumped {
+        restored.push(read_reply(&mut connection, timeout).await?);
+    }
+    Ok(restored)
+}
+
+/// 读目标节点的一条回复，目标节点回复错误时返回Ok(Err)
+async fn read_reply(connection: &mut Connection, timeout: Duration) -> Result<Result<(), String>, Frame> {
+    match tokio::time::timeout(timeout, connection.read_frame()).await {
+        Ok(Ok(Some(Frame::Error(err)))) => Ok(Err(err)),
+        Ok(Ok(Some(_))) => Ok(Ok(())),
+        Ok(Ok(None)) => Err(io_error(&"connection closed")),
+        Ok(Err(err)) => Err(io_error(&err)),
+        Err(_) => Err(io_error(&"timeout")),
+    }
+}
+
+fn io_error(err: &dyn std::fmt::Display) -> Frame {
+    Frame::error(format!("IOERR error or timeout migrating to target instance: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use bytes::Bytes;
+    use tokio::net::{TcpListener, TcpStream};
+    use resp::{Connection, Frame};
+    use crate::cluster::{crc16, key_slot, Cluster};
+    use crate::config::Config;
+    use crate::server::Server;
+    use crate::tls::Listener;
+
+    #[test]
+    fn test_key_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"{user1000}.followers"));
+        assert_eq!(key_slot(b"foo{bar}{zap}"), key_slot(b"bar"));
+        // 花括号中间为空时对整个键求哈希，只看第一个`{`和它后面第一个`}`
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % 16384);
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+    }
+
+    /// 在随机端口上启动一个集群节点，返回端口和连接
+    async fn start_node() -> (u16, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let path = std::env::temp_dir().join(format!("my-redis-nodes-{}-{}.conf", std::process::id(), port));
+        let _ = std::fs::remove_file(&path);
+        let mut server = Server::with_config(Config { port, cluster_enabled: true, ..Config::default() });
+        server.cluster = Some(Cluster::open(&path, "127.0.0.1", port).unwrap());
+        tokio::spawn(crate::run(vec![Listener::new(listener, None)], Arc::new(server)));
+        (port, Connection::new(TcpStream::connect(("127.0.0.1", port)).await.unwrap()))
+    }
+
+    async fn request(connection: &mut Connection, parts: &[&str]) -> Frame {
+        let frame = Frame::Array(parts.iter().map(|part| Frame::Bulk(Bytes::from(part.to_string()))).collect());
+        connection.write_frame(&frame).await.unwrap();
+        connection.read_frame().await.unwrap().unwrap()
+    }
+
+    /// 交换视图是异步的，反复查询直到结果符合预期
+    async fn wait_until(connection: &mut Connection, parts: &[&str], check: impl Fn(&Frame) -> bool) {
+        let mut last = Frame::Null;
+        for _ in 0..100 {
+            last = request(connection, parts).await;
+            if check(&last) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("{:?}: unexpected {:?}", parts, last);
+    }
+
+    fn bulk(s: &str) -> Frame {
+        Frame::Bulk(Bytes::from(s.to_string()))
+    }
+
+    fn error(s: String) -> Frame {
+        Frame::Error(s)
+    }
+
+    #[tokio::test]
+    async fn test_cluster() {
+        let (port_a, mut a) = start_node().await;
+        let (port_b, mut b) = start_node().await;
+        let (port_c, mut c) = start_node().await;
+        assert_eq!(request(&mut a, &["CLUSTER", "ADDSLOTSRANGE", "0", "5460"]).await, Frame::ok());
+        assert_eq!(request(&mut b, &["CLUSTER", "ADDSLOTSRANGE", "5461", "10922"]).await, Frame::ok());
+        assert_eq!(request(&mut c, &["CLUSTER", "ADDSLOTSRANGE", "10923", "16383"]).await, Frame::ok());
+        assert!(matches!(request(&mut a, &["CLUSTER", "ADDSLOTS", "5460"]).await, Frame::Error(err) if err.contains("busy")));
+        // 只需要让a认识b和c，b和c通过a互相认识
+        request(&mut a, &["CLUSTER", "MEET", "127.0.0.1", &port_b.to_string()]).await;
+        request(&mut a, &["CLUSTER", "MEET", "127.0.0.1", &port_c.to_string()]).await;
+        for node in [&mut a, &mut b, &mut c] {
+            wait_until(node, &["CLUSTER", "INFO"], |info| matches!(info, Frame::Bulk(info)
+                if info.starts_with(b"cluster_enabled:1\r\ncluster_state:ok\r\n") && String::from_utf8_lossy(info).contains("cluster_known_nodes:3"))).await;
+        }
+        let id_a = match request(&mut a, &["CLUSTER", "MYID"]).await {
+            Frame::Bulk(id) => String::from_utf8(id.to_vec()).unwrap(),
+            other => panic!("{:?}", other),
+        };
+        let id_c = match request(&mut c, &["CLUSTER", "MYID"]).await {
+            Frame::Bulk(id) => String::from_utf8(id.to_vec()).unwrap(),
+            other => panic!("{:?}", other),
+        };
+
+        // foo在12182号槽上，由c负责
+        assert_eq!(request(&mut a, &["SET", "foo", "bar"]).await, error(format!("MOVED 12182 127.0.0.1:{}", port_c)));
+        assert_eq!(request(&mut c, &["SET", "foo", "bar"]).await, Frame::ok());
+        assert_eq!(request(&mut c, &["SET", "{foo}.b", "2"]).await, Frame::ok());
+        assert!(matches!(request(&mut c, &["MGET", "foo", "bar"]).await, Frame::Error(err) if err.starts_with("CROSSSLOT")));
+        assert_eq!(request(&mut c, &["MGET", "foo", "{foo}.b"]).await, Frame::Array(vec![bulk("bar"), bulk("2")]));
+
+        // 把12182号槽从c迁到a
+        assert_eq!(request(&mut a, &["CLUSTER", "SETSLOT", "12182", "IMPORTING", &id_c]).await, Frame::ok());
+        assert_eq!(request(&mut c, &["CLUSTER", "SETSLOT", "12182", "MIGRATING", &id_a]).await, Frame::ok());
+        assert_eq!(request(&mut c, &["GET", "foo"]).await, bulk("bar"));
+        let ask = error(format!("ASK 12182 127.0.0.1:{}", port_a));
+        assert_eq!(request(&mut c, &["GET", "{foo}.missing"]).await, ask);
+        assert_eq!(request(&mut a, &["GET", "foo"]).await, error(format!("MOVED 12182 127.0.0.1:{}", port_c)));
+        assert_eq!(request(&mut a, &["ASKING"]).await, Frame::ok());
+        assert_eq!(request(&mut a, &["GET", "foo"]).await, Frame::Null);
+        assert_eq!(request(&mut c, &["CLUSTER", "COUNTKEYSINSLOT", "12182"]).await, Frame::Integer(2));
+        assert!(matches!(request(&mut c, &["CLUSTER", "GETKEYSINSLOT", "12182", "1"]).await, Frame::Array(keys) if keys.len() == 1));
+        // 事务和脚本已经锁住了foo所在的分片，不能再执行要自己加锁的CLUSTER子命令
+        assert_eq!(request(&mut c, &["MULTI"]).await, Frame::ok());
+        assert_eq!(request(&mut c, &["SET", "foo", "bar"]).await, Frame::Simple("QUEUED".to_string()));
+        assert_eq!(request(&mut c, &["CLUSTER", "COUNTKEYSINSLOT", "12182"]).await, Frame::error("ERR Command not allowed inside a transaction"));
+        assert!(matches!(request(&mut c, &["EXEC"]).await, Frame::Error(err) if err.starts_with("EXECABORT")));
+        assert_eq!(request(&mut c, &["EVAL", r#"redis.command("CLUSTER", "COUNTKEYSINSLOT", "12182")"#, "1", "foo"]).await,
+            Frame::error("ERR This Redis command is not allowed from script"));
+        assert_eq!(request(&mut c, &["CLUSTER", "COUNTKEYSINSLOT", "12182"]).await, Frame::Integer(2));
+        let port = port_a.to_string();
+        assert_eq!(request(&mut c, &["MIGRATE", "127.0.0.1", &port, "", "0", "5000", "KEYS", "foo", "{foo}.b"]).await, Frame::ok());
+        assert_eq!(request(&mut c, &["MIGRATE", "127.0.0.1", &port, "foo", "0", "5000"]).await, Frame::Simple("NOKEY".to_string()));
+        assert_eq!(request(&mut c, &["GET", "foo"]).await, ask);
+        assert_eq!(request(&mut a, &["ASKING"]).await, Frame::ok());
+        assert_eq!(request(&mut a, &["GET", "foo"]).await, bulk("bar"));
+
+        // 交出槽之后c回复MOVED，b通过a增大的配置纪元得知新的归属
+        assert_eq!(request(&mut a, &["CLUSTER", "SETSLOT", "12182", "NODE", &id_a]).await, Frame::ok());
+        assert_eq!(request(&mut c, &["CLUSTER", "SETSLOT", "12182", "NODE", &id_a]).await, Frame::ok());
+        let moved = error(format!("MOVED 12182 127.0.0.1:{}", port_a));
+        assert_eq!(request(&mut c, &["GET", "foo"]).await, moved);
+        assert_eq!(request(&mut a, &["GET", "foo"]).await, bulk("bar"));
+        wait_until(&mut b, &["GET", "foo"], |reply| *reply == moved).await;
+        match request(&mut b, &["CLUSTER", "SLOTS"]).await {
+            Frame::Array(ranges) => assert!(ranges.contains(&Frame::Array(vec![
+                Frame::Integer(12182),
+                Frame::Integer(12182),
+                Frame::Array(vec![bulk("127.0.0.1"), Frame::Integer(port_a as i64), bulk(&id_a)]),
+            ]))),
+            other => panic!("{:?}", other),
+        }
+        for port in [port_a, port_b, port_c] {
+            let _ = std::fs::remove_file(std::env::temp_dir().join(format!("my-redis-nodes-{}-{}.conf", std::process::id(), port)));
+        }
+    }
+}
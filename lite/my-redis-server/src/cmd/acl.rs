@@ -0,0 +1,92 @@
+use bytes::Bytes;
+use resp::Frame;
+use crate::acl::{self, CATEGORIES};
+use crate::cmd::{self, Reply};
+use crate::server::{Client, Server};
+
+/// AUTH [username] password，由process调用，成功之后连接以这个用户的身份执行命令
+pub fn auth(server: &Server, client: &Client, args: &[Bytes]) -> Reply {
+    let (name, password) = match args {
+        [_] if server.acl.default_nopass() => {
+            return Err(Frame::error("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?"));
+        }
+        [password] => ("default".to_string(), password),
+        [name, password] => (String::from_utf8_lossy(name).to_string(), password),
+        _ => return Err(cmd::syntax_error()),
+    };
+    authenticate(server, client, &name, password)?;
+    Ok(Frame::ok())
+}
+
+/// AUTH和HELLO AUTH共用
+pub fn authenticate(server: &Server, client: &Client, name: &str, password: &[u8]) -> Result<(), Frame> {
+    if !server.acl.authenticate(name, password) {
+        verbose!("Authentication failed for user '{}' from {}", name, client.addr);
+        return Err(acl::wrongpass());
+    }
+    client.set_user(Some(name.to_string()));
+    Ok(())
+}
+
+/// ACL SETUSER|GETUSER|DELUSER|USERS|LIST|WHOAMI|CAT|LOAD|SAVE，由process调用
+pub fn acl(server: &Server, client: &Client, args: &[Bytes]) -> Reply {
+    let sub = String::from_utf8_lossy(&args[0]).to_lowercase();
+    let bulk = |s: String| Frame::Bulk(Bytes::from(s));
+    match (sub.as_str(), &args[1..]) {
+        ("setuser", [name, rules @ ..]) => {
+            server.acl.set_user(&String::from_utf8_lossy(name), rules)?;
+            Ok(Frame::ok())
+        }
+        ("getuser", [name]) => Ok(server.acl.user(&String::from_utf8_lossy(name)).map_or(Frame::Null, |user| user.info())),
+        ("deluser", names) if !names.is_empty() => {
+            let deleted = server.acl.delete_users(names)?;
+            kill_clients(server, &deleted);
+            Ok(Frame::Integer(deleted.len() as i64))
+        }
+        ("users", []) => Ok(Frame::Array(server.acl.usernames().into_iter().map(bulk).collect())),
+        ("list", []) => Ok(Frame::Array(server.acl.list().into_iter().map(bulk).collect())),
+        ("whoami", []) => Ok(bulk(client.user().unwrap_or_else(|| "default".to_string()))),
+        ("cat", []) => Ok(Frame::Array(CATEGORIES.iter().map(|(name, _)| bulk(name.to_string())).collect())),
+        ("cat", [category]) => {
+            let category = String::from_utf8_lossy(category);
+            let bit = acl::category(&category).ok_or_else(|| Frame::error(format!("ERR Unknown category '{}'", category)))?;
+            Ok(Frame::Array(cmd::commands()
+                .filter(|spec| acl::categories(spec) & bit != 0)
+                .map(|spec| bulk(spec.name.to_string()))
+                .collect()))
+        }
+        ("load", []) => {
+            let path = acl_file(server)?;
+            let before = server.acl.usernames();
+            server.acl.load(&path).map_err(|err| Frame::error(format!("ERR Error loading ACLs: {}", err)))?;
+            // 文件里已经没有的用户和被删掉一样，断开用它认证的连接
+            let users = server.acl.usernames();
+            kill_clients(server, &before.into_iter().filter(|name| !users.contains(name)).collect::<Vec<_>>());
+            Ok(Frame::ok())
+        }
+        ("save", []) => {
+            let path = acl_file(server)?;
+            server.acl.save(&path).map_err(|err| {
+                warning!("Error saving ACLs to {}: {}", path, err);
+                Frame::error("ERR There was an error trying to save the ACLs. Please check the server logs for more information")
+            })?;
+            Ok(Frame::ok())
+        }
+        _ => Err(Frame::error(format!("ERR unknown subcommand or wrong number of arguments for '{}'. Try ACL HELP.", sub))),
+    }
+}
+
+fn acl_file(server: &Server) -> Result<String, Frame> {
+    let path = server.config.read().unwrap().aclfile.clone();
+    if path.is_empty() {
+        return Err(Frame::error("ERR This Redis instance is not configured to use an ACL file. You may want to specify users via the ACL SETUSER command."));
+    }
+    Ok(path)
+}
+
+/// 断开用这些用户认证的连接
+fn kill_clients(server: &Server, users: &[String]) {
+    server.clients.list().into_iter()
+        .filter(|client| client.user().is_some_and(|user| users.contains(&user)))
+        .for_each(|client| client.kill());
+}
@@ -0,0 +1,70 @@
+use bytes::Bytes;
+use resp::Frame;
+use crate::cluster::{key_slot, SetSlot, SLOTS};
+use crate::cmd::{eq_ignore_case, parse_i64, Reply};
+use crate::server::Server;
+
+/// CLUSTER INFO|MYID|NODES|SLOTS|KEYSLOT|COUNTKEYSINSLOT|GETKEYSINSLOT|MEET|ADDSLOTS|ADDSLOTSRANGE|DELSLOTS|SETSLOT
+pub fn cluster(server: &Server, args: &[Bytes]) -> Reply {
+    let cluster = server.cluster.as_ref().ok_or_else(|| Frame::error("ERR This instance has cluster support disabled"))?;
+    let sub = String::from_utf8_lossy(&args[0]).to_lowercase();
+    match (sub.as_str(), &args[1..]) {
+        ("info", []) => Ok(Frame::Verbatim("txt".to_string(), Bytes::from(cluster.info()))),
+        ("myid", []) => Ok(Frame::Bulk(Bytes::from(cluster.myid()))),
+        ("nodes", []) => Ok(Frame::Verbatim("txt".to_string(), Bytes::from(cluster.nodes()))),
+        ("slots", []) => Ok(cluster.slots()),
+        ("keyslot", [key]) => Ok(Frame::Integer(key_slot(key) as i64)),
+        ("countkeysinslot", [slot]) => Ok(Frame::Integer(server.db.count_keys_in_slot(parse_slot(slot)?) as i64)),
+        ("getkeysinslot", [slot, count]) => {
+            let slot = parse_slot(slot)?;
+            let count = usize::try_from(parse_i64(count)?).map_err(|_| Frame::error("ERR Invalid number of keys"))?;
+            Ok(Frame::Array(server.db.keys_in_slot(slot, count).into_iter().map(Frame::Bulk).collect()))
+        }
+        ("meet", [ip, port, ..]) => {
+            let port = std::str::from_utf8(port).ok().and_then(|port| port.parse().ok())
+                .ok_or_else(|| Frame::error(format!("ERR Invalid base port specified: {}", String::from_utf8_lossy(port))))?;
+            cluster.meet(String::from_utf8_lossy(ip).to_string(), port);
+            Ok(Frame::ok())
+        }
+        ("addslots", slots) if !slots.is_empty() => {
+            cluster.add_slots(&slots.iter().map(parse_slot).collect::<Result<Vec<_>, _>>()?)?;
+            Ok(Frame::ok())
+        }
+        ("addslotsrange", ranges) if !ranges.is_empty() && ranges.len().is_multiple_of(2) => {
+            let mut slots = Vec::new();
+            for range in ranges.chunks(2) {
+                let (start, end) = (parse_slot(&range[0])?, parse_slot(&range[1])?);
+                if start > end {
+                    return Err(Frame::error(format!("ERR start slot number {} is greater than end slot number {}", start, end)));
+                }
+                slots.extend(start..=end);
+            }
+            cluster.add_slots(&slots)?;
+            Ok(Frame::ok())
+        }
+        ("delslots", slots) if !slots.is_empty() => {
+            cluster.del_slots(&slots.iter().map(parse_slot).collect::<Result<Vec<_>, _>>()?)?;
+            Ok(Frame::ok())
+        }
+        ("setslot", [slot, action, rest @ ..]) => {
+            let slot = parse_slot(slot)?;
+            let action = match rest {
+                [id] if eq_ignore_case(action, "importing") => SetSlot::Importing(id.clone()),
+                [id] if eq_ignore_case(action, "migrating") => SetSlot::Migrating(id.clone()),
+                [id] if eq_ignore_case(action, "node") => SetSlot::Node(id.clone()),
+                [] if eq_ignore_case(action, "stable") => SetSlot::Stable,
+                _ => return Err(Frame::error("ERR Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP")),
+            };
+            cluster.set_slot(&server.db, slot, action)?;
+            Ok(Frame::ok())
+        }
+        _ => Err(Frame::error(format!("ERR unknown subcommand or wrong number of arguments for '{}'. Try CLUSTER HELP.", sub))),
+    }
+}
+
+fn parse_slot(arg: &Bytes) -> Result<u16, Frame> {
+    std::str::from_utf8(arg).ok()
+        .and_then(|slot| slot.parse::<u16>().ok())
+        .filter(|slot| (*slot as usize) < SLOTS)
+        .ok_or_else(|| Frame::error("ERR Invalid or out of range slot"))
+}
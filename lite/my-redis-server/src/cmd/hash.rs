@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use bytes::Bytes;
+use resp::Frame;
+use crate::cmd::{wrong_arity, Reply};
+use crate::db::{wrong_type, Db};
+use crate::value::Value;
+
+fn get_hash<'a>(db: &'a mut Db, key: &Bytes) -> Result<Option<&'a mut HashMap<Bytes, Bytes>>, Frame> {
+    match db.get_mut(key) {
+        Some(Value::Hash(hash)) => Ok(Some(hash)),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+/// HSET key field value [field value ...]，返回新增的字段数
+pub fn hset(db: &mut Db, args: &[Bytes]) -> Reply {
+    if !(args.len() - 1).is_multiple_of(2) {
+        return Err(wrong_arity("hset"));
+    }
+    let hash = match db.get_or_insert_with(&args[0], || Value::Hash(HashMap::new())) {
+        Value::Hash(hash) => hash,
+        _ => return Err(wrong_type()),
+    };
+    let added = args[1..].chunks(2)
+        .filter(|pair| hash.insert(pair[0].clone(), pair[1].clone()).is_none())
+        .count();
+    // 和Redis一样，覆盖已有的字段也算修改
+    db.add_dirty((args.len() - 1) / 2);
+    Ok(Frame::Integer(added as i64))
+}
+
+pub fn hget(db: &mut Db, args: &[Bytes]) -> Reply {
+    Ok(get_hash(db, &args[0])?.and_then(|hash| hash.get(&args[1]).cloned()).into())
+}
+
+/// HDEL key field [field ...]，返回删除的字段数
+pub fn hdel(db: &mut Db, args: &[Bytes]) -> Reply {
+    let removed = match get_hash(db, &args[0])? {
+        Some(hash) => args[1..].iter().filter(|field| hash.remove(*field).is_some()).count(),
+        None => 0,
+    };
+    db.remove_if_empty(&args[0]);
+    db.add_dirty(removed);
+    Ok(Frame::Integer(removed as i64))
+}
+
+/// HGETALL key，RESP3返回map，RESP2是字段和值交替排列的数组
+pub fn hgetall(db: &mut Db, args: &[Bytes]) -> Reply {
+    let hash = match get_hash(db, &args[0])? {
+        Some(hash) => hash,
+        None => return Ok(Frame::Map(vec![])),
+    };
+    Ok(Frame::Map(hash.iter()
+        .map(|(field, value)| (Frame::Bulk(field.clone()), Frame::Bulk(value.clone())))
+        .collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use resp::Frame;
+    use crate::cmd::testing::{bulk, run};
+    use crate::db::Keyspace;
+
+    #[test]
+    fn test_hash() {
+        let db = Keyspace::new(4, 1);
+        assert_eq!(run(&db, "HSET h a 1 b 2"), Frame::Integer(2));
+        assert_eq!(run(&db, "HSET h a 3"), Frame::Integer(0));
+        assert_eq!(run(&db, "HGET h a"), bulk("3"));
+        assert_eq!(run(&db, "HGET h x"), Frame::Null);
+        assert_eq!(run(&db, "HDEL h a x"), Frame::Integer(1));
+        assert_eq!(run(&db, "HGETALL h"), Frame::Map(vec![(bulk("b"), bulk("2"))]));
+        assert_eq!(run(&db, "HDEL h b"), Frame::Integer(1));
+        assert_eq!(run(&db, "TYPE h"), Frame::Simple("none".to_string()));
+        assert_eq!(run(&db, "HSET h a"), Frame::error("ERR wrong number of arguments for 'hset' command"));
+    }
+}
@@ -0,0 +1,211 @@
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use resp::Frame;
+use crate::cmd::{eq_ignore_case, parse_i64, syntax_error, Reply};
+use crate::db::{from_unix_millis, Db};
+use crate::rdb;
+
+/// EXPIRE key seconds
+pub fn expire(db: &mut Db, args: &[Bytes]) -> Reply {
+    expire_in(db, args, 1000, "expire")
+}
+
+/// PEXPIRE key milliseconds
+pub fn pexpire(db: &mut Db, args: &[Bytes]) -> Reply {
+    expire_in(db, args, 1, "pexpire")
+}
+
+fn expire_in(db: &mut Db, args: &[Bytes], unit: i64, name: &str) -> Reply {
+    let key = &args[0];
+    let invalid = || Frame::error(format!("ERR invalid expire time in '{}' command", name));
+    let millis = parse_i64(&args[1])?.checked_mul(unit).ok_or_else(invalid)?;
+    // 和Redis一样，过期时间不是正数时直接删除，太大加上当前时间会溢出的也算无效
+    let when = match millis {
+        millis if millis <= 0 => None,
+        millis => Some(Instant::now().checked_add(Duration::from_millis(millis as u64)).ok_or_else(invalid)?),
+    };
+    if !db.contains_key(key) {
+        return Ok(Frame::Integer(0));
+    }
+    match when {
+        Some(_) => {
+            db.set_expire(key, when);
+        }
+        None => {
+            db.remove(key);
+        }
+    }
+    Ok(Frame::Integer(1))
+}
+
+/// EXPIREAT key unix-time-seconds
+pub fn expireat(db: &mut Db, args: &[Bytes]) -> Reply {
+    expire_at(db, args, 1000, "expireat")
+}
+
+/// PEXPIREAT key unix-time-milliseconds，AOF里的过期时间都转换成这个命令
+pub fn pexpireat(db: &mut Db, args: &[Bytes]) -> Reply {
+    expire_at(db, args, 1, "pexpireat")
+}
+
+fn expire_at(db: &mut Db, args: &[Bytes], unit: i64, name: &str) -> Reply {
+    let key = &args[0];
+    let millis = parse_i64(&args[1])?.checked_mul(unit)
+        .ok_or_else(|| Frame::error(format!("ERR invalid expire time in '{}' command", name)))?;
+    if !db.contains_key(key) {
+        return Ok(Frame::Integer(0));
+    }
+    let when = from_unix_millis(millis);
+    if when <= Instant::now() {
+        db.remove(key);
+    } else {
+        db.set_expire(key, Some(when));
+    }
+    Ok(Frame::Integer(1))
+}
+
+/// TTL key，键不存在返回-2，没有过期时间返回-1
+pub fn ttl(db: &mut Db, args: &[Bytes]) -> Reply {
+    Ok(Frame::Integer(remaining_millis(db, &args[0]).map_or_else(|code| code, |millis| (millis + 500) / 1000)))
+}
+
+/// PTTL key
+pub fn pttl(db: &mut Db, args: &[Bytes]) -> Reply {
+    Ok(Frame::Integer(remaining_millis(db, &args[0]).unwrap_or_else(|code| code)))
+}
+
+fn remaining_millis(db: &mut Db, key: &Bytes) -> Result<i64, i64> {
+    match db.expires_at(key) {
+        None => Err(-2),
+        Some(None) => Err(-1),
+        Some(Some(when)) => Ok(when.saturating_duration_since(Instant::now()).as_millis() as i64),
+    }
+}
+
+/// TYPE key，不存在时返回none
+pub fn type_of(db: &mut Db, args: &[Bytes]) -> Reply {
+    Ok(Frame::Simple(db.get(&args[0]).map_or("none", |value| value.type_name()).to_string()))
+}
+
+/// PERSIST key，移除过期时间
+pub fn persist(db: &mut Db, args: &[Bytes]) -> Reply {
+    let removed = matches!(db.expires_at(&args[0]), Some(Some(_))) && db.set_expire(&args[0], None);
+    Ok(Frame::Integer(removed as i64))
+}
+
+/// MOVE key db，目标数据库里已经有这个键时什么都不做，返回0
+pub fn move_key(db: &mut Db, args: &[Bytes]) -> Reply {
+    let dst = parse_db_index(&args[1], db.databases())?;
+    if dst == db.index() {
+        return Err(Frame::error("ERR source and destination objects are the same"));
+    }
+    Ok(Frame::Integer(db.move_to(&args[0], dst) as i64))
+}
+
+/// 解析数据库编号，SELECT、MOVE、SWAPDB使用
+pub fn parse_db_index(arg: &Bytes, databases: usize) -> Result<usize, Frame> {
+    let index = parse_i64(arg).map_err(|_| Frame::error("ERR invalid DB index"))?;
+    if index < 0 || index as usize >= databases {
+        return Err(Frame::error("ERR DB index is out of range"));
+    }
+    Ok(index as usize)
+}
+
+/// MEMORY USAGE key，返回估算的字节数
+pub fn memory(db: &mut Db, args: &[Bytes]) -> Reply {
+    if !eq_ignore_case(&args[0], "usage") || args.len() != 2 {
+        return Err(syntax_error());
+    }
+    Ok(db.memory_usage(&args[1]).map_or(Frame::Null, |size| Frame::Integer(size as i64)))
+}
+
+/// DUMP key，序列化的格式是只有这一个键的快照，不带过期时间
+pub fn dump(db: &mut Db, args: &[Bytes]) -> Reply {
+    Ok(match db.get(&args[0]) {
+        Some(value) => Frame::Bulk(Bytes::from(rdb::encode_entries(&[(args[0].clone(), value.clone(), None)]))),
+        None => Frame::Null,
+    })
+}
+
+/// RESTORE key ttl serialized-value [REPLACE] [ABSTTL]，ttl为0表示不过期，快照里的键名不用
+pub fn restore(db: &mut Db, args: &[Bytes]) -> Reply {
+    let key = &args[0];
+    let ttl = parse_i64(&args[1])?;
+    if ttl < 0 {
+        return Err(Frame::error("ERR Invalid TTL value, must be >= 0"));
+    }
+    let (mut replace, mut absttl) = (false, false);
+    for option in &args[3..] {
+        if eq_ignore_case(option, "replace") {
+            replace = true;
+        } else if eq_ignore_case(option, "absttl") {
+            absttl = true;
+        } else {
+            return Err(syntax_error());
+        }
+    }
+    let value = match rdb::decode(&args[2]) {
+        Ok(mut entries) if entries.len() == 1 => entries.pop().unwrap().2,
+        _ => return Err(Frame::error("ERR DUMP payload version or checksum are wrong")),
+    };
+    if !replace && db.contains_key(key) {
+        return Err(Frame::error("BUSYKEY Target key name already exists."));
+    }
+    let expires_at = match ttl {
+        0 => None,
+        ttl if absttl => Some(from_unix_millis(ttl)),
+        ttl => Some(Instant::now() + Duration::from_millis(ttl as u64)),
+    };
+    // 和Redis一样，已经过期的键只删除旧值
+    if expires_at.is_some_and(|when| when <= Instant::now()) {
+        db.remove(key);
+    } else {
+        db.insert_with_expire(key.clone(), value, expires_at);
+    }
+    Ok(Frame::ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use resp::Frame;
+    use crate::cmd::testing::{bulk, run};
+    use crate::db::Keyspace;
+
+    #[test]
+    fn test_ttl() {
+        let db = Keyspace::new(4, 1);
+        assert_eq!(run(&db, "TTL k"), Frame::Integer(-2));
+        assert_eq!(run(&db, "SET k v"), Frame::ok());
+        assert_eq!(run(&db, "TTL k"), Frame::Integer(-1));
+        assert_eq!(run(&db, "EXPIRE k 100"), Frame::Integer(1));
+        assert_eq!(run(&db, "TTL k"), Frame::Integer(100));
+        // INCR、APPEND之类的修改保留过期时间，SET会清除
+        assert_eq!(run(&db, "APPEND k v"), Frame::Integer(2));
+        assert_eq!(run(&db, "TTL k"), Frame::Integer(100));
+        assert_eq!(run(&db, "PERSIST k"), Frame::Integer(1));
+        assert_eq!(run(&db, "PERSIST k"), Frame::Integer(0));
+        assert_eq!(run(&db, "SET k v EX 10"), Frame::ok());
+        assert_eq!(run(&db, "SET k v2 KEEPTTL"), Frame::ok());
+        assert_eq!(run(&db, "TTL k"), Frame::Integer(10));
+        assert_eq!(run(&db, "SET k v"), Frame::ok());
+        assert_eq!(run(&db, "TTL k"), Frame::Integer(-1));
+        assert_eq!(run(&db, "SET k v EX 0"), Frame::error("ERR invalid expire time in 'set' command"));
+        assert_eq!(run(&db, "EXPIRE k 9223372036854775807"), Frame::error("ERR invalid expire time in 'expire' command"));
+        assert_eq!(run(&db, "TTL k"), Frame::Integer(-1));
+        assert_eq!(run(&db, "EXPIRE k -1"), Frame::Integer(1));
+        assert_eq!(run(&db, "GET k"), Frame::Null);
+    }
+
+    #[test]
+    fn test_expire() {
+        let db = Keyspace::new(4, 1);
+        assert_eq!(run(&db, "SET k v PX 20"), Frame::ok());
+        assert_eq!(run(&db, "PSETEX p 20 v"), Frame::ok());
+        assert_eq!(run(&db, "GET k"), bulk("v"));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(run(&db, "GET k"), Frame::Null);
+        assert_eq!(run(&db, "EXISTS p"), Frame::Integer(0));
+        assert_eq!(run(&db, "PTTL k"), Frame::Integer(-2));
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::VecDeque;
+use bytes::Bytes;
+use resp::Frame;
+use crate::cmd::{normalize_range, parse_i64, Reply};
+use crate::db::{wrong_type, Db};
+use crate::value::Value;
+
+fn get_list<'a>(db: &'a mut Db, key: &Bytes) -> Result<Option<&'a mut VecDeque<Bytes>>, Frame> {
+    match db.get_mut(key) {
+        Some(Value::List(list)) => Ok(Some(list)),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+fn push(db: &mut Db, args: &[Bytes], front: bool) -> Reply {
+    let key = &args[0];
+    let list = match db.get_or_insert_with(key, || Value::List(VecDeque::new())) {
+        Value::List(list) => list,
+        _ => return Err(wrong_type()),
+    };
+    for value in &args[1..] {
+        if front {
+            list.push_front(value.clone());
+        } else {
+            list.push_back(value.clone());
+        }
+    }
+    let len = list.len();
+    db.add_dirty(args.len() - 1);
+    Ok(Frame::Integer(len as i64))
+}
+
+/// LPUSH key element [element ...]，依次插到表头，所以最后一个元素在最前面
+pub fn lpush(db: &mut Db, args: &[Bytes]) -> Reply {
+    push(db, args, true)
+}
+
+/// RPUSH key element [element ...]
+pub fn rpush(db: &mut Db, args: &[Bytes]) -> Reply {
+    push(db, args, false)
+}
+
+fn pop(db: &mut Db, args: &[Bytes], front: bool) -> Reply {
+    let key = &args[0];
+    let count = match args.get(1) {
+        Some(count) => match parse_i64(count)? {
+            count if count >= 0 => Some(count as usize),
+            _ => return Err(Frame::error("ERR value is out of range, must be positive")),
+        },
+        None => None,
+    };
+    let list = match get_list(db, key)? {
+        Some(list) => list,
+        None => return Ok(if count.is_some() { Frame::Array(vec![]) } else { Frame::Null }),
+    };
+    let mut popped = Vec::new();
+    for _ in 0..count.unwrap_or(1) {
+        let value = if front { list.pop_front() } else { list.pop_back() };
+        match value {
+            Some(value) => popped.push(Frame::Bulk(value)),
+            None => break,
+        }
+    }
+    db.remove_if_empty(key);
+    db.add_dirty(popped.len());
+    Ok(match count {
+        Some(_) => Frame::Array(popped),
+        None => popped.pop().unwrap_or(Frame::Null),
+    })
+}
+
+/// LPOP key [count]
+pub fn lpop(db: &mut Db, args: &[Bytes]) -> Reply {
+    pop(db, args, true)
+}
+
+/// RPOP key [count]
+pub fn rpop(db: &mut Db, args: &[Bytes]) -> Reply {
+    pop(db, args, false)
+}
+
+/// LRANGE key start stop，两端都包含，负数从末尾开始算
+pub fn lrange(db: &mut Db, args: &[Bytes]) -> Reply {
+    let (start, stop) = (parse_i64(&args[1])?, parse_i64(&args[2])?);
+    let list = match get_list(db, &args[0])? {
+        Some(list) => list,
+        None => return Ok(Frame::Array(vec![])),
+    };
+    Ok(Frame::Array(match normalize_range(start, stop, list.len()) {
+        Some((start, stop)) => list.range(start..=stop).cloned().map(Frame::Bulk).collect(),
+        None => vec![],
+    }))
+}
+
+pub fn llen(db: &mut Db, args: &[Bytes]) -> Reply {
+    Ok(Frame::Integer(get_list(db, &args[0])?.map_or(0, |list| list.len()) as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use resp::Frame;
+    use crate::cmd::testing::{bulk, run};
+    use crate::db::Keyspace;
+
+    #[test]
+    fn test_list() {
+        let db = Keyspace::new(4, 1);
+        assert_eq!(run(&db, "RPUSH l a b c"), Frame::Integer(3));
+        assert_eq!(run(&db, "LPUSH l x y"), Frame::Integer(5));
+        assert_eq!(run(&db, "LRANGE l 0 -1"), Frame::Array(vec![bulk("y"), bulk("x"), bulk("a"), bulk("b"), bulk("c")]));
+        assert_eq!(run(&db, "LRANGE l -2 100"), Frame::Array(vec![bulk("b"), bulk("c")]));
+        assert_eq!(run(&db, "LPOP l"), bulk("y"));
+        assert_eq!(run(&db, "RPOP l 2"), Frame::Array(vec![bulk("c"), bulk("b")]));
+        assert_eq!(run(&db, "LLEN l"), Frame::Integer(2));
+        assert_eq!(run(&db, "RPOP l 5"), Frame::Array(vec![bulk("a"), bulk("x")]));
+        // 列表空了之后键也被删除
+        assert_eq!(run(&db, "EXISTS l"), Frame::Integer(0));
+        assert_eq!(run(&db, "LPOP l"), Frame::Null);
+        assert_eq!(run(&db, "SET s v"), Frame::ok());
+        assert!(matches!(run(&db, "LPUSH s a"), Frame::Error(err) if err.starts_with("WRONGTYPE")));
+        assert!(matches!(run(&db, "RPUSH l a"), Frame::Integer(1)));
+        assert!(matches!(run(&db, "GET l"), Frame::Error(err) if err.starts_with("WRONGTYPE")));
+    }
+}
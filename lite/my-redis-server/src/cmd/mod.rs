@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use bytes::Bytes;
+use resp::Frame;
+use crate::acl::{ADMIN, CONNECTION, DANGEROUS, FAST, HASH, KEYSPACE, LIST, PUBSUB, READ, SCRIPTING, SET, SORTEDSET, STRING, TRANSACTION};
+use crate::aof;
+use crate::db::Db;
+use crate::evict;
+use crate::server::Server;
+
+mod acl;
+mod cluster;
+mod hash;
+mod keys;
+mod list;
+mod pubsub;
+mod script;
+mod server;
+mod set;
+mod string;
+mod zset;
+
+pub use acl::{acl, auth};
+pub use keys::parse_db_index;
+pub use script::{eval, evalsha};
+pub use server::{client, hello, replconf, replicaof, select, shutdown};
+
+/// 处理函数的返回值，Err里放的是要直接回给客户端的错误帧，这样处理函数里可以用`?`
+pub type Reply = Result<Frame, Frame>;
+
+/// 命令的处理方式
+pub enum Handler {
+    /// 只访问键空间，执行时持有键空间的锁
+    Db(fn(&mut Db, &[Bytes]) -> Reply),
+    /// 需要访问键空间以外的服务端状态
+    Server(fn(&Server, &[Bytes]) -> Reply),
+    /// 和Server一样，但还需要连接当前选择的数据库编号
+    Database(fn(&Server, usize, &[Bytes]) -> Reply),
+    /// 会改变连接状态的命令(比如进入订阅模式)，由process直接处理
+    Connection,
+}
+
+/// 命令参数中哪些是键，和Redis的first key、last key、step约定一致：下标从命令名之后的第一个参数算起，
+/// last为负数表示从末尾倒数。执行前根据它锁住对应的分片
+#[derive(Clone, Copy)]
+pub struct KeySpec {
+    pub first: usize,
+    pub last: isize,
+    pub step: usize,
+    /// 为true时下标first的参数是键的个数，紧跟在它后面的这么多个参数是键，last和step不用
+    pub numkeys: bool,
+}
+
+/// 不涉及键
+const NO_KEYS: KeySpec = KeySpec { first: 0, last: -1, step: 0, numkeys: false };
+/// 只有第一个参数是键
+const FIRST_KEY: KeySpec = KeySpec { first: 0, last: 0, step: 1, numkeys: false };
+/// 所有参数都是键，比如DEL、MGET
+const ALL_KEYS: KeySpec = KeySpec { first: 0, last: -1, step: 1, numkeys: false };
+/// 键值交替，比如MSET
+const PAIR_KEYS: KeySpec = KeySpec { first: 0, last: -1, step: 2, numkeys: false };
+/// 第二个参数是键的个数，比如EVAL script numkeys key [key ...] arg [arg ...]
+const NUM_KEYS: KeySpec = KeySpec { first: 1, last: 0, step: 1, numkeys: true };
+
+impl KeySpec {
+    pub fn keys<'a>(&self, args: &'a [Bytes]) -> impl Iterator<Item = &'a Bytes> {
+        if self.numkeys {
+            // 键的个数不合法时由命令自己报错
+            let count = args.get(self.first).and_then(|count| std::str::from_utf8(count).ok()?.parse::<usize>().ok()).unwrap_or(0);
+            let keys = args.get(self.first + 1..).unwrap_or_default();
+            return keys[..count.min(keys.len())].iter().step_by(1);
+        }
+        let last = if self.last < 0 { args.len() as isize + self.last } else { self.last };
+        let end = if self.step == 0 { 0 } else { (last + 1).clamp(0, args.len() as isize) as usize };
+        args.get(self.first.min(end)..end).unwrap_or_default().iter().step_by(self.step.max(1))
+    }
+}
+
+/// 命令表中的一项，arity和Redis的约定一致：包含命令名本身，负数表示至少需要这么多个参数。
+/// categories是ACL的命令类别，见acl模块
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub arity: i32,
+    pub keys: KeySpec,
+    pub flags: u32,
+    pub categories: u32,
+    pub handler: Handler,
+}
+
+/// 会修改键空间，真正修改了键空间时计入快照的修改次数并写入AOF
+pub const WRITE: u32 = 1;
+/// 可能增加内存占用，执行前先检查maxmemory
+pub const DENY_OOM: u32 = 1 << 1;
+/// 不能在MULTI中排队，比如SAVE会锁住所有分片，而EXEC执行时已经持有了分片锁
+pub const NO_MULTI: u32 = 1 << 2;
+/// 集群模式下相当于前面带了一条ASKING，MIGRATE发给目标节点的RESTORE-ASKING使用
+pub const ASKING: u32 = 1 << 3;
+/// 没有认证的连接也可以执行，也不受ACL限制
+pub const NO_AUTH: u32 = 1 << 4;
+
+impl CommandSpec {
+    pub fn has(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+static COMMANDS: &[CommandSpec] = &[
+    CommandSpec { name: "get", arity: 2, keys: FIRST_KEY, flags: 0, categories: STRING | READ | FAST, handler: Handler::Db(string::get) },
+    CommandSpec { name: "set", arity: -3, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: STRING, handler: Handler::Db(string::set) },
+    CommandSpec { name: "del", arity: -2, keys: ALL_KEYS, flags: WRITE, categories: KEYSPACE, handler: Handler::Db(string::del) },
+    CommandSpec { name: "exists", arity: -2, keys: ALL_KEYS, flags: 0, categories: KEYSPACE | READ | FAST, handler: Handler::Db(string::exists) },
+    CommandSpec { name: "incr", arity: 2, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: STRING | FAST, handler: Handler::Db(string::incr) },
+    CommandSpec { name: "decr", arity: 2, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: STRING | FAST, handler: Handler::Db(string::decr) },
+    CommandSpec { name: "incrby", arity: 3, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: STRING | FAST, handler: Handler::Db(string::incrby) },
+    CommandSpec { name: "decrby", arity: 3, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: STRING | FAST, handler: Handler::Db(string::decrby) },
+    CommandSpec { name: "append", arity: 3, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: STRING | FAST, handler: Handler::Db(string::append) },
+    CommandSpec { name: "strlen", arity: 2, keys: FIRST_KEY, flags: 0, categories: STRING | READ | FAST, handler: Handler::Db(string::strlen) },
+    CommandSpec { name: "mget", arity: -2, keys: ALL_KEYS, flags: 0, categories: STRING | READ | FAST, handler: Handler::Db(string::mget) },
+    CommandSpec { name: "mset", arity: -3, keys: PAIR_KEYS, flags: WRITE | DENY_OOM, categories: STRING, handler: Handler::Db(string::mset) },
+    CommandSpec { name: "setnx", arity: 3, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: STRING | FAST, handler: Handler::Db(string::setnx) },
+    CommandSpec { name: "getset", arity: 3, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: STRING | FAST, handler: Handler::Db(string::getset) },
+    CommandSpec { name: "getrange", arity: 4, keys: FIRST_KEY, flags: 0, categories: STRING | READ, handler: Handler::Db(string::getrange) },
+    CommandSpec { name: "setrange", arity: 4, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: STRING, handler: Handler::Db(string::setrange) },
+    CommandSpec { name: "setex", arity: 4, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: STRING, handler: Handler::Db(string::setex) },
+    CommandSpec { name: "psetex", arity: 4, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: STRING, handler: Handler::Db(string::psetex) },
+    CommandSpec { name: "expire", arity: 3, keys: FIRST_KEY, flags: WRITE, categories: KEYSPACE | FAST, handler: Handler::Db(keys::expire) },
+    CommandSpec { name: "pexpire", arity: 3, keys: FIRST_KEY, flags: WRITE, categories: KEYSPACE | FAST, handler: Handler::Db(keys::pexpire) },
+    CommandSpec { name: "expireat", arity: 3, keys: FIRST_KEY, flags: WRITE, categories: KEYSPACE | FAST, handler: Handler::Db(keys::expireat) },
+    CommandSpec { name: "pexpireat", arity: 3, keys: FIRST_KEY, flags: WRITE, categories: KEYSPACE | FAST, handler: Handler::Db(keys::pexpireat) },
+    CommandSpec { name: "ttl", arity: 2, keys: FIRST_KEY, flags: 0, categories: KEYSPACE | READ | FAST, handler: Handler::Db(keys::ttl) },
+    CommandSpec { name: "pttl", arity: 2, keys: FIRST_KEY, flags: 0, categories: KEYSPACE | READ | FAST, handler: Handler::Db(keys::pttl) },
+    CommandSpec { name: "persist", arity: 2, keys: FIRST_KEY, flags: WRITE, categories: KEYSPACE | FAST, handler: Handler::Db(keys::persist) },
+    CommandSpec { name: "type", arity: 2, keys: FIRST_KEY, flags: 0, categories: KEYSPACE | READ | FAST, handler: Handler::Db(keys::type_of) },
+    CommandSpec { name: "dump", arity: 2, keys: FIRST_KEY, flags: 0, categories: KEYSPACE | READ, handler: Handler::Db(keys::dump) },
+    CommandSpec { name: "restore", arity: -4, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: KEYSPACE | DANGEROUS, handler: Handler::Db(keys::restore) },
+    CommandSpec { name: "restore-asking", arity: -4, keys: FIRST_KEY, flags: WRITE | DENY_OOM | ASKING, categories: KEYSPACE | DANGEROUS, handler: Handler::Db(keys::restore) },
+    CommandSpec { name: "memory", arity: -2, keys: KeySpec { first: 1, last: 1, step: 1, numkeys: false }, flags: 0, categories: READ, handler: Handler::Db(keys::memory) },
+    CommandSpec { name: "lpush", arity: -3, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: LIST | FAST, handler: Handler::Db(list::lpush) },
+    CommandSpec { name: "rpush", arity: -3, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: LIST | FAST, handler: Handler::Db(list::rpush) },
+    CommandSpec { name: "lpop", arity: -2, keys: FIRST_KEY, flags: WRITE, categories: LIST | FAST, handler: Handler::Db(list::lpop) },
+    CommandSpec { name: "rpop", arity: -2, keys: FIRST_KEY, flags: WRITE, categories: LIST | FAST, handler: Handler::Db(list::rpop) },
+    CommandSpec { name: "lrange", arity: 4, keys: FIRST_KEY, flags: 0, categories: LIST | READ, handler: Handler::Db(list::lrange) },
+    CommandSpec { name: "llen", arity: 2, keys: FIRST_KEY, flags: 0, categories: LIST | READ | FAST, handler: Handler::Db(list::llen) },
+    CommandSpec { name: "hset", arity: -4, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: HASH | FAST, handler: Handler::Db(hash::hset) },
+    CommandSpec { name: "hget", arity: 3, keys: FIRST_KEY, flags: 0, categories: HASH | READ | FAST, handler: Handler::Db(hash::hget) },
+    CommandSpec { name: "hdel", arity: -3, keys: FIRST_KEY, flags: WRITE, categories: HASH | FAST, handler: Handler::Db(hash::hdel) },
+    CommandSpec { name: "hgetall", arity: 2, keys: FIRST_KEY, flags: 0, categories: HASH | READ, handler: Handler::Db(hash::hgetall) },
+    CommandSpec { name: "sadd", arity: -3, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: SET | FAST, handler: Handler::Db(set::sadd) },
+    CommandSpec { name: "srem", arity: -3, keys: FIRST_KEY, flags: WRITE, categories: SET | FAST, handler: Handler::Db(set::srem) },
+    CommandSpec { name: "smembers", arity: 2, keys: FIRST_KEY, flags: 0, categories: SET | READ, handler: Handler::Db(set::smembers) },
+    CommandSpec { name: "sinter", arity: -2, keys: ALL_KEYS, flags: 0, categories: SET | READ, handler: Handler::Db(set::sinter) },
+    CommandSpec { name: "zadd", arity: -4, keys: FIRST_KEY, flags: WRITE | DENY_OOM, categories: SORTEDSET | FAST, handler: Handler::Db(zset::zadd) },
+    CommandSpec { name: "zrange", arity: -4, keys: FIRST_KEY, flags: 0, categories: SORTEDSET | READ, handler: Handler::Db(zset::zrange) },
+    CommandSpec { name: "zrem", arity: -3, keys: FIRST_KEY, flags: WRITE, categories: SORTEDSET | FAST, handler: Handler::Db(zset::zrem) },
+    CommandSpec { name: "zrank", arity: 3, keys: FIRST_KEY, flags: 0, categories: SORTEDSET | READ | FAST, handler: Handler::Db(zset::zrank) },
+    CommandSpec { name: "zscore", arity: 3, keys: FIRST_KEY, flags: 0, categories: SORTEDSET | READ | FAST, handler: Handler::Db(zset::zscore) },
+    CommandSpec { name: "publish", arity: 3, keys: NO_KEYS, flags: 0, categories: PUBSUB | FAST, handler: Handler::Server(pubsub::publish) },
+    CommandSpec { name: "subscribe", arity: -2, keys: NO_KEYS, flags: NO_MULTI, categories: PUBSUB, handler: Handler::Connection },
+    CommandSpec { name: "psubscribe", arity: -2, keys: NO_KEYS, flags: NO_MULTI, categories: PUBSUB, handler: Handler::Connection },
+    CommandSpec { name: "unsubscribe", arity: -1, keys: NO_KEYS, flags: NO_MULTI, categories: PUBSUB, handler: Handler::Connection },
+    CommandSpec { name: "punsubscribe", arity: -1, keys: NO_KEYS, flags: NO_MULTI, categories: PUBSUB, handler: Handler::Connection },
+    CommandSpec { name: "ping", arity: -1, keys: NO_KEYS, flags: 0, categories: CONNECTION | FAST, handler: Handler::Server(server::ping) },
+    CommandSpec { name: "echo", arity: 2, keys: NO_KEYS, flags: 0, categories: CONNECTION | FAST, handler: Handler::Server(server::echo) },
+    CommandSpec { name: "save", arity: 1, keys: NO_KEYS, flags: NO_MULTI, categories: ADMIN | DANGEROUS, handler: Handler::Server(server::save) },
+    CommandSpec { name: "bgsave", arity: -1, keys: NO_KEYS, flags: NO_MULTI, categories: ADMIN | DANGEROUS, handler: Handler::Server(server::bgsave) },
+    CommandSpec { name: "lastsave", arity: 1, keys: NO_KEYS, flags: 0, categories: ADMIN | DANGEROUS | FAST, handler: Handler::Server(server::lastsave) },
+    CommandSpec { name: "bgrewriteaof", arity: 1, keys: NO_KEYS, flags: NO_MULTI, categories: ADMIN | DANGEROUS, handler: Handler::Server(server::bgrewriteaof) },
+    CommandSpec { name: "config", arity: -2, keys: NO_KEYS, flags: 0, categories: ADMIN | DANGEROUS, handler: Handler::Server(server::config) },
+    CommandSpec { name: "multi", arity: 1, keys: NO_KEYS, flags: NO_MULTI, categories: TRANSACTION | FAST, handler: Handler::Connection },
+    CommandSpec { name: "exec", arity: 1, keys: NO_KEYS, flags: NO_MULTI, categories: TRANSACTION, handler: Handler::Connection },
+    CommandSpec { name: "discard", arity: 1, keys: NO_KEYS, flags: NO_MULTI, categories: TRANSACTION | FAST, handler: Handler::Connection },
+    CommandSpec { name: "watch", arity: -2, keys: ALL_KEYS, flags: NO_MULTI, categories: TRANSACTION | FAST, handler: Handler::Connection },
+    CommandSpec { name: "unwatch", arity: 1, keys: NO_KEYS, flags: NO_MULTI, categories: TRANSACTION | FAST, handler: Handler::Connection },
+    CommandSpec { name: "hello", arity: -1, keys: NO_KEYS, flags: NO_MULTI | NO_AUTH, categories: CONNECTION | FAST, handler: Handler::Connection },
+    CommandSpec { name: "replicaof", arity: 3, keys: NO_KEYS, flags: NO_MULTI, categories: ADMIN | DANGEROUS, handler: Handler::Connection },
+    CommandSpec { name: "replconf", arity: -1, keys: NO_KEYS, flags: NO_MULTI, categories: ADMIN | DANGEROUS, handler: Handler::Connection },
+    CommandSpec { name: "psync", arity: 3, keys: NO_KEYS, flags: NO_MULTI, categories: ADMIN | DANGEROUS, handler: Handler::Connection },
+    CommandSpec { name: "role", arity: 1, keys: NO_KEYS, flags: 0, categories: ADMIN | DANGEROUS | FAST, handler: Handler::Server(server::role) },
+    // 这几个命令要逐个锁住所有分片，和SAVE一样不能在EXEC里执行
+    CommandSpec { name: "info", arity: -1, keys: NO_KEYS, flags: NO_MULTI, categories: DANGEROUS, handler: Handler::Server(server::info) },
+    CommandSpec { name: "dbsize", arity: 1, keys: NO_KEYS, flags: NO_MULTI, categories: KEYSPACE | READ | FAST, handler: Handler::Database(server::dbsize) },
+    CommandSpec { name: "keys", arity: 2, keys: NO_KEYS, flags: NO_MULTI, categories: KEYSPACE | READ | DANGEROUS, handler: Handler::Database(server::keys) },
+    CommandSpec { name: "scan", arity: -2, keys: NO_KEYS, flags: NO_MULTI, categories: KEYSPACE | READ, handler: Handler::Database(server::scan) },
+    CommandSpec { name: "flushdb", arity: -1, keys: NO_KEYS, flags: WRITE | NO_MULTI, categories: KEYSPACE | DANGEROUS, handler: Handler::Database(server::flushdb) },
+    CommandSpec { name: "flushall", arity: -1, keys: NO_KEYS, flags: WRITE | NO_MULTI, categories: KEYSPACE | DANGEROUS, handler: Handler::Database(server::flushall) },
+    CommandSpec { name: "swapdb", arity: 3, keys: NO_KEYS, flags: WRITE | NO_MULTI, categories: KEYSPACE | FAST | DANGEROUS, handler: Handler::Database(server::swapdb) },
+    CommandSpec { name: "move", arity: 3, keys: FIRST_KEY, flags: WRITE, categories: KEYSPACE | FAST, handler: Handler::Db(keys::move_key) },
+    CommandSpec { name: "select", arity: 2, keys: NO_KEYS, flags: NO_MULTI, categories: CONNECTION | FAST, handler: Handler::Connection },
+    CommandSpec { name: "slowlog", arity: -2, keys: NO_KEYS, flags: 0, categories: ADMIN | DANGEROUS, handler: Handler::Server(server::slowlog) },
+    CommandSpec { name: "client", arity: -2, keys: NO_KEYS, flags: NO_MULTI, categories: ADMIN | CONNECTION | DANGEROUS, handler: Handler::Connection },
+    CommandSpec { name: "monitor", arity: 1, keys: NO_KEYS, flags: NO_MULTI, categories: ADMIN | DANGEROUS, handler: Handler::Connection },
+    // COUNTKEYSINSLOT之类的子命令要自己加锁，EXEC和脚本执行时已经持有分片锁，会死锁
+    CommandSpec { name: "cluster", arity: -2, keys: NO_KEYS, flags: NO_MULTI, categories: ADMIN | DANGEROUS, handler: Handler::Server(cluster::cluster) },
+    CommandSpec { name: "asking", arity: 1, keys: NO_KEYS, flags: NO_MULTI, categories: CONNECTION | FAST, handler: Handler::Connection },
+    // 要迁移的键由MIGRATE自己加锁，不参与集群的路由检查
+    CommandSpec { name: "migrate", arity: -6, keys: NO_KEYS, flags: NO_MULTI, categories: KEYSPACE | DANGEROUS, handler: Handler::Connection },
+    CommandSpec { name: "shutdown", arity: -1, keys: NO_KEYS, flags: NO_MULTI, categories: ADMIN | DANGEROUS, handler: Handler::Connection },
+    CommandSpec { name: "auth", arity: -2, keys: NO_KEYS, flags: NO_MULTI | NO_AUTH, categories: CONNECTION | FAST, handler: Handler::Connection },
+    CommandSpec { name: "acl", arity: -2, keys: NO_KEYS, flags: NO_MULTI, categories: ADMIN | DANGEROUS, handler: Handler::Connection },
+    CommandSpec { name: "eval", arity: -3, keys: NUM_KEYS, flags: NO_MULTI, categories: SCRIPTING, handler: Handler::Connection },
+    CommandSpec { name: "evalsha", arity: -3, keys: NUM_KEYS, flags: NO_MULTI, categories: SCRIPTING, handler: Handler::Connection },
+    CommandSpec { name: "script", arity: -2, keys: NO_KEYS, flags: NO_MULTI, categories: SCRIPTING, handler: Handler::Server(script::script) },
+    CommandSpec { name: "quit", arity: -1, keys: NO_KEYS, flags: NO_AUTH, categories: CONNECTION | FAST, handler: Handler::Connection },
+];
+
+/// 命令表里的所有命令，ACL按类别授权时使用
+pub fn commands() -> impl Iterator<Item = &'static CommandSpec> {
+    COMMANDS.iter()
+}
+
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    static TABLE: OnceLock<HashMap<&'static str, &'static CommandSpec>> = OnceLock::new();
+    TABLE.get_or_init(|| COMMANDS.iter().map(|spec| (spec.name, spec)).collect())
+        .get(name)
+        .copied()
+}
+
+/// 客户端发来的一条命令，name统一转成小写，args不包含命令名
+#[derive(Clone, Debug)]
+pub struct Command {
+    pub name: String,
+    pub args: Vec<Bytes>,
+}
+
+impl Command {
+    pub fn from_frame(frame: Frame) -> Result<Command, Frame> {
+        let parts = match frame {
+            Frame::Array(parts) if !parts.is_empty() => parts,
+            _ => return Err(Frame::error("ERR Protocol error: expected non-empty array")),
+        };
+        let mut args = Vec::with_capacity(parts.len());
+        for part in parts {
+            match part {
+                Frame::Bulk(val) => args.push(val),
+                Frame::Simple(val) => args.push(Bytes::from(val)),
+                Frame::Integer(val) => args.push(Bytes::from(val.to_string())),
+                _ => return Err(Frame::error("ERR Protocol error: expected bulk string")),
+            }
+        }
+        let name = String::from_utf8_lossy(&args.remove(0)).to_lowercase();
+        Ok(Command { name, args })
+    }
+}
+
+/// 查表并校验参数个数
+pub fn check(cmd: &Command) -> Result<&'static CommandSpec, Frame> {
+    let spec = lookup(&cmd.name).ok_or_else(|| unknown_command(cmd))?;
+    let argc = cmd.args.len() as i32 + 1;
+    if (spec.arity > 0 && argc != spec.arity) || (spec.arity < 0 && argc < -spec.arity) {
+        return Err(wrong_arity(&cmd.name));
+    }
+    Ok(spec)
+}
+
+/// 只读的从库只接受主库同步过来的写命令
+pub fn check_writable(server: &Server, spec: &CommandSpec) -> Result<(), Frame> {
+    if spec.has(WRITE) && server.replication.is_replica() && server.config.read().unwrap().replica_read_only {
+        return Err(Frame::error("READONLY You can't write against a read only replica."));
+    }
+    Ok(())
+}
+
+/// 在编号为index的数据库上执行一条命令
+pub fn execute(server: &Server, index: usize, cmd: &Command) -> Frame {
+    let spec = match check(cmd).and_then(|spec| check_writable(server, spec).map(|_| spec)) {
+        Ok(spec) => spec,
+        Err(err) => return err,
+    };
+    if spec.has(DENY_OOM) {
+        // 淘汰时要逐个锁分片，必须在给本命令加锁之前做
+        if let Err(err) = evict::free_memory(server) {
+            return err;
+        }
+    }
+    match spec.handler {
+        Handler::Db(_) => {
+            let mut db = server.db.lock(index, spec.keys.keys(&cmd.args));
+            call(server, spec, cmd, &mut db)
+        }
+        _ => call(server, spec, cmd, &mut server.db.lock(index, [])),
+    }
+}
+
+/// 在已经加锁的键空间上执行一条通过了检查的命令，db必须锁住了命令涉及的所有键。
+/// EXEC先锁住整个事务涉及的分片，再逐条调用它
+pub fn call(server: &Server, spec: &CommandSpec, cmd: &Command, db: &mut Db) -> Frame {
+    let ans = match spec.handler {
+        Handler::Db(handler) => {
+            let dirty = db.dirty();
+            let ans = handler(db, &cmd.args);
+            // 和Redis一样，什么也没改的写命令(比如删除不存在的键)不写入AOF和复制流
+            let changes = db.dirty() - dirty;
+            if changes > 0 {
+                server.rdb.record_change(changes);
+                server.propagate(db.index(), &aof::log_frames(cmd, db));
+            }
+            ans
+        }
+        Handler::Server(handler) => handler(server, &cmd.args),
+        Handler::Database(handler) => handler(server, db.index(), &cmd.args),
+        Handler::Connection => Err(Frame::error(format!("ERR '{}' is not allowed here", cmd.name))),
+    };
+    ans.unwrap_or_else(|err| err)
+}
+
+fn unknown_command(cmd: &Command) -> Frame {
+    let args: String = cmd.args.iter()
+        .map(|arg| format!("'{}' ", String::from_utf8_lossy(arg)))
+        .collect();
+    Frame::error(format!("ERR unknown command '{}', with args beginning with: {}", cmd.name, args))
+}
+
+pub fn wrong_arity(name: &str) -> Frame {
+    Frame::error(format!("ERR wrong number of arguments for '{}' command", name))
+}
+
+pub fn syntax_error() -> Frame {
+    Frame::error("ERR syntax error")
+}
+
+pub fn not_integer() -> Frame {
+    Frame::error("ERR value is not an integer or out of range")
+}
+
+pub fn parse_i64(arg: &Bytes) -> Result<i64, Frame> {
+    std::str::from_utf8(arg).ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(not_integer)
+}
+
+/// 把LRANGE、ZRANGE风格的闭区间下标(负数从末尾开始算)转换成合法的下标，区间为空时返回None
+pub fn normalize_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
+
+/// 大小写无关地比较参数，用来匹配NX、XX之类的选项
+pub fn eq_ignore_case(arg: &Bytes, option: &str) -> bool {
+    arg.eq_ignore_ascii_case(option.as_bytes())
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use bytes::Bytes;
+    use resp::Frame;
+    use crate::cmd::{check, Command, Handler};
+    use crate::db::Keyspace;
+
+    /// 按空格切分一行命令，直接在键空间的0号数据库上执行，测试用
+    pub fn run(keyspace: &Keyspace, line: &str) -> Frame {
+        let parts = line.split_whitespace().map(|s| Frame::Bulk(Bytes::from(s.to_string()))).collect();
+        let cmd = Command::from_frame(Frame::Array(parts)).unwrap();
+        match check(&cmd) {
+            Ok(spec) => match spec.handler {
+                Handler::Db(handler) => {
+                    let mut db = keyspace.lock(0, spec.keys.keys(&cmd.args));
+                    handler(&mut db, &cmd.args).unwrap_or_else(|err| err)
+                }
+                _ => panic!("{} is not a keyspace command", cmd.name),
+            },
+            Err(err) => err,
+        }
+    }
+
+    pub fn bulk(s: &str) -> Frame {
+        Frame::Bulk(Bytes::from(s.to_string()))
+    }
+}
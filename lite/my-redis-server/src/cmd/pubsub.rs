@@ -0,0 +1,9 @@
+use bytes::Bytes;
+use resp::Frame;
+use crate::cmd::Reply;
+use crate::server::Server;
+
+/// PUBLISH channel message，返回收到消息的订阅数(包括模式订阅)
+pub fn publish(server: &Server, args: &[Bytes]) -> Reply {
+    Ok(Frame::Integer(server.pubsub.publish(&args[0], args[1].clone()) as i64))
+}
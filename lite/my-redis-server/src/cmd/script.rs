@@ -0,0 +1,47 @@
+use std::sync::Arc;
+use bytes::Bytes;
+use resp::Frame;
+use crate::cmd::{self, Reply};
+use crate::script;
+use crate::server::{Client, Server};
+
+/// EVAL script numkeys [key ...] [arg ...]，由process调用，脚本以连接当前的用户和数据库执行
+pub async fn eval(server: &Arc<Server>, client: &Client, args: &[Bytes]) -> Reply {
+    let sha = server.scripts.load(&args[0])?;
+    run(server, client, &sha, &args[1..]).await
+}
+
+/// EVALSHA sha1 numkeys [key ...] [arg ...]，由process调用
+pub async fn evalsha(server: &Arc<Server>, client: &Client, args: &[Bytes]) -> Reply {
+    run(server, client, &String::from_utf8_lossy(&args[0]), &args[1..]).await
+}
+
+async fn run(server: &Arc<Server>, client: &Client, sha: &str, args: &[Bytes]) -> Reply {
+    let numkeys = cmd::parse_i64(&args[0])?;
+    if numkeys < 0 {
+        return Err(Frame::error("ERR Number of keys can't be negative"));
+    }
+    if numkeys as usize > args.len() - 1 {
+        return Err(Frame::error("ERR Number of keys can't be greater than number of args"));
+    }
+    let ast = server.scripts.get(sha).ok_or_else(|| Frame::error("NOSCRIPT No matching script. Please use EVAL."))?;
+    let (keys, argv) = args[1..].split_at(numkeys as usize);
+    Ok(script::run(server.clone(), client.user(), client.db(), ast, keys.to_vec(), argv.to_vec()).await)
+}
+
+/// SCRIPT LOAD|EXISTS|FLUSH
+pub fn script(server: &Server, args: &[Bytes]) -> Reply {
+    let sub = String::from_utf8_lossy(&args[0]).to_lowercase();
+    match (sub.as_str(), &args[1..]) {
+        ("load", [source]) => Ok(Frame::Bulk(Bytes::from(server.scripts.load(source)?))),
+        ("exists", shas) if !shas.is_empty() => {
+            Ok(Frame::Array(shas.iter().map(|sha| Frame::Integer(server.scripts.exists(&String::from_utf8_lossy(sha)) as i64)).collect()))
+        }
+        // ASYNC和SYNC效果一样，缓存直接清空
+        ("flush", modes) if matches!(modes, [] | [_]) && modes.iter().all(|mode| cmd::eq_ignore_case(mode, "async") || cmd::eq_ignore_case(mode, "sync")) => {
+            server.scripts.flush();
+            Ok(Frame::ok())
+        }
+        _ => Err(Frame::error(format!("ERR unknown subcommand or wrong number of arguments for '{}'. Try SCRIPT HELP.", sub))),
+    }
+}
@@ -0,0 +1,493 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use bytes::Bytes;
+use resp::{Frame, Protocol};
+use crate::cmd::{acl, eq_ignore_case, parse_db_index, parse_i64, syntax_error, wrong_arity, Reply};
+use crate::db::{Db, Flushed};
+use crate::config::Config;
+use crate::{aof, glob, log, rdb, replication};
+use crate::server::{Client, ClientKind, Server};
+use crate::shutdown::Mode;
+
+/// PING [message]
+pub fn ping(_server: &Server, args: &[Bytes]) -> Reply {
+    match args {
+        [] => Ok(Frame::Simple("PONG".to_string())),
+        [msg] => Ok(Frame::Bulk(msg.clone())),
+        _ => Err(wrong_arity("ping")),
+    }
+}
+
+/// HELLO [protover [AUTH username password] [SETNAME clientname]]，切换协议版本并返回服务端信息，由process调用，
+/// 返回切换后的协议版本和回复，回复要按新的版本编码
+pub fn hello(server: &Server, client: &Client, args: &[Bytes], current: Protocol) -> Result<(Protocol, Frame), Frame> {
+    let (protocol, mut options) = match args {
+        [] => (current, args),
+        [version, options @ ..] => match std::str::from_utf8(version).ok().and_then(|s| s.parse::<i64>().ok()) {
+            Some(2) => (Protocol::Resp2, options),
+            Some(3) => (Protocol::Resp3, options),
+            Some(_) => return Err(Frame::error("NOPROTO unsupported protocol version")),
+            None => return Err(Frame::error("ERR Protocol version is not an integer or out of range")),
+        },
+    };
+    // 所有选项都合法才生效
+    let mut name = None;
+    let mut auth = None;
+    while let [option, rest @ ..] = options {
+        match rest {
+            [value, rest @ ..] if eq_ignore_case(option, "setname") => {
+                name = Some(check_client_name(value)?);
+                options = rest;
+            }
+            [user, password, rest @ ..] if eq_ignore_case(option, "auth") => {
+                auth = Some((String::from_utf8_lossy(user).to_string(), password));
+                options = rest;
+            }
+            _ => return Err(Frame::error(format!("ERR Syntax error in HELLO option '{}'", String::from_utf8_lossy(option)))),
+        }
+    }
+    match auth {
+        Some((user, password)) => acl::authenticate(server, client, &user, password)?,
+        None if client.user().is_none() => {
+            return Err(Frame::error("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time"));
+        }
+        None => {}
+    }
+    if let Some(name) = name {
+        client.set_name(name);
+    }
+    let field = |name: &'static str| Frame::Bulk(Bytes::from_static(name.as_bytes()));
+    let info = Frame::Map(vec![
+        (field("server"), field("my-redis-server")),
+        (field("version"), field(env!("CARGO_PKG_VERSION"))),
+        (field("proto"), Frame::Integer(if protocol == Protocol::Resp3 { 3 } else { 2 })),
+        (field("id"), Frame::Integer(client.id as i64)),
+        (field("mode"), field(if server.cluster.is_some() { "cluster" } else { "standalone" })),
+        (field("role"), field(if server.replication.is_replica() { "replica" } else { "master" })),
+        (field("modules"), Frame::Array(vec![])),
+    ]);
+    Ok((protocol, info))
+}
+
+/// 和Redis一样，连接名不能包含空格、换行等字符，CLIENT LIST要靠空格分隔字段
+fn check_client_name(name: &Bytes) -> Result<String, Frame> {
+    if name.iter().any(|byte| !(b'!'..=b'~').contains(byte)) {
+        return Err(Frame::error("ERR Client names cannot contain spaces, newlines or special characters."));
+    }
+    Ok(String::from_utf8_lossy(name).to_string())
+}
+
+/// CLIENT ID|GETNAME|SETNAME|INFO|LIST|KILL，由process调用
+pub fn client(server: &Server, client: &Client, args: &[Bytes]) -> Reply {
+    let sub = String::from_utf8_lossy(&args[0]).to_lowercase();
+    let text = |lines: Vec<String>| Frame::Verbatim("txt".to_string(), Bytes::from(lines.into_iter().map(|line| line + "\n").collect::<String>()));
+    match (sub.as_str(), &args[1..]) {
+        ("id", []) => Ok(Frame::Integer(client.id as i64)),
+        ("getname", []) => match client.name() {
+            name if name.is_empty() => Ok(Frame::Null),
+            name => Ok(Frame::Bulk(Bytes::from(name))),
+        },
+        ("setname", [name]) => {
+            client.set_name(check_client_name(name)?);
+            Ok(Frame::ok())
+        }
+        ("info", []) => Ok(text(vec![client.describe()])),
+        ("list", []) => Ok(text(server.clients.list().iter().map(|client| client.describe()).collect())),
+        ("list", [option, kind]) if eq_ignore_case(option, "type") => {
+            let kind = parse_client_kind(kind)?;
+            Ok(text(server.clients.list().iter().filter(|client| client.kind() == kind).map(|client| client.describe()).collect()))
+        }
+        // 旧的形式：CLIENT KILL ip:port
+        ("kill", [addr]) => {
+            let addr = String::from_utf8_lossy(addr);
+            match server.clients.list().into_iter().find(|client| client.addr.to_string() == addr) {
+                Some(target) => {
+                    target.kill();
+                    Ok(Frame::ok())
+                }
+                None => Err(Frame::error("ERR No such client")),
+            }
+        }
+        // CLIENT KILL [ID id] [ADDR ip:port] [TYPE type] [SKIPME yes|no]，返回断开的连接数
+        ("kill", filters) if !filters.is_empty() && filters.len().is_multiple_of(2) => {
+            let (mut id, mut addr, mut kind, mut skipme) = (None, None, None, true);
+            for pair in filters.chunks(2) {
+                let value = &pair[1];
+                match String::from_utf8_lossy(&pair[0]).to_lowercase().as_str() {
+                    "id" => id = Some(parse_i64(value)?),
+                    "addr" => addr = Some(String::from_utf8_lossy(value).to_string()),
+                    "type" => kind = Some(parse_client_kind(value)?),
+                    "skipme" if eq_ignore_case(value, "yes") => skipme = true,
+                    "skipme" if eq_ignore_case(value, "no") => skipme = false,
+                    _ => return Err(syntax_error()),
+                }
+            }
+            let targets: Vec<_> = server.clients.list().into_iter()
+                .filter(|target| id.is_none_or(|id| target.id as i64 == id))
+                .filter(|target| addr.as_ref().is_none_or(|addr| target.addr.to_string() == *addr))
+                .filter(|target| kind.is_none_or(|kind| target.kind() == kind))
+                .filter(|target| !skipme || target.id != client.id)
+                .collect();
+            targets.iter().for_each(|target| target.kill());
+            Ok(Frame::Integer(targets.len() as i64))
+        }
+        _ => Err(Frame::error(format!("ERR unknown subcommand or wrong number of arguments for '{}'. Try CLIENT HELP.", sub))),
+    }
+}
+
+fn parse_client_kind(kind: &Bytes) -> Result<ClientKind, Frame> {
+    ClientKind::parse(&String::from_utf8_lossy(kind).to_lowercase())
+        .ok_or_else(|| Frame::error(format!("ERR Unknown client type '{}'", String::from_utf8_lossy(kind))))
+}
+
+/// SHUTDOWN [NOSAVE|SAVE]，由process调用。只是发起关闭，连接都断开之后才落盘，
+/// 所以保存失败时没法像Redis那样把错误回给客户端，只能记日志并以非0状态码退出
+pub fn shutdown(server: &Server, args: &[Bytes]) -> Result<(), Frame> {
+    let mode = match args {
+        [] => Mode::Default,
+        [option] if eq_ignore_case(option, "save") => Mode::Save,
+        [option] if eq_ignore_case(option, "nosave") => Mode::NoSave,
+        _ => return Err(syntax_error()),
+    };
+    notice!("User requested shutdown...");
+    server.shutdown.trigger(mode);
+    Ok(())
+}
+
+/// SELECT index，由process调用，返回连接要切换到的数据库
+pub fn select(server: &Server, args: &[Bytes]) -> Result<usize, Frame> {
+    let [index] = args else {
+        return Err(wrong_arity("select"));
+    };
+    let index = parse_db_index(index, server.db.databases())?;
+    if index != 0 && server.cluster.is_some() {
+        return Err(Frame::error("ERR SELECT is not allowed in cluster mode"));
+    }
+    Ok(index)
+}
+
+/// REPLICAOF host port | REPLICAOF NO ONE，由process调用，开始复制需要在后台启动任务
+pub fn replicaof(server: &Arc<Server>, args: &[Bytes]) -> Frame {
+    let master = if eq_ignore_case(&args[0], "no") && eq_ignore_case(&args[1], "one") {
+        None
+    } else {
+        match std::str::from_utf8(&args[1]).ok().and_then(|port| port.parse().ok()) {
+            Some(port) => Some((String::from_utf8_lossy(&args[0]).to_string(), port)),
+            None => return Frame::error("ERR Invalid master port"),
+        }
+    };
+    if replication::set_master(server, master) {
+        Frame::ok()
+    } else {
+        Frame::Simple("OK Already connected to specified master".to_string())
+    }
+}
+
+/// REPLCONF option value [option value ...]，从库在PSYNC之前告诉主库自己的监听端口，由process调用
+pub fn replconf(args: &[Bytes], listening_port: &mut u16) -> Frame {
+    if !args.len().is_multiple_of(2) {
+        return syntax_error();
+    }
+    for pair in args.chunks(2) {
+        let option = String::from_utf8_lossy(&pair[0]).to_lowercase();
+        match option.as_str() {
+            "listening-port" => match std::str::from_utf8(&pair[1]).ok().and_then(|port| port.parse().ok()) {
+                Some(port) => *listening_port = port,
+                None => return Frame::error("ERR Invalid listening port"),
+            },
+            "capa" | "ack" => {}
+            _ => return Frame::error(format!("ERR Unrecognized REPLCONF option: {}", option)),
+        }
+    }
+    Frame::ok()
+}
+
+/// ROLE
+pub fn role(server: &Server, _args: &[Bytes]) -> Reply {
+    Ok(server.replication.role())
+}
+
+/// ECHO message
+pub fn echo(_server: &Server, args: &[Bytes]) -> Reply {
+    Ok(Frame::Bulk(args[0].clone()))
+}
+
+/// SAVE，同步保存快照，期间当前连接阻塞
+pub fn save(server: &Server, _args: &[Bytes]) -> Reply {
+    match rdb::save(server) {
+        Ok(()) => Ok(Frame::ok()),
+        Err(err) => Err(Frame::error(format!("ERR {}", err))),
+    }
+}
+
+/// BGSAVE [SCHEDULE]，没有正在进行的子任务可以等待，SCHEDULE和不带参数的效果一样
+pub fn bgsave(server: &Server, args: &[Bytes]) -> Reply {
+    match args {
+        [] => {}
+        [option] if eq_ignore_case(option, "schedule") => {}
+        _ => return Err(syntax_error()),
+    }
+    rdb::bgsave(server)?;
+    Ok(Frame::Simple("Background saving started".to_string()))
+}
+
+/// LASTSAVE，上次成功保存的unix时间戳
+pub fn lastsave(server: &Server, _args: &[Bytes]) -> Reply {
+    let secs = server.rdb.last_save().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    Ok(Frame::Integer(secs as i64))
+}
+
+/// BGREWRITEAOF，在后台根据当前数据重写追加写日志
+pub fn bgrewriteaof(server: &Server, _args: &[Bytes]) -> Reply {
+    aof::rewrite(server)?;
+    Ok(Frame::Simple("Background append only file rewriting started".to_string()))
+}
+
+/// CONFIG GET pattern [pattern ...] | CONFIG SET name value [name value ...]
+pub fn config(server: &Server, args: &[Bytes]) -> Reply {
+    let sub = String::from_utf8_lossy(&args[0]).to_lowercase();
+    match sub.as_str() {
+        "get" if args.len() >= 2 => {
+            let config = server.config.read().unwrap();
+            let mut params = Vec::new();
+            for pattern in &args[1..] {
+                for (name, value) in config.matching(&String::from_utf8_lossy(pattern)) {
+                    if !params.iter().any(|(param, _)| *param == name) {
+                        params.push((name, value));
+                    }
+                }
+            }
+            Ok(Frame::Map(params.into_iter()
+                .map(|(name, value)| (Frame::Bulk(Bytes::from(name)), Frame::Bulk(Bytes::from(value))))
+                .collect()))
+        }
+        "set" if args.len() >= 3 && !args.len().is_multiple_of(2) => config_set(server, &args[1..]),
+        _ => Err(Frame::error(format!("ERR unknown subcommand or wrong number of arguments for '{}'. Try CONFIG HELP.", sub))),
+    }
+}
+
+/// 所有参数都校验通过才一起生效
+fn config_set(server: &Server, args: &[Bytes]) -> Reply {
+    let mut config = server.config.write().unwrap();
+    let mut updated = config.clone();
+    for pair in args.chunks(2) {
+        let name = String::from_utf8_lossy(&pair[0]).to_lowercase();
+        let value = String::from_utf8_lossy(&pair[1]);
+        match Config::is_mutable(&name) {
+            None => return Err(Frame::error(format!("ERR Unknown option or number of arguments for CONFIG SET - '{}'", name))),
+            Some(false) => return Err(Frame::error(format!("ERR CONFIG SET failed (possibly related to argument '{}') - can't set immutable config", name))),
+            Some(true) => updated.set(&name, &value)
+                .map_err(|err| Frame::error(format!("ERR CONFIG SET failed (possibly related to argument '{}') - {}", name, err)))?,
+        }
+    }
+    // 大部分配置在使用的地方直接读，这几个需要通知对应的模块
+    log::set_level(updated.loglevel);
+    server.clients.set_limit(updated.maxclients);
+    server.replication.set_backlog_size(updated.repl_backlog_size as usize);
+    if updated.requirepass != config.requirepass {
+        server.acl.set_requirepass(&updated.requirepass);
+    }
+    if let Some(aof) = &server.aof {
+        aof.set_policy(updated.appendfsync);
+    }
+    *config = updated;
+    Ok(Frame::ok())
+}
+
+/// INFO的各个部分，不带参数时按这个顺序全部输出
+const INFO_SECTIONS: &[(&str, &str)] = &[
+    ("server", "Server"),
+    ("clients", "Clients"),
+    ("memory", "Memory"),
+    ("persistence", "Persistence"),
+    ("stats", "Stats"),
+    ("replication", "Replication"),
+    ("cluster", "Cluster"),
+    ("keyspace", "Keyspace"),
+];
+
+/// INFO [section ...]，不带参数或者是default、all、everything时返回所有部分，不认识的部分忽略
+pub fn info(server: &Server, args: &[Bytes]) -> Reply {
+    let all = args.is_empty() || args.iter().any(|arg| ["default", "all", "everything"].iter().any(|name| eq_ignore_case(arg, name)));
+    let text = INFO_SECTIONS.iter()
+        .filter(|(name, _)| all || args.iter().any(|arg| eq_ignore_case(arg, name)))
+        .map(|(name, title)| format!("# {}\r\n{}", title, info_section(server, name)))
+        .collect::<Vec<_>>()
+        .join("\r\n");
+    Ok(Frame::Verbatim("txt".to_string(), Bytes::from(text)))
+}
+
+fn info_section(server: &Server, name: &str) -> String {
+    let config = server.config.read().unwrap().clone();
+    let lines = match name {
+        "server" => {
+            let uptime = server.stats.started.elapsed().as_secs();
+            vec![
+                format!("redis_version:{}", env!("CARGO_PKG_VERSION")),
+                format!("redis_mode:{}", if server.cluster.is_some() { "cluster" } else { "standalone" }),
+                format!("process_id:{}", std::process::id()),
+                format!("run_id:{}", server.stats.run_id),
+                format!("tcp_port:{}", config.port),
+                format!("uptime_in_seconds:{}", uptime),
+                format!("uptime_in_days:{}", uptime / 86400),
+            ]
+        }
+        "clients" => vec![
+            format!("connected_clients:{}", server.clients.count()),
+            format!("maxclients:{}", config.maxclients),
+        ],
+        "memory" => {
+            let used = server.db.used_memory() as u64;
+            vec![
+                format!("used_memory:{}", used),
+                format!("used_memory_human:{}", human_bytes(used)),
+                format!("maxmemory:{}", config.maxmemory),
+                format!("maxmemory_human:{}", human_bytes(config.maxmemory)),
+                format!("maxmemory_policy:{}", config.maxmemory_policy.name()),
+            ]
+        }
+        "persistence" => {
+            let last_save = server.rdb.last_save().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            vec![
+                "loading:0".to_string(),
+                format!("rdb_changes_since_last_save:{}", server.rdb.dirty()),
+                format!("rdb_bgsave_in_progress:{}", server.rdb.is_saving() as u8),
+                format!("rdb_last_save_time:{}", last_save),
+                format!("aof_enabled:{}", server.aof.is_some() as u8),
+                format!("aof_rewrite_in_progress:{}", server.aof.as_ref().is_some_and(|aof| aof.is_rewriting()) as u8),
+            ]
+        }
+        "stats" => {
+            let stats = &server.stats;
+            let (sync_full, sync_partial_ok, sync_partial_err) = server.replication.sync_stats();
+            vec![
+                format!("total_connections_received:{}", stats.connections.load(Ordering::Relaxed)),
+                format!("total_commands_processed:{}", stats.commands.load(Ordering::Relaxed)),
+                format!("rejected_connections:{}", stats.rejected_connections.load(Ordering::Relaxed)),
+                format!("sync_full:{}", sync_full),
+                format!("sync_partial_ok:{}", sync_partial_ok),
+                format!("sync_partial_err:{}", sync_partial_err),
+                format!("expired_keys:{}", server.db.expired_keys()),
+                format!("evicted_keys:{}", stats.evicted_keys.load(Ordering::Relaxed)),
+            ]
+        }
+        "replication" => return server.replication.info(),
+        "cluster" => vec![format!("cluster_enabled:{}", server.cluster.is_some() as u8)],
+        _ => (0..server.db.databases())
+            .map(|index| (index, server.db.len(index)))
+            .filter(|(_, keys)| *keys > 0)
+            .map(|(index, keys)| format!("db{}:keys={},expires={},avg_ttl=0", index, keys, server.db.volatile_len(index)))
+            .collect(),
+    };
+    lines.into_iter().map(|line| line + "\r\n").collect()
+}
+
+/// 和Redis的bytesToHuman一样保留两位小数
+fn human_bytes(n: u64) -> String {
+    let n = n as f64;
+    match n {
+        _ if n < 1024.0 => format!("{}B", n),
+        _ if n < 1024.0 * 1024.0 => format!("{:.2}K", n / 1024.0),
+        _ if n < 1024.0 * 1024.0 * 1024.0 => format!("{:.2}M", n / 1024.0 / 1024.0),
+        _ => format!("{:.2}G", n / 1024.0 / 1024.0 / 1024.0),
+    }
+}
+
+/// DBSIZE
+pub fn dbsize(server: &Server, index: usize, _args: &[Bytes]) -> Reply {
+    Ok(Frame::Integer(server.db.len(index) as i64))
+}
+
+/// KEYS pattern，要遍历所有的键，键很多时会很慢
+pub fn keys(server: &Server, index: usize, args: &[Bytes]) -> Reply {
+    Ok(Frame::Array(server.db.keys(index, &args[0]).into_iter().map(Frame::Bulk).collect()))
+}
+
+/// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]，MATCH和TYPE在取出COUNT个键之后再过滤，
+/// 所以可能返回空的一批。遍历期间一直存在的键至少返回一次
+pub fn scan(server: &Server, index: usize, args: &[Bytes]) -> Reply {
+    let cursor = std::str::from_utf8(&args[0]).ok()
+        .and_then(|cursor| cursor.parse::<u64>().ok())
+        .ok_or_else(|| Frame::error("ERR invalid cursor"))?;
+    let (mut pattern, mut count, mut kind) = (None, 10, None);
+    if args.len().is_multiple_of(2) {
+        return Err(syntax_error());
+    }
+    for pair in args[1..].chunks(2) {
+        let value = &pair[1];
+        match String::from_utf8_lossy(&pair[0]).to_lowercase().as_str() {
+            "match" => pattern = Some(value),
+            "count" => count = usize::try_from(parse_i64(value)?).ok().filter(|count| *count > 0).ok_or_else(syntax_error)?,
+            "type" => kind = Some(String::from_utf8_lossy(value).to_lowercase()),
+            _ => return Err(syntax_error()),
+        }
+    }
+    let (next, keys) = server.db.scan(index, cursor, count);
+    let keys = keys.into_iter()
+        .filter(|(key, type_name)| pattern.is_none_or(|pattern| glob::matches(pattern, key)) && kind.as_ref().is_none_or(|kind| kind == type_name))
+        .map(|(key, _)| Frame::Bulk(key))
+        .collect();
+    Ok(Frame::Array(vec![Frame::Bulk(Bytes::from(next.to_string())), Frame::Array(keys)]))
+}
+
+/// FLUSHDB [ASYNC|SYNC]，清空当前数据库
+pub fn flushdb(server: &Server, index: usize, args: &[Bytes]) -> Reply {
+    flush(server, index, args, "flushdb", |db| db.clear())
+}
+
+/// FLUSHALL [ASYNC|SYNC]，清空所有数据库
+pub fn flushall(server: &Server, index: usize, args: &[Bytes]) -> Reply {
+    flush(server, index, args, "flushall", |db| db.clear_all())
+}
+
+/// 锁住所有分片清空，并在释放锁之前写入AOF和复制流。ASYNC时删掉的键值交给后台线程释放
+fn flush(server: &Server, index: usize, args: &[Bytes], name: &str, clear: impl FnOnce(&mut Db) -> Flushed) -> Reply {
+    let lazy = match args {
+        [] => false,
+        [mode] if eq_ignore_case(mode, "async") => true,
+        [mode] if eq_ignore_case(mode, "sync") => false,
+        _ => return Err(syntax_error()),
+    };
+    let mut db = server.db.lock_all(index);
+    let flushed = clear(&mut db);
+    server.rdb.record_change(1);
+    server.propagate(index, &[aof::command_frame(name, args)]);
+    drop(db);
+    verbose!("{} removed {} keys", name.to_uppercase(), flushed.len());
+    if lazy {
+        std::thread::spawn(move || drop(flushed));
+    }
+    Ok(Frame::ok())
+}
+
+/// SWAPDB index1 index2，锁住所有分片后交换，其他连接看到的是原子的切换
+pub fn swapdb(server: &Server, index: usize, args: &[Bytes]) -> Reply {
+    let databases = server.db.databases();
+    let a = parse_db_index(&args[0], databases)?;
+    let b = parse_db_index(&args[1], databases)?;
+    let mut db = server.db.lock_all(index);
+    db.swap(a, b);
+    server.rdb.record_change(1);
+    server.propagate(index, &[aof::command_frame("swapdb", args)]);
+    Ok(Frame::ok())
+}
+
+/// SLOWLOG GET [count]|LEN|RESET，count默认为10，-1表示全部
+pub fn slowlog(server: &Server, args: &[Bytes]) -> Reply {
+    let sub = String::from_utf8_lossy(&args[0]).to_lowercase();
+    match (sub.as_str(), &args[1..]) {
+        ("get", []) => Ok(server.slowlog.get(10)),
+        ("get", [count]) => match parse_i64(count)? {
+            -1 => Ok(server.slowlog.get(usize::MAX)),
+            count if count >= 0 => Ok(server.slowlog.get(count as usize)),
+            _ => Err(Frame::error("ERR count should be greater than or equal to -1")),
+        },
+        ("len", []) => Ok(Frame::Integer(server.slowlog.len() as i64)),
+        ("reset", []) => {
+            server.slowlog.reset();
+            Ok(Frame::ok())
+        }
+        _ => Err(Frame::error(format!("ERR unknown subcommand or wrong number of arguments for '{}'. Try SLOWLOG HELP.", sub))),
+    }
+}
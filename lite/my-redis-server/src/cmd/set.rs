@@ -0,0 +1,87 @@
+use std::collections::HashSet;
+use bytes::Bytes;
+use resp::Frame;
+use crate::cmd::Reply;
+use crate::db::{wrong_type, Db};
+use crate::value::Value;
+
+fn get_set<'a>(db: &'a mut Db, key: &Bytes) -> Result<Option<&'a mut HashSet<Bytes>>, Frame> {
+    match db.get_mut(key) {
+        Some(Value::Set(set)) => Ok(Some(set)),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+/// SADD key member [member ...]，返回新增的成员数
+pub fn sadd(db: &mut Db, args: &[Bytes]) -> Reply {
+    let set = match db.get_or_insert_with(&args[0], || Value::Set(HashSet::new())) {
+        Value::Set(set) => set,
+        _ => return Err(wrong_type()),
+    };
+    let added = args[1..].iter().filter(|member| set.insert((*member).clone())).count();
+    db.add_dirty(added);
+    Ok(Frame::Integer(added as i64))
+}
+
+/// SREM key member [member ...]，返回删除的成员数
+pub fn srem(db: &mut Db, args: &[Bytes]) -> Reply {
+    let removed = match get_set(db, &args[0])? {
+        Some(set) => args[1..].iter().filter(|member| set.remove(*member)).count(),
+        None => 0,
+    };
+    db.remove_if_empty(&args[0]);
+    db.add_dirty(removed);
+    Ok(Frame::Integer(removed as i64))
+}
+
+pub fn smembers(db: &mut Db, args: &[Bytes]) -> Reply {
+    Ok(Frame::Set(match get_set(db, &args[0])? {
+        Some(set) => set.iter().cloned().map(Frame::Bulk).collect(),
+        None => vec![],
+    }))
+}
+
+/// SINTER key [key ...]，任何一个键不存在结果都是空集
+pub fn sinter(db: &mut Db, args: &[Bytes]) -> Reply {
+    let mut sets = Vec::with_capacity(args.len());
+    for key in args {
+        match get_set(db, key)? {
+            Some(set) => sets.push(set.clone()),
+            None => return Ok(Frame::Set(vec![])),
+        }
+    }
+    // 从最小的集合开始求交集
+    sets.sort_by_key(|set| set.len());
+    let (first, rest) = sets.split_first().unwrap();
+    Ok(Frame::Set(first.iter()
+        .filter(|member| rest.iter().all(|set| set.contains(*member)))
+        .cloned()
+        .map(Frame::Bulk)
+        .collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use resp::Frame;
+    use crate::cmd::testing::{bulk, run};
+    use crate::db::Keyspace;
+
+    #[test]
+    fn test_set() {
+        let db = Keyspace::new(4, 1);
+        assert_eq!(run(&db, "SADD a x y z"), Frame::Integer(3));
+        assert_eq!(run(&db, "SADD a x"), Frame::Integer(0));
+        assert_eq!(run(&db, "SADD b y z w"), Frame::Integer(3));
+        let mut inter = match run(&db, "SINTER a b") {
+            Frame::Set(members) => members,
+            other => panic!("{:?}", other),
+        };
+        inter.sort_by_key(|frame| format!("{:?}", frame));
+        assert_eq!(inter, vec![bulk("y"), bulk("z")]);
+        assert_eq!(run(&db, "SINTER a missing"), Frame::Set(vec![]));
+        assert_eq!(run(&db, "SREM a x y z q"), Frame::Integer(3));
+        assert_eq!(run(&db, "SMEMBERS a"), Frame::Set(vec![]));
+        assert_eq!(run(&db, "TYPE b"), Frame::Simple("set".to_string()));
+    }
+}
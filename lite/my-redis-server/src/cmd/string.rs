@@ -0,0 +1,265 @@
+use std::time::{Duration, Instant};
+use bytes::{Bytes, BytesMut};
+use resp::Frame;
+use crate::cmd::{eq_ignore_case, parse_i64, syntax_error, wrong_arity, Reply};
+use crate::db::Db;
+use crate::value::Value;
+
+/// 字符串最大长度，和Redis的proto-max-bulk-len默认值一致
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+pub fn get(db: &mut Db, args: &[Bytes]) -> Reply {
+    Ok(db.get_string(&args[0])?.cloned().into())
+}
+
+/// SET key value [NX|XX] [GET] [EX seconds|PX milliseconds|KEEPTTL]
+pub fn set(db: &mut Db, args: &[Bytes]) -> Reply {
+    let (key, value) = (&args[0], &args[1]);
+    let (mut nx, mut xx, mut get, mut keep_ttl) = (false, false, false, false);
+    let mut expire = None;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        if eq_ignore_case(option, "nx") && !xx {
+            nx = true;
+        } else if eq_ignore_case(option, "xx") && !nx {
+            xx = true;
+        } else if eq_ignore_case(option, "get") {
+            get = true;
+        } else if eq_ignore_case(option, "keepttl") && expire.is_none() {
+            keep_ttl = true;
+        } else if (eq_ignore_case(option, "ex") || eq_ignore_case(option, "px")) && expire.is_none() && !keep_ttl {
+            let amount = options.next().ok_or_else(syntax_error)?;
+            let millis = if eq_ignore_case(option, "ex") {
+                parse_i64(amount)?.checked_mul(1000)
+            } else {
+                Some(parse_i64(amount)?)
+            };
+            match millis {
+                Some(millis) if millis > 0 => expire = Some(Duration::from_millis(millis as u64)),
+                _ => return Err(Frame::error("ERR invalid expire time in 'set' command")),
+            }
+        } else {
+            return Err(syntax_error());
+        }
+    }
+    // 带GET时旧值必须是字符串，否则只关心键是否存在
+    let old = if get { db.get_string(key)?.cloned() } else { None };
+    let exists = db.contains_key(key);
+    if (nx && exists) || (xx && !exists) {
+        return Ok(if get { old.into() } else { Frame::Null });
+    }
+    if keep_ttl {
+        db.update(key.clone(), value.clone().into());
+    } else {
+        db.insert_with_expire(key.clone(), value.clone().into(), expire.map(|expire| Instant::now() + expire));
+    }
+    Ok(if get { old.into() } else { Frame::ok() })
+}
+
+/// SETEX key seconds value
+pub fn setex(db: &mut Db, args: &[Bytes]) -> Reply {
+    set_with_expire(db, args, 1000, "setex")
+}
+
+/// PSETEX key milliseconds value
+pub fn psetex(db: &mut Db, args: &[Bytes]) -> Reply {
+    set_with_expire(db, args, 1, "psetex")
+}
+
+fn set_with_expire(db: &mut Db, args: &[Bytes], unit: i64, name: &str) -> Reply {
+    let millis = match parse_i64(&args[1])?.checked_mul(unit) {
+        Some(millis) if millis > 0 => millis,
+        _ => return Err(Frame::error(format!("ERR invalid expire time in '{}' command", name))),
+    };
+    let expires_at = Instant::now() + Duration::from_millis(millis as u64);
+    db.insert_with_expire(args[0].clone(), args[2].clone().into(), Some(expires_at));
+    Ok(Frame::ok())
+}
+
+pub fn del(db: &mut Db, args: &[Bytes]) -> Reply {
+    Ok(Frame::Integer(args.iter().filter(|key| db.remove(key).is_some()).count() as i64))
+}
+
+pub fn exists(db: &mut Db, args: &[Bytes]) -> Reply {
+    Ok(Frame::Integer(args.iter().filter(|key| db.contains_key(key)).count() as i64))
+}
+
+fn incr_by(db: &mut Db, key: &Bytes, delta: i64) -> Reply {
+    let curr = match db.get_string(key)? {
+        Some(val) => parse_i64(val)?,
+        None => 0,
+    };
+    let next = curr.checked_add(delta)
+        .ok_or_else(|| Frame::error("ERR increment or decrement would overflow"))?;
+    db.update(key.clone(), Bytes::from(next.to_string()).into());
+    Ok(Frame::Integer(next))
+}
+
+pub fn incr(db: &mut Db, args: &[Bytes]) -> Reply {
+    incr_by(db, &args[0], 1)
+}
+
+pub fn decr(db: &mut Db, args: &[Bytes]) -> Reply {
+    incr_by(db, &args[0], -1)
+}
+
+pub fn incrby(db: &mut Db, args: &[Bytes]) -> Reply {
+    incr_by(db, &args[0], parse_i64(&args[1])?)
+}
+
+pub fn decrby(db: &mut Db, args: &[Bytes]) -> Reply {
+    let delta = parse_i64(&args[1])?;
+    let delta = delta.checked_neg()
+        .ok_or_else(|| Frame::error("ERR decrement would overflow"))?;
+    incr_by(db, &args[0], delta)
+}
+
+pub fn append(db: &mut Db, args: &[Bytes]) -> Reply {
+    let (key, value) = (&args[0], &args[1]);
+    let mut buf = BytesMut::new();
+    if let Some(old) = db.get_string(key)? {
+        if old.len() + value.len() > MAX_STRING_LEN {
+            return Err(too_long());
+        }
+        buf.extend_from_slice(old);
+    }
+    buf.extend_from_slice(value);
+    let len = buf.len();
+    db.update(key.clone(), buf.freeze().into());
+    Ok(Frame::Integer(len as i64))
+}
+
+pub fn strlen(db: &mut Db, args: &[Bytes]) -> Reply {
+    Ok(Frame::Integer(db.get_string(&args[0])?.map_or(0, |val| val.len()) as i64))
+}
+
+pub fn mget(db: &mut Db, args: &[Bytes]) -> Reply {
+    // 不是字符串的键和不存在一样返回nil
+    Ok(Frame::Array(args.iter().map(|key| match db.get(key) {
+        Some(Value::String(val)) => Frame::Bulk(val.clone()),
+        _ => Frame::Null,
+    }).collect()))
+}
+
+pub fn mset(db: &mut Db, args: &[Bytes]) -> Reply {
+    if !args.len().is_multiple_of(2) {
+        return Err(wrong_arity("mset"));
+    }
+    for pair in args.chunks(2) {
+        db.insert(pair[0].clone(), pair[1].clone().into());
+    }
+    Ok(Frame::ok())
+}
+
+pub fn setnx(db: &mut Db, args: &[Bytes]) -> Reply {
+    if db.contains_key(&args[0]) {
+        return Ok(Frame::Integer(0));
+    }
+    db.insert(args[0].clone(), args[1].clone().into());
+    Ok(Frame::Integer(1))
+}
+
+pub fn getset(db: &mut Db, args: &[Bytes]) -> Reply {
+    let old = db.get_string(&args[0])?.cloned();
+    db.insert(args[0].clone(), args[1].clone().into());
+    Ok(old.into())
+}
+
+/// GETRANGE key start end，负数下标从末尾开始算，两端都包含
+pub fn getrange(db: &mut Db, args: &[Bytes]) -> Reply {
+    let (start, end) = (parse_i64(&args[1])?, parse_i64(&args[2])?);
+    let val = match db.get_string(&args[0])? {
+        Some(val) => val,
+        None => return Ok(Frame::Bulk(Bytes::new())),
+    };
+    let len = val.len() as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let end = if end < 0 { len + end } else { end.min(len - 1) };
+    if len == 0 || end < 0 || start > end {
+        return Ok(Frame::Bulk(Bytes::new()));
+    }
+    Ok(Frame::Bulk(val.slice(start as usize..=end as usize)))
+}
+
+/// SETRANGE key offset value，offset超过原长度时中间用0填充
+pub fn setrange(db: &mut Db, args: &[Bytes]) -> Reply {
+    let (key, value) = (&args[0], &args[2]);
+    let offset = parse_i64(&args[1])?;
+    if offset < 0 {
+        return Err(Frame::error("ERR offset is out of range"));
+    }
+    let offset = offset as usize;
+    let old = db.get_string(key)?.cloned();
+    if value.is_empty() {
+        return Ok(Frame::Integer(old.map_or(0, |val| val.len()) as i64));
+    }
+    if offset + value.len() > MAX_STRING_LEN {
+        return Err(too_long());
+    }
+    let mut buf = BytesMut::new();
+    if let Some(old) = old {
+        buf.extend_from_slice(&old);
+    }
+    if buf.len() < offset + value.len() {
+        buf.resize(offset + value.len(), 0);
+    }
+    buf[offset..offset + value.len()].copy_from_slice(value);
+    let len = buf.len();
+    db.update(key.clone(), buf.freeze().into());
+    Ok(Frame::Integer(len as i64))
+}
+
+fn too_long() -> Frame {
+    Frame::error("ERR string exceeds maximum allowed size (proto-max-bulk-len)")
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use resp::Frame;
+    use crate::cmd::testing::{bulk, run};
+    use crate::db::Keyspace;
+
+    #[test]
+    fn test_counter() {
+        let db = Keyspace::new(4, 1);
+        assert_eq!(run(&db, "INCR c"), Frame::Integer(1));
+        assert_eq!(run(&db, "DECRBY c 5"), Frame::Integer(-4));
+        assert_eq!(run(&db, "incrby c 10"), Frame::Integer(6));
+        assert_eq!(run(&db, "SET s abc"), Frame::ok());
+        assert_eq!(run(&db, "INCR s"), Frame::error("ERR value is not an integer or out of range"));
+        assert_eq!(run(&db, "SET m 9223372036854775807"), Frame::ok());
+        assert_eq!(run(&db, "INCR m"), Frame::error("ERR increment or decrement would overflow"));
+    }
+
+    #[test]
+    fn test_string() {
+        let db = Keyspace::new(4, 1);
+        assert_eq!(run(&db, "APPEND k Hello"), Frame::Integer(5));
+        assert_eq!(run(&db, "APPEND k World"), Frame::Integer(10));
+        assert_eq!(run(&db, "STRLEN k"), Frame::Integer(10));
+        assert_eq!(run(&db, "GETRANGE k 0 4"), bulk("Hello"));
+        assert_eq!(run(&db, "GETRANGE k -5 -1"), bulk("World"));
+        assert_eq!(run(&db, "GETRANGE k 5 100"), bulk("World"));
+        assert_eq!(run(&db, "GETRANGE k 3 1"), bulk(""));
+        assert_eq!(run(&db, "SETRANGE k 5 Redis"), Frame::Integer(10));
+        assert_eq!(run(&db, "GET k"), bulk("HelloRedis"));
+        assert_eq!(run(&db, "SETRANGE z 2 ab"), Frame::Integer(4));
+        assert_eq!(run(&db, "GET z"), Frame::Bulk(Bytes::from_static(b"\0\0ab")));
+    }
+
+    #[test]
+    fn test_multi_key() {
+        let db = Keyspace::new(4, 1);
+        assert_eq!(run(&db, "MSET a 1 b 2"), Frame::ok());
+        assert_eq!(run(&db, "MSET a 1 b"), Frame::error("ERR wrong number of arguments for 'mset' command"));
+        assert_eq!(run(&db, "MGET a x b"), Frame::Array(vec![bulk("1"), Frame::Null, bulk("2")]));
+        assert_eq!(run(&db, "SETNX a 3"), Frame::Integer(0));
+        assert_eq!(run(&db, "GETSET a 3"), bulk("1"));
+        assert_eq!(run(&db, "SET a 4 NX GET"), bulk("3"));
+        assert_eq!(run(&db, "EXISTS a b x a"), Frame::Integer(3));
+        assert_eq!(run(&db, "DEL a b x"), Frame::Integer(2));
+        assert_eq!(run(&db, "FOO a"), Frame::error("ERR unknown command 'foo', with args beginning with: 'a' "));
+        assert_eq!(run(&db, "GET"), Frame::error("ERR wrong number of arguments for 'get' command"));
+    }
+}
@@ -0,0 +1,146 @@
+use bytes::Bytes;
+use resp::Frame;
+use crate::cmd::{eq_ignore_case, normalize_range, parse_i64, syntax_error, Reply};
+use crate::db::{wrong_type, Db};
+use crate::value::{SortedSet, Value};
+
+fn get_zset<'a>(db: &'a mut Db, key: &Bytes) -> Result<Option<&'a mut SortedSet>, Frame> {
+    match db.get_mut(key) {
+        Some(Value::ZSet(zset)) => Ok(Some(zset)),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+fn parse_score(arg: &Bytes) -> Result<f64, Frame> {
+    std::str::from_utf8(arg).ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|score| !score.is_nan())
+        .ok_or_else(|| Frame::error("ERR value is not a valid float"))
+}
+
+/// 和Redis一样，整数分数不带小数点
+pub fn format_score(score: f64) -> Bytes {
+    Bytes::from(score.to_string())
+}
+
+/// ZADD key [NX|XX] [CH] score member [score member ...]
+pub fn zadd(db: &mut Db, args: &[Bytes]) -> Reply {
+    let key = &args[0];
+    let (mut nx, mut xx, mut ch) = (false, false, false);
+    let mut idx = 1;
+    while idx < args.len() {
+        if eq_ignore_case(&args[idx], "nx") {
+            nx = true;
+        } else if eq_ignore_case(&args[idx], "xx") {
+            xx = true;
+        } else if eq_ignore_case(&args[idx], "ch") {
+            ch = true;
+        } else {
+            break;
+        }
+        idx += 1;
+    }
+    let pairs = &args[idx..];
+    if (nx && xx) || pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return Err(syntax_error());
+    }
+    // 先把分数全部解析完，避免解析到一半出错时已经修改了一部分
+    let pairs = pairs.chunks(2)
+        .map(|pair| parse_score(&pair[0]).map(|score| (score, pair[1].clone())))
+        .collect::<Result<Vec<_>, _>>()?;
+    let zset = match db.get_or_insert_with(key, || Value::ZSet(SortedSet::default())) {
+        Value::ZSet(zset) => zset,
+        _ => return Err(wrong_type()),
+    };
+    let (mut added, mut changed) = (0, 0);
+    for (score, member) in pairs {
+        match zset.score(&member) {
+            Some(old) if !nx && old != score => {
+                zset.insert(member, score);
+                changed += 1;
+            }
+            None if !xx => {
+                zset.insert(member, score);
+                added += 1;
+            }
+            _ => {}
+        }
+    }
+    // XX时键不存在也会先建一个空的，这里删掉，没有修改
+    db.remove_if_empty(key);
+    db.add_dirty((added + changed) as usize);
+    Ok(Frame::Integer(if ch { added + changed } else { added }))
+}
+
+/// ZRANGE key start stop [WITHSCORES]，按分数从小到大
+pub fn zrange(db: &mut Db, args: &[Bytes]) -> Reply {
+    let (start, stop) = (parse_i64(&args[1])?, parse_i64(&args[2])?);
+    let with_scores = match args.get(3) {
+        Some(option) if eq_ignore_case(option, "withscores") && args.len() == 4 => true,
+        Some(_) => return Err(syntax_error()),
+        None => false,
+    };
+    let zset = match get_zset(db, &args[0])? {
+        Some(zset) => zset,
+        None => return Ok(Frame::Array(vec![])),
+    };
+    let (start, stop) = match normalize_range(start, stop, zset.len()) {
+        Some(range) => range,
+        None => return Ok(Frame::Array(vec![])),
+    };
+    let mut ans = Vec::new();
+    for (member, score) in zset.iter().skip(start).take(stop - start + 1) {
+        ans.push(Frame::Bulk(member.clone()));
+        if with_scores {
+            ans.push(Frame::Bulk(format_score(score)));
+        }
+    }
+    Ok(Frame::Array(ans))
+}
+
+/// ZREM key member [member ...]，返回删除的成员数
+pub fn zrem(db: &mut Db, args: &[Bytes]) -> Reply {
+    let removed = match get_zset(db, &args[0])? {
+        Some(zset) => args[1..].iter().filter(|member| zset.remove(member).is_some()).count(),
+        None => 0,
+    };
+    db.remove_if_empty(&args[0]);
+    db.add_dirty(removed);
+    Ok(Frame::Integer(removed as i64))
+}
+
+pub fn zrank(db: &mut Db, args: &[Bytes]) -> Reply {
+    Ok(match get_zset(db, &args[0])?.and_then(|zset| zset.rank(&args[1])) {
+        Some(rank) => Frame::Integer(rank as i64),
+        None => Frame::Null,
+    })
+}
+
+pub fn zscore(db: &mut Db, args: &[Bytes]) -> Reply {
+    Ok(get_zset(db, &args[0])?.and_then(|zset| zset.score(&args[1])).map_or(Frame::Null, Frame::Double))
+}
+
+#[cfg(test)]
+mod tests {
+    use resp::Frame;
+    use crate::cmd::testing::{bulk, run};
+    use crate::db::Keyspace;
+
+    #[test]
+    fn test_zset() {
+        let db = Keyspace::new(4, 1);
+        assert_eq!(run(&db, "ZADD z 1 a 2.5 b -1 c"), Frame::Integer(3));
+        assert_eq!(run(&db, "ZADD z CH 3 a 4 d"), Frame::Integer(2));
+        assert_eq!(run(&db, "ZADD z NX 10 a"), Frame::Integer(0));
+        assert_eq!(run(&db, "ZSCORE z a"), Frame::Double(3.0));
+        assert_eq!(run(&db, "ZSCORE z b"), Frame::Double(2.5));
+        assert_eq!(run(&db, "ZRANK z a"), Frame::Integer(2));
+        assert_eq!(run(&db, "ZRANK z x"), Frame::Null);
+        assert_eq!(run(&db, "ZRANGE z 0 -1"), Frame::Array(vec![bulk("c"), bulk("b"), bulk("a"), bulk("d")]));
+        assert_eq!(run(&db, "ZRANGE z 0 1 WITHSCORES"), Frame::Array(vec![bulk("c"), bulk("-1"), bulk("b"), bulk("2.5")]));
+        assert_eq!(run(&db, "ZREM z d x"), Frame::Integer(1));
+        assert_eq!(run(&db, "ZADD z x a"), Frame::error("ERR value is not a valid float"));
+        assert_eq!(run(&db, "ZADD z NX XX 1 a"), Frame::error("ERR syntax error"));
+    }
+}
@@ -0,0 +1,448 @@
+//! 服务端配置：先取默认值，再依次应用redis.conf风格的配置文件和命令行参数，
+//! 运行时可以用CONFIG GET/CONFIG SET查看和修改其中可以在线生效的部分。
+
+use crate::aof::Fsync;
+use crate::db::{DEFAULT_DATABASES, DEFAULT_SHARDS};
+use crate::evict::Policy;
+use crate::glob;
+use crate::log::Level;
+use crate::rdb::{parse_save_rules, SaveRule};
+use crate::tls::AuthClients;
+
+pub const USAGE: &str = "usage: my-redis-server [/path/to/redis.conf] [--<option> <value> ...]
+options: bind, port, shards, databases, maxclients, timeout, client-query-buffer-limit, maxmemory, maxmemory-policy, maxmemory-samples, dbfilename, save, appendonly, appendfilename, appendfsync, replicaof, replica-read-only, repl-backlog-size, cluster-enabled, cluster-config-file, cluster-announce-ip, slowlog-log-slower-than, slowlog-max-len, loglevel, requirepass, aclfile, masteruser, masterauth, tls-port, tls-cert-file, tls-key-file, tls-ca-cert-file, tls-auth-clients, script-time-limit";
+
+/// 配置项名字和是否可以在运行时修改，CONFIG GET按这个顺序输出
+const PARAMS: &[(&str, bool)] = &[
+    ("bind", false),
+    ("port", false),
+    ("shards", false),
+    ("databases", false),
+    ("maxclients", true),
+    ("timeout", true),
+    ("client-query-buffer-limit", true),
+    ("maxmemory", true),
+    ("maxmemory-policy", true),
+    ("maxmemory-samples", true),
+    ("dbfilename", false),
+    ("save", true),
+    ("appendonly", false),
+    ("appendfilename", false),
+    ("appendfsync", true),
+    ("replicaof", false),
+    ("replica-read-only", true),
+    ("repl-backlog-size", true),
+    ("cluster-enabled", false),
+    ("cluster-config-file", false),
+    ("cluster-announce-ip", false),
+    ("slowlog-log-slower-than", true),
+    ("slowlog-max-len", true),
+    ("loglevel", true),
+    ("requirepass", true),
+    ("aclfile", false),
+    ("masteruser", true),
+    ("masterauth", true),
+    ("tls-port", false),
+    ("tls-cert-file", false),
+    ("tls-key-file", false),
+    ("tls-ca-cert-file", false),
+    ("tls-auth-clients", false),
+    ("script-time-limit", true),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub bind: String,
+    pub port: u16,
+    pub shards: usize,
+    /// 逻辑数据库的个数，SELECT的编号从0到databases-1
+    pub databases: usize,
+    pub maxclients: usize,
+    /// 客户端空闲多少秒后断开，0表示不断开
+    pub timeout: u64,
+    /// 单个请求最大的字节数
+    pub client_query_buffer_limit: u64,
+    /// 字节数，0表示不限制
+    pub maxmemory: u64,
+    pub maxmemory_policy: Policy,
+    /// 淘汰时每个分片取样的键数
+    pub maxmemory_samples: usize,
+    pub dbfilename: String,
+    pub save: Vec<SaveRule>,
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: Fsync,
+    /// 启动时就作为这个主库的从库，运行时用REPLICAOF修改
+    pub replicaof: Option<(String, u16)>,
+    pub replica_read_only: bool,
+    /// 复制积压缓冲区的字节数，从库断开的这段时间里写入的数据不超过它就可以部分同步
+    pub repl_backlog_size: u64,
+    pub cluster_enabled: bool,
+    /// 节点ID、已知节点和槽的分配，由服务端自己维护
+    pub cluster_config_file: String,
+    /// 告诉其他节点和客户端的IP，为空时用bind
+    pub cluster_announce_ip: String,
+    /// 执行时间超过这么多微秒的命令记进慢查询日志，负数表示不记录
+    pub slowlog_log_slower_than: i64,
+    pub slowlog_max_len: usize,
+    pub loglevel: Level,
+    /// default用户的密码，为空时default用户不需要密码
+    pub requirepass: String,
+    /// 启动时从这个文件加载用户，ACL LOAD和ACL SAVE也读写它，为空时不使用
+    pub aclfile: String,
+    /// 从库连接主库、集群节点之间通信时认证用的用户名和密码，masterauth为空时不认证
+    pub masteruser: String,
+    pub masterauth: String,
+    /// 接受TLS连接的端口，0表示不开启。开启时port为0表示不接受明文连接
+    pub tls_port: u16,
+    pub tls_cert_file: String,
+    pub tls_key_file: String,
+    /// 校验客户端证书用的CA，tls-auth-clients为no时不需要
+    pub tls_ca_cert_file: String,
+    pub tls_auth_clients: AuthClients,
+    /// 脚本最多执行这么多毫秒，超过时中止
+    pub script_time_limit: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: "127.0.0.1".to_string(),
+            port: 16379,
+            shards: DEFAULT_SHARDS,
+            databases: DEFAULT_DATABASES,
+            maxclients: 10000,
+            timeout: 0,
+            client_query_buffer_limit: resp::DEFAULT_MAX_FRAME_SIZE as u64,
+            maxmemory: 0,
+            maxmemory_policy: Policy::NoEviction,
+            maxmemory_samples: 5,
+            dbfilename: "dump.rdb".to_string(),
+            save: parse_save_rules("3600 1 300 100 60 10000").unwrap(),
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: Fsync::EverySec,
+            replicaof: None,
+            replica_read_only: true,
+            repl_backlog_size: 1024 * 1024,
+            cluster_enabled: false,
+            cluster_config_file: "nodes.conf".to_string(),
+            cluster_announce_ip: String::new(),
+            slowlog_log_slower_than: 10000,
+            slowlog_max_len: 128,
+            loglevel: Level::Notice,
+            requirepass: String::new(),
+            aclfile: String::new(),
+            masteruser: String::new(),
+            masterauth: String::new(),
+            tls_port: 0,
+            tls_cert_file: String::new(),
+            tls_key_file: String::new(),
+            tls_ca_cert_file: String::new(),
+            tls_auth_clients: AuthClients::No,
+            script_time_limit: 5000,
+        }
+    }
+}
+
+impl Config {
+    /// 解析命令行：第一个参数不以`--`开头时当作配置文件路径，之后的`--name value`覆盖文件里的配置
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut args = args.into_iter().peekable();
+        if let Some(path) = args.next_if(|arg| !arg.starts_with("--")) {
+            let content = std::fs::read_to_string(&path).map_err(|err| format!("can't open config file '{}': {}", path, err))?;
+            config.load(&content)?;
+        }
+        while let Some(arg) = args.next() {
+            let name = arg.strip_prefix("--").ok_or_else(|| format!("unexpected argument '{}'", arg))?;
+            let value = args.next().ok_or_else(|| format!("missing value for '--{}'", name))?;
+            config.set(name, &value)?;
+        }
+        Ok(config)
+    }
+
+    /// 解析redis.conf风格的内容：每行一个`name value...`，#开头是注释，值可以用双引号括起来。
+    /// 和Redis一样，多行save规则会合并在一起，替换掉默认规则
+    pub fn load(&mut self, content: &str) -> Result<(), String> {
+        let mut save: Option<Vec<String>> = None;
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let tokens = split_line(line).ok_or_else(|| format!("line {}: unbalanced quotes", lineno + 1))?;
+            let name = tokens[0].to_ascii_lowercase();
+            if name == "save" {
+                save.get_or_insert_with(Vec::new).extend(tokens[1..].iter().cloned());
+                continue;
+            }
+            self.set(&name, &tokens[1..].join(" ")).map_err(|err| format!("line {}: {}", lineno + 1, err))?;
+        }
+        if let Some(save) = save {
+            self.set("save", &save.join(" "))?;
+        }
+        Ok(())
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("invalid value '{}' for '{}'", value, name);
+        match name.to_ascii_lowercase().as_str() {
+            "bind" if !value.is_empty() => self.bind = value.to_string(),
+            "port" => self.port = value.parse().map_err(|_| invalid())?,
+            "shards" => self.shards = value.parse().ok().filter(|shards| *shards > 0).ok_or_else(invalid)?,
+            "databases" => self.databases = value.parse().ok().filter(|databases| *databases > 0).ok_or_else(invalid)?,
+            "maxclients" => self.maxclients = value.parse().ok().filter(|max| *max > 0).ok_or_else(invalid)?,
+            "timeout" => self.timeout = value.parse().map_err(|_| invalid())?,
+            "client-query-buffer-limit" => self.client_query_buffer_limit = parse_memory(value).filter(|limit| *limit > 0).ok_or_else(invalid)?,
+            "maxmemory" => self.maxmemory = parse_memory(value).ok_or_else(invalid)?,
+            "maxmemory-policy" => self.maxmemory_policy = Policy::parse(value).ok_or_else(invalid)?,
+            "maxmemory-samples" => self.maxmemory_samples = value.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?,
+            "dbfilename" if !value.is_empty() => self.dbfilename = value.to_string(),
+            "save" => self.save = parse_save_rules(value).ok_or_else(invalid)?,
+            "appendonly" => self.appendonly = parse_bool(value).ok_or_else(invalid)?,
+            "appendfilename" if !value.is_empty() => self.appendfilename = value.to_string(),
+            "appendfsync" => self.appendfsync = Fsync::parse(value).ok_or_else(invalid)?,
+            "replicaof" => self.replicaof = parse_replicaof(value).ok_or_else(invalid)?,
+            "replica-read-only" => self.replica_read_only = parse_bool(value).ok_or_else(invalid)?,
+            "repl-backlog-size" => self.repl_backlog_size = parse_memory(value).filter(|size| *size > 0).ok_or_else(invalid)?,
+            "cluster-enabled" => self.cluster_enabled = parse_bool(value).ok_or_else(invalid)?,
+            "cluster-config-file" if !value.is_empty() => self.cluster_config_file = value.to_string(),
+            "cluster-announce-ip" => self.cluster_announce_ip = value.to_string(),
+            "slowlog-log-slower-than" => self.slowlog_log_slower_than = value.parse().map_err(|_| invalid())?,
+            "slowlog-max-len" => self.slowlog_max_len = value.parse().map_err(|_| invalid())?,
+            "loglevel" => self.loglevel = Level::parse(value).ok_or_else(invalid)?,
+            "requirepass" => self.requirepass = value.to_string(),
+            "aclfile" => self.aclfile = value.to_string(),
+            "masteruser" => self.masteruser = value.to_string(),
+            "masterauth" => self.masterauth = value.to_string(),
+            "tls-port" => self.tls_port = value.parse().map_err(|_| invalid())?,
+            "tls-cert-file" => self.tls_cert_file = value.to_string(),
+            "tls-key-file" => self.tls_key_file = value.to_string(),
+            "tls-ca-cert-file" => self.tls_ca_cert_file = value.to_string(),
+            "tls-auth-clients" => self.tls_auth_clients = AuthClients::parse(value).ok_or_else(invalid)?,
+            "script-time-limit" => self.script_time_limit = value.parse().ok().filter(|ms| *ms > 0).ok_or_else(invalid)?,
+            "bind" | "dbfilename" | "appendfilename" | "cluster-config-file" => return Err(invalid()),
+            _ => return Err(format!("unknown option '{}'", name)),
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        Some(match name {
+            "bind" => self.bind.clone(),
+            "port" => self.port.to_string(),
+            "shards" => self.shards.to_string(),
+            "databases" => self.databases.to_string(),
+            "maxclients" => self.maxclients.to_string(),
+            "timeout" => self.timeout.to_string(),
+            "client-query-buffer-limit" => self.client_query_buffer_limit.to_string(),
+            "maxmemory" => self.maxmemory.to_string(),
+            "maxmemory-policy" => self.maxmemory_policy.name().to_string(),
+            "maxmemory-samples" => self.maxmemory_samples.to_string(),
+            "dbfilename" => self.dbfilename.clone(),
+            "save" => self.save.iter().map(|rule| format!("{} {}", rule.seconds, rule.changes)).collect::<Vec<_>>().join(" "),
+            "appendonly" => if self.appendonly { "yes" } else { "no" }.to_string(),
+            "appendfilename" => self.appendfilename.clone(),
+            "appendfsync" => match self.appendfsync {
+                Fsync::Always => "always",
+                Fsync::EverySec => "everysec",
+                Fsync::No => "no",
+            }.to_string(),
+            "replicaof" => self.replicaof.as_ref().map(|(host, port)| format!("{} {}", host, port)).unwrap_or_default(),
+            "replica-read-only" => if self.replica_read_only { "yes" } else { "no" }.to_string(),
+            "repl-backlog-size" => self.repl_backlog_size.to_string(),
+            "cluster-enabled" => if self.cluster_enabled { "yes" } else { "no" }.to_string(),
+            "cluster-config-file" => self.cluster_config_file.clone(),
+            "cluster-announce-ip" => self.cluster_announce_ip.clone(),
+            "slowlog-log-slower-than" => self.slowlog_log_slower_than.to_string(),
+            "slowlog-max-len" => self.slowlog_max_len.to_string(),
+            "loglevel" => self.loglevel.name().to_string(),
+            "requirepass" => self.requirepass.clone(),
+            "aclfile" => self.aclfile.clone(),
+            "masteruser" => self.masteruser.clone(),
+            "masterauth" => self.masterauth.clone(),
+            "tls-port" => self.tls_port.to_string(),
+            "tls-cert-file" => self.tls_cert_file.clone(),
+            "tls-key-file" => self.tls_key_file.clone(),
+            "tls-ca-cert-file" => self.tls_ca_cert_file.clone(),
+            "tls-auth-clients" => self.tls_auth_clients.name().to_string(),
+            "script-time-limit" => self.script_time_limit.to_string(),
+            _ => return None,
+        })
+    }
+
+    /// 按glob模式匹配配置项，返回(名字, 值)
+    pub fn matching(&self, pattern: &str) -> Vec<(&'static str, String)> {
+        let pattern = pattern.to_ascii_lowercase();
+        PARAMS.iter()
+            .filter(|(name, _)| glob::matches(pattern.as_bytes(), name.as_bytes()))
+            .map(|(name, _)| (*name, self.get(name).unwrap()))
+            .collect()
+    }
+
+    /// 从库连接主库、集群节点之间通信时AUTH的参数，masterauth为空时不需要认证，返回空
+    pub fn auth_args(&self) -> Vec<String> {
+        match (self.masteruser.as_str(), self.masterauth.as_str()) {
+            (_, "") => Vec::new(),
+            ("", password) => vec![password.to_string()],
+            (user, password) => vec![user.to_string(), password.to_string()],
+        }
+    }
+
+    /// None表示没有这个配置项
+    pub fn is_mutable(name: &str) -> Option<bool> {
+        PARAMS.iter().find(|(param, _)| param.eq_ignore_ascii_case(name)).map(|(_, mutable)| *mutable)
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// `host port`，空字符串或者`no one`表示不是从库
+fn parse_replicaof(value: &str) -> Option<Option<(String, u16)>> {
+    match value.split_whitespace().collect::<Vec<_>>()[..] {
+        [] => Some(None),
+        [no, one] if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") => Some(None),
+        [host, port] => Some(Some((host.to_string(), port.parse().ok()?))),
+        _ => None,
+    }
+}
+
+/// 解析`100mb`、`1gb`、`512k`这样的内存大小，单位不区分大小写，和Redis一样k是1000、kb是1024
+pub fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_ascii_lowercase();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let unit = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// 按空白切分一行，双引号括起来的部分算一个词，`""`表示空字符串
+fn split_line(line: &str) -> Option<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Some(tokens);
+        };
+        let mut token = String::new();
+        if first == '"' {
+            chars.next();
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => token.push(chars.next()?),
+                    c => token.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                token.push(c);
+            }
+        }
+        tokens.push(token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use resp::Frame;
+    use crate::aof::Fsync;
+    use crate::cmd::{execute, Command};
+    use crate::config::{parse_memory, Config};
+    use crate::log::Level;
+    use crate::rdb::SaveRule;
+    use crate::server::Server;
+
+    #[test]
+    fn test_load() {
+        let mut config = Config::default();
+        config.load(r#"
+            # 注释
+            bind 0.0.0.0
+            port 6380
+            maxmemory 100mb
+            save 900 1
+            save 60 10000
+            appendonly yes
+            appendfilename "my aof.aof"
+            loglevel warning
+            replicaof 127.0.0.1 6379
+        "#).unwrap();
+        assert_eq!(config.bind, "0.0.0.0");
+        assert_eq!(config.port, 6380);
+        assert_eq!(config.maxmemory, 100 * 1024 * 1024);
+        assert_eq!(config.save, vec![SaveRule { seconds: 900, changes: 1 }, SaveRule { seconds: 60, changes: 10000 }]);
+        assert!(config.appendonly);
+        assert_eq!(config.appendfilename, "my aof.aof");
+        assert_eq!(config.loglevel, Level::Warning);
+        assert_eq!(config.replicaof, Some(("127.0.0.1".to_string(), 6379)));
+        assert!(config.load("save \"\"").is_ok());
+        assert!(config.save.is_empty());
+        assert!(config.load("port abc").is_err());
+        assert!(config.load("nosuchoption 1").is_err());
+    }
+
+    #[test]
+    fn test_from_args() {
+        let path = std::env::temp_dir().join(format!("my-redis-{}.conf", std::process::id()));
+        std::fs::write(&path, "port 7000\nappendfsync always\n").unwrap();
+        let args = [path.to_str().unwrap(), "--port", "7001", "--shards", "4"].map(String::from);
+        let config = Config::from_args(args).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.port, 7001);
+        assert_eq!(config.shards, 4);
+        assert_eq!(config.appendfsync, Fsync::Always);
+        assert!(Config::from_args(["--port".to_string()]).is_err());
+        assert!(Config::from_args(["--shards", "0"].map(String::from)).is_err());
+    }
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("0"), Some(0));
+        assert_eq!(parse_memory("1k"), Some(1000));
+        assert_eq!(parse_memory("1KB"), Some(1024));
+        assert_eq!(parse_memory("2gb"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_memory("1tb"), None);
+        assert_eq!(parse_memory("mb"), None);
+    }
+
+    #[test]
+    fn test_config_command() {
+        let server = Server::new();
+        let run = |args: &[&str]| {
+            let parts = args.iter().map(|s| Frame::Bulk(Bytes::from(s.to_string()))).collect();
+            execute(&server, 0, &Command::from_frame(Frame::Array(parts)).unwrap())
+        };
+        let bulk = |s: &str| Frame::Bulk(Bytes::from(s.to_string()));
+        assert_eq!(run(&["CONFIG", "GET", "port"]), Frame::Map(vec![(bulk("port"), bulk("16379"))]));
+        assert_eq!(run(&["CONFIG", "GET", "append*", "appendonly"]), Frame::Map(vec![
+            (bulk("appendonly"), bulk("no")), (bulk("appendfilename"), bulk("appendonly.aof")), (bulk("appendfsync"), bulk("everysec")),
+        ]));
+        assert_eq!(run(&["CONFIG", "SET", "maxmemory", "10mb", "save", ""]), Frame::ok());
+        assert_eq!(run(&["CONFIG", "GET", "maxmemory"]), Frame::Map(vec![(bulk("maxmemory"), bulk("10485760"))]));
+        assert!(server.config.read().unwrap().save.is_empty());
+        assert!(matches!(run(&["CONFIG", "SET", "port", "1"]), Frame::Error(err) if err.contains("immutable")));
+        // 任何一个参数不合法时都不生效
+        assert!(matches!(run(&["CONFIG", "SET", "maxmemory", "1", "loglevel", "loud"]), Frame::Error(err) if err.contains("loglevel")));
+        assert_eq!(server.config.read().unwrap().maxmemory, 10 * 1024 * 1024);
+        assert!(matches!(run(&["CONFIG", "SET", "nosuch", "1"]), Frame::Error(_)));
+    }
+}
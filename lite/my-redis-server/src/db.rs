@@ -0,0 +1,884 @@
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use indexmap::{IndexMap, IndexSet};
+use tokio::sync::Notify;
+use resp::Frame;
+use crate::cluster;
+use crate::evict::{self, Access};
+use crate::glob;
+use crate::value::Value;
+
+/// 每个键除了键和值本身以外的固定开销，估算用
+const ENTRY_OVERHEAD: usize = 64;
+
+struct Entry {
+    // 快照只复制Arc，之后被修改时才复制值本身(写时复制)，这样生成快照时持有分片锁的时间和值的大小无关
+    value: Arc<Value>,
+    expires_at: Option<Instant>,
+    // 估算的内存占用，值被修改后在命令结束时刷新
+    size: usize,
+    access: Access,
+}
+
+fn entry_size(key: &Bytes, value: &Value) -> usize {
+    ENTRY_OVERHEAD + key.len() + value.memory_usage()
+}
+
+/// SCAN遍历的顺序，DefaultHasher::new()用的是固定的密钥，同一个键的哈希值不会变
+fn scan_hash(key: &Bytes) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// 整个键空间共用的计数器
+#[derive(Default)]
+struct Counters {
+    used_memory: AtomicUsize,
+    // 因为过期被删除的键数
+    expired: AtomicU64,
+}
+
+/// 键空间的一个分片，支持按键过期：访问时惰性删除，另外有后台任务按截止时间顺序定期清理。
+/// 键放在IndexMap里，淘汰时可以按下标随机取样
+pub struct Shard {
+    entries: IndexMap<Bytes, Entry>,
+    // 按(截止时间, 键)排序，后台清理任务只需要从头开始看
+    expirations: BTreeSet<(Instant, Bytes)>,
+    // 设置了过期时间的键，volatile-*淘汰策略从这里取样
+    volatile: IndexSet<Bytes>,
+    // 按(哈希值, 键)排序，SCAN的游标就是哈希值，遍历期间一直存在的键都能被遍历到
+    order: BTreeSet<(u64, Bytes)>,
+    counters: Arc<Counters>,
+    // 本次命令中可能被原地修改过的键，命令结束时重新估算它们的内存占用
+    touched: Vec<Bytes>,
+    // 被WATCH的键：(监视它的连接数, 版本号)，键每次被修改版本号加一
+    watched: HashMap<Bytes, (usize, u64)>,
+    // 出现了更早的截止时间时通知后台清理任务重新计算睡眠时间，同一个分片在各个数据库里的部分共用一个
+    background: Arc<Notify>,
+}
+
+impl Shard {
+    pub fn new() -> Shard {
+        Shard::with_counters(Arc::new(Counters::default()), Arc::new(Notify::new()))
+    }
+
+    fn with_counters(counters: Arc<Counters>, background: Arc<Notify>) -> Shard {
+        Shard {
+            entries: IndexMap::new(),
+            expirations: BTreeSet::new(),
+            volatile: IndexSet::new(),
+            order: BTreeSet::new(),
+            counters,
+            touched: Vec::new(),
+            watched: HashMap::new(),
+            background,
+        }
+    }
+
+    /// 所有新增的键都经过这里，维护过期索引和内存计数
+    fn put(&mut self, key: Bytes, value: Arc<Value>, expires_at: Option<Instant>) -> &mut Entry {
+        self.signal_modified(&key);
+        let size = entry_size(&key, &value);
+        self.counters.used_memory.fetch_add(size, Ordering::Relaxed);
+        if let Some(when) = expires_at {
+            self.add_expiration(when, key.clone());
+        }
+        self.order.insert((scan_hash(&key), key.clone()));
+        let entry = Entry { value, expires_at, size, access: Access::new() };
+        let (idx, _) = self.entries.insert_full(key, entry);
+        &mut self.entries[idx]
+    }
+
+    /// 所有删除的键都经过这里
+    fn take(&mut self, key: &Bytes) -> Option<Entry> {
+        let entry = self.entries.swap_remove(key)?;
+        self.signal_modified(key);
+        self.counters.used_memory.fetch_sub(entry.size, Ordering::Relaxed);
+        if let Some(when) = entry.expires_at {
+            self.expirations.remove(&(when, key.clone()));
+            self.volatile.swap_remove(key);
+        }
+        self.order.remove(&(scan_hash(key), key.clone()));
+        Some(entry)
+    }
+
+    /// 访问前先检查过期，过期了就顺手删掉
+    fn expire_if_needed(&mut self, key: &Bytes) {
+        let expired = matches!(self.entries.get(key), Some(Entry { expires_at: Some(when), .. }) if *when <= Instant::now());
+        if expired {
+            self.remove(key);
+            self.counters.expired.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get(&mut self, key: &Bytes) -> Option<&Value> {
+        self.expire_if_needed(key);
+        let entry = self.entries.get_mut(key)?;
+        entry.access.touch();
+        Some(entry.value.as_ref())
+    }
+
+    pub fn get_mut(&mut self, key: &Bytes) -> Option<&mut Value> {
+        self.expire_if_needed(key);
+        if !self.entries.contains_key(key) {
+            return None;
+        }
+        self.touched.push(key.clone());
+        self.signal_modified(key);
+        let entry = self.entries.get_mut(key)?;
+        entry.access.touch();
+        Some(Arc::make_mut(&mut entry.value))
+    }
+
+    /// 读取字符串类型的值，类型不对时返回WRONGTYPE错误
+    pub fn get_string(&mut self, key: &Bytes) -> Result<Option<&Bytes>, Frame> {
+        match self.get(key) {
+            Some(Value::String(val)) => Ok(Some(val)),
+            Some(_) => Err(wrong_type()),
+            None => Ok(None),
+        }
+    }
+
+    /// 取出集合类型的值，不存在时用init创建一个空的，类型由调用方检查
+    pub fn get_or_insert_with(&mut self, key: &Bytes, init: impl FnOnce() -> Value) -> &mut Value {
+        self.expire_if_needed(key);
+        self.touched.push(key.clone());
+        if !self.entries.contains_key(key) {
+            return Arc::make_mut(&mut self.put(key.clone(), Arc::new(init()), None).value);
+        }
+        self.signal_modified(key);
+        let entry = self.entries.get_mut(key).unwrap();
+        entry.access.touch();
+        Arc::make_mut(&mut entry.value)
+    }
+
+    /// 集合类型的值被删空之后删除整个键
+    pub fn remove_if_empty(&mut self, key: &Bytes) {
+        if self.entries.get(key).is_some_and(|entry| entry.value.is_empty_collection()) {
+            self.remove(key);
+        }
+    }
+
+    pub fn contains_key(&mut self, key: &Bytes) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// 写入新值并清除原来的过期时间，返回旧值
+    pub fn insert(&mut self, key: Bytes, value: Value) -> Option<Value> {
+        self.insert_with_expire(key, value, None)
+    }
+
+    pub fn insert_with_expire(&mut self, key: Bytes, value: Value, expires_at: Option<Instant>) -> Option<Value> {
+        let old = self.remove(&key);
+        self.put(key, Arc::new(value), expires_at);
+        old
+    }
+
+    /// 只替换值，保留原来的过期时间，INCR、APPEND之类的命令使用
+    pub fn update(&mut self, key: Bytes, value: Value) {
+        self.expire_if_needed(&key);
+        match self.entries.get_mut(&key) {
+            Some(entry) => {
+                entry.value = Arc::new(value);
+                entry.access.touch();
+                self.signal_modified(&key);
+                self.touched.push(key);
+            }
+            None => {
+                self.put(key, Arc::new(value), None);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &Bytes) -> Option<Value> {
+        let entry = self.take(key)?;
+        match entry.expires_at {
+            Some(when) if when <= Instant::now() => None,
+            _ => Some(Arc::unwrap_or_clone(entry.value)),
+        }
+    }
+
+    /// 修改过期时间，None表示永不过期，键不存在时返回false
+    pub fn set_expire(&mut self, key: &Bytes, expires_at: Option<Instant>) -> bool {
+        self.expire_if_needed(key);
+        let entry = match self.entries.get_mut(key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let old = std::mem::replace(&mut entry.expires_at, expires_at);
+        self.signal_modified(key);
+        if let Some(when) = old {
+            self.expirations.remove(&(when, key.clone()));
+            self.volatile.swap_remove(key);
+        }
+        if let Some(when) = expires_at {
+            self.add_expiration(when, key.clone());
+        }
+        true
+    }
+
+    /// 键不存在返回None，存在时返回它的过期时间
+    pub fn expires_at(&mut self, key: &Bytes) -> Option<Option<Instant>> {
+        self.expire_if_needed(key);
+        self.entries.get(key).map(|entry| entry.expires_at)
+    }
+
+    /// 估算的内存占用，MEMORY USAGE使用
+    pub fn memory_usage(&mut self, key: &Bytes) -> Option<usize> {
+        self.expire_if_needed(key);
+        self.entries.get(key).map(|entry| entry.size)
+    }
+
+    /// 开始监视一个键，返回它当前的版本号
+    pub fn watch(&mut self, key: &Bytes) -> u64 {
+        self.expire_if_needed(key);
+        let (watchers, version) = self.watched.entry(key.clone()).or_insert((0, 0));
+        *watchers += 1;
+        *version
+    }
+
+    pub fn unwatch(&mut self, key: &Bytes) {
+        if let Some((watchers, _)) = self.watched.get_mut(key) {
+            *watchers -= 1;
+            if *watchers == 0 {
+                self.watched.remove(key);
+            }
+        }
+    }
+
+    /// 被监视的键的版本号，和watch时的返回值不同说明期间被修改过(包括过期)
+    pub fn version(&mut self, key: &Bytes) -> u64 {
+        self.expire_if_needed(key);
+        self.watched.get(key).map_or(0, |(_, version)| *version)
+    }
+
+    fn signal_modified(&mut self, key: &Bytes) {
+        if self.watched.is_empty() {
+            return;
+        }
+        if let Some((_, version)) = self.watched.get_mut(key) {
+            *version += 1;
+        }
+    }
+
+    fn add_expiration(&mut self, when: Instant, key: Bytes) {
+        let earliest = self.expirations.first().is_none_or(|(next, _)| when < *next);
+        self.expirations.insert((when, key.clone()));
+        self.volatile.insert(key);
+        if earliest {
+            self.background.notify_one();
+        }
+    }
+
+    /// 删除所有的键，被监视的键都算被修改了。返回被删掉的键值，由调用方决定在哪里释放
+    fn clear(&mut self) -> IndexMap<Bytes, Entry> {
+        let size: usize = self.entries.values().map(|entry| entry.size).sum();
+        self.counters.used_memory.fetch_sub(size, Ordering::Relaxed);
+        self.expirations.clear();
+        self.volatile.clear();
+        self.order.clear();
+        self.touched.clear();
+        self.touch_all_watched();
+        std::mem::take(&mut self.entries)
+    }
+
+    /// 和另一个数据库在这个分片上的部分交换所有的键，被监视的键留在原来的数据库，都算被修改了
+    fn swap(&mut self, other: &mut Shard) {
+        std::mem::swap(&mut self.entries, &mut other.entries);
+        std::mem::swap(&mut self.expirations, &mut other.expirations);
+        std::mem::swap(&mut self.volatile, &mut other.volatile);
+        std::mem::swap(&mut self.order, &mut other.order);
+        self.touch_all_watched();
+        other.touch_all_watched();
+        // 两边共用一个后台清理任务，让它重新计算最早的截止时间
+        self.background.notify_one();
+    }
+
+    fn touch_all_watched(&mut self) {
+        for (_, version) in self.watched.values_mut() {
+            *version += 1;
+        }
+    }
+
+    /// 删除所有已经过期的键，返回下一个截止时间
+    pub fn purge_expired(&mut self, now: Instant) -> Option<Instant> {
+        while let Some((when, key)) = self.expirations.first().cloned() {
+            if when > now {
+                return Some(when);
+            }
+            self.take(&key);
+            self.counters.expired.fetch_add(1, Ordering::Relaxed);
+        }
+        None
+    }
+
+    /// 重新估算本次命令修改过的键的内存占用
+    fn refresh_sizes(&mut self) {
+        for key in std::mem::take(&mut self.touched) {
+            if let Some(entry) = self.entries.get_mut(&key) {
+                let size = entry_size(&key, &entry.value);
+                self.counters.used_memory.fetch_add(size, Ordering::Relaxed);
+                self.counters.used_memory.fetch_sub(entry.size, Ordering::Relaxed);
+                entry.size = size;
+            }
+        }
+    }
+
+    /// 随机取最多n个键，volatile为true时只从设置了过期时间的键里取
+    fn sample(&self, n: usize, volatile: bool) -> Vec<(Bytes, Access, Option<Instant>)> {
+        let len = if volatile { self.volatile.len() } else { self.entries.len() };
+        let mut samples = Vec::with_capacity(n.min(len));
+        if len == 0 {
+            return samples;
+        }
+        // 从随机位置开始连续取，样本之间不会重复
+        let start = evict::random() as usize % len;
+        for idx in (0..n.min(len)).map(|i| (start + i) % len) {
+            let (key, entry) = if volatile {
+                let key = &self.volatile[idx];
+                (key, &self.entries[key])
+            } else {
+                self.entries.get_index(idx).unwrap()
+            };
+            samples.push((key.clone(), entry.access, entry.expires_at));
+        }
+        samples
+    }
+}
+
+impl Shard {
+    /// 把键连同过期时间移到另一个数据库，目标数据库里已经有这个键时不移动，返回是否移动了
+    fn move_to(&mut self, key: &Bytes, other: &mut Shard) -> bool {
+        self.expire_if_needed(key);
+        other.expire_if_needed(key);
+        if !self.entries.contains_key(key) || other.entries.contains_key(key) {
+            return false;
+        }
+        let entry = self.take(key).unwrap();
+        other.put(key.clone(), entry.value, entry.expires_at);
+        true
+    }
+
+    /// 从哈希值cursor开始按顺序取至少count个没有过期的键(哈希值相同的键不拆开)，返回(哈希值, 键, 类型)，
+    /// 以及剩下的键里最小的哈希值，没有剩下的键时为None
+    fn scan(&self, cursor: u64, count: usize) -> (Vec<(u64, Bytes, &'static str)>, Option<u64>) {
+        let now = Instant::now();
+        let mut found = Vec::new();
+        let mut iter = self.order.range((cursor, Bytes::new())..).peekable();
+        while let Some((hash, key)) = iter.next() {
+            let entry = &self.entries[key];
+            if entry.expires_at.is_none_or(|when| when > now) {
+                found.push((*hash, key.clone(), entry.value.type_name()));
+            }
+            if found.len() >= count && iter.peek().is_none_or(|(next, _)| next != hash) {
+                break;
+            }
+        }
+        (found, iter.peek().map(|(next, _)| *next))
+    }
+}
+
+/// 把过期时间换算成毫秒级unix时间戳，快照和AOF里保存的都是绝对时间
+pub fn to_unix_millis(when: Instant) -> i64 {
+    let now = SystemTime::now();
+    let when = match when.checked_duration_since(Instant::now()) {
+        Some(remaining) => now + remaining,
+        None => now - Instant::now().duration_since(when),
+    };
+    when.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+/// to_unix_millis的逆运算，已经过去的时间换算成当前时刻
+pub fn from_unix_millis(millis: i64) -> Instant {
+    let when = UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64);
+    Instant::now() + when.duration_since(SystemTime::now()).unwrap_or_default()
+}
+
+pub fn wrong_type() -> Frame {
+    Frame::error("WRONGTYPE Operation against a key holding the wrong kind of value")
+}
+
+
+impl Default for Shard {
+    fn default() -> Self {
+        Shard::new()
+    }
+}
+
+/// 默认分片数，分片越多不同键上的命令越不容易互相等锁
+pub const DEFAULT_SHARDS: usize = 16;
+/// 默认的数据库个数，和Redis一样
+pub const DEFAULT_DATABASES: usize = 16;
+
+/// 每个数据库的全部键值和过期时间，下标是数据库编号
+pub type Snapshot = Vec<Vec<(Bytes, Arc<Value>, Option<Instant>)>>;
+
+/// 按键所在的哈希槽分成若干个独立加锁的分片，只有落在同一个分片上的命令才会互相等待。
+/// 同一个槽的键总在同一个分片上，集群模式下按槽找键只需要看一个分片。
+/// 每个分片的锁同时保护这个分片在所有数据库里的部分，同一个键在各个数据库里总在同一个分片上，
+/// MOVE只需要锁一个分片，SWAPDB、FLUSHALL锁住所有分片之后对所有连接来说都是原子的
+pub struct Keyspace {
+    shards: Vec<Mutex<Vec<Shard>>>,
+    databases: usize,
+    counters: Arc<Counters>,
+}
+
+impl Keyspace {
+    pub fn new(shards: usize, databases: usize) -> Keyspace {
+        assert!(shards > 0, "keyspace needs at least one shard");
+        assert!(databases > 0, "keyspace needs at least one database");
+        let counters = Arc::new(Counters::default());
+        Keyspace {
+            shards: (0..shards)
+                .map(|_| {
+                    let background = Arc::new(Notify::new());
+                    Mutex::new((0..databases).map(|_| Shard::with_counters(counters.clone(), background.clone())).collect())
+                })
+                .collect(),
+            databases,
+            counters,
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn databases(&self) -> usize {
+        self.databases
+    }
+
+    /// 所有键估算的内存占用之和
+    pub fn used_memory(&self) -> usize {
+        self.counters.used_memory.load(Ordering::Relaxed)
+    }
+
+    /// 因为过期被删除的键数，INFO使用
+    pub fn expired_keys(&self) -> u64 {
+        self.counters.expired.load(Ordering::Relaxed)
+    }
+
+    /// 数据库index里键的总数，包括已经过期但是还没被删除的键
+    pub fn len(&self, index: usize) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap()[index].entries.len()).sum()
+    }
+
+    /// 数据库index里设置了过期时间的键数
+    pub fn volatile_len(&self, index: usize) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap()[index].volatile.len()).sum()
+    }
+
+    /// 数据库index里匹配glob模式的所有键，逐个分片加锁，不是某一时刻的快照
+    pub fn keys(&self, index: usize, pattern: &[u8]) -> Vec<Bytes> {
+        let now = Instant::now();
+        let mut keys = Vec::new();
+        for shard in &self.shards {
+            let shard = &shard.lock().unwrap()[index];
+            keys.extend(shard.entries.iter()
+                .filter(|(key, entry)| entry.expires_at.is_none_or(|when| when > now) && glob::matches(pattern, key))
+                .map(|(key, _)| key.clone()));
+        }
+        keys
+    }
+
+    /// SCAN：返回下一个游标和(键, 类型)，游标为0表示遍历结束。逐个分片加锁，
+    /// 每个分片只返回比所有分片剩下的键的哈希值都小的部分，遍历期间一直存在的键至少返回一次
+    pub fn scan(&self, index: usize, cursor: u64, count: usize) -> (u64, Vec<(Bytes, &'static str)>) {
+        let mut found = Vec::new();
+        let mut bound: Option<u64> = None;
+        for shard in &self.shards {
+            let (keys, rest) = shard.lock().unwrap()[index].scan(cursor, count);
+            found.extend(keys);
+            if let Some(rest) = rest {
+                bound = Some(bound.map_or(rest, |bound| bound.min(rest)));
+            }
+        }
+        found.retain(|(hash, _, _)| bound.is_none_or(|bound| *hash < bound));
+        found.sort_unstable_by_key(|(hash, _, _)| *hash);
+        (bound.unwrap_or(0), found.into_iter().map(|(_, key, kind)| (key, kind)).collect())
+    }
+
+    pub fn shard_of(&self, key: &Bytes) -> usize {
+        cluster::key_slot(key) as usize % self.shards.len()
+    }
+
+    /// 锁住这些键所在的分片，返回的Db访问数据库index。总是按分片下标从小到大加锁，多键命令之间不会死锁
+    pub fn lock<'a>(&self, index: usize, keys: impl IntoIterator<Item = &'a Bytes>) -> Db<'_> {
+        let mut indexes: Vec<usize> = keys.into_iter().map(|key| self.shard_of(key)).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        Db {
+            keyspace: self,
+            index,
+            guards: indexes.into_iter().map(|idx| (idx, self.shards[idx].lock().unwrap())).collect(),
+            dirty: 0,
+        }
+    }
+
+    /// 锁住所有分片，比如全量同步时替换整个键空间
+    pub fn lock_all(&self, index: usize) -> Db<'_> {
+        Db {
+            keyspace: self,
+            index,
+            guards: self.shards.iter().enumerate().map(|(idx, shard)| (idx, shard.lock().unwrap())).collect(),
+            dirty: 0,
+        }
+    }
+
+    /// 从第idx个分片的所有数据库里随机取样，返回(数据库编号, 键, 访问信息, 过期时间)，淘汰键时使用
+    pub fn sample(&self, idx: usize, n: usize, volatile: bool) -> Vec<(usize, Bytes, Access, Option<Instant>)> {
+        self.shards[idx].lock().unwrap().iter().enumerate()
+            .flat_map(|(index, shard)| shard.sample(n, volatile).into_iter().map(move |(key, access, expires_at)| (index, key, access, expires_at)))
+            .collect()
+    }
+
+    /// 某个槽里最多count个没有过期的键，CLUSTER GETKEYSINSLOT使用，集群模式下只有0号数据库
+    pub fn keys_in_slot(&self, slot: u16, count: usize) -> Vec<Bytes> {
+        let shard = &self.shards[slot as usize % self.shards.len()].lock().unwrap()[0];
+        let now = Instant::now();
+        shard.entries.iter()
+            .filter(|(key, entry)| cluster::key_slot(key) == slot && entry.expires_at.is_none_or(|when| when > now))
+            .map(|(key, _)| key.clone())
+            .take(count)
+            .collect()
+    }
+
+    pub fn count_keys_in_slot(&self, slot: u16) -> usize {
+        self.keys_in_slot(slot, usize::MAX).len()
+    }
+
+    /// 同时锁住所有分片，复制出某一时刻的全部键值和过期时间，用于生成快照。值只复制Arc，加锁时间和值的大小无关
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshot_with(|| {})
+    }
+
+    /// 和snapshot一样，另外在持有所有分片锁的时候调用f，AOF重写靠它保证快照和重写缓冲区刚好衔接
+    pub fn snapshot_with(&self, f: impl FnOnce()) -> Snapshot {
+        let shards: Vec<_> = self.shards.iter().map(|shard| shard.lock().unwrap()).collect();
+        f();
+        let now = Instant::now();
+        (0..self.databases)
+            .map(|index| shards.iter()
+                .flat_map(|shard| shard[index].entries.iter())
+                .filter(|(_, entry)| entry.expires_at.is_none_or(|when| when > now))
+                .map(|(key, entry)| (key.clone(), entry.value.clone(), entry.expires_at))
+                .collect())
+            .collect()
+    }
+}
+
+impl Default for Keyspace {
+    fn default() -> Self {
+        Keyspace::new(DEFAULT_SHARDS, DEFAULT_DATABASES)
+    }
+}
+
+/// FLUSHDB、FLUSHALL删掉的键值，drop时才真正释放内存，ASYNC时放到后台线程里drop
+pub struct Flushed(Vec<IndexMap<Bytes, Entry>>);
+
+impl Flushed {
+    /// 删掉的键数
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|entries| entries.len()).sum()
+    }
+}
+
+/// 已经加锁的若干分片，命令处理函数通过它访问数据库index里的键，只能访问加锁时声明过的键
+pub struct Db<'a> {
+    keyspace: &'a Keyspace,
+    index: usize,
+    guards: Vec<(usize, MutexGuard<'a, Vec<Shard>>)>,
+    // 通过这个Db做的修改次数，和Redis的server.dirty一样。写入、删除和修改过期时间自动计数，
+    // 通过get_mut和get_or_insert_with原地修改集合的命令自己调用add_dirty
+    dirty: u64,
+}
+
+impl Db<'_> {
+    fn shard(&mut self, key: &Bytes) -> &mut Shard {
+        let idx = self.keyspace.shard_of(key);
+        match self.guards.iter_mut().find(|(locked, _)| *locked == idx) {
+            Some((_, shards)) => &mut shards[self.index],
+            None => panic!("key {:?} is not locked by this command", key),
+        }
+    }
+
+    /// 当前访问的数据库编号
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// 到目前为止做过的修改次数，执行命令前后的差值为0说明命令什么也没改
+    pub fn dirty(&self) -> u64 {
+        self.dirty
+    }
+
+    /// 原地修改了集合里的changes个元素
+    pub fn add_dirty(&mut self, changes: usize) {
+        self.dirty += changes as u64;
+    }
+
+    pub fn databases(&self) -> usize {
+        self.keyspace.databases()
+    }
+
+    /// 切换到另一个数据库，已经加的锁不变
+    pub fn select(&mut self, index: usize) {
+        assert!(index < self.keyspace.databases(), "DB index {} is out of range", index);
+        self.index = index;
+    }
+
+    pub fn get(&mut self, key: &Bytes) -> Option<&Value> {
+        self.shard(key).get(key)
+    }
+
+    /// 清空当前数据库在加锁的分片上的部分
+    pub fn clear(&mut self) -> Flushed {
+        let index = self.index;
+        Flushed(self.guards.iter_mut().map(|(_, shards)| shards[index].clear()).collect())
+    }
+
+    /// 清空所有数据库在加锁的分片上的部分
+    pub fn clear_all(&mut self) -> Flushed {
+        Flushed(self.guards.iter_mut().flat_map(|(_, shards)| shards.iter_mut().map(Shard::clear)).collect())
+    }
+
+    /// 交换两个数据库在加锁的分片上的部分，SWAPDB锁住所有分片后调用
+    pub fn swap(&mut self, a: usize, b: usize) {
+        if a == b {
+            return;
+        }
+        for (_, shards) in &mut self.guards {
+            let (low, high) = shards.split_at_mut(a.max(b));
+            low[a.min(b)].swap(&mut high[0]);
+        }
+    }
+
+    /// 把键移到数据库dst，MOVE使用
+    pub fn move_to(&mut self, key: &Bytes, dst: usize) -> bool {
+        let (src, idx) = (self.index, self.keyspace.shard_of(key));
+        let shards = match self.guards.iter_mut().find(|(locked, _)| *locked == idx) {
+            Some((_, shards)) => shards,
+            None => panic!("key {:?} is not locked by this command", key),
+        };
+        if src == dst {
+            return false;
+        }
+        let (low, high) = shards.split_at_mut(src.max(dst));
+        let moved = if src < dst {
+            low[src].move_to(key, &mut high[0])
+        } else {
+            high[0].move_to(key, &mut low[dst])
+        };
+        self.dirty += moved as u64;
+        moved
+    }
+
+    pub fn get_mut(&mut self, key: &Bytes) -> Option<&mut Value> {
+        self.shard(key).get_mut(key)
+    }
+
+    pub fn get_string(&mut self, key: &Bytes) -> Result<Option<&Bytes>, Frame> {
+        self.shard(key).get_string(key)
+    }
+
+    pub fn get_or_insert_with(&mut self, key: &Bytes, init: impl FnOnce() -> Value) -> &mut Value {
+        self.shard(key).get_or_insert_with(key, init)
+    }
+
+    pub fn remove_if_empty(&mut self, key: &Bytes) {
+        self.shard(key).remove_if_empty(key)
+    }
+
+    pub fn contains_key(&mut self, key: &Bytes) -> bool {
+        self.shard(key).contains_key(key)
+    }
+
+    pub fn insert(&mut self, key: Bytes, value: Value) -> Option<Value> {
+        self.dirty += 1;
+        self.shard(&key).insert(key, value)
+    }
+
+    pub fn insert_with_expire(&mut self, key: Bytes, value: Value, expires_at: Option<Instant>) -> Option<Value> {
+        self.dirty += 1;
+        self.shard(&key).insert_with_expire(key, value, expires_at)
+    }
+
+    pub fn update(&mut self, key: Bytes, value: Value) {
+        self.dirty += 1;
+        self.shard(&key).update(key, value)
+    }
+
+    pub fn remove(&mut self, key: &Bytes) -> Option<Value> {
+        let removed = self.shard(key).remove(key);
+        self.dirty += removed.is_some() as u64;
+        removed
+    }
+
+    pub fn set_expire(&mut self, key: &Bytes, expires_at: Option<Instant>) -> bool {
+        let found = self.shard(key).set_expire(key, expires_at);
+        self.dirty += found as u64;
+        found
+    }
+
+    pub fn expires_at(&mut self, key: &Bytes) -> Option<Option<Instant>> {
+        self.shard(key).expires_at(key)
+    }
+
+    pub fn memory_usage(&mut self, key: &Bytes) -> Option<usize> {
+        self.shard(key).memory_usage(key)
+    }
+
+    pub fn watch(&mut self, key: &Bytes) -> u64 {
+        self.shard(key).watch(key)
+    }
+
+    pub fn unwatch(&mut self, key: &Bytes) {
+        self.shard(key).unwatch(key)
+    }
+
+    pub fn version(&mut self, key: &Bytes) -> u64 {
+        self.shard(key).version(key)
+    }
+}
+
+impl Drop for Db<'_> {
+    fn drop(&mut self) {
+        for (_, shards) in &mut self.guards {
+            shards.iter_mut().for_each(Shard::refresh_sizes);
+        }
+    }
+}
+
+/// 后台清理任务，每个分片一个，负责这个分片在所有数据库里的部分：睡到最早的截止时间，醒来后删除过期的键；
+/// 有更早的截止时间加入时会被提前叫醒
+pub async fn purge_expired_keys(keyspace: Arc<Keyspace>, idx: usize) {
+    let shard = &keyspace.shards[idx];
+    let background = shard.lock().unwrap()[0].background.clone();
+    loop {
+        let now = Instant::now();
+        let next = shard.lock().unwrap().iter_mut().filter_map(|shard| shard.purge_expired(now)).min();
+        match next {
+            Some(when) => {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(when.into()) => {}
+                    _ = background.notified() => {}
+                }
+            }
+            None => background.notified().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use bytes::Bytes;
+    use crate::db::{purge_expired_keys, Keyspace, Shard};
+    use crate::value::Value;
+
+    #[test]
+    fn test_lazy_expire() {
+        let mut db = Shard::new();
+        let key = Bytes::from("k");
+        db.insert_with_expire(key.clone(), Bytes::from("v").into(), Some(Instant::now() + Duration::from_millis(20)));
+        assert!(db.contains_key(&key));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(db.get(&key).is_none());
+        assert!(db.expirations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_background_purge() {
+        let keyspace = Arc::new(Keyspace::new(1, 2));
+        tokio::spawn(purge_expired_keys(keyspace.clone(), 0));
+        let now = Instant::now();
+        {
+            let mut shards = keyspace.shards[0].lock().unwrap();
+            shards[0].insert_with_expire(Bytes::from("a"), Bytes::from("1").into(), Some(now + Duration::from_millis(200)));
+            // 另一个数据库里更早的截止时间也要把后台任务提前叫醒
+            shards[1].insert_with_expire(Bytes::from("b"), Bytes::from("2").into(), Some(now + Duration::from_millis(20)));
+            shards[0].insert(Bytes::from("c"), Bytes::from("3").into());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!((keyspace.len(0), keyspace.len(1)), (2, 0));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(keyspace.len(0), 1);
+    }
+
+    #[test]
+    fn test_lock_shards() {
+        let keyspace = Keyspace::new(8, 1);
+        let keys: Vec<Bytes> = (0..64).map(|i| Bytes::from(format!("key{}", i))).collect();
+        {
+            let mut db = keyspace.lock(0, &keys);
+            for key in &keys {
+                db.insert(key.clone(), key.clone().into());
+            }
+        }
+        // 键应该分散到多个分片上
+        let used = keyspace.shards.iter().filter(|shard| !shard.lock().unwrap()[0].entries.is_empty()).count();
+        assert!(used > 1);
+        let mut db = keyspace.lock(0, [&keys[3]]);
+        assert_eq!(db.guards.len(), 1);
+        assert!(db.get_string(&keys[3]).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_snapshot_copy_on_write() {
+        let keyspace = Keyspace::new(4, 1);
+        let key = Bytes::from("list");
+        keyspace.lock(0, [&key]).insert(key.clone(), Value::List(VecDeque::from([Bytes::from("a")])));
+        let snapshot = keyspace.snapshot();
+        // 快照和键空间共用同一份值，修改之后键空间才有自己的副本
+        assert_eq!(Arc::strong_count(&snapshot[0][0].1), 2);
+        match keyspace.lock(0, [&key]).get_mut(&key) {
+            Some(Value::List(list)) => list.push_back(Bytes::from("b")),
+            _ => unreachable!(),
+        }
+        assert_eq!(Arc::strong_count(&snapshot[0][0].1), 1);
+        assert!(matches!(&*snapshot[0][0].1, Value::List(list) if list.len() == 1));
+    }
+
+    #[test]
+    fn test_scan() {
+        let keyspace = Keyspace::new(4, 1);
+        let keys: Vec<Bytes> = (0..200).map(|i| Bytes::from(format!("key{}", i))).collect();
+        for key in &keys {
+            keyspace.lock(0, [key]).insert(key.clone(), key.clone().into());
+        }
+        // 遍历期间删掉一半、再加入一批新键，一直存在的键都要返回
+        let mut seen = std::collections::HashSet::new();
+        let mut cursor = 0;
+        let mut rounds = 0;
+        loop {
+            let (next, batch) = keyspace.scan(0, cursor, 10);
+            seen.extend(batch.into_iter().map(|(key, kind)| {
+                assert_eq!(kind, "string");
+                key
+            }));
+            rounds += 1;
+            if rounds == 5 {
+                for key in keys.iter().step_by(2) {
+                    keyspace.lock(0, [key]).remove(key);
+                }
+                for i in 0..100 {
+                    let key = Bytes::from(format!("new{}", i));
+                    keyspace.lock(0, [&key]).insert(key.clone(), key.clone().into());
+                }
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert!(keys.iter().skip(1).step_by(2).all(|key| seen.contains(key)));
+        assert!(rounds > 5 && rounds < 100);
+    }
+}
@@ -0,0 +1,276 @@
+//! maxmemory和淘汰策略。和Redis一样用近似算法：每次从各个分片随机取几个键，
+//! 按策略挑出最该淘汰的那个删掉，直到内存占用回到限制以内。
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
+use std::time::Instant;
+use bytes::Bytes;
+use resp::Frame;
+use crate::aof::command_frame;
+use crate::server::Server;
+
+/// LFU计数器的初始值，新键不至于马上被淘汰
+const LFU_INIT_VAL: u8 = 5;
+/// 计数器越大越难增长，对应Redis的lfu-log-factor
+const LFU_LOG_FACTOR: f64 = 10.0;
+/// 每过这么多分钟计数器减一，对应Redis的lfu-decay-time
+const LFU_DECAY_MINUTES: u64 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    NoEviction,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysLfu,
+    VolatileLfu,
+    AllKeysRandom,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl Policy {
+    pub fn parse(s: &str) -> Option<Policy> {
+        Some(match s.to_ascii_lowercase().as_str() {
+            "noeviction" => Policy::NoEviction,
+            "allkeys-lru" => Policy::AllKeysLru,
+            "volatile-lru" => Policy::VolatileLru,
+            "allkeys-lfu" => Policy::AllKeysLfu,
+            "volatile-lfu" => Policy::VolatileLfu,
+            "allkeys-random" => Policy::AllKeysRandom,
+            "volatile-random" => Policy::VolatileRandom,
+            "volatile-ttl" => Policy::VolatileTtl,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Policy::NoEviction => "noeviction",
+            Policy::AllKeysLru => "allkeys-lru",
+            Policy::VolatileLru => "volatile-lru",
+            Policy::AllKeysLfu => "allkeys-lfu",
+            Policy::VolatileLfu => "volatile-lfu",
+            Policy::AllKeysRandom => "allkeys-random",
+            Policy::VolatileRandom => "volatile-random",
+            Policy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    /// 是否只淘汰设置了过期时间的键
+    fn volatile(&self) -> bool {
+        matches!(self, Policy::VolatileLru | Policy::VolatileLfu | Policy::VolatileRandom | Policy::VolatileTtl)
+    }
+
+    /// 分数越大越应该被淘汰
+    fn score(&self, access: &Access, expires_at: Option<Instant>) -> u64 {
+        match self {
+            Policy::NoEviction => 0,
+            Policy::AllKeysLru | Policy::VolatileLru => access.idle_millis(),
+            // 频率相同时淘汰更久没访问的
+            Policy::AllKeysLfu | Policy::VolatileLfu => ((255 - access.frequency() as u64) << 48) | access.idle_millis().min((1 << 48) - 1),
+            Policy::AllKeysRandom | Policy::VolatileRandom => random(),
+            Policy::VolatileTtl => {
+                let remaining = expires_at.map_or(u64::MAX, |when| when.saturating_duration_since(Instant::now()).as_millis() as u64);
+                u64::MAX - remaining
+            }
+        }
+    }
+}
+
+/// 每个键的访问信息，LRU用最近一次访问时间，LFU用对数增长、随时间衰减的计数器
+#[derive(Clone, Copy, Debug)]
+pub struct Access {
+    // 进程启动以来的毫秒数
+    lru: u64,
+    counter: u8,
+    // 计数器上次衰减的时间，进程启动以来的分钟数
+    ldt: u64,
+}
+
+impl Access {
+    pub fn new() -> Access {
+        let now = clock_millis();
+        Access { lru: now, counter: LFU_INIT_VAL, ldt: now / 60_000 }
+    }
+
+    pub fn touch(&mut self) {
+        let now = clock_millis();
+        self.counter = self.frequency();
+        self.ldt = now / 60_000;
+        // 计数器越大，增长的概率越小
+        let base = self.counter.saturating_sub(LFU_INIT_VAL) as f64;
+        let p = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+        if self.counter < 255 && (random() as f64 / u64::MAX as f64) < p {
+            self.counter += 1;
+        }
+        self.lru = now;
+    }
+
+    pub fn idle_millis(&self) -> u64 {
+        clock_millis().saturating_sub(self.lru)
+    }
+
+    /// 衰减之后的访问频率
+    pub fn frequency(&self) -> u8 {
+        let periods = (clock_millis() / 60_000).saturating_sub(self.ldt) / LFU_DECAY_MINUTES;
+        self.counter.saturating_sub(periods.min(255) as u8)
+    }
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access::new()
+    }
+}
+
+fn clock_millis() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// 每次调用都换一组随机密钥，拿它的哈希值当随机数，用来取样足够了
+pub fn random() -> u64 {
+    RandomState::new().hash_one(clock_millis())
+}
+
+pub fn oom() -> Frame {
+    Frame::error("OOM command not allowed when used memory > 'maxmemory'.")
+}
+
+/// 内存占用超过maxmemory时按策略淘汰键，淘汰不出足够空间时返回OOM错误。
+/// 每次只锁一个分片，调用时不能持有任何分片锁
+pub fn free_memory(server: &Server) -> Result<(), Frame> {
+    let (maxmemory, policy, samples) = {
+        let config = server.config.read().unwrap();
+        (config.maxmemory as usize, config.maxmemory_policy, config.maxmemory_samples)
+    };
+    if maxmemory == 0 {
+        return Ok(());
+    }
+    let keyspace = &server.db;
+    while keyspace.used_memory() > maxmemory {
+        if policy == Policy::NoEviction {
+            return Err(oom());
+        }
+        // 每个分片各取几个样本，挑分数最高的
+        let best = (0..keyspace.shard_count())
+            .flat_map(|idx| keyspace.sample(idx, samples, policy.volatile()))
+            .map(|(index, key, access, expires_at)| (policy.score(&access, expires_at), index, key))
+            .max_by_key(|(score, _, _)| *score);
+        let (index, key) = match best {
+            Some((_, index, key)) => (index, key),
+            None => return Err(oom()),
+        };
+        evict(server, index, key);
+    }
+    Ok(())
+}
+
+fn evict(server: &Server, index: usize, key: Bytes) {
+    let mut db = server.db.lock(index, [&key]);
+    if db.remove(&key).is_some() {
+        verbose!("Evicted key {:?}", key);
+        server.stats.evicted_keys.fetch_add(1, Ordering::Relaxed);
+        // 淘汰也要写进AOF并发给从库，否则重放之后内存又会超出限制
+        server.propagate(index, &[command_frame("del", &[key])]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use resp::Frame;
+    use crate::cmd::{execute, Command};
+    use crate::config::Config;
+    use crate::evict::{Access, Policy, LFU_INIT_VAL};
+    use crate::server::Server;
+
+    fn run(server: &Server, line: &str) -> Frame {
+        let parts = line.split_whitespace().map(|s| Frame::Bulk(Bytes::from(s.to_string()))).collect();
+        execute(server, 0, &Command::from_frame(Frame::Array(parts)).unwrap())
+    }
+
+    fn server(policy: Policy) -> Server {
+        Server::with_config(Config { shards: 4, maxmemory: 64 * 1024, maxmemory_policy: policy, ..Config::default() })
+    }
+
+    fn fill(server: &Server, prefix: &str) -> usize {
+        let value = "x".repeat(1000);
+        let mut written = 0;
+        for i in 0..200 {
+            if run(server, &format!("SET {}{} {}", prefix, i, value)) == Frame::ok() {
+                written += 1;
+            }
+        }
+        written
+    }
+
+    #[test]
+    fn test_noeviction() {
+        let server = server(Policy::NoEviction);
+        let written = fill(&server, "key");
+        assert!(written > 0 && written < 200);
+        assert!(matches!(run(&server, "SET more v"), Frame::Error(err) if err.starts_with("OOM")));
+        // 删除和读取不受影响
+        assert_eq!(run(&server, "DEL key0"), Frame::Integer(1));
+        assert!(matches!(run(&server, "GET key1"), Frame::Bulk(_)));
+    }
+
+    #[test]
+    fn test_allkeys_lru() {
+        let server = server(Policy::AllKeysLru);
+        assert_eq!(fill(&server, "key"), 200);
+        // 淘汰发生在命令执行之前，最后一条命令写入之后最多超出一个键
+        assert!(server.db.used_memory() < 66 * 1024);
+        assert_eq!(run(&server, "EXISTS key199"), Frame::Integer(1));
+    }
+
+    #[test]
+    fn test_volatile() {
+        let server = server(Policy::VolatileTtl);
+        // 没有设置过期时间的键不会被淘汰
+        assert!(fill(&server, "key") < 200);
+        // 样本数足够多时总是淘汰剩余时间最短的
+        let config = Config { shards: 4, maxmemory: 64 * 1024, maxmemory_policy: Policy::VolatileTtl, maxmemory_samples: 20, ..Config::default() };
+        let server = Server::with_config(config);
+        for i in 0..40 {
+            assert_eq!(run(&server, &format!("SET v{} {} EX {}", i, "x".repeat(1000), 1000 + i)), Frame::ok());
+        }
+        for i in 0..30 {
+            assert_eq!(run(&server, &format!("SET key{} {}", i, "x".repeat(1000))), Frame::ok());
+        }
+        assert_eq!(run(&server, "EXISTS v0"), Frame::Integer(0));
+        assert_eq!(run(&server, "EXISTS v39"), Frame::Integer(1));
+        assert_eq!(run(&server, "EXISTS key0"), Frame::Integer(1));
+    }
+
+    #[test]
+    fn test_lfu_counter() {
+        let mut access = Access::new();
+        assert_eq!(access.frequency(), LFU_INIT_VAL);
+        for _ in 0..10000 {
+            access.touch();
+        }
+        // 对数增长，一万次访问远远到不了上限
+        assert!(access.frequency() > LFU_INIT_VAL && access.frequency() < 255);
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let server = server(Policy::NoEviction);
+        assert_eq!(run(&server, "MEMORY USAGE missing"), Frame::Null);
+        run(&server, "SET small v");
+        run(&server, &format!("SET big {}", "x".repeat(1000)));
+        let usage = |key: &str| match run(&server, &format!("MEMORY USAGE {}", key)) {
+            Frame::Integer(size) => size,
+            other => panic!("{:?}", other),
+        };
+        assert!(usage("big") > usage("small") + 900);
+        run(&server, "RPUSH list a b c");
+        let before = usage("list");
+        run(&server, &format!("RPUSH list {}", vec!["element"; 100].join(" ")));
+        assert!(usage("list") > before);
+    }
+}
@@ -0,0 +1,117 @@
+/// Redis风格的glob匹配：支持`*`、`?`、`[abc]`、`[^a]`、`[a-z]`和`\`转义，
+/// PSUBSCRIBE、KEYS之类的命令都用它
+pub fn matches(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // 最近一个`*`的位置和当时匹配到的字符串位置，失配时回溯到这里
+    let mut backtrack: Option<(usize, usize)> = None;
+    while s < string.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    // 连续的*等价于一个
+                    while p < pattern.len() && pattern[p] == b'*' {
+                        p += 1;
+                    }
+                    if p == pattern.len() {
+                        return true;
+                    }
+                    backtrack = Some((p, s));
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    s += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(pattern, p, string[s]) {
+                        if matched {
+                            p = next;
+                            s += 1;
+                            continue;
+                        }
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == string[s] {
+                        p += 2;
+                        s += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == string[s] {
+                        p += 1;
+                        s += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        // 失配了，让上一个*多吃一个字符再试
+        match backtrack {
+            Some((star_p, star_s)) => {
+                backtrack = Some((star_p, star_s + 1));
+                p = star_p;
+                s = star_s + 1;
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// 匹配从pattern[start]开始的`[...]`，返回是否匹配以及`]`之后的位置
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut p = start + 1;
+    let negate = p < pattern.len() && pattern[p] == b'^';
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    loop {
+        if p >= pattern.len() {
+            // 没有闭合的`[`，和Redis一样当作到结尾为止
+            return Some((matched != negate, p));
+        }
+        match pattern[p] {
+            b']' => return Some((matched != negate, p + 1)),
+            b'\\' if p + 1 < pattern.len() => {
+                matched |= pattern[p + 1] == c;
+                p += 2;
+            }
+            lo if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' => {
+                let hi = pattern[p + 2];
+                let (lo, hi) = if lo <= hi { (lo, hi) } else { (hi, lo) };
+                matched |= lo <= c && c <= hi;
+                p += 3;
+            }
+            other => {
+                matched |= other == c;
+                p += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::glob::matches;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches(b"*", b"anything"));
+        assert!(matches(b"news.*", b"news.sports"));
+        assert!(!matches(b"news.*", b"weather"));
+        assert!(matches(b"h?llo", b"hello"));
+        assert!(matches(b"h[ae]llo", b"hallo"));
+        assert!(!matches(b"h[^e]llo", b"hello"));
+        assert!(matches(b"h[a-b]llo", b"hbllo"));
+        assert!(matches(b"a*b*c", b"aXXbYYc"));
+        assert!(!matches(b"a*b*c", b"aXXbYY"));
+        assert!(matches(b"\\*", b"*"));
+        assert!(!matches(b"\\*", b"a"));
+        assert!(matches(b"", b""));
+        assert!(!matches(b"", b"a"));
+    }
+}
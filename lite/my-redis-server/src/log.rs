@@ -0,0 +1,79 @@
+//! 按loglevel过滤的日志输出，级别和Redis一致：debug < verbose < notice < warning
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Debug,
+    Verbose,
+    Notice,
+    Warning,
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Option<Level> {
+        match s.to_ascii_lowercase().as_str() {
+            "debug" => Some(Level::Debug),
+            "verbose" => Some(Level::Verbose),
+            "notice" => Some(Level::Notice),
+            "warning" => Some(Level::Warning),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Verbose => "verbose",
+            Level::Notice => "notice",
+            Level::Warning => "warning",
+        }
+    }
+
+    /// Redis日志里表示级别的符号
+    fn mark(&self) -> char {
+        match self {
+            Level::Debug => '.',
+            Level::Verbose => '-',
+            Level::Notice => '*',
+            Level::Warning => '#',
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Notice as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 >= LEVEL.load(Ordering::Relaxed)
+}
+
+/// 一般不直接调用，用下面的宏
+pub fn write(level: Level, args: std::fmt::Arguments) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    eprintln!("{}:{}.{:03} {} {}", std::process::id(), now.as_secs(), now.subsec_millis(), level.mark(), args);
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write($level, format_args!($($arg)*));
+        }
+    };
+}
+
+macro_rules! verbose {
+    ($($arg:tt)*) => { log!($crate::log::Level::Verbose, $($arg)*) };
+}
+
+macro_rules! notice {
+    ($($arg:tt)*) => { log!($crate::log::Level::Notice, $($arg)*) };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => { log!($crate::log::Level::Warning, $($arg)*) };
+}
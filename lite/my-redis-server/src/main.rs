@@ -1,50 +1,701 @@
-use std::collections::HashMap;
-use std::fmt::Debug;
-use std::sync::{Arc, Mutex};
-use bytes::Bytes;
-use mini_redis::{Command, Connection, Frame};
-use mini_redis::Command::{Get, Set};
-use tokio::net::{TcpListener, TcpStream};
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use resp::{Connection, Frame};
+use crate::cmd::Command;
+use crate::config::Config;
+use crate::multi::Transaction;
+use crate::server::{Client, ClientKind, Server};
+use crate::tls::{Listener, Stream};
 
-type DB = Arc<Mutex<HashMap<String, Bytes>>>;
+#[macro_use]
+mod log;
+mod acl;
+mod aof;
+mod cluster;
+mod cmd;
+mod config;
+mod db;
+mod evict;
+mod glob;
+mod monitor;
+mod multi;
+mod pubsub;
+mod rdb;
+mod replication;
+mod script;
+mod server;
+mod shutdown;
+mod slowlog;
+mod tls;
+mod value;
 
+/// 用法：my-redis-server [/path/to/redis.conf] [--port 16379] [--maxmemory 100mb] ...
 #[tokio::main]
 async fn main() {
-    let addr = "127.0.0.1:16379";
-    let listener = TcpListener::bind(addr).await.unwrap();
-    println!("server listen on: {}", addr);
-    let db = Arc::new(Mutex::new(HashMap::new()));
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}\n{}", err, config::USAGE);
+            std::process::exit(1);
+        }
+    };
+    log::set_level(config.loglevel);
+    let mut server = Server::with_config(config.clone());
+    // 开启AOF并且日志文件存在时以日志为准，否则加载快照；数据文件损坏时拒绝启动，而不是带着不完整的数据继续运行
+    let aof_exists = std::path::Path::new(&config.appendfilename).exists();
+    let loaded = if config.appendonly && aof_exists {
+        aof::load(&server, &config.appendfilename).map(|count| format!("{} commands replayed from append only file", count))
+    } else {
+        rdb::load(&server.rdb, &server.db).map(|count| format!("DB loaded from disk: {} keys", count))
+    };
+    match loaded {
+        Ok(msg) => notice!("{}", msg),
+        Err(err) => {
+            warning!("Fatal error loading the DB: {}. Exiting.", err);
+            std::process::exit(1);
+        }
+    }
+    // ACL文件不合法时拒绝启动，否则可能以比预期宽松的权限运行
+    if !config.aclfile.is_empty() {
+        if let Err(err) = server.acl.load(&config.aclfile) {
+            warning!("Error loading ACLs from {}: {}. Exiting.", config.aclfile, err);
+            std::process::exit(1);
+        }
+    }
+    if config.appendonly {
+        match aof::Aof::open(&config.appendfilename, config.appendfsync) {
+            Ok(aof) => server.aof = Some(Arc::new(aof)),
+            Err(err) => {
+                warning!("Can't open the append-only file {}: {}", config.appendfilename, err);
+                std::process::exit(1);
+            }
+        }
+        // 刚开启AOF时日志是空的，先用快照里的数据重写一次，否则下次启动会丢掉这些数据
+        if !aof_exists {
+            aof::rewrite(&server).unwrap();
+        }
+    }
+    if config.cluster_enabled {
+        let ip = if config.cluster_announce_ip.is_empty() { &config.bind } else { &config.cluster_announce_ip };
+        match cluster::Cluster::open(&config.cluster_config_file, ip, config.port) {
+            Ok(cluster) => {
+                notice!("Cluster node ID: {}", cluster.myid());
+                server.cluster = Some(cluster);
+            }
+            Err(err) => {
+                warning!("Can't load the cluster config file {}: {}", config.cluster_config_file, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut listeners = Vec::new();
+    // 开启了TLS时port为0表示只接受TLS连接
+    if config.port != 0 || config.tls_port == 0 {
+        listeners.push(Listener::new(bind(&config.bind, config.port).await, None));
+    }
+    if config.tls_port != 0 {
+        match tls::acceptor(&config) {
+            Ok(acceptor) => listeners.push(Listener::new(bind(&config.bind, config.tls_port).await, Some(acceptor))),
+            Err(err) => {
+                warning!("Failed to configure TLS: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    notice!("Ready to accept connections on {} port {} tls-port {}, shards: {}", config.bind, config.port, config.tls_port, config.shards);
+    let server = Arc::new(server);
+    tokio::spawn(shutdown::listen_signals(server.clone()));
+    if let Err(err) = run(listeners, server).await {
+        warning!("Error trying to save the DB, exiting anyway: {}", err);
+        std::process::exit(1);
+    }
+    notice!("my-redis-server is now ready to exit, bye bye...");
+}
+
+async fn bind(host: &str, port: u16) -> TcpListener {
+    match TcpListener::bind((host, port)).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            warning!("Could not create server TCP listening socket {}:{}: {}", host, port, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// 在所有端口上接受连接直到发起关闭，然后等所有连接断开、数据落盘之后返回
+async fn run(listeners: Vec<Listener>, server: Arc<Server>) -> io::Result<()> {
+    // 每个分片一个后台任务定期清理过期的键
+    for idx in 0..server.db.shard_count() {
+        tokio::spawn(db::purge_expired_keys(server.db.clone(), idx));
+    }
+    tokio::spawn(rdb::auto_save(server.clone()));
+    if let Some(aof) = server.aof.clone() {
+        tokio::spawn(aof::fsync_every_second(aof));
+    }
+    tokio::spawn(replication::ping_replicas(server.clone()));
+    if server.cluster.is_some() {
+        tokio::spawn(cluster::gossip(server.clone()));
+    }
+    let replicaof = server.config.read().unwrap().replicaof.clone();
+    if let Some(master) = replicaof {
+        replication::set_master(&server, Some(master));
+    }
+    // 每个连接持有一个发送端，全部断开之后接收端才会收到None
+    let (done_sender, mut done) = mpsc::channel::<()>(1);
     loop {
-        let (socket, _) = listener.accept().await.unwrap();
+        let accepted = tokio::select! {
+            accepted = tls::accept(&listeners) => accepted,
+            _ = server.shutdown.wait() => break,
+        };
+        let (socket, addr, acceptor) = match accepted {
+            Ok(conn) => conn,
+            Err(err) => {
+                // 比如文件描述符用完了，等一会儿再试，不能让整个服务端退出
+                warning!("Error accepting client connection: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
         // 实现多个连接之间共享数据
-        let db0 = db.clone();
+        let server0 = server.clone();
+        let done_sender = done_sender.clone();
         tokio::spawn(async move {
-            process(socket, db0).await;
+            let stream = match tls::handshake(socket, acceptor).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    verbose!("Error accepting a client connection from {}: {}", addr, err);
+                    return;
+                }
+            };
+            let _permit = match server0.clients.try_acquire() {
+                Some(permit) => permit,
+                None => {
+                    server0.stats.rejected_connections.fetch_add(1, Ordering::Relaxed);
+                    let mut connection = Connection::new(stream);
+                    let _ = connection.write_frame(&Frame::error("ERR max number of clients reached")).await;
+                    return;
+                }
+            };
+            server0.stats.connections.fetch_add(1, Ordering::Relaxed);
+            let registration = server0.clients.register(addr);
+            let client = &registration.client;
+            verbose!("Accepted {}", addr);
+            // 被CLIENT KILL时直接丢掉连接，正在等待的读写一起取消
+            let res = tokio::select! {
+                res = process(stream, client, server0.clone()) => res,
+                _ = client.killed() => Err(resp::Error::Io(io::Error::other("killed by CLIENT KILL"))),
+            };
+            match res {
+                Ok(()) => verbose!("Client closed connection {}", addr),
+                Err(err) => verbose!("Closing client {}: {}", addr, err),
+            }
+            drop(done_sender);
         });
     }
+    drop(listeners);
+    drop(done_sender);
+    // 连接在读下一条命令之前发现要关闭就会断开，正在执行的命令不受影响
+    if tokio::time::timeout(shutdown::GRACE_PERIOD, done.recv()).await.is_err() {
+        warning!("{} clients did not close in time, closing anyway", server.clients.count());
+    }
+    let mode = server.shutdown.mode().unwrap_or(shutdown::Mode::Default);
+    let server0 = server.clone();
+    tokio::task::spawn_blocking(move || shutdown::persist(&server0, mode)).await?
 }
 
-async fn process(socket: TcpStream, db: DB) {
-    let mut connection = Connection::new(socket);
-    while let Some(frame) = connection.read_frame().await.unwrap() {
-        let response = match Command::from_frame(frame).unwrap() {
-            Set(cmd) => {
-                let mut database = db.lock().unwrap();
-                database.insert(cmd.key().to_string(), cmd.value().clone());
-                Frame::Simple("OK".to_string())
-            }
-            Get(cmd) => {
-                let database = db.lock().unwrap();
-                if let Some(val) = database.get(cmd.key()) {
-                    Frame::Bulk(val.clone())
-                } else {
-                    Frame::Null
+/// 处理一个连接上的所有请求。连接正常关闭时返回Ok，读写出错、协议错误或者空闲超时返回Err，由调用方记录
+async fn process(stream: Stream, client: &Client, server: Arc<Server>) -> resp::Result<()> {
+    let mut connection = Connection::new(stream);
+    let mut transaction = Transaction::new(server.db.clone());
+    // 从库用REPLCONF告诉我们的监听端口
+    let mut listening_port = 0;
+    // 集群模式下ASKING只对紧接着的一条命令有效
+    let mut asking = false;
+    // SELECT选择的数据库
+    let mut db = 0;
+    // 默认用户不需要密码时不用认证
+    if server.acl.default_nopass() {
+        client.set_user(Some("default".to_string()));
+    }
+    loop {
+        let (timeout, limit, slower_than, slowlog_len) = {
+            let config = server.config.read().unwrap();
+            (config.timeout, config.client_query_buffer_limit, config.slowlog_log_slower_than, config.slowlog_max_len)
+        };
+        connection.set_max_frame_size(limit as usize);
+        let request = tokio::select! {
+            request = read_request(&mut connection, timeout) => request,
+            _ = server.shutdown.wait() => return Ok(()),
+        };
+        let frame = match request {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(()),
+            // 和Redis一样，协议错误时先把原因告诉客户端再断开，之后的数据已经没法正确分帧了
+            Err(resp::Error::Protocol(msg)) => {
+                let _ = connection.write_frame(&Frame::error(format!("ERR Protocol error: {}", msg))).await;
+                return Err(resp::Error::Protocol(msg));
+            }
+            Err(err) => return Err(err),
+        };
+        let asked = std::mem::take(&mut asking);
+        // 没有认证或者没有权限时拒绝执行，集群模式下键不归本节点负责时直接回复重定向
+        let request = Command::from_frame(frame)
+            .and_then(|cmd| acl::authorize(&server.acl, client.user().as_deref(), &cmd).map(|_| cmd))
+            .and_then(|cmd| cluster::route(&server, &cmd, asked).map(|_| cmd));
+        if let Ok(cmd) = &request {
+            server.monitors.feed(client, cmd);
+        }
+        let start = Instant::now();
+        let response = match &request {
+            Ok(cmd) if cmd.name == "quit" => {
+                connection.write_frame(&Frame::ok()).await?;
+                return Ok(());
+            }
+            Ok(cmd) if cmd.name == "asking" => match &server.cluster {
+                Some(_) => {
+                    asking = true;
+                    Frame::ok()
                 }
+                None => Frame::error("ERR This instance has cluster support disabled"),
+            },
+            // 事务中的命令只排队，EXEC时再一起执行
+            Ok(cmd) if transaction.is_active() || multi::is_transaction_command(&cmd.name) => {
+                transaction.handle(&server, db, cmd.clone())
+            }
+            // 订阅相关的命令会让连接进入订阅模式，直到退订所有频道才回来
+            Ok(cmd) if pubsub::is_subscribe_command(&cmd.name) => {
+                match cmd::check(cmd) {
+                    Ok(_) => {
+                        client.set_kind(ClientKind::PubSub);
+                        if !pubsub::subscribe(&server, &mut connection, client, cmd.clone()).await? {
+                            return Ok(());
+                        }
+                        client.set_kind(ClientKind::Normal);
+                        continue;
+                    }
+                    Err(err) => err,
+                }
+            }
+            // 之后这个连接只用来接收监视到的命令
+            Ok(cmd) if cmd.name == "monitor" => match cmd::check(cmd) {
+                Ok(_) => return monitor::run(&server, &mut connection, client).await,
+                Err(err) => err,
+            },
+            Ok(cmd) if cmd.name == "select" => match cmd::select(&server, &cmd.args) {
+                Ok(index) => {
+                    db = index;
+                    client.set_db(index);
+                    Frame::ok()
+                }
+                Err(err) => err,
+            },
+            Ok(cmd) if cmd.name == "client" => match cmd::check(cmd) {
+                Ok(_) => cmd::client(&server, client, &cmd.args).unwrap_or_else(|err| err),
+                Err(err) => err,
+            },
+            // 成功时和Redis一样不回复，直接断开
+            Ok(cmd) if cmd.name == "shutdown" => match cmd::shutdown(&server, &cmd.args) {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            },
+            Ok(cmd) if cmd.name == "migrate" => match cmd::check(cmd) {
+                Ok(_) => cluster::migrate(&server, db, &cmd.args).await,
+                Err(err) => err,
+            },
+            Ok(cmd) if cmd.name == "replicaof" => match cmd::check(cmd) {
+                Ok(_) => cmd::replicaof(&server, &cmd.args),
+                Err(err) => err,
+            },
+            Ok(cmd) if cmd.name == "replconf" => cmd::replconf(&cmd.args, &mut listening_port),
+            // 之后这个连接只用来给从库发送复制流
+            Ok(cmd) if cmd.name == "psync" => match cmd::check(cmd) {
+                Ok(_) => {
+                    client.set_kind(ClientKind::Replica);
+                    return replication::serve(&server, &mut connection, &cmd.args, client.addr.ip(), listening_port).await;
+                }
+                Err(err) => err,
+            },
+            Ok(cmd) if cmd.name == "auth" => match cmd::check(cmd) {
+                Ok(_) => cmd::auth(&server, client, &cmd.args).unwrap_or_else(|err| err),
+                Err(err) => err,
+            },
+            Ok(cmd) if cmd.name == "acl" => match cmd::check(cmd) {
+                Ok(_) => cmd::acl(&server, client, &cmd.args).unwrap_or_else(|err| err),
+                Err(err) => err,
+            },
+            // 脚本执行期间一直持有声明的键所在的分片锁，其他连接看不到执行到一半的状态
+            Ok(cmd) if cmd.name == "eval" || cmd.name == "evalsha" => match cmd::check(cmd) {
+                Ok(_) if cmd.name == "eval" => cmd::eval(&server, client, &cmd.args).await.unwrap_or_else(|err| err),
+                Ok(_) => cmd::evalsha(&server, client, &cmd.args).await.unwrap_or_else(|err| err),
+                Err(err) => err,
+            },
+            Ok(cmd) if cmd.name == "hello" => match cmd::hello(&server, client, &cmd.args, connection.protocol()) {
+                Ok((protocol, info)) => {
+                    connection.set_protocol(protocol);
+                    info
+                }
+                Err(err) => err,
+            },
+            Ok(cmd) => cmd::execute(&server, db, cmd),
+            // 事务中出错时EXEC会放弃整个事务
+            Err(err) if transaction.is_active() => transaction.queue_error(err.clone()),
+            // 不是合法的命令格式或者需要重定向，直接把错误回给客户端
+            Err(err) => err.clone(),
+        };
+        if let Ok(cmd) = &request {
+            server.stats.commands.fetch_add(1, Ordering::Relaxed);
+            client.record(&cmd.name, transaction.is_active());
+            server.slowlog.record(slower_than, slowlog_len, client, cmd, start.elapsed());
+        }
+        connection.write_frame(&response).await?;
+    }
+}
+
+/// 读取下一个请求，timeout秒内没有收到完整的请求就超时，0表示不限制
+async fn read_request(connection: &mut Connection<Stream>, timeout: u64) -> resp::Result<Option<Frame>> {
+    if timeout == 0 {
+        return connection.read_request().await;
+    }
+    match tokio::time::timeout(Duration::from_secs(timeout), connection.read_request()).await {
+        Ok(res) => res,
+        Err(_) => Err(resp::Error::Io(io::Error::new(io::ErrorKind::TimedOut, "idle timeout"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use bytes::Bytes;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use resp::{Connection, Frame, Protocol};
+    use crate::config::Config;
+    use crate::db::Keyspace;
+    use crate::rdb::{self, Rdb};
+    use crate::server::Server;
+    use crate::tls::Listener;
+
+    /// 在随机端口上启动一个服务端，测试用
+    pub(crate) async fn start_server() -> SocketAddr {
+        start_server_with(Server::new()).await
+    }
+
+    pub(crate) async fn start_server_with(server: Server) -> SocketAddr {
+        start_shared_server(Arc::new(server)).await
+    }
+
+    /// 测试里还要直接检查服务端的状态时用
+    pub(crate) async fn start_shared_server(server: Arc<Server>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(crate::run(vec![Listener::new(listener, None)], server));
+        addr
+    }
+
+    fn command(parts: &[&str]) -> Frame {
+        Frame::Array(parts.iter().map(|s| Frame::Bulk(Bytes::from(s.to_string()))).collect())
+    }
+
+    #[tokio::test]
+    async fn test_hello() {
+        let addr = start_server().await;
+        let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+        connection.write_frame(&command(&["HELLO", "4"])).await.unwrap();
+        assert!(matches!(connection.read_frame().await.unwrap().unwrap(), Frame::Error(err) if err.starts_with("NOPROTO")));
+        connection.write_frame(&command(&["HELLO", "3"])).await.unwrap();
+        match connection.read_frame().await.unwrap().unwrap() {
+            Frame::Map(fields) => assert!(fields.contains(&(Frame::Bulk(Bytes::from("proto")), Frame::Integer(3)))),
+            other => panic!("{:?}", other),
+        }
+        connection.set_protocol(Protocol::Resp3);
+        connection.write_frame(&command(&["HSET", "h", "f", "v"])).await.unwrap();
+        assert_eq!(connection.read_frame().await.unwrap().unwrap(), Frame::Integer(1));
+        connection.write_frame(&command(&["HGETALL", "h"])).await.unwrap();
+        assert_eq!(connection.read_frame().await.unwrap().unwrap(), Frame::Map(vec![
+            (Frame::Bulk(Bytes::from("f")), Frame::Bulk(Bytes::from("v"))),
+        ]));
+    }
+
+    #[tokio::test]
+    async fn test_inline() {
+        let addr = start_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"SET k v\r\nGET  k\r\nHGETALL missing\n").await.unwrap();
+        let expected = b"+OK\r\n$1\r\nv\r\n*0\r\n";
+        let mut buf = vec![0; expected.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected);
+    }
+
+    /// 发送一段原始字节，读取服务端的回复直到连接关闭
+    async fn send_raw(addr: SocketAddr, data: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(data).await.unwrap();
+        // 服务端断开时可能还有没读完的数据，连接会被重置，读到出错为止
+        let mut buf = Vec::new();
+        let _ = stream.read_to_end(&mut buf).await;
+        String::from_utf8_lossy(&buf).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_garbage() {
+        let addr = start_server().await;
+        // 不认识的内联命令只是回复错误，连接保持
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"\x00\xff\xfe junk\r\nPING\r\n").await.unwrap();
+        let mut buf = [0; 256];
+        let mut received = String::new();
+        while !received.ends_with("+PONG\r\n") {
+            let len = stream.read(&mut buf).await.unwrap();
+            received.push_str(&String::from_utf8_lossy(&buf[..len]));
+        }
+        assert!(received.starts_with("-ERR unknown command"));
+        // 协议错误回复原因之后断开
+        for garbage in [&b"*abc\r\n"[..], b"*1\r\n$-5\r\n", b"*1\r\n$3\r\nGETxx\r\n", b"*1\r\n?x\r\n"] {
+            let reply = send_raw(addr, garbage).await;
+            assert!(reply.starts_with("-ERR Protocol error"), "{:?}", reply);
+        }
+        // 服务端没有受影响
+        assert_eq!(send_raw(addr, b"PING\r\nQUIT\r\n").await, "+PONG\r\n+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let config = Config { timeout: 1, client_query_buffer_limit: 1024, ..Config::default() };
+        let addr = start_server_with(Server::with_config(config)).await;
+        let big = format!("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4096\r\n{}\r\n", "x".repeat(4096));
+        assert!(send_raw(addr, big.as_bytes()).await.starts_with("-ERR Protocol error: frame exceeds"));
+        // 空闲超过timeout秒后被断开
+        let started = Instant::now();
+        assert_eq!(send_raw(addr, b"PING\r\n").await, "+PONG\r\n");
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let path = std::env::temp_dir().join(format!("my-redis-shutdown-{}.rdb", std::process::id()));
+        let config = Config { dbfilename: path.to_string_lossy().to_string(), save: vec![], ..Config::default() };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(crate::run(vec![Listener::new(listener, None)], Arc::new(Server::with_config(config))));
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(send_raw(addr, b"SET k v\r\nSHUTDOWN FORCE\r\nQUIT\r\n").await, "+OK\r\n-ERR syntax error\r\n+OK\r\n");
+        // 成功时不回复，直接断开
+        assert_eq!(send_raw(addr, b"SHUTDOWN SAVE\r\n").await, "");
+        tokio::time::timeout(Duration::from_secs(5), handle).await.unwrap().unwrap().unwrap();
+        // 空闲的连接被断开，不再接受新连接，数据已经落盘
+        assert_eq!(idle.read(&mut [0; 16]).await.unwrap(), 0);
+        assert!(TcpStream::connect(addr).await.is_err());
+        let keyspace = Keyspace::new(4, 1);
+        assert_eq!(rdb::load(&Rdb::new(&path), &keyspace).unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_maxclients() {
+        let addr = start_server_with(Server::with_config(Config { maxclients: 1, ..Config::default() })).await;
+        let mut first = Connection::new(TcpStream::connect(addr).await.unwrap());
+        first.write_frame(&command(&["PING"])).await.unwrap();
+        assert_eq!(first.read_frame().await.unwrap().unwrap(), Frame::Simple("PONG".to_string()));
+        assert_eq!(send_raw(addr, b"PING\r\n").await, "-ERR max number of clients reached\r\n");
+        first.write_frame(&command(&["CONFIG", "SET", "maxclients", "2"])).await.unwrap();
+        assert_eq!(first.read_frame().await.unwrap().unwrap(), Frame::ok());
+        assert_eq!(send_raw(addr, b"PING\r\nQUIT\r\n").await, "+PONG\r\n+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_introspection() {
+        let addr = start_server().await;
+        let mut first = Connection::new(TcpStream::connect(addr).await.unwrap());
+        // 参数个数不对时报错，连接还能继续用
+        first.write_frame(&command(&["MONITOR", "x"])).await.unwrap();
+        assert_eq!(first.read_frame().await.unwrap().unwrap(), Frame::error("ERR wrong number of arguments for 'monitor' command"));
+        first.write_frame(&command(&["CLIENT"])).await.unwrap();
+        assert_eq!(first.read_frame().await.unwrap().unwrap(), Frame::error("ERR wrong number of arguments for 'client' command"));
+        let mut monitor = Connection::new(TcpStream::connect(addr).await.unwrap());
+        monitor.write_frame(&command(&["MONITOR"])).await.unwrap();
+        assert_eq!(monitor.read_frame().await.unwrap().unwrap(), Frame::ok());
+        first.write_frame(&command(&["CLIENT", "SETNAME", "first"])).await.unwrap();
+        assert_eq!(first.read_frame().await.unwrap().unwrap(), Frame::ok());
+        first.write_frame(&command(&["SET", "k", "a b"])).await.unwrap();
+        first.read_frame().await.unwrap();
+        monitor.read_frame().await.unwrap();
+        match monitor.read_frame().await.unwrap().unwrap() {
+            Frame::Simple(line) => assert!(line.ends_with(r#"] "set" "k" "a b""#), "{}", line),
+            other => panic!("{:?}", other),
+        }
+
+        first.write_frame(&command(&["CLIENT", "LIST"])).await.unwrap();
+        let list = match first.read_frame().await.unwrap().unwrap() {
+            Frame::Bulk(list) => String::from_utf8(list.to_vec()).unwrap(),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(list.lines().count(), 2);
+        assert!(list.lines().any(|line| line.contains(" name=first ") && line.contains(" flags=N ")));
+        assert!(list.lines().any(|line| line.contains(" flags=O ")));
+        first.write_frame(&command(&["INFO", "keyspace", "stats"])).await.unwrap();
+        let info = match first.read_frame().await.unwrap().unwrap() {
+            Frame::Bulk(info) => String::from_utf8(info.to_vec()).unwrap(),
+            other => panic!("{:?}", other),
+        };
+        assert!(info.starts_with("# Stats\r\n") && info.contains("db0:keys=1,expires=0"), "{}", info);
+
+        // 断开监视者之后它从列表里消失
+        first.write_frame(&command(&["CLIENT", "KILL", "TYPE", "monitor"])).await.unwrap();
+        assert_eq!(first.read_frame().await.unwrap().unwrap(), Frame::Integer(1));
+        while let Ok(Some(_)) = monitor.read_frame().await {}
+        // 连接关闭之后才从列表里删掉，可能要等一会儿
+        for _ in 0..50 {
+            first.write_frame(&command(&["CLIENT", "LIST", "TYPE", "monitor"])).await.unwrap();
+            if first.read_frame().await.unwrap().unwrap() == Frame::Bulk(Bytes::new()) {
+                return;
             }
-            cmd=> {
-                panic!("todo implement")
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("monitor is still listed");
+    }
+
+    #[tokio::test]
+    async fn test_databases() {
+        let addr = start_server().await;
+        let mut first = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let mut second = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let request = async |connection: &mut Connection, parts: &[&str]| {
+            connection.write_frame(&command(parts)).await.unwrap();
+            connection.read_frame().await.unwrap().unwrap()
+        };
+        let bulk = |s: &str| Frame::Bulk(Bytes::from(s.to_string()));
+        assert_eq!(request(&mut first, &["SET", "k", "0"]).await, Frame::ok());
+        assert_eq!(request(&mut first, &["SELECT", "1"]).await, Frame::ok());
+        assert_eq!(request(&mut first, &["GET", "k"]).await, Frame::Null);
+        assert_eq!(request(&mut first, &["SET", "k", "1"]).await, Frame::ok());
+        assert_eq!(request(&mut first, &["SELECT", "16"]).await, Frame::error("ERR DB index is out of range"));
+        assert_eq!(request(&mut second, &["GET", "k"]).await, bulk("0"));
+
+        // 目标数据库里已经有这个键时不移动
+        assert_eq!(request(&mut first, &["MOVE", "k", "0"]).await, Frame::Integer(0));
+        assert_eq!(request(&mut first, &["SET", "m", "v", "EX", "100"]).await, Frame::ok());
+        assert_eq!(request(&mut first, &["MOVE", "m", "2"]).await, Frame::Integer(1));
+        assert_eq!(request(&mut first, &["MOVE", "m", "1"]).await, Frame::error("ERR source and destination objects are the same"));
+        assert_eq!(request(&mut first, &["EXISTS", "m"]).await, Frame::Integer(0));
+
+        // SWAPDB之后已经选择了0号数据库的连接马上看到1号数据库原来的内容
+        assert_eq!(request(&mut first, &["SWAPDB", "0", "1"]).await, Frame::ok());
+        assert_eq!(request(&mut second, &["GET", "k"]).await, bulk("1"));
+        assert_eq!(request(&mut second, &["SELECT", "2"]).await, Frame::ok());
+        assert!(matches!(request(&mut second, &["TTL", "m"]).await, Frame::Integer(ttl) if ttl > 90));
+        assert_eq!(request(&mut second, &["FLUSHDB", "ASYNC"]).await, Frame::ok());
+        assert_eq!(request(&mut second, &["DBSIZE"]).await, Frame::Integer(0));
+        assert_eq!(request(&mut first, &["DBSIZE"]).await, Frame::Integer(1));
+        assert_eq!(request(&mut first, &["FLUSHALL"]).await, Frame::ok());
+        assert_eq!(request(&mut first, &["SELECT", "0"]).await, Frame::ok());
+        assert_eq!(request(&mut first, &["DBSIZE"]).await, Frame::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let addr = start_server_with(Server::with_config(Config { requirepass: "secret".to_string(), ..Config::default() })).await;
+        let mut first = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let request = async |connection: &mut Connection, parts: &[&str]| {
+            connection.write_frame(&command(parts)).await.unwrap();
+            connection.read_frame().await.unwrap().unwrap()
+        };
+        assert_eq!(request(&mut first, &["GET", "k"]).await, Frame::error("NOAUTH Authentication required."));
+        // 参数个数不对也不能绕过认证
+        for parts in [&["MONITOR", "x"][..], &["PSYNC", "?", "-1", "x"], &["REPLICAOF"], &["REPLICAOF", "127.0.0.1", "1", "x"]] {
+            assert_eq!(request(&mut first, parts).await, Frame::error("NOAUTH Authentication required."), "{:?}", parts);
+        }
+        assert!(matches!(request(&mut first, &["HELLO", "3"]).await, Frame::Error(err) if err.starts_with("NOAUTH")));
+        assert!(matches!(request(&mut first, &["AUTH", "wrong"]).await, Frame::Error(err) if err.starts_with("WRONGPASS")));
+        assert_eq!(request(&mut first, &["AUTH", "secret"]).await, Frame::ok());
+        assert_eq!(request(&mut first, &["SET", "cache:1", "v"]).await, Frame::ok());
+        assert_eq!(request(&mut first, &["ACL", "SETUSER", "reader", "on", ">pw", "~cache:*", "+@read", "+@transaction"]).await, Frame::ok());
+
+        let mut second = Connection::new(TcpStream::connect(addr).await.unwrap());
+        assert!(matches!(request(&mut second, &["HELLO", "2", "AUTH", "reader", "pw"]).await, Frame::Array(_)));
+        assert_eq!(request(&mut second, &["GET", "cache:1"]).await, Frame::Bulk(Bytes::from("v")));
+        assert_eq!(request(&mut second, &["GET", "other"]).await, Frame::error("NOPERM No permissions to access a key"));
+        assert_eq!(request(&mut second, &["ACL", "WHOAMI"]).await, Frame::error("NOPERM User reader has no permissions to run the 'acl' command"));
+        // 事务中没有权限的命令让整个事务失败
+        assert_eq!(request(&mut second, &["MULTI"]).await, Frame::ok());
+        assert_eq!(request(&mut second, &["SET", "cache:1", "w"]).await, Frame::error("NOPERM User reader has no permissions to run the 'set' command"));
+        assert!(matches!(request(&mut second, &["EXEC"]).await, Frame::Error(err) if err.starts_with("EXECABORT")));
+
+        // 删掉用户之后用它认证的连接被断开
+        assert_eq!(request(&mut first, &["ACL", "DELUSER", "reader"]).await, Frame::Integer(1));
+        assert!(matches!(second.read_frame().await, Ok(None) | Err(_)));
+        // 慢查询日志里看不到密码
+        assert_eq!(request(&mut first, &["CONFIG", "SET", "slowlog-log-slower-than", "0"]).await, Frame::ok());
+        assert_eq!(request(&mut first, &["AUTH", "default", "secret"]).await, Frame::ok());
+        match request(&mut first, &["SLOWLOG", "GET", "1"]).await {
+            Frame::Array(entries) => assert!(format!("{:?}", entries).contains("(redacted)")),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_eval() {
+        let addr = start_server().await;
+        let request = async |connection: &mut Connection, parts: &[&str]| {
+            connection.write_frame(&command(parts)).await.unwrap();
+            connection.read_frame().await.unwrap().unwrap()
+        };
+        // 先读后写，多个连接同时执行也不会丢失更新。测试用的是单线程运行时，等待脚本时不能阻塞唯一的工作线程
+        let script = r#"
+            let v = redis.command("GET", KEYS[0]);
+            let n = if type_of(v) == "()" { 0 } else { parse_int(v) } + parse_int(ARGV[0]);
+            redis.command("SET", KEYS[0], n);
+            n
+        "#;
+        let tasks: Vec<_> = (0..4).map(|_| tokio::spawn(async move {
+            let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+            for _ in 0..50 {
+                let reply = request(&mut connection, &["EVAL", script, "1", "counter", "2"]).await;
+                assert!(matches!(reply, Frame::Integer(_)), "{:?}", reply);
             }
+        })).collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+        assert_eq!(request(&mut connection, &["GET", "counter"]).await, Frame::Bulk(Bytes::from("400")));
+
+        let sha = match request(&mut connection, &["SCRIPT", "LOAD", "[KEYS[0], ARGV[0], 1.5, true, false]"]).await {
+            Frame::Bulk(sha) => String::from_utf8(sha.to_vec()).unwrap(),
+            other => panic!("{:?}", other),
         };
-        connection.write_frame(&response).await.unwrap();
+        assert_eq!(request(&mut connection, &["SCRIPT", "EXISTS", &sha, "ffff"]).await, Frame::Array(vec![Frame::Integer(1), Frame::Integer(0)]));
+        assert_eq!(request(&mut connection, &["EVALSHA", &sha, "1", "k", "a"]).await, Frame::Array(vec![
+            Frame::Bulk(Bytes::from("k")), Frame::Bulk(Bytes::from("a")), Frame::Integer(1), Frame::Integer(1), Frame::Null,
+        ]));
+        assert_eq!(request(&mut connection, &["SCRIPT", "FLUSH"]).await, Frame::ok());
+        assert_eq!(request(&mut connection, &["EVALSHA", &sha, "0"]).await, Frame::error("NOSCRIPT No matching script. Please use EVAL."));
+        assert_eq!(request(&mut connection, &["EVAL", "1", "2", "k"]).await, Frame::error("ERR Number of keys can't be greater than number of args"));
+        assert!(matches!(request(&mut connection, &["EVAL", "let", "0"]).await, Frame::Error(err) if err.starts_with("ERR Error compiling script")));
+
+        // 只能访问声明过的键，redis.command出错时中止脚本，redis.try_command把错误返回给脚本
+        assert_eq!(request(&mut connection, &["EVAL", r#"redis.command("GET", "other")"#, "0"]).await,
+            Frame::error("ERR Script attempted to access a key that was not declared in the keys argument"));
+        assert_eq!(request(&mut connection, &["EVAL", r#"redis.command("INCR", KEYS[0]); 1"#, "1", "h"]).await, Frame::Integer(1));
+        assert_eq!(request(&mut connection, &["EVAL", r#"redis.command("SUBSCRIBE", "c")"#, "0"]).await, Frame::error("ERR This Redis command is not allowed from script"));
+        assert_eq!(request(&mut connection, &["HSET", "h2", "f", "v"]).await, Frame::Integer(1));
+        assert!(matches!(request(&mut connection, &["EVAL", r#"redis.command("INCR", KEYS[0]); 1"#, "1", "h2"]).await, Frame::Error(err) if err.starts_with("WRONGTYPE")));
+        assert_eq!(request(&mut connection, &["EVAL", r#"let r = redis.try_command("INCR", KEYS[0]); if "err" in r { #{ok: "handled"} } else { r }"#, "1", "h2"]).await,
+            Frame::Simple("handled".to_string()));
+        assert!(matches!(request(&mut connection, &["EVAL", r#"throw "boom""#, "0"]).await, Frame::Error(err) if err.starts_with("ERR Error running script") && err.contains("boom")));
+
+        // 超时的脚本被中止，之前的写入保留，之后其他命令可以继续访问这个键
+        assert_eq!(request(&mut connection, &["CONFIG", "SET", "script-time-limit", "100"]).await, Frame::ok());
+        let started = Instant::now();
+        assert_eq!(request(&mut connection, &["EVAL", r#"redis.command("SET", KEYS[0], "before"); loop {}"#, "1", "slow"]).await,
+            Frame::error("ERR Script killed after exceeding the time limit of 100 milliseconds"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(request(&mut connection, &["GET", "slow"]).await, Frame::Bulk(Bytes::from("before")));
     }
-}
\ No newline at end of file
+}
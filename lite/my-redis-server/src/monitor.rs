@@ -0,0 +1,96 @@
+//! MONITOR：把服务端收到的每条命令实时转发给所有执行过MONITOR的连接，
+//! 格式和Redis一样：`<unix时间戳.微秒> [<数据库编号> <客户端地址>] "命令" "参数" ...`。
+
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use resp::{Connection, Frame};
+use crate::acl;
+use crate::cmd::Command;
+use crate::server::{Client, ClientKind, Server};
+use crate::tls::Stream;
+
+/// 监视者落后太多时丢掉最旧的行
+const CHANNEL_CAPACITY: usize = 4096;
+
+pub struct Monitors {
+    sender: broadcast::Sender<String>,
+}
+
+impl Monitors {
+    pub fn new() -> Monitors {
+        Monitors { sender: broadcast::channel(CHANNEL_CAPACITY).0 }
+    }
+
+    /// 每条命令执行之前调用，没有监视者时什么都不做
+    pub fn feed(&self, client: &Client, cmd: &Command) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut line = format!("{}.{:06} [{} {}] ", now.as_secs(), now.subsec_micros(), client.db(), client.addr);
+        repr(&mut line, cmd.name.as_bytes());
+        // 参数里可能有密码
+        if acl::hides_args(&cmd.name) {
+            line.push_str(" \"(redacted)\"");
+        } else {
+            for arg in &cmd.args {
+                line.push(' ');
+                repr(&mut line, arg);
+            }
+        }
+        let _ = self.sender.send(line);
+    }
+}
+
+impl Default for Monitors {
+    fn default() -> Self {
+        Monitors::new()
+    }
+}
+
+/// 用双引号括起来，不可打印的字节转义，和Redis的sdscatrepr一致
+fn repr(line: &mut String, arg: &[u8]) {
+    line.push('"');
+    for &byte in arg {
+        match byte {
+            b'\\' => line.push_str("\\\\"),
+            b'"' => line.push_str("\\\""),
+            b'\n' => line.push_str("\\n"),
+            b'\r' => line.push_str("\\r"),
+            b'\t' => line.push_str("\\t"),
+            0x07 => line.push_str("\\a"),
+            0x08 => line.push_str("\\b"),
+            0x20..=0x7e => line.push(byte as char),
+            _ => {
+                let _ = write!(line, "\\x{:02x}", byte);
+            }
+        }
+    }
+    line.push('"');
+}
+
+/// 连接进入监视模式，由process调用。之后只接受QUIT，其他命令都忽略，直到连接关闭
+pub async fn run(server: &Server, connection: &mut Connection<Stream>, client: &Client) -> resp::Result<()> {
+    let mut lines = server.monitors.sender.subscribe();
+    client.set_kind(ClientKind::Monitor);
+    connection.write_frame(&Frame::ok()).await?;
+    loop {
+        tokio::select! {
+            line = lines.recv() => match line {
+                Ok(line) => connection.write_frame(&Frame::Simple(line)).await?,
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            request = connection.read_request() => match request?.map(Command::from_frame) {
+                None => return Ok(()),
+                Some(Ok(cmd)) if cmd.name == "quit" => {
+                    connection.write_frame(&Frame::ok()).await?;
+                    return Ok(());
+                }
+                Some(_) => {}
+            },
+            _ = server.shutdown.wait() => return Ok(()),
+        }
+    }
+}
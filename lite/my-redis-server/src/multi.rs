@@ -0,0 +1,267 @@
+//! 事务：MULTI之后的命令先在连接上排队，EXEC时锁住所有涉及的分片再依次执行，
+//! 其他连接看不到执行到一半的状态。WATCH是乐观锁，被监视的键在EXEC之前被修改过时事务不执行。
+
+use std::sync::Arc;
+use bytes::Bytes;
+use resp::Frame;
+use crate::cmd::{self, Command, DENY_OOM, NO_MULTI};
+use crate::db::Keyspace;
+use crate::evict;
+use crate::server::Server;
+
+/// 事务相关的命令，不管是否在事务中都由Transaction处理
+pub fn is_transaction_command(name: &str) -> bool {
+    matches!(name, "multi" | "exec" | "discard" | "watch" | "unwatch")
+}
+
+/// 每个连接一个的事务状态
+pub struct Transaction {
+    keyspace: Arc<Keyspace>,
+    // MULTI之后排队的命令，None表示不在事务中
+    queued: Option<Vec<Command>>,
+    // 排队时出过错，EXEC直接放弃整个事务
+    aborted: bool,
+    // 被监视的键所在的数据库、键和WATCH时的版本号
+    watched: Vec<(usize, Bytes, u64)>,
+}
+
+impl Transaction {
+    pub fn new(keyspace: Arc<Keyspace>) -> Transaction {
+        Transaction { keyspace, queued: None, aborted: false, watched: Vec::new() }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.queued.is_some()
+    }
+
+    /// 处理事务相关的命令，或者在事务中把命令加入队列，index是连接当前选择的数据库
+    pub fn handle(&mut self, server: &Server, index: usize, cmd: Command) -> Frame {
+        match cmd.name.as_str() {
+            "multi" if self.is_active() => Frame::error("ERR MULTI calls can not be nested"),
+            "multi" => {
+                self.queued = Some(Vec::new());
+                self.aborted = false;
+                Frame::ok()
+            }
+            "exec" if !self.is_active() => Frame::error("ERR EXEC without MULTI"),
+            "exec" => self.exec(server, index),
+            "discard" if !self.is_active() => Frame::error("ERR DISCARD without MULTI"),
+            "discard" => {
+                self.queued = None;
+                self.unwatch();
+                Frame::ok()
+            }
+            "watch" if self.is_active() => self.queue_error(Frame::error("ERR WATCH inside MULTI is not allowed")),
+            "watch" => match cmd::check(&cmd) {
+                Ok(_) => {
+                    self.watch(index, &cmd.args);
+                    Frame::ok()
+                }
+                Err(err) => err,
+            },
+            "unwatch" if !self.is_active() => {
+                self.unwatch();
+                Frame::ok()
+            }
+            // 和Redis一样可以排队，EXEC总会取消监视，所以执行时什么也不做
+            "unwatch" => match cmd::check(&cmd) {
+                Ok(_) => {
+                    self.queued.as_mut().unwrap().push(cmd);
+                    Frame::Simple("QUEUED".to_string())
+                }
+                Err(err) => self.queue_error(err),
+            },
+            _ => self.queue(server, cmd),
+        }
+    }
+
+    fn queue(&mut self, server: &Server, cmd: Command) -> Frame {
+        let spec = match cmd::check(&cmd) {
+            Ok(spec) => spec,
+            Err(err) => return self.queue_error(err),
+        };
+        if spec.has(NO_MULTI) {
+            return self.queue_error(Frame::error("ERR Command not allowed inside a transaction"));
+        }
+        if let Err(err) = cmd::check_writable(server, spec) {
+            return self.queue_error(err);
+        }
+        // 和Redis一样，排队时就检查内存，明显会失败的事务不用等到EXEC
+        if spec.has(DENY_OOM) {
+            if let Err(err) = evict::free_memory(server) {
+                return self.queue_error(err);
+            }
+        }
+        self.queued.as_mut().unwrap().push(cmd);
+        Frame::Simple("QUEUED".to_string())
+    }
+
+    /// 事务中出错时调用，EXEC会放弃整个事务
+    pub fn queue_error(&mut self, err: Frame) -> Frame {
+        self.aborted = true;
+        err
+    }
+
+    fn exec(&mut self, server: &Server, index: usize) -> Frame {
+        let queued = self.queued.take().unwrap();
+        let watched = std::mem::take(&mut self.watched);
+        if self.aborted {
+            self.release(&watched);
+            return Frame::error("EXECABORT Transaction discarded because of previous errors.");
+        }
+        let specs: Vec<_> = queued.iter().map(|cmd| cmd::lookup(&cmd.name).unwrap()).collect();
+        // 加锁之后就不能再淘汰了，先把内存腾出来
+        if specs.iter().any(|spec| spec.has(DENY_OOM)) {
+            if let Err(err) = evict::free_memory(server) {
+                self.release(&watched);
+                return err;
+            }
+        }
+        let keys = specs.iter().zip(&queued)
+            .flat_map(|(spec, cmd)| spec.keys.keys(&cmd.args))
+            .chain(watched.iter().map(|(_, key, _)| key));
+        // 同一个键在所有数据库里都在同一个分片上，被监视的键可能在别的数据库里，切过去检查
+        let mut db = server.db.lock(index, keys);
+        let mut modified = false;
+        for (watched_index, key, version) in &watched {
+            db.select(*watched_index);
+            modified |= db.version(key) != *version;
+            db.unwatch(key);
+        }
+        db.select(index);
+        if modified {
+            return Frame::Null;
+        }
+        let replies = specs.iter().zip(&queued)
+            .map(|(spec, cmd)| match spec.name {
+                "unwatch" => Frame::ok(),
+                _ => cmd::call(server, spec, cmd, &mut db),
+            })
+            .collect();
+        Frame::Array(replies)
+    }
+
+    fn watch(&mut self, index: usize, keys: &[Bytes]) {
+        let mut db = self.keyspace.lock(index, keys);
+        for key in keys {
+            if !self.watched.iter().any(|(watched_index, watched, _)| *watched_index == index && watched == key) {
+                let version = db.watch(key);
+                self.watched.push((index, key.clone(), version));
+            }
+        }
+    }
+
+    fn unwatch(&mut self) {
+        let watched = std::mem::take(&mut self.watched);
+        self.release(&watched);
+    }
+
+    fn release(&self, watched: &[(usize, Bytes, u64)]) {
+        if watched.is_empty() {
+            return;
+        }
+        let mut db = self.keyspace.lock(0, watched.iter().map(|(_, key, _)| key));
+        for (index, key, _) in watched {
+            db.select(*index);
+            db.unwatch(key);
+        }
+    }
+}
+
+impl Drop for Transaction {
+    /// 连接断开时取消监视
+    fn drop(&mut self) {
+        self.unwatch();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use resp::Frame;
+    use crate::cmd::{execute, testing::bulk, Command};
+    use crate::multi::Transaction;
+    use crate::server::Server;
+
+    fn command(line: &str) -> Command {
+        let parts = line.split_whitespace().map(|s| Frame::Bulk(Bytes::from(s.to_string()))).collect();
+        Command::from_frame(Frame::Array(parts)).unwrap()
+    }
+
+    fn queued() -> Frame {
+        Frame::Simple("QUEUED".to_string())
+    }
+
+    #[test]
+    fn test_exec() {
+        let server = Server::with_shards(4);
+        let mut tx = Transaction::new(server.db.clone());
+        assert_eq!(tx.handle(&server, 0, command("MULTI")), Frame::ok());
+        assert_eq!(tx.handle(&server, 0, command("SET a 1")), queued());
+        assert_eq!(tx.handle(&server, 0, command("INCR a")), queued());
+        assert_eq!(tx.handle(&server, 0, command("LPUSH a x")), queued());
+        assert_eq!(tx.handle(&server, 0, command("MSET b 2 c 3")), queued());
+        // 排队期间其他连接看不到这些修改
+        assert_eq!(execute(&server, 0, &command("GET a")), Frame::Null);
+        let wrong_type = Frame::error("WRONGTYPE Operation against a key holding the wrong kind of value");
+        assert_eq!(
+            tx.handle(&server, 0, command("EXEC")),
+            Frame::Array(vec![Frame::ok(), Frame::Integer(2), wrong_type, Frame::ok()])
+        );
+        assert_eq!(execute(&server, 0, &command("GET c")), bulk("3"));
+        assert_eq!(tx.handle(&server, 0, command("EXEC")), Frame::error("ERR EXEC without MULTI"));
+    }
+
+    #[test]
+    fn test_abort() {
+        let server = Server::with_shards(4);
+        let mut tx = Transaction::new(server.db.clone());
+        tx.handle(&server, 0, command("MULTI"));
+        assert_eq!(tx.handle(&server, 0, command("SET a 1")), queued());
+        assert!(matches!(tx.handle(&server, 0, command("GET")), Frame::Error(_)));
+        assert!(matches!(tx.handle(&server, 0, command("SAVE")), Frame::Error(_)));
+        assert!(matches!(tx.handle(&server, 0, command("EXEC")), Frame::Error(err) if err.starts_with("EXECABORT")));
+        assert_eq!(execute(&server, 0, &command("EXISTS a")), Frame::Integer(0));
+
+        tx.handle(&server, 0, command("MULTI"));
+        tx.handle(&server, 0, command("SET a 1"));
+        assert_eq!(tx.handle(&server, 0, command("DISCARD")), Frame::ok());
+        assert_eq!(tx.handle(&server, 0, command("DISCARD")), Frame::error("ERR DISCARD without MULTI"));
+        assert_eq!(execute(&server, 0, &command("EXISTS a")), Frame::Integer(0));
+    }
+
+    #[test]
+    fn test_watch() {
+        let server = Server::with_shards(4);
+        let mut tx = Transaction::new(server.db.clone());
+        execute(&server, 0, &command("SET balance 10"));
+        assert_eq!(tx.handle(&server, 0, command("WATCH balance other")), Frame::ok());
+        // 其他连接修改了被监视的键，事务不执行
+        execute(&server, 0, &command("INCRBY balance 5"));
+        tx.handle(&server, 0, command("MULTI"));
+        tx.handle(&server, 0, command("SET balance 0"));
+        assert_eq!(tx.handle(&server, 0, command("EXEC")), Frame::Null);
+        assert_eq!(execute(&server, 0, &command("GET balance")), bulk("15"));
+
+        // EXEC之后自动取消监视，没有被修改时正常执行
+        tx.handle(&server, 0, command("WATCH balance"));
+        execute(&server, 0, &command("GET balance"));
+        tx.handle(&server, 0, command("MULTI"));
+        tx.handle(&server, 0, command("SET balance 0"));
+        assert_eq!(tx.handle(&server, 0, command("EXEC")), Frame::Array(vec![Frame::ok()]));
+
+        // 不存在的键被创建也算修改，UNWATCH之后不再检查
+        tx.handle(&server, 0, command("WATCH other"));
+        execute(&server, 0, &command("SET other 1"));
+        tx.handle(&server, 0, command("UNWATCH"));
+        tx.handle(&server, 0, command("MULTI"));
+        tx.handle(&server, 0, command("DEL other"));
+        assert_eq!(tx.handle(&server, 0, command("EXEC")), Frame::Array(vec![Frame::Integer(1)]));
+
+        // 事务中的UNWATCH正常排队，不会让EXEC放弃事务
+        tx.handle(&server, 0, command("MULTI"));
+        assert_eq!(tx.handle(&server, 0, command("UNWATCH")), queued());
+        assert_eq!(tx.handle(&server, 0, command("SET other 2")), queued());
+        assert_eq!(tx.handle(&server, 0, command("EXEC")), Frame::Array(vec![Frame::ok(), Frame::ok()]));
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Mutex;
+use bytes::Bytes;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt, StreamMap};
+use resp::{Connection, Frame};
+use crate::acl;
+use crate::cmd::{self, Command};
+use crate::glob;
+use crate::server::{Client, Server};
+use crate::tls;
+
+/// 每个频道的广播缓冲区大小，订阅者落后太多时会丢掉最旧的消息
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// 基于broadcast channel的发布订阅：每个频道、每个模式各有一个Sender，
+/// 订阅就是持有对应的Receiver，发布时返回收到消息的订阅数
+pub struct PubSub {
+    channels: Mutex<HashMap<Bytes, broadcast::Sender<Bytes>>>,
+    // 模式订阅收到的是(频道, 消息)
+    patterns: Mutex<HashMap<Bytes, broadcast::Sender<(Bytes, Bytes)>>>,
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        PubSub {
+            channels: Mutex::new(HashMap::new()),
+            patterns: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn publish(&self, channel: &Bytes, message: Bytes) -> usize {
+        let mut receivers = 0;
+        if let Some(sender) = self.channels.lock().unwrap().get(channel) {
+            receivers += sender.send(message.clone()).unwrap_or(0);
+        }
+        for (pattern, sender) in self.patterns.lock().unwrap().iter() {
+            if glob::matches(pattern, channel) {
+                receivers += sender.send((channel.clone(), message.clone())).unwrap_or(0);
+            }
+        }
+        receivers
+    }
+
+    fn subscribe(&self, channel: &Bytes) -> broadcast::Receiver<Bytes> {
+        self.channels.lock().unwrap()
+            .entry(channel.clone())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    fn psubscribe(&self, pattern: &Bytes) -> broadcast::Receiver<(Bytes, Bytes)> {
+        self.patterns.lock().unwrap()
+            .entry(pattern.clone())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// 没有订阅者的频道直接删掉，避免频道表无限增长
+    fn release(&self, channel: &Bytes) {
+        let mut channels = self.channels.lock().unwrap();
+        if channels.get(channel).is_some_and(|sender| sender.receiver_count() == 0) {
+            channels.remove(channel);
+        }
+    }
+
+    fn prelease(&self, pattern: &Bytes) {
+        let mut patterns = self.patterns.lock().unwrap();
+        if patterns.get(pattern).is_some_and(|sender| sender.receiver_count() == 0) {
+            patterns.remove(pattern);
+        }
+    }
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        PubSub::new()
+    }
+}
+
+type Messages = Pin<Box<dyn Stream<Item = Frame> + Send>>;
+
+pub fn is_subscribe_command(name: &str) -> bool {
+    matches!(name, "subscribe" | "psubscribe" | "unsubscribe" | "punsubscribe")
+}
+
+/// 订阅模式下连接的状态
+struct Subscriber {
+    channels: StreamMap<Bytes, Messages>,
+    patterns: StreamMap<Bytes, Messages>,
+}
+
+impl Subscriber {
+    fn count(&self) -> i64 {
+        (self.channels.len() + self.patterns.len()) as i64
+    }
+
+    /// 订阅相关的回复和消息在RESP3里都是推送帧，RESP2连接上会编码成普通数组
+    fn reply(&self, kind: &'static str, name: Option<Bytes>) -> Frame {
+        Frame::Push(vec![
+            Frame::Bulk(Bytes::from_static(kind.as_bytes())),
+            name.into(),
+            Frame::Integer(self.count()),
+        ])
+    }
+
+    /// 处理一条(P)SUBSCRIBE/(P)UNSUBSCRIBE，每个频道各回复一帧
+    async fn apply(&mut self, server: &Server, connection: &mut Connection<tls::Stream>, cmd: Command) -> std::io::Result<()> {
+        match cmd.name.as_str() {
+            "subscribe" => {
+                for channel in cmd.args {
+                    let stream = BroadcastStream::new(server.pubsub.subscribe(&channel));
+                    let name = channel.clone();
+                    // 落后太多丢掉的消息直接跳过
+                    let messages = stream.filter_map(move |msg| msg.ok().map(|msg| Frame::Push(vec![
+                        Frame::Bulk(Bytes::from_static(b"message")),
+                        Frame::Bulk(name.clone()),
+                        Frame::Bulk(msg),
+                    ])));
+                    self.channels.insert(channel.clone(), Box::pin(messages));
+                    connection.write_frame(&self.reply("subscribe", Some(channel))).await?;
+                }
+            }
+            "psubscribe" => {
+                for pattern in cmd.args {
+                    let stream = BroadcastStream::new(server.pubsub.psubscribe(&pattern));
+                    let name = pattern.clone();
+                    let messages = stream.filter_map(move |msg| msg.ok().map(|(channel, msg)| Frame::Push(vec![
+                        Frame::Bulk(Bytes::from_static(b"pmessage")),
+                        Frame::Bulk(name.clone()),
+                        Frame::Bulk(channel),
+                        Frame::Bulk(msg),
+                    ])));
+                    self.patterns.insert(pattern.clone(), Box::pin(messages));
+                    connection.write_frame(&self.reply("psubscribe", Some(pattern))).await?;
+                }
+            }
+            "unsubscribe" => {
+                // 不带参数表示退订所有频道
+                let channels = if cmd.args.is_empty() {
+                    self.channels.keys().cloned().collect()
+                } else {
+                    cmd.args
+                };
+                if channels.is_empty() {
+                    connection.write_frame(&self.reply("unsubscribe", None)).await?;
+                }
+                for channel in channels {
+                    self.channels.remove(&channel);
+                    server.pubsub.release(&channel);
+                    connection.write_frame(&self.reply("unsubscribe", Some(channel))).await?;
+                }
+            }
+            _ => {
+                let patterns = if cmd.args.is_empty() {
+                    self.patterns.keys().cloned().collect()
+                } else {
+                    cmd.args
+                };
+                if patterns.is_empty() {
+                    connection.write_frame(&self.reply("punsubscribe", None)).await?;
+                }
+                for pattern in patterns {
+                    self.patterns.remove(&pattern);
+                    server.pubsub.prelease(&pattern);
+                    connection.write_frame(&self.reply("punsubscribe", Some(pattern))).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn release(&mut self, server: &Server) {
+        let channels: Vec<_> = self.channels.keys().cloned().collect();
+        self.channels.clear();
+        channels.iter().for_each(|channel| server.pubsub.release(channel));
+        let patterns: Vec<_> = self.patterns.keys().cloned().collect();
+        self.patterns.clear();
+        patterns.iter().for_each(|pattern| server.pubsub.prelease(pattern));
+    }
+}
+
+/// 连接进入订阅模式，转发消息并处理订阅相关的命令，直到退订了所有频道和模式。
+/// 返回false表示连接已经关闭
+pub async fn subscribe(server: &Server, connection: &mut Connection<tls::Stream>, client: &Client, cmd: Command) -> resp::Result<bool> {
+    let mut subscriber = Subscriber {
+        channels: StreamMap::new(),
+        patterns: StreamMap::new(),
+    };
+    let ans = run(server, connection, client, cmd, &mut subscriber).await;
+    subscriber.release(server);
+    ans
+}
+
+async fn run(server: &Server, connection: &mut Connection<tls::Stream>, client: &Client, cmd: Command, subscriber: &mut Subscriber) -> resp::Result<bool> {
+    subscriber.apply(server, connection, cmd).await?;
+    while subscriber.count() > 0 {
+        tokio::select! {
+            Some((_, frame)) = subscriber.channels.next() => connection.write_frame(&frame).await?,
+            Some((_, frame)) = subscriber.patterns.next() => connection.write_frame(&frame).await?,
+            _ = server.shutdown.wait() => return Ok(false),
+            frame = connection.read_frame() => {
+                let frame = match frame? {
+                    Some(frame) => frame,
+                    None => return Ok(false),
+                };
+                // 订阅新的频道也要检查权限，期间用户可能被ACL SETUSER修改过
+                let cmd = Command::from_frame(frame)
+                    .and_then(|cmd| cmd::check(&cmd).map(|_| cmd))
+                    .and_then(|cmd| acl::authorize(&server.acl, client.user().as_deref(), &cmd).map(|_| cmd));
+                let cmd = match cmd {
+                    Ok(cmd) => cmd,
+                    Err(err) => {
+                        connection.write_frame(&err).await?;
+                        continue;
+                    }
+                };
+                match cmd.name.as_str() {
+                    name if is_subscribe_command(name) => subscriber.apply(server, connection, cmd).await?,
+                    "ping" => {
+                        let frame = Frame::Array(vec![
+                            Frame::Bulk(Bytes::from_static(b"pong")),
+                            Frame::Bulk(cmd.args.first().cloned().unwrap_or_default()),
+                        ]);
+                        connection.write_frame(&frame).await?;
+                    }
+                    "quit" => {
+                        connection.write_frame(&Frame::ok()).await?;
+                        return Ok(false);
+                    }
+                    name => {
+                        let err = Frame::error(format!(
+                            "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context", name));
+                        connection.write_frame(&err).await?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use mini_redis::client;
+    use tokio::net::TcpStream;
+    use resp::{Connection, Frame};
+    use crate::tests::start_server;
+
+    fn command(parts: &[&str]) -> Frame {
+        Frame::Array(parts.iter().map(|s| Frame::Bulk(Bytes::from(s.to_string()))).collect())
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let addr = start_server().await;
+        let subscriber = client::connect(addr).await.unwrap();
+        let mut subscriber = subscriber.subscribe(vec!["news".to_string(), "sports".to_string()]).await.unwrap();
+        let mut publisher = client::connect(addr).await.unwrap();
+        assert_eq!(publisher.publish("news", Bytes::from("hello")).await.unwrap(), 1);
+        assert_eq!(publisher.publish("weather", Bytes::from("sunny")).await.unwrap(), 0);
+        let msg = subscriber.next_message().await.unwrap().unwrap();
+        assert_eq!(msg.channel, "news");
+        assert_eq!(msg.content, Bytes::from("hello"));
+        subscriber.unsubscribe(&["news".to_string()]).await.unwrap();
+        assert_eq!(publisher.publish("news", Bytes::from("again")).await.unwrap(), 0);
+        assert_eq!(publisher.publish("sports", Bytes::from("goal")).await.unwrap(), 1);
+        let msg = subscriber.next_message().await.unwrap().unwrap();
+        assert_eq!(msg.channel, "sports");
+    }
+
+    #[tokio::test]
+    async fn test_psubscribe() {
+        let addr = start_server().await;
+        let mut subscriber = Connection::new(TcpStream::connect(addr).await.unwrap());
+        subscriber.write_frame(&command(&["PSUBSCRIBE", "news.*"])).await.unwrap();
+        assert_eq!(subscriber.read_frame().await.unwrap().unwrap(), Frame::Array(vec![
+            Frame::Bulk(Bytes::from("psubscribe")), Frame::Bulk(Bytes::from("news.*")), Frame::Integer(1),
+        ]));
+        subscriber.write_frame(&command(&["GET", "k"])).await.unwrap();
+        assert!(matches!(subscriber.read_frame().await.unwrap().unwrap(), Frame::Error(_)));
+        let mut publisher = client::connect(addr).await.unwrap();
+        assert_eq!(publisher.publish("news.tech", Bytes::from("rust")).await.unwrap(), 1);
+        assert_eq!(subscriber.read_frame().await.unwrap().unwrap(), Frame::Array(vec![
+            Frame::Bulk(Bytes::from("pmessage")), Frame::Bulk(Bytes::from("news.*")),
+            Frame::Bulk(Bytes::from("news.tech")), Frame::Bulk(Bytes::from("rust")),
+        ]));
+        // 退订所有模式后回到普通模式
+        subscriber.write_frame(&command(&["PUNSUBSCRIBE"])).await.unwrap();
+        assert_eq!(subscriber.read_frame().await.unwrap().unwrap(), Frame::Array(vec![
+            Frame::Bulk(Bytes::from("punsubscribe")), Frame::Bulk(Bytes::from("news.*")), Frame::Integer(0),
+        ]));
+        subscriber.write_frame(&command(&["GET", "k"])).await.unwrap();
+        assert_eq!(subscriber.read_frame().await.unwrap().unwrap(), Frame::Null);
+    }
+}
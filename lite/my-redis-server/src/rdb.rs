@@ -0,0 +1,516 @@
+//! RDB风格的快照：把某一时刻的整个键空间写成紧凑的二进制文件，启动时再加载回来。
+//!
+//! 文件格式：`MYRDB` + 版本号，之后是若干条记录，每条记录是
+//! [可选的过期时间：0xFC + 毫秒级unix时间戳(i64小端)] + 类型 + 键 + 值，
+//! 每个非空的数据库前面有一个0xFE + 数据库编号，没有的话属于0号数据库。
+//! 以0xFF结束，最后8个字节是前面所有内容的CRC64(小端)。长度都用LEB128变长编码。
+
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use bytes::Bytes;
+use resp::Frame;
+use crate::db::{from_unix_millis, to_unix_millis, Keyspace, Snapshot};
+use crate::server::Server;
+use crate::value::{SortedSet, Value};
+
+const MAGIC: &[u8] = b"MYRDB";
+const VERSION: u8 = 1;
+
+const OP_EXPIRE_MS: u8 = 0xFC;
+const OP_SELECT_DB: u8 = 0xFE;
+const OP_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_HASH: u8 = 2;
+const TYPE_SET: u8 = 3;
+const TYPE_ZSET: u8 = 4;
+
+/// 自动保存规则：距离上次保存超过seconds秒并且至少有changes次修改时触发BGSAVE
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// 快照相关的状态，保存规则在配置里，可以在运行时修改
+pub struct Rdb {
+    pub path: PathBuf,
+    // 上次保存之后的修改次数
+    dirty: AtomicU64,
+    last_save: Mutex<SystemTime>,
+    saving: AtomicBool,
+}
+
+impl Rdb {
+    pub fn new(path: impl Into<PathBuf>) -> Rdb {
+        Rdb {
+            path: path.into(),
+            dirty: AtomicU64::new(0),
+            last_save: Mutex::new(SystemTime::now()),
+            saving: AtomicBool::new(false),
+        }
+    }
+
+    /// 写命令修改了键空间之后调用，changes是修改的次数，什么也没改的写命令不调用
+    pub fn record_change(&self, changes: u64) {
+        self.dirty.fetch_add(changes, Ordering::Relaxed);
+    }
+
+    pub fn dirty(&self) -> u64 {
+        self.dirty.load(Ordering::Relaxed)
+    }
+
+    pub fn is_saving(&self) -> bool {
+        self.saving.load(Ordering::Acquire)
+    }
+
+    pub fn last_save(&self) -> SystemTime {
+        *self.last_save.lock().unwrap()
+    }
+
+    /// 先写临时文件再改名，保存到一半崩溃也不会破坏原来的快照。
+    /// 改名之前先把数据刷到磁盘，否则崩溃后可能改名生效了，数据却没有落盘
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        let tmp = self.path.with_extension(format!("tmp-{}", std::process::id()));
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)
+    }
+
+    /// 保存成功后扣掉快照开始时已经计入的修改次数，快照期间新产生的修改留给下一次
+    fn saved(&self, dirty: u64) {
+        self.dirty.fetch_sub(dirty, Ordering::Relaxed);
+        *self.last_save.lock().unwrap() = SystemTime::now();
+    }
+}
+
+/// 解析`900 1 300 10`这样的保存规则，空字符串表示不自动保存
+pub fn parse_save_rules(rules: &str) -> Option<Vec<SaveRule>> {
+    let parts: Vec<u64> = rules.split_whitespace().map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    if !parts.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(parts.chunks(2).map(|pair| SaveRule { seconds: pair[0], changes: pair[1] }).collect())
+}
+
+/// SAVE：在当前线程完成保存
+pub fn save(server: &Server) -> io::Result<()> {
+    let rdb = &server.rdb;
+    if rdb.saving.swap(true, Ordering::AcqRel) {
+        return Err(io::Error::other("Background save already in progress"));
+    }
+    let dirty = rdb.dirty();
+    let res = rdb.write(&encode(&server.db));
+    if res.is_ok() {
+        rdb.saved(dirty);
+    }
+    rdb.saving.store(false, Ordering::Release);
+    res
+}
+
+/// BGSAVE：加锁期间只复制值的引用，之后被修改的值才真正复制，编码和写文件都在后台线程里做，不阻塞其他客户端
+pub fn bgsave(server: &Server) -> Result<(), Frame> {
+    let rdb = server.rdb.clone();
+    if rdb.saving.swap(true, Ordering::AcqRel) {
+        return Err(Frame::error("ERR Background save already in progress"));
+    }
+    let dirty = rdb.dirty();
+    let snapshot = server.db.snapshot();
+    std::thread::spawn(move || {
+        match rdb.write(&encode_snapshot(&snapshot)) {
+            Ok(()) => {
+                rdb.saved(dirty);
+                notice!("Background saving terminated with success");
+            }
+            Err(err) => warning!("Background saving error: {}", err),
+        }
+        rdb.saving.store(false, Ordering::Release);
+    });
+    Ok(())
+}
+
+/// 后台任务：每秒检查一次保存规则，满足任意一条就触发BGSAVE
+pub async fn auto_save(server: Arc<Server>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let rdb = &server.rdb;
+        let elapsed = rdb.last_save().elapsed().unwrap_or_default().as_secs();
+        let dirty = rdb.dirty();
+        let triggered = server.config.read().unwrap().save.iter().any(|rule| dirty >= rule.changes && elapsed >= rule.seconds);
+        if triggered && dirty > 0 && !rdb.is_saving() {
+            notice!("{} changes in {} seconds. Saving...", dirty, elapsed);
+            let _ = bgsave(&server);
+        }
+    }
+}
+
+/// 启动时加载快照文件，返回加载的键数。文件不存在不算错误
+pub fn load(rdb: &Rdb, keyspace: &Keyspace) -> io::Result<usize> {
+    let data = match std::fs::read(&rdb.path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let mut count = 0;
+    for (index, key, value, expires_at) in decode(&data)? {
+        if index >= keyspace.databases() {
+            return Err(invalid("snapshot contains more databases than configured"));
+        }
+        let expires_at = expires_at.map(from_unix_millis);
+        // 保存之后已经过期的键直接丢弃
+        if expires_at.is_some_and(|when| when <= Instant::now()) {
+            continue;
+        }
+        keyspace.lock(index, [&key]).insert_with_expire(key, value, expires_at);
+        count += 1;
+    }
+    Ok(count)
+}
+
+pub fn encode(keyspace: &Keyspace) -> Vec<u8> {
+    encode_snapshot(&keyspace.snapshot())
+}
+
+/// 编码所有数据库
+pub fn encode_snapshot(snapshot: &Snapshot) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.push(VERSION);
+    for (index, entries) in snapshot.iter().enumerate().filter(|(_, entries)| !entries.is_empty()) {
+        buf.push(OP_SELECT_DB);
+        put_len(&mut buf, index as u64);
+        put_entries(&mut buf, entries);
+    }
+    finish(buf)
+}
+
+/// 只编码0号数据库的这些键，DUMP和MIGRATE使用
+pub fn encode_entries(entries: &[(Bytes, Value, Option<Instant>)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.push(VERSION);
+    put_entries(&mut buf, entries);
+    finish(buf)
+}
+
+fn finish(mut buf: Vec<u8>) -> Vec<u8> {
+    buf.push(OP_EOF);
+    let checksum = crc64(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+/// 快照里的值是Arc，DUMP和MIGRATE的是Value本身
+fn put_entries<V: Borrow<Value>>(buf: &mut Vec<u8>, entries: &[(Bytes, V, Option<Instant>)]) {
+    for (key, value, expires_at) in entries {
+        if let Some(when) = expires_at {
+            buf.push(OP_EXPIRE_MS);
+            buf.extend_from_slice(&to_unix_millis(*when).to_le_bytes());
+        }
+        match value.borrow() {
+            Value::String(val) => {
+                buf.push(TYPE_STRING);
+                put_bytes(buf, key);
+                put_bytes(buf, val);
+            }
+            Value::List(list) => {
+                buf.push(TYPE_LIST);
+                put_bytes(buf, key);
+                put_len(buf, list.len() as u64);
+                list.iter().for_each(|val| put_bytes(buf, val));
+            }
+            Value::Hash(hash) => {
+                buf.push(TYPE_HASH);
+                put_bytes(buf, key);
+                put_len(buf, hash.len() as u64);
+                for (field, val) in hash {
+                    put_bytes(buf, field);
+                    put_bytes(buf, val);
+                }
+            }
+            Value::Set(set) => {
+                buf.push(TYPE_SET);
+                put_bytes(buf, key);
+                put_len(buf, set.len() as u64);
+                set.iter().for_each(|member| put_bytes(buf, member));
+            }
+            Value::ZSet(zset) => {
+                buf.push(TYPE_ZSET);
+                put_bytes(buf, key);
+                put_len(buf, zset.len() as u64);
+                for (member, score) in zset.iter() {
+                    put_bytes(buf, member);
+                    buf.extend_from_slice(&score.to_le_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// (数据库编号, 键, 值, 过期时间)，过期时间是毫秒级unix时间戳
+pub type Record = (usize, Bytes, Value, Option<i64>);
+
+/// 解析快照文件，校验和不对时返回InvalidData错误
+pub fn decode(data: &[u8]) -> io::Result<Vec<Record>> {
+    if data.len() < MAGIC.len() + 1 + 1 + 8 || !data.starts_with(MAGIC) {
+        return Err(invalid("not a snapshot file"));
+    }
+    let (body, checksum) = data.split_at(data.len() - 8);
+    if crc64(body).to_le_bytes() != checksum {
+        return Err(invalid("checksum mismatch, snapshot file is corrupted"));
+    }
+    if body[MAGIC.len()] != VERSION {
+        return Err(invalid("unsupported snapshot version"));
+    }
+    let mut reader = Reader { data: &body[MAGIC.len() + 1..] };
+    let mut entries = Vec::new();
+    let mut index = 0;
+    loop {
+        let mut op = reader.u8()?;
+        if op == OP_SELECT_DB {
+            index = reader.len()?;
+            continue;
+        }
+        let mut expires_at = None;
+        if op == OP_EXPIRE_MS {
+            expires_at = Some(i64::from_le_bytes(reader.array()?));
+            op = reader.u8()?;
+        }
+        if op == OP_EOF {
+            break;
+        }
+        let key = reader.bytes()?;
+        let value = match op {
+            TYPE_STRING => Value::String(reader.bytes()?),
+            TYPE_LIST => {
+                let len = reader.len()?;
+                Value::List((0..len).map(|_| reader.bytes()).collect::<io::Result<VecDeque<_>>>()?)
+            }
+            TYPE_HASH => {
+                let len = reader.len()?;
+                Value::Hash((0..len).map(|_| Ok((reader.bytes()?, reader.bytes()?))).collect::<io::Result<HashMap<_, _>>>()?)
+            }
+            TYPE_SET => {
+                let len = reader.len()?;
+                Value::Set((0..len).map(|_| reader.bytes()).collect::<io::Result<HashSet<_>>>()?)
+            }
+            TYPE_ZSET => {
+                let mut zset = SortedSet::default();
+                for _ in 0..reader.len()? {
+                    let member = reader.bytes()?;
+                    zset.insert(member, f64::from_le_bytes(reader.array()?));
+                }
+                Value::ZSet(zset)
+            }
+            _ => return Err(invalid("unknown value type")),
+        };
+        entries.push((index, key, value, expires_at));
+    }
+    Ok(entries)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn put_len(buf: &mut Vec<u8>, mut len: u64) {
+    while len >= 0x80 {
+        buf.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    buf.push(len as u8);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, val: &[u8]) {
+    put_len(buf, val.len() as u64);
+    buf.extend_from_slice(val);
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> io::Result<&[u8]> {
+        if self.data.len() < n {
+            return Err(invalid("unexpected end of snapshot file"));
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn len(&mut self) -> io::Result<usize> {
+        let mut len = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            len |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(len as usize);
+            }
+        }
+        Err(invalid("length overflow"))
+    }
+
+    fn bytes(&mut self) -> io::Result<Bytes> {
+        let len = self.len()?;
+        Ok(Bytes::copy_from_slice(self.take(len)?))
+    }
+}
+
+/// CRC-64/Jones，和Redis的RDB文件用的是同一个多项式
+const CRC64_TABLE: [u64; 256] = {
+    const POLY: u64 = 0x95AC_9329_AC4B_C9B5;
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc64(data: &[u8]) -> u64 {
+    data.iter().fold(0, |crc, byte| CRC64_TABLE[((crc ^ *byte as u64) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use bytes::Bytes;
+    use resp::Frame;
+    use crate::cmd::{execute, Command};
+    use crate::cmd::testing::{bulk, run};
+    use crate::db::Keyspace;
+    use crate::rdb::{crc64, decode, encode, load, parse_save_rules, Rdb, SaveRule};
+    use crate::server::Server;
+    use crate::value::Value;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("my-redis-{}-{}.rdb", name, std::process::id()))
+    }
+
+    fn command(line: &str) -> Command {
+        let parts = line.split_whitespace().map(|s| Frame::Bulk(Bytes::from(s.to_string()))).collect();
+        Command::from_frame(Frame::Array(parts)).unwrap()
+    }
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let keyspace = Keyspace::new(4, 2);
+        let key = Bytes::from("s");
+        keyspace.lock(1, [&key]).insert(key.clone(), Value::String(Bytes::from("db1")));
+        run(&keyspace, "SET s hello");
+        run(&keyspace, "SET e v EX 100");
+        run(&keyspace, "RPUSH l a b c");
+        run(&keyspace, "HSET h f v");
+        run(&keyspace, "SADD set x y");
+        run(&keyspace, "ZADD z 1.5 a -2 b");
+        let data = encode(&keyspace);
+        assert_eq!(decode(&data).unwrap().len(), 7);
+
+        let path = temp_path("round-trip");
+        std::fs::write(&path, &data).unwrap();
+        // 快照里的数据库比配置的多时拒绝加载
+        assert!(load(&Rdb::new(&path), &Keyspace::new(2, 1)).is_err());
+        let loaded = Keyspace::new(2, 2);
+        assert_eq!(load(&Rdb::new(&path), &loaded).unwrap(), 7);
+        assert_eq!(loaded.lock(1, [&key]).get_string(&key), Ok(Some(&Bytes::from("db1"))));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(run(&loaded, "GET s"), bulk("hello"));
+        assert!(matches!(run(&loaded, "TTL e"), Frame::Integer(ttl) if ttl > 90));
+        assert_eq!(run(&loaded, "LRANGE l 0 -1"), Frame::Array(vec![bulk("a"), bulk("b"), bulk("c")]));
+        assert_eq!(run(&loaded, "HGET h f"), bulk("v"));
+        assert_eq!(run(&loaded, "SREM set x y"), Frame::Integer(2));
+        assert_eq!(run(&loaded, "ZRANGE z 0 -1 WITHSCORES"), Frame::Array(vec![bulk("b"), bulk("-2"), bulk("a"), bulk("1.5")]));
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let keyspace = Keyspace::new(1, 1);
+        run(&keyspace, "SET key value");
+        let mut data = encode(&keyspace);
+        let idx = data.len() / 2;
+        data[idx] ^= 0x01;
+        assert_eq!(decode(&data).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        // 截断的文件同样能发现
+        let data = encode(&keyspace);
+        assert!(decode(&data[..data.len() - 3]).is_err());
+    }
+
+    #[test]
+    fn test_save_rules() {
+        assert_eq!(parse_save_rules("900 1 60 10000"), Some(vec![SaveRule { seconds: 900, changes: 1 }, SaveRule { seconds: 60, changes: 10000 }]));
+        assert_eq!(parse_save_rules(""), Some(vec![]));
+        assert_eq!(parse_save_rules("900"), None);
+    }
+
+    #[test]
+    fn test_dirty() {
+        let server = Server::with_shards(4);
+        execute(&server, 0, &command("SET a 1"));
+        execute(&server, 0, &command("SADD s x y"));
+        execute(&server, 0, &command("ZADD z 1 m"));
+        assert_eq!(server.rdb.dirty(), 4);
+        // 什么也没改的写命令不算修改
+        for line in ["DEL missing", "SREM s z", "SET a 2 NX", "ZADD z XX 1 n", "ZADD missing XX 1 m", "HDEL missing f", "LPOP missing", "EXPIRE missing 10", "PERSIST a"] {
+            assert!(!matches!(execute(&server, 0, &command(line)), Frame::Error(_)), "{}", line);
+        }
+        assert_eq!(server.rdb.dirty(), 4);
+        assert_eq!(execute(&server, 0, &command("EXISTS missing")), Frame::Integer(0));
+        assert_eq!(execute(&server, 0, &command("SET a 2 XX")), Frame::ok());
+        assert_eq!(execute(&server, 0, &command("SREM s x y")), Frame::Integer(2));
+        assert_eq!(server.rdb.dirty(), 7);
+    }
+
+    #[test]
+    fn test_bgsave() {
+        let path = temp_path("bgsave");
+        let mut server = Server::with_shards(4);
+        server.rdb = Arc::new(Rdb::new(&path));
+        assert_eq!(execute(&server, 0, &command("SET a 1")), Frame::ok());
+        assert_eq!(execute(&server, 0, &command("MSET b 2 c 3")), Frame::ok());
+        assert_eq!(server.rdb.dirty(), 3);
+        assert_eq!(execute(&server, 0, &command("BGSAVE")), Frame::Simple("Background saving started".to_string()));
+        let start = Instant::now();
+        while server.rdb.is_saving() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.rdb.dirty(), 0);
+        assert_eq!(decode(&std::fs::read(&path).unwrap()).unwrap().len(), 3);
+        assert_eq!(execute(&server, 0, &command("DEL a")), Frame::Integer(1));
+        assert_eq!(execute(&server, 0, &command("SAVE")), Frame::ok());
+        assert_eq!(decode(&std::fs::read(&path).unwrap()).unwrap().len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,723 @@
+//! 主从复制，握手和Redis的PSYNC一致：从库连上主库后发送`PSYNC <replid> <offset>`，
+//! 主库的复制积压缓冲区里还有从库缺的那部分数据时回复`+CONTINUE`，只补发缺的部分；
+//! 否则回复`+FULLRESYNC <replid> <offset>`，发送那一时刻的快照，之后再接着发送新的写命令。
+//!
+//! 复制偏移量是复制流的累计字节数，从库每秒用`REPLCONF ACK <offset>`汇报自己处理到了哪里。
+//! 从库把收到的复制流原样写进自己的积压缓冲区，它下面的从库和它共用replid和偏移量。
+//! 和AOF一样，命令所在的数据库和复制流里的上一条不同时先写一条SELECT。
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use bytes::{Bytes, BytesMut};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use resp::{Connection, Frame, Protocol};
+use crate::aof::{self, command_frame};
+use crate::cmd::{self, Command};
+use crate::db::from_unix_millis;
+use crate::evict;
+use crate::rdb;
+use crate::server::Server;
+use crate::tls::Stream;
+
+/// 和主库断开之后多久重连
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// 主库每隔这么久往复制流里写一个PING，从库超过REPL_TIMEOUT没有收到任何数据就认为主库挂了
+const PING_PERIOD: Duration = Duration::from_secs(10);
+const REPL_TIMEOUT: Duration = Duration::from_secs(60);
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+/// 每次最多从积压缓冲区取这么多字节发给从库
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// 复制积压缓冲区：最近写入复制流的size个字节
+struct Backlog {
+    data: VecDeque<u8>,
+    size: usize,
+    // data[0]之前的复制偏移量
+    start: u64,
+}
+
+impl Backlog {
+    fn new(size: usize, offset: u64) -> Backlog {
+        Backlog { data: VecDeque::new(), size, start: offset }
+    }
+
+    fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        self.data.extend(data);
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        let excess = self.data.len().saturating_sub(self.size);
+        self.data.drain(..excess);
+        self.start += excess as u64;
+    }
+
+    /// 偏移量offset之后的数据，已经被挤出缓冲区时返回None
+    fn read(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset < self.start || offset > self.end() {
+            return None;
+        }
+        let from = (offset - self.start) as usize;
+        let to = self.data.len().min(from + CHUNK_SIZE);
+        Some(self.data.range(from..to).copied().collect())
+    }
+}
+
+/// 从库和主库之间连接的状态，ROLE里显示
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LinkState {
+    Connect,
+    Connecting,
+    Sync,
+    Connected,
+}
+
+impl LinkState {
+    fn name(&self) -> &'static str {
+        match self {
+            LinkState::Connect => "connect",
+            LinkState::Connecting => "connecting",
+            LinkState::Sync => "sync",
+            LinkState::Connected => "connected",
+        }
+    }
+}
+
+/// 作为从库时跟随的主库
+struct Master {
+    host: String,
+    port: u16,
+    state: LinkState,
+    task: JoinHandle<()>,
+}
+
+/// 连在这个服务端上的从库
+struct Replica {
+    id: u64,
+    ip: IpAddr,
+    port: u16,
+    // 从库汇报的偏移量
+    ack: u64,
+}
+
+struct State {
+    replid: String,
+    // 上一段历史的replid和它最后一个字节之后的偏移量，从库提升为主库之后，原来的兄弟从库还可以用它部分同步
+    replid2: String,
+    second_offset: u64,
+    offset: u64,
+    // 第一个从库连上来或者自己成为从库时才创建
+    backlog: Option<Backlog>,
+    // 复制流里最后一条SELECT选择的数据库，None表示下一条命令前必须先SELECT
+    selected: Option<usize>,
+    backlog_size: usize,
+    // 积压缓冲区的历史被整个丢弃的次数，发送任务发现它变了就断开从库，让从库重新同步
+    epoch: u64,
+    master: Option<Master>,
+    replicas: Vec<Replica>,
+    next_replica_id: u64,
+    sync_full: u64,
+    sync_partial_ok: u64,
+    sync_partial_err: u64,
+}
+
+impl State {
+    fn append(&mut self, data: &[u8]) {
+        let (offset, size) = (self.offset, self.backlog_size);
+        self.backlog.get_or_insert_with(|| Backlog::new(size, offset)).append(data);
+        self.offset += data.len() as u64;
+    }
+
+    /// 从库要的数据是否还在积压缓冲区里，wanted是从库缺的第一个字节的偏移量
+    fn can_continue(&self, replid: &str, wanted: u64) -> bool {
+        let same_history = replid == self.replid || (replid == self.replid2 && wanted <= self.second_offset);
+        match &self.backlog {
+            Some(backlog) => same_history && wanted > backlog.start && wanted <= self.offset + 1,
+            None => false,
+        }
+    }
+}
+
+/// 40个十六进制字符的复制ID，每段新的复制历史一个
+pub fn new_replid() -> String {
+    format!("{:016x}{:016x}{:08x}", evict::random(), evict::random(), evict::random() as u32)
+}
+
+pub struct Replication {
+    state: Mutex<State>,
+    // 偏移量变化时通知向从库发送数据的任务，自己持有一个接收端，发送总是成功
+    sender: watch::Sender<u64>,
+    receiver: watch::Receiver<u64>,
+}
+
+impl Replication {
+    pub fn new(backlog_size: usize) -> Replication {
+        let (sender, receiver) = watch::channel(0);
+        Replication {
+            state: Mutex::new(State {
+                replid: new_replid(),
+                replid2: "0".repeat(40),
+                second_offset: 0,
+                offset: 0,
+                backlog: None,
+                selected: None,
+                backlog_size,
+                epoch: 0,
+                master: None,
+                replicas: Vec::new(),
+                next_replica_id: 0,
+                sync_full: 0,
+                sync_partial_ok: 0,
+                sync_partial_err: 0,
+            }),
+            sender,
+            receiver,
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.state.lock().unwrap().master.is_some()
+    }
+
+    /// 主库执行成功的写命令进入复制流；从库的复制流只来自主库，自己执行的写命令不往下传
+    pub fn feed(&self, index: usize, frames: &[Frame]) {
+        let mut state = self.state.lock().unwrap();
+        if state.master.is_some() || state.backlog.is_none() {
+            return;
+        }
+        let mut data = BytesMut::new();
+        if state.selected != Some(index) {
+            aof::select_frame(index).encode(&mut data, Protocol::Resp2);
+            state.selected = Some(index);
+        }
+        for frame in frames {
+            frame.encode(&mut data, Protocol::Resp2);
+        }
+        state.append(&data);
+        let _ = self.sender.send(state.offset);
+    }
+
+    /// 从库把主库发来的数据原样追加到自己的复制流
+    fn feed_raw(&self, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.append(data);
+        let _ = self.sender.send(state.offset);
+    }
+
+    pub fn set_backlog_size(&self, size: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.backlog_size = size;
+        if let Some(backlog) = state.backlog.as_mut() {
+            backlog.size = size;
+            backlog.trim();
+        }
+    }
+
+    /// (全量同步次数, 部分同步成功次数, 部分同步失败次数)，INFO stats使用
+    pub fn sync_stats(&self) -> (u64, u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.sync_full, state.sync_partial_ok, state.sync_partial_err)
+    }
+
+    /// INFO replication的内容
+    pub fn info(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut info = String::new();
+        match &state.master {
+            Some(master) => {
+                info += &format!("role:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\n", master.host, master.port);
+                let status = if master.state == LinkState::Connected { "up" } else { "down" };
+                info += &format!("master_link_status:{}\r\nslave_repl_offset:{}\r\n", status, state.offset);
+            }
+            None => info += "role:master\r\n",
+        }
+        info += &format!("connected_slaves:{}\r\n", state.replicas.len());
+        for (idx, replica) in state.replicas.iter().enumerate() {
+            info += &format!("slave{}:ip={},port={},state=online,offset={}\r\n", idx, replica.ip, replica.port, replica.ack);
+        }
+        info += &format!("master_replid:{}\r\nmaster_replid2:{}\r\n", state.replid, state.replid2);
+        info += &format!("master_repl_offset:{}\r\nsecond_repl_offset:{}\r\n", state.offset, state.second_offset);
+        info += &format!("repl_backlog_active:{}\r\nrepl_backlog_size:{}\r\n", state.backlog.is_some() as u8, state.backlog_size);
+        info
+    }
+
+    /// ROLE的回复
+    pub fn role(&self) -> Frame {
+        let state = self.state.lock().unwrap();
+        let bulk = |s: String| Frame::Bulk(Bytes::from(s));
+        match &state.master {
+            Some(master) => Frame::Array(vec![
+                bulk("slave".to_string()),
+                bulk(master.host.clone()),
+                Frame::Integer(master.port as i64),
+                bulk(master.state.name().to_string()),
+                Frame::Integer(state.offset as i64),
+            ]),
+            None => Frame::Array(vec![
+                bulk("master".to_string()),
+                Frame::Integer(state.offset as i64),
+                Frame::Array(state.replicas.iter()
+                    .map(|replica| Frame::Array(vec![
+                        bulk(replica.ip.to_string()),
+                        bulk(replica.port.to_string()),
+                        bulk(replica.ack.to_string()),
+                    ]))
+                    .collect()),
+            ]),
+        }
+    }
+
+    fn set_link_state(&self, link: LinkState) {
+        if let Some(master) = self.state.lock().unwrap().master.as_mut() {
+            master.state = link;
+        }
+    }
+
+    /// 全量同步时记下快照对应的偏移量，第一个从库连上来时创建积压缓冲区。
+    /// 调用时持有所有分片锁，之后的写命令都会进入积压缓冲区
+    fn start_full_sync(&self) -> (String, u64, u64) {
+        let mut state = self.state.lock().unwrap();
+        let (offset, size) = (state.offset, state.backlog_size);
+        state.backlog.get_or_insert_with(|| Backlog::new(size, offset));
+        // 新的从库从0号数据库开始接收复制流
+        state.selected = None;
+        state.sync_full += 1;
+        (state.replid.clone(), state.offset, state.epoch)
+    }
+
+    /// 从库全量同步完成，之前的历史作废
+    fn reset(&self, replid: String, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.replid = replid;
+        state.replid2 = "0".repeat(40);
+        state.second_offset = 0;
+        state.offset = offset;
+        state.backlog = Some(Backlog::new(state.backlog_size, offset));
+        state.selected = None;
+        state.epoch += 1;
+        let _ = self.sender.send(offset);
+    }
+
+    /// 主库换了复制ID(比如它是刚提升的从库)，部分同步之后沿用新的ID，旧的ID留给自己的从库部分同步
+    fn switch_replid(&self, replid: String) {
+        let mut state = self.state.lock().unwrap();
+        if state.replid != replid {
+            state.replid2 = std::mem::replace(&mut state.replid, replid);
+            state.second_offset = state.offset + 1;
+        }
+    }
+}
+
+/// REPLICAOF host port | REPLICAOF NO ONE，已经在跟随这个主库时返回false
+pub fn set_master(server: &Arc<Server>, master: Option<(String, u16)>) -> bool {
+    let mut state = server.replication.state.lock().unwrap();
+    if let (Some(current), Some((host, port))) = (&state.master, &master) {
+        if current.host == *host && current.port == *port {
+            return false;
+        }
+    }
+    if let Some(current) = state.master.take() {
+        current.task.abort();
+    }
+    match master {
+        Some((host, port)) => {
+            notice!("Connecting to MASTER {}:{}", host, port);
+            let task = tokio::spawn(replicate(server.clone(), host.clone(), port));
+            state.master = Some(Master { host, port, state: LinkState::Connect, task });
+        }
+        None => {
+            // 开始一段新的历史，原来的兄弟从库还可以用旧的ID部分同步
+            state.replid2 = std::mem::replace(&mut state.replid, new_replid());
+            state.second_offset = state.offset + 1;
+            // 不知道从库停在哪个数据库，自己的第一条写命令前要先SELECT
+            state.selected = None;
+            notice!("MASTER MODE enabled");
+        }
+    }
+    true
+}
+
+/// 后台任务：有从库时定期往复制流里写PING
+pub async fn ping_replicas(server: Arc<Server>) {
+    let mut interval = tokio::time::interval(PING_PERIOD);
+    loop {
+        interval.tick().await;
+        if !server.replication.state.lock().unwrap().replicas.is_empty() {
+            server.replication.feed(0, &[command_frame("ping", &[])]);
+        }
+    }
+}
+
+/// 主库一端：处理从库发来的PSYNC，之后这个连接只用来发送复制流
+pub async fn serve(server: &Server, connection: &mut Connection<Stream>, args: &[Bytes], ip: IpAddr, port: u16) -> resp::Result<()> {
+    let replid = String::from_utf8_lossy(&args[0]).to_string();
+    let wanted = std::str::from_utf8(&args[1]).ok().and_then(|s| s.parse::<i64>().ok()).unwrap_or(-1);
+    let repl = &server.replication;
+    let partial = {
+        let mut state = repl.state.lock().unwrap();
+        if wanted > 0 && state.can_continue(&replid, wanted as u64) {
+            state.sync_partial_ok += 1;
+            Some((state.replid.clone(), wanted as u64 - 1, state.epoch))
+        } else {
+            if replid != "?" {
+                state.sync_partial_err += 1;
+            }
+            None
+        }
+    };
+    let (offset, epoch) = match partial {
+        Some((replid, offset, epoch)) => {
+            notice!("Partial resynchronization request from {}:{} accepted, offset {}", ip, port, offset);
+            connection.write_frame(&Frame::Simple(format!("CONTINUE {}", replid))).await?;
+            (offset, epoch)
+        }
+        None => {
+            notice!("Starting full resync with replica {}:{}", ip, port);
+            let mut started = None;
+            let snapshot = server.db.snapshot_with(|| started = Some(repl.start_full_sync()));
+            let (replid, offset, epoch) = started.unwrap();
+            connection.write_frame(&Frame::Simple(format!("FULLRESYNC {} {}", replid, offset))).await?;
+            let payload = tokio::task::spawn_blocking(move || rdb::encode_snapshot(&snapshot)).await.map_err(io::Error::other)?;
+            connection.write_raw(format!("${}\r\n", payload.len()).as_bytes()).await?;
+            connection.write_raw(&payload).await?;
+            notice!("Synchronization with replica {}:{} succeeded", ip, port);
+            (offset, epoch)
+        }
+    };
+    let id = {
+        let mut state = repl.state.lock().unwrap();
+        state.next_replica_id += 1;
+        let id = state.next_replica_id;
+        state.replicas.push(Replica { id, ip, port, ack: offset });
+        id
+    };
+    let res = stream(server, connection, id, offset, epoch).await;
+    repl.state.lock().unwrap().replicas.retain(|replica| replica.id != id);
+    res
+}
+
+/// 把积压缓冲区里offset之后的数据发给从库，同时接收从库的ACK。关闭服务端时把已有的数据发完再断开
+async fn stream(server: &Server, connection: &mut Connection<Stream>, id: u64, mut offset: u64, epoch: u64) -> resp::Result<()> {
+    let repl = &server.replication;
+    let mut changed = repl.receiver.clone();
+    let mut closing = false;
+    loop {
+        loop {
+            let data = {
+                let state = repl.state.lock().unwrap();
+                match &state.backlog {
+                    Some(backlog) if state.epoch == epoch => backlog.read(offset),
+                    _ => None,
+                }
+            };
+            let data = data.ok_or_else(|| io::Error::other("replica is too far behind or the replication history changed"))?;
+            if data.is_empty() {
+                break;
+            }
+            connection.write_raw(&data).await?;
+            offset += data.len() as u64;
+        }
+        if closing {
+            return Ok(());
+        }
+        tokio::select! {
+            _ = changed.changed() => {}
+            frame = connection.read_frame() => match frame? {
+                Some(frame) => {
+                    // 从库只会发REPLCONF ACK <offset>
+                    if let Ok(Command { name, args }) = Command::from_frame(frame) {
+                        if let (true, [option, ack]) = (name == "replconf", &args[..]) {
+                            if cmd::eq_ignore_case(option, "ack") {
+                                let ack = std::str::from_utf8(ack).ok().and_then(|s| s.parse().ok()).unwrap_or(0);
+                                let mut state = repl.state.lock().unwrap();
+                                if let Some(replica) = state.replicas.iter_mut().find(|replica| replica.id == id) {
+                                    replica.ack = ack;
+                                }
+                            }
+                        }
+                    }
+                }
+                None => return Ok(()),
+            },
+            _ = server.shutdown.wait() => closing = true,
+        }
+    }
+}
+
+/// 从库一端：和主库保持连接，断开之后隔一会儿重连，重连时先尝试部分同步。
+/// 部分同步接着原来的复制流，复制流当前选择的数据库要跨连接保留
+async fn replicate(server: Arc<Server>, host: String, port: u16) {
+    let mut selected = 0;
+    loop {
+        match sync_with_master(&server, &host, port, &mut selected).await {
+            Ok(()) => notice!("Connection with master lost"),
+            Err(err) => warning!("Error condition on socket for SYNC with {}:{}: {}", host, port, err),
+        }
+        server.replication.set_link_state(LinkState::Connect);
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn sync_with_master(server: &Server, host: &str, port: u16, selected: &mut usize) -> resp::Result<()> {
+    let repl = &server.replication;
+    repl.set_link_state(LinkState::Connecting);
+    let mut connection = Connection::new(TcpStream::connect((host, port)).await?);
+    // 主库开启了认证时PING也会被拒绝，要先认证
+    let auth = server.config.read().unwrap().auth_args();
+    if !auth.is_empty() {
+        let parts: Vec<&str> = std::iter::once("AUTH").chain(auth.iter().map(String::as_str)).collect();
+        request(&mut connection, &parts).await?;
+    }
+    request(&mut connection, &["PING"]).await?;
+    let listening_port = server.config.read().unwrap().port.to_string();
+    request(&mut connection, &["REPLCONF", "listening-port", &listening_port]).await?;
+    request(&mut connection, &["REPLCONF", "capa", "psync2"]).await?;
+    let (replid, offset) = {
+        let state = repl.state.lock().unwrap();
+        (state.replid.clone(), state.offset)
+    };
+    let reply = request(&mut connection, &["PSYNC", &replid, &(offset + 1).to_string()]).await?;
+    let reply = match reply {
+        Frame::Simple(reply) => reply,
+        other => return Err(io::Error::other(format!("unexpected reply to PSYNC: {:?}", other)).into()),
+    };
+    let parts: Vec<&str> = reply.split_whitespace().collect();
+    match parts[..] {
+        ["FULLRESYNC", replid, offset] => {
+            let offset = offset.parse().map_err(|_| io::Error::other("invalid offset in FULLRESYNC"))?;
+            notice!("Full resync from master: {}:{}", replid, offset);
+            repl.set_link_state(LinkState::Sync);
+            let payload = connection.read_payload().await?;
+            let count = load_snapshot(server, replid.to_string(), offset, &payload)?;
+            *selected = 0;
+            notice!("MASTER <-> REPLICA sync: Finished with success, {} keys loaded", count);
+        }
+        ["CONTINUE", replid] => {
+            notice!("Successful partial resynchronization with master, offset {}", offset);
+            repl.switch_replid(replid.to_string());
+        }
+        ["CONTINUE"] => notice!("Successful partial resynchronization with master, offset {}", offset),
+        _ => return Err(io::Error::other(format!("unexpected reply to PSYNC: {}", reply)).into()),
+    }
+    repl.set_link_state(LinkState::Connected);
+    let mut ack = tokio::time::interval(ACK_PERIOD);
+    let mut last_received = Instant::now();
+    loop {
+        tokio::select! {
+            frame = connection.read_frame() => match frame? {
+                Some(frame) => {
+                    last_received = Instant::now();
+                    apply(server, frame, selected)?;
+                }
+                None => return Ok(()),
+            },
+            _ = ack.tick() => {
+                if last_received.elapsed() > REPL_TIMEOUT {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "timeout, no data nor PING received").into());
+                }
+                let offset = repl.state.lock().unwrap().offset.to_string();
+                connection.write_frame(&command_frame("replconf", &[Bytes::from("ACK"), Bytes::from(offset)])).await?;
+            }
+        }
+    }
+}
+
+/// 握手阶段发一条命令并等待回复，主库回复错误时返回Err
+async fn request(connection: &mut Connection, parts: &[&str]) -> resp::Result<Frame> {
+    let args: Vec<Bytes> = parts[1..].iter().map(|part| Bytes::from(part.to_string())).collect();
+    connection.write_frame(&command_frame(parts[0], &args)).await?;
+    match tokio::time::timeout(REPL_TIMEOUT, connection.read_frame()).await {
+        Ok(Ok(Some(Frame::Error(err)))) => Err(io::Error::other(format!("master replied to {}: {}", parts[0], err)).into()),
+        Ok(Ok(Some(frame))) => Ok(frame),
+        Ok(Ok(None)) => Err(io::Error::from(io::ErrorKind::ConnectionReset).into()),
+        Ok(Err(err)) => Err(err),
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timeout waiting for master").into()),
+    }
+}
+
+/// 用主库的快照替换整个键空间，加载期间客户端看不到新旧数据混在一起的状态
+fn load_snapshot(server: &Server, replid: String, offset: u64, data: &[u8]) -> io::Result<usize> {
+    let entries = rdb::decode(data)?;
+    if entries.iter().any(|(index, ..)| *index >= server.db.databases()) {
+        return Err(io::Error::other("master has more databases than configured"));
+    }
+    let now = Instant::now();
+    let mut count = 0;
+    {
+        let mut db = server.db.lock_all(0);
+        db.clear_all();
+        for (index, key, value, expires_at) in entries {
+            let expires_at = expires_at.map(from_unix_millis);
+            if expires_at.is_some_and(|when| when <= now) {
+                continue;
+            }
+            db.select(index);
+            db.insert_with_expire(key, value, expires_at);
+            count += 1;
+        }
+        server.replication.reset(replid, offset);
+    }
+    // AOF里还是旧的数据，用新数据重写一次
+    if server.aof.is_some() {
+        if let Err(Frame::Error(err)) = aof::rewrite(server) {
+            warning!("Can't rewrite the AOF after the full resync: {}", err);
+        }
+    }
+    Ok(count)
+}
+
+/// 执行主库发来的一条命令，selected是复制流当前选择的数据库。
+/// 从库不检查只读和内存限制，命令执行出错只记日志，偏移量照样累加
+fn apply(server: &Server, frame: Frame, selected: &mut usize) -> resp::Result<()> {
+    let mut data = BytesMut::new();
+    frame.encode(&mut data, Protocol::Resp2);
+    let cmd = Command::from_frame(frame).map_err(|_| resp::Error::Protocol("expected a command from master".to_string()))?;
+    if cmd.name == "select" {
+        match cmd.args.first().map(|arg| cmd::parse_db_index(arg, server.db.databases())) {
+            Some(Ok(index)) => *selected = index,
+            _ => warning!("Can't select {:?} from master", cmd.args),
+        }
+        server.replication.feed_raw(&data);
+        return Ok(());
+    }
+    match cmd::check(&cmd) {
+        Ok(spec) if cmd.name != "ping" => {
+            // 持有分片锁时写进积压缓冲区，全量同步给下级从库的快照和偏移量才能对得上
+            let mut db = server.db.lock(*selected, spec.keys.keys(&cmd.args));
+            if let Frame::Error(err) = cmd::call(server, spec, &cmd, &mut db) {
+                warning!("Error executing '{}' from master: {}", cmd.name, err);
+            }
+            server.replication.feed_raw(&data);
+        }
+        Ok(_) => server.replication.feed_raw(&data),
+        Err(err) => {
+            warning!("Can't execute '{}' from master: {:?}", cmd.name, err);
+            server.replication.feed_raw(&data);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use bytes::Bytes;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+    use resp::{Connection, Frame};
+    use crate::server::Server;
+    use crate::tests::{start_server, start_shared_server};
+
+    /// 把连接转发到target，可以随时切断所有转发中的连接，模拟网络闪断
+    struct Proxy {
+        addr: SocketAddr,
+        connections: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    }
+
+    impl Proxy {
+        async fn start(target: SocketAddr) -> Proxy {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let connections = Arc::new(Mutex::new(Vec::new()));
+            let connections0 = connections.clone();
+            tokio::spawn(async move {
+                while let Ok((mut inbound, _)) = listener.accept().await {
+                    let mut outbound = TcpStream::connect(target).await.unwrap();
+                    let handle = tokio::spawn(async move {
+                        let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                    });
+                    connections0.lock().unwrap().push(handle);
+                }
+            });
+            Proxy { addr, connections }
+        }
+
+        fn cut(&self) {
+            self.connections.lock().unwrap().drain(..).for_each(|handle| handle.abort());
+        }
+    }
+
+    async fn request(connection: &mut Connection, parts: &[&str]) -> Frame {
+        let frame = Frame::Array(parts.iter().map(|part| Frame::Bulk(Bytes::from(part.to_string()))).collect());
+        connection.write_frame(&frame).await.unwrap();
+        connection.read_frame().await.unwrap().unwrap()
+    }
+
+    /// 复制是异步的，反复查询直到从库上的结果符合预期
+    async fn wait_for(connection: &mut Connection, parts: &[&str], expected: Frame) {
+        let mut last = Frame::Null;
+        for _ in 0..100 {
+            last = request(connection, parts).await;
+            if last == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("{:?}: expected {:?}, got {:?}", parts, expected, last);
+    }
+
+    fn bulk(s: &str) -> Frame {
+        Frame::Bulk(Bytes::from(s.to_string()))
+    }
+
+    #[tokio::test]
+    async fn test_replication() {
+        let master = Arc::new(Server::new());
+        let master_addr = start_shared_server(master.clone()).await;
+        let mut client = Connection::new(TcpStream::connect(master_addr).await.unwrap());
+        // 全量同步之前就有的数据通过快照传过去
+        request(&mut client, &["SET", "a", "1"]).await;
+        request(&mut client, &["SET", "t", "1", "EX", "100"]).await;
+        let proxy = Proxy::start(master_addr).await;
+        let mut replica = Connection::new(TcpStream::connect(start_server().await).await.unwrap());
+        let port = proxy.addr.port().to_string();
+        // 参数个数不对时报错，不会让连接崩溃
+        assert_eq!(request(&mut replica, &["REPLICAOF"]).await, Frame::error("ERR wrong number of arguments for 'replicaof' command"));
+        assert_eq!(request(&mut replica, &["PSYNC", "x"]).await, Frame::error("ERR wrong number of arguments for 'psync' command"));
+        assert_eq!(request(&mut replica, &["REPLICAOF", "127.0.0.1", &port]).await, Frame::ok());
+        wait_for(&mut replica, &["GET", "a"], bulk("1")).await;
+        assert!(matches!(request(&mut replica, &["TTL", "t"]).await, Frame::Integer(ttl) if ttl > 90));
+        // 之后的写命令通过复制流同步
+        request(&mut client, &["RPUSH", "list", "x", "y"]).await;
+        wait_for(&mut replica, &["LRANGE", "list", "0", "-1"], Frame::Array(vec![bulk("x"), bulk("y")])).await;
+        // 别的数据库上的写命令前面带着SELECT
+        request(&mut client, &["SELECT", "1"]).await;
+        request(&mut client, &["SET", "a", "db1"]).await;
+        request(&mut client, &["SELECT", "0"]).await;
+        request(&mut replica, &["SELECT", "1"]).await;
+        wait_for(&mut replica, &["GET", "a"], bulk("db1")).await;
+        request(&mut replica, &["SELECT", "0"]).await;
+        assert_eq!(request(&mut replica, &["GET", "a"]).await, bulk("1"));
+        assert!(matches!(request(&mut replica, &["SET", "b", "1"]).await, Frame::Error(err) if err.starts_with("READONLY")));
+        assert!(matches!(request(&mut replica, &["REPLICAOF", "127.0.0.1", &port]).await, Frame::Simple(reply) if reply.contains("Already")));
+
+        // 断开期间的写命令在重连之后通过部分同步补上
+        proxy.cut();
+        request(&mut client, &["SET", "b", "2"]).await;
+        request(&mut client, &["DEL", "a"]).await;
+        wait_for(&mut replica, &["GET", "b"], bulk("2")).await;
+        assert_eq!(request(&mut replica, &["EXISTS", "a"]).await, Frame::Integer(0));
+        // 第一次连接时从库用自己的replid尝试过部分同步，和Redis一样计入失败次数
+        assert_eq!(master.replication.sync_stats(), (1, 1, 1));
+        match request(&mut replica, &["ROLE"]).await {
+            Frame::Array(parts) => assert_eq!((&parts[0], &parts[3]), (&bulk("slave"), &bulk("connected"))),
+            other => panic!("{:?}", other),
+        }
+
+        // 提升为主库之后可以写
+        assert_eq!(request(&mut replica, &["REPLICAOF", "NO", "ONE"]).await, Frame::ok());
+        assert_eq!(request(&mut replica, &["SET", "b", "3"]).await, Frame::ok());
+        assert!(matches!(request(&mut replica, &["ROLE"]).await, Frame::Array(parts) if parts[0] == bulk("master")));
+    }
+}
@@ -0,0 +1,356 @@
+//! 服务端脚本：用Rhai代替Redis的Lua，语义和EVAL一致。脚本里用KEYS和ARGV两个数组访问参数(下标从0开始)，
+//! 用`redis.command("SET", KEYS[0], ARGV[0])`执行命令，相当于Lua里的redis.call，命令出错时脚本中止并把错误
+//! 回给客户端；`redis.try_command`相当于redis.pcall，把错误作为`#{err: "..."}`返回。call在Rhai里是保留字，所以换了名字。
+//!
+//! 执行期间锁住所有声明过的键所在的分片，其他连接看不到执行到一半的状态，所以脚本只能访问声明过的键。
+//! 分片锁不能跨线程传递，脚本在专门的脚本线程上依次执行(和Redis一样同一时间只有一个脚本在运行)，
+//! 通过管道把命令交给持有锁的线程执行。持有锁的一端在运行时的阻塞线程池上，连接异步地等待结果，不占用运行时的工作线程。
+//! 执行时间超过script-time-limit毫秒的脚本会被中止，已经执行的写命令不会回滚。
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use resp::Frame;
+use rhai::{Array, Blob, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use sha1::{Digest, Sha1};
+use crate::cmd::{self, Command, Handler, DENY_OOM, NO_MULTI};
+use crate::db::Db;
+use crate::evict;
+use crate::server::Server;
+
+/// 每执行这么多步检查一次是否超时
+const PROGRESS_INTERVAL: u64 = 1024;
+
+/// 脚本里的redis常量，command和try_command是它的方法
+#[derive(Clone)]
+struct Redis;
+
+/// 给command和try_command注册每一种参数个数的版本
+macro_rules! register_calls {
+    ($engine:ident; $first:ident $(, $rest:ident)*) => {
+        $engine.register_fn("command", |_: &mut Redis, $first: Dynamic $(, $rest: Dynamic)*| command(&[$first $(, $rest)*], true));
+        $engine.register_fn("try_command", |_: &mut Redis, $first: Dynamic $(, $rest: Dynamic)*| command(&[$first $(, $rest)*], false));
+        register_calls!($engine; $($rest),*);
+    };
+    ($engine:ident;) => {};
+}
+
+/// 脚本线程上正在执行的脚本，command通过它把命令发给连接线程
+struct Session {
+    requests: Sender<Command>,
+    replies: Receiver<Frame>,
+    deadline: Instant,
+}
+
+thread_local! {
+    static SESSION: RefCell<Option<Session>> = const { RefCell::new(None) };
+}
+
+/// 交给脚本线程执行的一个脚本，结果通过result发回来
+struct Job {
+    ast: Arc<AST>,
+    keys: Vec<Bytes>,
+    argv: Vec<Bytes>,
+    time_limit: Duration,
+    requests: Sender<Command>,
+    replies: Receiver<Frame>,
+    result: Sender<Frame>,
+}
+
+/// 脚本引擎、按SHA1缓存的编译结果和脚本线程，EVAL和SCRIPT LOAD都会加入缓存
+pub struct Scripts {
+    engine: Arc<Engine>,
+    cache: Mutex<HashMap<String, Arc<AST>>>,
+    // 脚本线程在发送端drop时退出
+    jobs: Sender<Job>,
+}
+
+impl Scripts {
+    pub fn new() -> Scripts {
+        let mut engine = Engine::new();
+        engine.register_type_with_name::<Redis>("Redis");
+        // 最多16个参数，包括命令名
+        register_calls!(engine; a, b, c, d, e, f, g, h, i, j, k, l, m, n, o, p);
+        engine.on_progress(|ops| {
+            if ops % PROGRESS_INTERVAL != 0 {
+                return None;
+            }
+            SESSION.with(|session| match &*session.borrow() {
+                Some(session) if Instant::now() >= session.deadline => Some(Dynamic::UNIT),
+                _ => None,
+            })
+        });
+        let engine = Arc::new(engine);
+        let (jobs, receiver) = mpsc::channel();
+        let worker = engine.clone();
+        std::thread::Builder::new()
+            .name("script".to_string())
+            .spawn(move || receiver.into_iter().for_each(|job| execute_job(&worker, job)))
+            .expect("failed to spawn the script thread");
+        Scripts { engine, cache: Mutex::new(HashMap::new()), jobs }
+    }
+
+    /// 编译并缓存，返回脚本的SHA1
+    pub fn load(&self, source: &[u8]) -> Result<String, Frame> {
+        let sha = sha1_hex(source);
+        if self.cache.lock().unwrap().contains_key(&sha) {
+            return Ok(sha);
+        }
+        let source = std::str::from_utf8(source).map_err(|_| Frame::error("ERR Error compiling script: script is not valid UTF-8"))?;
+        let ast = self.engine.compile(source).map_err(|err| Frame::error(format!("ERR Error compiling script: {}", err)))?;
+        self.cache.lock().unwrap().insert(sha.clone(), Arc::new(ast));
+        Ok(sha)
+    }
+
+    pub fn get(&self, sha: &str) -> Option<Arc<AST>> {
+        self.cache.lock().unwrap().get(&sha.to_ascii_lowercase()).cloned()
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        self.cache.lock().unwrap().contains_key(&sha.to_ascii_lowercase())
+    }
+
+    pub fn flush(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+}
+
+impl Default for Scripts {
+    fn default() -> Self {
+        Scripts::new()
+    }
+}
+
+fn sha1_hex(data: &[u8]) -> String {
+    Sha1::digest(data).iter().fold(String::with_capacity(40), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+/// 在编号为index的数据库上执行脚本，user是连接认证的用户，脚本里的命令同样要检查权限。
+/// 加锁和替脚本执行命令都会阻塞，放到运行时的阻塞线程池上做，连接只是等待结果，单线程运行时上也能用
+pub async fn run(server: Arc<Server>, user: Option<String>, index: usize, ast: Arc<AST>, keys: Vec<Bytes>, argv: Vec<Bytes>) -> Frame {
+    tokio::task::spawn_blocking(move || serve(&server, user.as_deref(), index, ast, keys, argv))
+        .await
+        .unwrap_or_else(|_| Frame::error("ERR Error running script: script thread panicked"))
+}
+
+/// 锁住脚本声明的键，把脚本交给脚本线程，替它执行命令直到脚本结束
+fn serve(server: &Server, user: Option<&str>, index: usize, ast: Arc<AST>, keys: Vec<Bytes>, argv: Vec<Bytes>) -> Frame {
+    // 加锁之后就不能再淘汰了，先把内存腾出来，失败时只拒绝脚本里会增加内存的命令
+    let oom = evict::free_memory(server).err();
+    let time_limit = Duration::from_millis(server.config.read().unwrap().script_time_limit);
+    let mut db = server.db.lock(index, &keys);
+    let (request_sender, requests) = mpsc::channel();
+    let (reply_sender, replies) = mpsc::channel();
+    let (result_sender, result) = mpsc::channel();
+    let job = Job {
+        ast,
+        keys: keys.clone(),
+        argv,
+        time_limit,
+        requests: request_sender,
+        replies,
+        result: result_sender,
+    };
+    if server.scripts.jobs.send(job).is_err() {
+        return Frame::error("ERR Error running script: script thread is not running");
+    }
+    // 脚本结束时发送端被drop，循环随之结束
+    for cmd in requests {
+        let reply = execute(server, user, &keys, oom.as_ref(), &cmd, &mut db);
+        if reply_sender.send(reply).is_err() {
+            break;
+        }
+    }
+    result.recv().unwrap_or_else(|_| Frame::error("ERR Error running script: script thread panicked"))
+}
+
+/// 在脚本线程上执行一个脚本，脚本panic时只丢掉这一个，线程继续处理后面的
+fn execute_job(engine: &Engine, job: Job) {
+    let Job { ast, keys, argv, time_limit, requests, replies, result } = job;
+    let session = Session { requests, replies, deadline: Instant::now() + time_limit };
+    SESSION.with(|current| *current.borrow_mut() = Some(session));
+    let ans = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut vars = Scope::new();
+        vars.push_constant("redis", Redis);
+        vars.push_constant("KEYS", keys.iter().map(bytes_to_dynamic).collect::<Array>());
+        vars.push_constant("ARGV", argv.iter().map(bytes_to_dynamic).collect::<Array>());
+        engine.eval_ast_with_scope::<Dynamic>(&mut vars, &ast)
+    }));
+    // 先释放请求的发送端，连接线程才会结束循环去等结果
+    SESSION.with(|current| current.borrow_mut().take());
+    if let Ok(ans) = ans {
+        let _ = result.send(match ans {
+            Ok(value) => dynamic_to_frame(value),
+            Err(err) => script_error(*err, time_limit),
+        });
+    }
+}
+
+/// 在连接线程上执行脚本发来的一条命令
+fn execute(server: &Server, user: Option<&str>, keys: &[Bytes], oom: Option<&Frame>, cmd: &Command, db: &mut Db) -> Frame {
+    let spec = match cmd::check(cmd) {
+        Ok(spec) => spec,
+        Err(err) => return err,
+    };
+    // 会锁住所有分片或者改变连接状态的命令在脚本里没法执行
+    if spec.has(NO_MULTI) || matches!(spec.handler, Handler::Connection) {
+        return Frame::error("ERR This Redis command is not allowed from script");
+    }
+    if let Some(user) = user {
+        if let Err(err) = server.acl.check(user, spec, cmd) {
+            return err;
+        }
+    }
+    if spec.keys.keys(&cmd.args).any(|key| !keys.contains(key)) {
+        return Frame::error("ERR Script attempted to access a key that was not declared in the keys argument");
+    }
+    if let Err(err) = cmd::check_writable(server, spec) {
+        return err;
+    }
+    match oom {
+        Some(err) if spec.has(DENY_OOM) => err.clone(),
+        _ => cmd::call(server, spec, cmd, db),
+    }
+}
+
+/// redis.command和redis.try_command，在脚本线程上执行。raise为true时遇到错误回复就中止脚本，否则把错误作为#{err: ...}返回
+fn command(args: &[Dynamic], raise: bool) -> Result<Dynamic, Box<EvalAltResult>> {
+    let mut parts = Vec::with_capacity(args.len());
+    for arg in args.iter() {
+        parts.push(Frame::Bulk(dynamic_to_bytes(arg).ok_or("Command arguments must be strings or integers")?));
+    }
+    let cmd = Command::from_frame(Frame::Array(parts)).map_err(|_| "Please specify at least one argument for redis.command")?;
+    let reply = SESSION.with(|session| {
+        let session = session.borrow();
+        let session = session.as_ref().ok_or("redis.command is only available while running a script")?;
+        session.requests.send(cmd).map_err(|_| "script is no longer running")?;
+        session.replies.recv().map_err(|_| "script is no longer running")
+    })?;
+    match reply {
+        Frame::Error(msg) if raise => Err(Box::new(EvalAltResult::ErrorRuntime(error_map(msg), rhai::Position::NONE))),
+        Frame::Error(msg) => Ok(error_map(msg)),
+        reply => Ok(frame_to_dynamic(reply)),
+    }
+}
+
+fn error_map(msg: String) -> Dynamic {
+    let mut map = Map::new();
+    map.insert("err".into(), msg.into());
+    map.into()
+}
+
+fn script_error(err: EvalAltResult, time_limit: Duration) -> Frame {
+    match err {
+        EvalAltResult::ErrorTerminated(..) => {
+            Frame::error(format!("ERR Script killed after exceeding the time limit of {} milliseconds", time_limit.as_millis()))
+        }
+        // redis.command出错时把命令的错误原样回给客户端
+        EvalAltResult::ErrorRuntime(value, _) if value.is_map() => {
+            match value.cast::<Map>().get("err").map(|err| err.to_string()) {
+                Some(err) => Frame::error(err),
+                None => Frame::error("ERR Error running script: thrown map has no err field"),
+            }
+        }
+        err => Frame::error(format!("ERR Error running script: {}", err).replace(['\r', '\n'], " ")),
+    }
+}
+
+/// 合法的UTF-8转成字符串，否则转成Blob
+fn bytes_to_dynamic(bytes: &Bytes) -> Dynamic {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.into(),
+        Err(_) => Dynamic::from_blob(bytes.to_vec()),
+    }
+}
+
+fn dynamic_to_bytes(value: &Dynamic) -> Option<Bytes> {
+    if value.is_string() || value.is_char() || value.is_int() || value.is_float() {
+        Some(Bytes::from(value.to_string()))
+    } else if value.is_blob() {
+        Some(Bytes::from(value.clone().cast::<Blob>()))
+    } else {
+        None
+    }
+}
+
+/// 命令的回复转换成脚本里的值，和RESP2一样Map展开成键值交替的数组
+fn frame_to_dynamic(frame: Frame) -> Dynamic {
+    match frame {
+        Frame::Simple(s) | Frame::Error(s) | Frame::BigNumber(s) => s.into(),
+        Frame::Bulk(bytes) | Frame::Verbatim(_, bytes) => bytes_to_dynamic(&bytes),
+        Frame::Integer(n) => n.into(),
+        Frame::Null => Dynamic::UNIT,
+        Frame::Boolean(b) => b.into(),
+        Frame::Double(d) => d.into(),
+        Frame::Array(items) | Frame::Set(items) | Frame::Push(items) => items.into_iter().map(frame_to_dynamic).collect::<Array>().into(),
+        Frame::Map(pairs) => pairs.into_iter().flat_map(|(key, value)| [frame_to_dynamic(key), frame_to_dynamic(value)]).collect::<Array>().into(),
+        Frame::Attribute(_, frame) => frame_to_dynamic(*frame),
+    }
+}
+
+/// 脚本的返回值转换成回复：()是Null，true是1，false是Null，浮点数截断成整数，
+/// `#{err: ...}`是错误回复，`#{ok: ...}`是状态回复，其他Map按RESP3的Map返回
+fn dynamic_to_frame(value: Dynamic) -> Frame {
+    if value.is_unit() {
+        Frame::Null
+    } else if value.is_bool() {
+        if value.as_bool().unwrap() { Frame::Integer(1) } else { Frame::Null }
+    } else if value.is_int() {
+        Frame::Integer(value.as_int().unwrap())
+    } else if value.is_float() {
+        Frame::Integer(value.as_float().unwrap() as i64)
+    } else if value.is_array() {
+        Frame::Array(value.cast::<Array>().into_iter().map(dynamic_to_frame).collect())
+    } else if value.is_map() {
+        let map = value.cast::<Map>();
+        if let Some(err) = map.get("err") {
+            return Frame::error(err.to_string());
+        }
+        if let Some(ok) = map.get("ok") {
+            return Frame::Simple(ok.to_string());
+        }
+        Frame::Map(map.into_iter().map(|(key, value)| (Frame::Bulk(Bytes::from(key.to_string())), dynamic_to_frame(value))).collect())
+    } else {
+        dynamic_to_bytes(&value).map_or_else(|| Frame::Bulk(Bytes::from(value.to_string())), Frame::Bulk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use resp::Frame;
+    use crate::script::{dynamic_to_frame, frame_to_dynamic, Scripts};
+
+    #[test]
+    fn test_load() {
+        let scripts = Scripts::new();
+        // 和Redis一样是脚本内容的SHA1
+        assert_eq!(scripts.load(b"").unwrap(), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        let sha = scripts.load(b"1 + 1").unwrap();
+        assert!(scripts.exists(&sha.to_uppercase()));
+        assert!(matches!(scripts.load(b"let"), Err(Frame::Error(err)) if err.starts_with("ERR Error compiling script")));
+        scripts.flush();
+        assert!(scripts.get(&sha).is_none());
+    }
+
+    #[test]
+    fn test_conversions() {
+        let reply = Frame::Array(vec![Frame::Bulk(Bytes::from("v")), Frame::Integer(3), Frame::Null]);
+        assert_eq!(dynamic_to_frame(frame_to_dynamic(reply.clone())), reply);
+        let scripts = Scripts::new();
+        let eval = |source: &str| dynamic_to_frame(scripts.engine.eval(source).unwrap());
+        assert_eq!(eval("true"), Frame::Integer(1));
+        assert_eq!(eval("false"), Frame::Null);
+        assert_eq!(eval("3.7"), Frame::Integer(3));
+        assert_eq!(eval(r#"#{ok: "done"}"#), Frame::Simple("done".to_string()));
+        assert_eq!(eval(r#"#{err: "ERR failed"}"#), Frame::error("ERR failed"));
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use tokio::sync::{Notify, Semaphore, SemaphorePermit};
+use resp::Frame;
+use crate::acl::Acl;
+use crate::aof::{self, Aof};
+use crate::cluster::Cluster;
+use crate::config::Config;
+use crate::db::Keyspace;
+use crate::monitor::Monitors;
+use crate::pubsub::PubSub;
+use crate::rdb::Rdb;
+use crate::replication::{self, Replication};
+use crate::script::Scripts;
+use crate::shutdown::Shutdown;
+use crate::slowlog::SlowLog;
+
+/// 所有连接共享的服务端状态
+pub struct Server {
+    pub config: RwLock<Config>,
+    pub clients: Clients,
+    pub db: Arc<Keyspace>,
+    pub pubsub: PubSub,
+    pub rdb: Arc<Rdb>,
+    /// 没有开启appendonly时为None
+    pub aof: Option<Arc<Aof>>,
+    pub replication: Replication,
+    /// 没有开启cluster-enabled时为None
+    pub cluster: Option<Cluster>,
+    pub shutdown: Shutdown,
+    pub stats: Stats,
+    pub slowlog: SlowLog,
+    pub monitors: Monitors,
+    pub acl: Acl,
+    pub scripts: Scripts,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server::with_config(Config::default())
+    }
+
+    /// 测试用
+    #[cfg(test)]
+    pub fn with_shards(shards: usize) -> Server {
+        Server::with_config(Config { shards, ..Config::default() })
+    }
+
+    /// 按配置创建，不会加载数据文件，也不会打开AOF和集群配置文件
+    pub fn with_config(config: Config) -> Server {
+        Server {
+            db: Arc::new(Keyspace::new(config.shards, config.databases)),
+            pubsub: PubSub::new(),
+            rdb: Arc::new(Rdb::new(&config.dbfilename)),
+            aof: None,
+            replication: Replication::new(config.repl_backlog_size as usize),
+            cluster: None,
+            clients: Clients::new(config.maxclients),
+            acl: Acl::new(&config.requirepass),
+            config: RwLock::new(config),
+            shutdown: Shutdown::new(),
+            stats: Stats::new(),
+            slowlog: SlowLog::new(),
+            monitors: Monitors::new(),
+            scripts: Scripts::new(),
+        }
+    }
+
+    /// 把执行成功的写命令写进AOF并发给从库，调用时还持有命令涉及的分片锁，顺序和执行顺序一致
+    pub fn propagate(&self, index: usize, frames: &[Frame]) {
+        if let Some(aof) = &self.aof {
+            aof::feed(aof, index, frames);
+        }
+        self.replication.feed(index, frames);
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
+}
+
+/// INFO stats里的计数器
+pub struct Stats {
+    pub started: Instant,
+    /// 每次启动随机生成，INFO server里的run_id
+    pub run_id: String,
+    pub connections: AtomicU64,
+    pub rejected_connections: AtomicU64,
+    pub commands: AtomicU64,
+    pub evicted_keys: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            started: Instant::now(),
+            run_id: replication::new_replid(),
+            connections: AtomicU64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            commands: AtomicU64::new(0),
+            evicted_keys: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+
+/// 连接的类型，CLIENT LIST的flags和CLIENT KILL TYPE使用
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientKind {
+    Normal,
+    PubSub,
+    Monitor,
+    /// 通过PSYNC连上来的从库
+    Replica,
+}
+
+impl ClientKind {
+    fn flag(&self) -> char {
+        match self {
+            ClientKind::Normal => 'N',
+            ClientKind::PubSub => 'P',
+            ClientKind::Monitor => 'O',
+            ClientKind::Replica => 'S',
+        }
+    }
+
+    /// CLIENT KILL TYPE的参数，和Redis一样slave是replica的别名
+    pub fn parse(name: &str) -> Option<ClientKind> {
+        match name {
+            "normal" => Some(ClientKind::Normal),
+            "pubsub" => Some(ClientKind::PubSub),
+            "replica" | "slave" => Some(ClientKind::Replica),
+            "monitor" => Some(ClientKind::Monitor),
+            _ => None,
+        }
+    }
+}
+
+struct ClientInfo {
+    name: String,
+    last_active: Instant,
+    last_cmd: String,
+    kind: ClientKind,
+    multi: bool,
+    db: usize,
+    // 认证过的用户，None表示还没有认证
+    user: Option<String>,
+}
+
+/// 一个连接，CLIENT LIST和CLIENT KILL通过它查看和断开其他连接
+pub struct Client {
+    pub id: u64,
+    pub addr: SocketAddr,
+    created: Instant,
+    info: Mutex<ClientInfo>,
+    killed: Notify,
+}
+
+impl Client {
+    pub fn name(&self) -> String {
+        self.info.lock().unwrap().name.clone()
+    }
+
+    pub fn set_name(&self, name: String) {
+        self.info.lock().unwrap().name = name;
+    }
+
+    pub fn kind(&self) -> ClientKind {
+        self.info.lock().unwrap().kind
+    }
+
+    pub fn set_kind(&self, kind: ClientKind) {
+        self.info.lock().unwrap().kind = kind;
+    }
+
+    /// 当前选择的数据库
+    pub fn db(&self) -> usize {
+        self.info.lock().unwrap().db
+    }
+
+    pub fn set_db(&self, db: usize) {
+        self.info.lock().unwrap().db = db;
+    }
+
+    /// 认证过的用户名
+    pub fn user(&self) -> Option<String> {
+        self.info.lock().unwrap().user.clone()
+    }
+
+    pub fn set_user(&self, user: Option<String>) {
+        self.info.lock().unwrap().user = user;
+    }
+
+    /// 每条命令执行完之后调用
+    pub fn record(&self, cmd: &str, multi: bool) {
+        let mut info = self.info.lock().unwrap();
+        info.last_active = Instant::now();
+        info.last_cmd = cmd.to_string();
+        info.multi = multi;
+    }
+
+    /// 让连接断开，连接所在的任务收到通知后关闭连接
+    pub fn kill(&self) {
+        self.killed.notify_one();
+    }
+
+    /// 等到被CLIENT KILL为止
+    pub async fn killed(&self) {
+        self.killed.notified().await
+    }
+
+    /// CLIENT LIST里的一行
+    pub fn describe(&self) -> String {
+        let info = self.info.lock().unwrap();
+        let mut flags = info.kind.flag().to_string();
+        if info.multi {
+            flags.push('x');
+        }
+        format!(
+            "id={} addr={} name={} age={} idle={} flags={} db={} user={} cmd={}",
+            self.id, self.addr, info.name, self.created.elapsed().as_secs(), info.last_active.elapsed().as_secs(),
+            flags, info.db, info.user.as_deref().unwrap_or("default"), if info.last_cmd.is_empty() { "NULL" } else { &info.last_cmd },
+        )
+    }
+}
+
+/// 连接数限制：每个连接持有一个许可，许可用完时拒绝新连接。
+/// 调小maxclients时不会断开已有的连接，多出来的许可等连接断开时再回收
+pub struct Clients {
+    semaphore: Semaphore,
+    limit: Mutex<usize>,
+    // 还没回收的许可数
+    debt: AtomicUsize,
+    // 所有连接，按id排序
+    registry: Mutex<BTreeMap<u64, Arc<Client>>>,
+    next_id: AtomicU64,
+}
+
+impl Clients {
+    pub fn new(limit: usize) -> Clients {
+        Clients {
+            semaphore: Semaphore::new(limit),
+            limit: Mutex::new(limit),
+            debt: AtomicUsize::new(0),
+            registry: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// 登记一个新连接，返回值drop时注销
+    pub fn register(&self, addr: SocketAddr) -> Registration<'_> {
+        let now = Instant::now();
+        let client = Arc::new(Client {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            addr,
+            created: now,
+            info: Mutex::new(ClientInfo { name: String::new(), last_active: now, last_cmd: String::new(), kind: ClientKind::Normal, multi: false, db: 0, user: None }),
+            killed: Notify::new(),
+        });
+        self.registry.lock().unwrap().insert(client.id, client.clone());
+        Registration { clients: self, client }
+    }
+
+    pub fn list(&self) -> Vec<Arc<Client>> {
+        self.registry.lock().unwrap().values().cloned().collect()
+    }
+
+    /// 达到上限时返回None
+    pub fn try_acquire(&self) -> Option<ClientPermit<'_>> {
+        self.semaphore.try_acquire().ok().map(|permit| ClientPermit { clients: self, permit: Some(permit) })
+    }
+
+    /// 当前的连接数
+    pub fn count(&self) -> usize {
+        let limit = *self.limit.lock().unwrap();
+        (limit + self.debt.load(Ordering::Acquire)).saturating_sub(self.semaphore.available_permits())
+    }
+
+    /// CONFIG SET maxclients时调用
+    pub fn set_limit(&self, new: usize) {
+        let mut limit = self.limit.lock().unwrap();
+        if new >= *limit {
+            // 先抵消还没回收的许可
+            let mut added = new - *limit;
+            let repaid = self.debt.fetch_update(Ordering::AcqRel, Ordering::Acquire, |debt| Some(debt - debt.min(added))).unwrap();
+            added -= repaid.min(added);
+            self.semaphore.add_permits(added);
+        } else {
+            let mut removed = *limit - new;
+            while removed > 0 {
+                match self.semaphore.try_acquire() {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => break,
+                }
+                removed -= 1;
+            }
+            self.debt.fetch_add(removed, Ordering::AcqRel);
+        }
+        *limit = new;
+    }
+}
+
+/// 连接断开时从列表里删掉
+pub struct Registration<'a> {
+    clients: &'a Clients,
+    pub client: Arc<Client>,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.clients.registry.lock().unwrap().remove(&self.client.id);
+    }
+}
+
+/// 连接断开时归还许可
+pub struct ClientPermit<'a> {
+    clients: &'a Clients,
+    permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Drop for ClientPermit<'_> {
+    fn drop(&mut self) {
+        let permit = self.permit.take().unwrap();
+        let debt = &self.clients.debt;
+        if debt.fetch_update(Ordering::AcqRel, Ordering::Acquire, |debt| debt.checked_sub(1)).is_ok() {
+            permit.forget();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::Clients;
+
+    #[test]
+    fn test_clients() {
+        let clients = Clients::new(2);
+        let first = clients.try_acquire().unwrap();
+        let second = clients.try_acquire().unwrap();
+        assert!(clients.try_acquire().is_none());
+        // 调小之后已有的连接不受影响，断开之后许可不再归还
+        clients.set_limit(1);
+        assert_eq!(clients.count(), 2);
+        drop(first);
+        assert_eq!(clients.count(), 1);
+        assert!(clients.try_acquire().is_none());
+        drop(second);
+        let third = clients.try_acquire().unwrap();
+        assert!(clients.try_acquire().is_none());
+        clients.set_limit(3);
+        let _more = [clients.try_acquire().unwrap(), clients.try_acquire().unwrap()];
+        assert!(clients.try_acquire().is_none());
+        assert_eq!(clients.count(), 3);
+        drop(third);
+    }
+}
@@ -0,0 +1,109 @@
+//! 优雅关闭：收到SIGINT/SIGTERM或者SHUTDOWN命令后不再接受新连接，已有的连接执行完手上的命令后断开，
+//! 所有连接都断开之后再把数据落盘，然后退出。
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use crate::rdb;
+use crate::server::Server;
+
+/// 等待连接断开的最长时间，卡在给慢客户端写数据的连接不能让服务端一直退不出去
+pub const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// 关闭前是否保存快照
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// 配置了保存规则时才保存，SIGINT/SIGTERM和不带参数的SHUTDOWN都是这种
+    Default,
+    Save,
+    NoSave,
+}
+
+/// 关闭信号，所有连接共享
+pub struct Shutdown {
+    sender: watch::Sender<Option<Mode>>,
+    // 自己持有一个接收端，发送时总有人接收，不会失败
+    receiver: watch::Receiver<Option<Mode>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (sender, receiver) = watch::channel(None);
+        Shutdown { sender, receiver }
+    }
+
+    /// 发起关闭，重复发起时以第一次的方式为准
+    pub fn trigger(&self, mode: Mode) {
+        if self.mode().is_none() {
+            let _ = self.sender.send(Some(mode));
+        }
+    }
+
+    pub fn mode(&self) -> Option<Mode> {
+        *self.receiver.borrow()
+    }
+
+    /// 等到发起关闭为止
+    pub async fn wait(&self) {
+        let mut receiver = self.receiver.clone();
+        while receiver.borrow().is_none() {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+/// 后台任务：收到SIGINT或者SIGTERM时发起关闭
+pub async fn listen_signals(server: Arc<Server>) {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut stream) => {
+                stream.recv().await;
+            }
+            Err(err) => {
+                warning!("Can't listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => notice!("Received SIGINT scheduling shutdown..."),
+        _ = terminate => notice!("Received SIGTERM scheduling shutdown..."),
+    }
+    server.shutdown.trigger(Mode::Default);
+}
+
+/// 所有连接都断开之后调用：AOF刷盘，按mode决定是否保存快照
+pub fn persist(server: &Server, mode: Mode) -> io::Result<()> {
+    if let Some(aof) = &server.aof {
+        notice!("Calling fsync() on the AOF file.");
+        aof.fsync()?;
+    }
+    let save = match mode {
+        Mode::Default => !server.config.read().unwrap().save.is_empty(),
+        Mode::Save => true,
+        Mode::NoSave => false,
+    };
+    if save {
+        notice!("Saving the final RDB snapshot before exiting.");
+        // 等正在进行的后台保存结束，否则SAVE会直接失败
+        while server.rdb.is_saving() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        rdb::save(server)?;
+        notice!("DB saved on disk");
+    }
+    Ok(())
+}
@@ -0,0 +1,102 @@
+//! 慢查询日志：执行时间超过slowlog-log-slower-than微秒的命令记在内存里，最多保留slowlog-max-len条，
+//! 执行时间只算命令本身，不包括读请求和写回复。
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use resp::Frame;
+use crate::acl;
+use crate::cmd::Command;
+use crate::server::Client;
+
+/// 和Redis一样，每条记录最多保存这么多个参数，每个参数最多保存这么多字节
+const MAX_ARGC: usize = 32;
+const MAX_ARG_LEN: usize = 128;
+
+struct Entry {
+    id: u64,
+    timestamp: u64,
+    // 微秒
+    duration: u64,
+    args: Vec<Bytes>,
+    addr: String,
+    name: String,
+}
+
+pub struct SlowLog {
+    // 最新的在前面
+    entries: Mutex<VecDeque<Entry>>,
+    next_id: AtomicU64,
+}
+
+impl SlowLog {
+    pub fn new() -> SlowLog {
+        SlowLog { entries: Mutex::new(VecDeque::new()), next_id: AtomicU64::new(0) }
+    }
+
+    /// 命令执行完之后调用，threshold和max_len是当时的配置
+    pub fn record(&self, threshold: i64, max_len: usize, client: &Client, cmd: &Command, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        if threshold < 0 || micros < threshold as u64 {
+            return;
+        }
+        // 参数里可能有密码
+        let redacted = [Bytes::from_static(b"(redacted)")];
+        let cmd_args = if acl::hides_args(&cmd.name) { &redacted[..] } else { &cmd.args[..] };
+        let total = cmd_args.len() + 1;
+        let mut args: Vec<Bytes> = std::iter::once(Bytes::from(cmd.name.clone()))
+            .chain(cmd_args.iter().cloned())
+            .take(if total > MAX_ARGC { MAX_ARGC - 1 } else { MAX_ARGC })
+            .map(|arg| if arg.len() > MAX_ARG_LEN {
+                let mut truncated = arg[..MAX_ARG_LEN].to_vec();
+                truncated.extend_from_slice(format!("... ({} more bytes)", arg.len() - MAX_ARG_LEN).as_bytes());
+                Bytes::from(truncated)
+            } else {
+                arg
+            })
+            .collect();
+        if total > MAX_ARGC {
+            args.push(Bytes::from(format!("... ({} more arguments)", total - MAX_ARGC + 1)));
+        }
+        let entry = Entry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            duration: micros,
+            args,
+            addr: client.addr.to_string(),
+            name: client.name(),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_front(entry);
+        entries.truncate(max_len);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// SLOWLOG GET的回复，最新的count条
+    pub fn get(&self, count: usize) -> Frame {
+        let bulk = |s: &str| Frame::Bulk(Bytes::from(s.to_string()));
+        Frame::Array(self.entries.lock().unwrap().iter().take(count).map(|entry| Frame::Array(vec![
+            Frame::Integer(entry.id as i64),
+            Frame::Integer(entry.timestamp as i64),
+            Frame::Integer(entry.duration as i64),
+            Frame::Array(entry.args.iter().cloned().map(Frame::Bulk).collect()),
+            bulk(&entry.addr),
+            bulk(&entry.name),
+        ])).collect())
+    }
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        SlowLog::new()
+    }
+}
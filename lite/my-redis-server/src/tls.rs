@@ -0,0 +1,307 @@
+//! TLS：tls-port不为0时在这个端口上用rustls接受加密连接，普通端口照常工作，从库和集群节点之间仍然走普通端口。
+//! tls-cert-file和tls-key-file是PEM格式的服务端证书链和私钥，tls-auth-clients不为no时用tls-ca-cert-file
+//! 校验客户端证书，也就是双向TLS。
+
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use crate::config::Config;
+
+/// 握手超过这么久还没完成就断开，避免半开的连接一直占着
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// tls-auth-clients，和Redis不同的是默认不要求客户端证书
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthClients {
+    No,
+    /// 必须出示tls-ca-cert-file签发的证书
+    Yes,
+    /// 可以不出示证书，出示了就必须是合法的
+    Optional,
+}
+
+impl AuthClients {
+    pub fn parse(s: &str) -> Option<AuthClients> {
+        match s.to_ascii_lowercase().as_str() {
+            "no" => Some(AuthClients::No),
+            "yes" => Some(AuthClients::Yes),
+            "optional" => Some(AuthClients::Optional),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuthClients::No => "no",
+            AuthClients::Yes => "yes",
+            AuthClients::Optional => "optional",
+        }
+    }
+}
+
+/// 按配置加载证书和私钥，启动时调用，文件不存在或者格式不对时返回Err
+pub fn acceptor(config: &Config) -> io::Result<TlsAcceptor> {
+    let certs = load_certs(&config.tls_cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(&config.tls_key_file)
+        .map_err(|err| invalid(format!("can't load private key from {}: {}", config.tls_key_file, err)))?;
+    let builder = match config.tls_auth_clients {
+        AuthClients::No => ServerConfig::builder().with_no_client_auth(),
+        auth => {
+            if config.tls_ca_cert_file.is_empty() {
+                return Err(invalid("tls-ca-cert-file is required when tls-auth-clients is enabled".to_string()));
+            }
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(&config.tls_ca_cert_file)? {
+                roots.add(cert).map_err(|err| invalid(format!("invalid CA certificate in {}: {}", config.tls_ca_cert_file, err)))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = if auth == AuthClients::Optional { verifier.allow_unauthenticated() } else { verifier };
+            ServerConfig::builder().with_client_cert_verifier(verifier.build().map_err(|err| invalid(err.to_string()))?)
+        }
+    };
+    let config = builder.with_single_cert(certs, key).map_err(|err| invalid(err.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| invalid(format!("can't load certificates from {}: {}", path, err)))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates found in {}", path)));
+    }
+    Ok(certs)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// 监听的一个端口，acceptor不为None时是TLS端口
+pub struct Listener {
+    tcp: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+}
+
+impl Listener {
+    pub fn new(tcp: TcpListener, acceptor: Option<TlsAcceptor>) -> Listener {
+        Listener { tcp, acceptor }
+    }
+}
+
+/// 在所有端口上等待下一个连接，返回连接所在端口的acceptor，握手交给连接自己的任务去做，不能卡住接受新连接
+pub async fn accept(listeners: &[Listener]) -> io::Result<(TcpStream, SocketAddr, Option<TlsAcceptor>)> {
+    poll_fn(|cx| {
+        for listener in listeners {
+            if let Poll::Ready(accepted) = listener.tcp.poll_accept(cx) {
+                return Poll::Ready(accepted.map(|(socket, addr)| (socket, addr, listener.acceptor.clone())));
+            }
+        }
+        Poll::Pending
+    }).await
+}
+
+/// acceptor为None时直接用明文连接
+pub async fn handshake(socket: TcpStream, acceptor: Option<TlsAcceptor>) -> io::Result<Stream> {
+    let acceptor = match acceptor {
+        Some(acceptor) => acceptor,
+        None => return Ok(Stream::Tcp(socket)),
+    };
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+        Ok(stream) => Ok(Stream::Tls(Box::new(stream?))),
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timeout")),
+    }
+}
+
+/// 客户端连接，明文或者TLS
+pub enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use bytes::Bytes;
+    use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+    use resp::{Connection, Frame};
+    use crate::config::Config;
+    use crate::server::Server;
+    use crate::tls::{self, AuthClients, Listener};
+
+    /// 测试时生成的CA、CA签发的服务端证书和客户端证书，都是PEM格式
+    struct Pki {
+        ca: String,
+        server: (String, String),
+        client: (String, String),
+    }
+
+    fn generate() -> Pki {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+        let issue = |name: &str, usage: ExtendedKeyUsagePurpose| {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            params.extended_key_usages = vec![usage];
+            let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+            (cert.pem(), key.serialize_pem())
+        };
+        Pki {
+            server: issue("localhost", ExtendedKeyUsagePurpose::ServerAuth),
+            client: issue("client", ExtendedKeyUsagePurpose::ClientAuth),
+            ca: ca.pem(),
+        }
+    }
+
+    fn write(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("my-redis-tls-{}-{}.pem", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// 在随机端口上启动只接受TLS连接的服务端
+    async fn start_tls_server(pki: &Pki, auth_clients: AuthClients, tag: &str) -> SocketAddr {
+        let config = Config {
+            tls_cert_file: write(&format!("{}-server-cert", tag), &pki.server.0).to_string_lossy().to_string(),
+            tls_key_file: write(&format!("{}-server-key", tag), &pki.server.1).to_string_lossy().to_string(),
+            tls_ca_cert_file: write(&format!("{}-ca", tag), &pki.ca).to_string_lossy().to_string(),
+            tls_auth_clients: auth_clients,
+            ..Config::default()
+        };
+        let acceptor = tls::acceptor(&config).unwrap();
+        for path in [&config.tls_cert_file, &config.tls_key_file, &config.tls_ca_cert_file] {
+            std::fs::remove_file(path).unwrap();
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(crate::run(vec![Listener::new(listener, Some(acceptor))], Arc::new(Server::with_config(config))));
+        addr
+    }
+
+    /// 信任测试CA，identity不为None时出示客户端证书
+    async fn connect(addr: SocketAddr, pki: &Pki, identity: Option<&(String, String)>) -> std::io::Result<Connection<TlsStream<TcpStream>>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_slice(pki.ca.as_bytes()).unwrap()).unwrap();
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let config = match identity {
+            Some((cert, key)) => builder.with_client_auth_cert(
+                vec![CertificateDer::from_pem_slice(cert.as_bytes()).unwrap()],
+                PrivateKeyDer::from_pem_slice(key.as_bytes()).unwrap(),
+            ).unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        let socket = TcpStream::connect(addr).await?;
+        let stream = TlsConnector::from(Arc::new(config)).connect(ServerName::try_from("localhost").unwrap(), socket).await?;
+        Ok(Connection::new(stream))
+    }
+
+    async fn request(connection: &mut Connection<TlsStream<TcpStream>>, parts: &[&str]) -> resp::Result<Option<Frame>> {
+        let frame = Frame::Array(parts.iter().map(|part| Frame::Bulk(Bytes::from(part.to_string()))).collect());
+        connection.write_frame(&frame).await?;
+        connection.read_frame().await
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        let pki = generate();
+        let addr = start_tls_server(&pki, AuthClients::No, "plain").await;
+        let mut connection = connect(addr, &pki, None).await.unwrap();
+        assert_eq!(request(&mut connection, &["SET", "k", "v"]).await.unwrap(), Some(Frame::ok()));
+        assert_eq!(request(&mut connection, &["GET", "k"]).await.unwrap(), Some(Frame::Bulk(Bytes::from("v"))));
+        // 明文客户端连不上TLS端口
+        let mut plain = Connection::new(TcpStream::connect(addr).await.unwrap());
+        plain.write_frame(&Frame::Array(vec![Frame::Bulk(Bytes::from("PING"))])).await.unwrap();
+        assert!(!matches!(plain.read_frame().await, Ok(Some(Frame::Simple(_)))));
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let pki = generate();
+        let addr = start_tls_server(&pki, AuthClients::Yes, "mutual").await;
+        let mut connection = connect(addr, &pki, Some(&pki.client)).await.unwrap();
+        assert_eq!(request(&mut connection, &["PING"]).await.unwrap(), Some(Frame::Simple("PONG".to_string())));
+        // TLS 1.3里客户端在服务端校验证书之前就认为握手完成了，错误在之后的读写中才出现
+        let rejected = match connect(addr, &pki, None).await {
+            Ok(mut connection) => !matches!(request(&mut connection, &["PING"]).await, Ok(Some(_))),
+            Err(_) => true,
+        };
+        assert!(rejected);
+
+        let addr = start_tls_server(&pki, AuthClients::Optional, "optional").await;
+        for identity in [None, Some(&pki.client)] {
+            let mut connection = connect(addr, &pki, identity).await.unwrap();
+            assert_eq!(request(&mut connection, &["PING"]).await.unwrap(), Some(Frame::Simple("PONG".to_string())));
+        }
+    }
+
+    #[test]
+    fn test_acceptor_errors() {
+        let config = Config { tls_cert_file: "/nonexistent/cert.pem".to_string(), tls_key_file: "/nonexistent/key.pem".to_string(), ..Config::default() };
+        assert!(tls::acceptor(&config).is_err());
+        let pki = generate();
+        let config = Config {
+            tls_cert_file: write("errors-cert", &pki.server.0).to_string_lossy().to_string(),
+            tls_key_file: write("errors-key", &pki.server.1).to_string_lossy().to_string(),
+            tls_auth_clients: AuthClients::Yes,
+            ..Config::default()
+        };
+        // 要求客户端证书时必须配置CA
+        assert!(tls::acceptor(&config).is_err());
+        std::fs::remove_file(&config.tls_cert_file).unwrap();
+        std::fs::remove_file(&config.tls_key_file).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use bytes::Bytes;
+
+/// 键空间中的值。列表本来想直接用ds里的双向链表，但它基于Rc实现，不是Send的，
+/// 没法放进多个连接共享的键空间，所以这里用VecDeque
+#[derive(Clone, Debug)]
+pub enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Hash(HashMap<Bytes, Bytes>),
+    Set(HashSet<Bytes>),
+    ZSet(SortedSet),
+}
+
+impl Value {
+    /// TYPE命令返回的类型名
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+        }
+    }
+
+    /// 集合类型为空时键应该被删除
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::List(list) => list.is_empty(),
+            Value::Hash(hash) => hash.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::ZSet(zset) => zset.is_empty(),
+        }
+    }
+
+    /// 估算占用的内存。集合类型和Redis的MEMORY USAGE一样只看前几个元素，再按元素个数放大
+    pub fn memory_usage(&self) -> usize {
+        match self {
+            Value::String(val) => val.len(),
+            Value::List(list) => estimate(list.len(), list.iter().map(|item| item.len() + ELEMENT_OVERHEAD)),
+            Value::Hash(hash) => estimate(hash.len(), hash.iter().map(|(field, val)| field.len() + val.len() + 2 * ELEMENT_OVERHEAD)),
+            Value::Set(set) => estimate(set.len(), set.iter().map(|member| member.len() + ELEMENT_OVERHEAD)),
+            // 成员在哈希表和BTreeSet里各存一份
+            Value::ZSet(zset) => estimate(zset.len(), zset.iter().map(|(member, _)| 2 * (member.len() + ELEMENT_OVERHEAD))),
+        }
+    }
+}
+
+/// 集合中每个元素的固定开销，估算用
+const ELEMENT_OVERHEAD: usize = 16;
+/// 估算集合大小时取样的元素个数
+const MEMORY_SAMPLES: usize = 5;
+
+fn estimate(len: usize, sizes: impl Iterator<Item = usize>) -> usize {
+    let (count, total) = sizes.take(MEMORY_SAMPLES).fold((0, 0), |(count, total), size| (count + 1, total + size));
+    (total * len).checked_div(count).unwrap_or(0)
+}
+
+impl From<Bytes> for Value {
+    fn from(val: Bytes) -> Self {
+        Value::String(val)
+    }
+}
+
+/// 按f64::total_cmp排序的分数，用作BTreeSet的键
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Score(pub f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// 有序集合：哈希表负责按成员查分数，BTreeSet负责按(分数, 成员)排序
+#[derive(Clone, Debug, Default)]
+pub struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    ordered: BTreeSet<(Score, Bytes)>,
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &Bytes) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// 插入或更新成员的分数，返回旧分数
+    pub fn insert(&mut self, member: Bytes, score: f64) -> Option<f64> {
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            self.ordered.remove(&(Score(old), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        old
+    }
+
+    pub fn remove(&mut self, member: &Bytes) -> Option<f64> {
+        let score = self.scores.remove(member)?;
+        self.ordered.remove(&(Score(score), member.clone()));
+        Some(score)
+    }
+
+    /// 按分数从小到大的排名，从0开始
+    pub fn rank(&self, member: &Bytes) -> Option<usize> {
+        let score = self.scores.get(member)?;
+        Some(self.ordered.range(..(Score(*score), member.clone())).count())
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Bytes, f64)> {
+        self.ordered.iter().map(|(score, member)| (member, score.0))
+    }
+}
@@ -0,0 +1,32 @@
+//! 解析器的模糊测试：任意输入都不能panic，解析出来的帧重新编码后应该能原样解析回来。
+//! 运行：cargo +nightly fuzz run parse
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use resp::{parse, parse_request, Frame, Protocol};
+
+fuzz_target!(|data: &[u8]| {
+    let mut buffer = BytesMut::from(data);
+    while let Ok(Some(frame)) = parse_request(&mut buffer) {
+        let mut encoded = BytesMut::new();
+        frame.encode(&mut encoded, Protocol::Resp3);
+        let decoded = parse(&mut encoded).expect("encoded frame should parse").expect("encoded frame should be complete");
+        assert!(encoded.is_empty());
+        // NaN不等于自己，只比较编码结果
+        if !contains_nan(&frame) {
+            assert_eq!(decoded, frame);
+        }
+    }
+});
+
+fn contains_nan(frame: &Frame) -> bool {
+    match frame {
+        Frame::Double(val) => val.is_nan(),
+        Frame::Array(items) | Frame::Set(items) | Frame::Push(items) => items.iter().any(contains_nan),
+        Frame::Map(pairs) => pairs.iter().any(|(key, val)| contains_nan(key) || contains_nan(val)),
+        Frame::Attribute(attrs, frame) => contains_nan(frame) || attrs.iter().any(|(key, val)| contains_nan(key) || contains_nan(val)),
+        _ => false,
+    }
+}
@@ -0,0 +1,171 @@
+use std::io;
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::TcpStream;
+use crate::frame::{Frame, Protocol};
+use crate::parse::{parse_request_with, parse_with, Progress};
+use crate::{Error, Result};
+
+/// 在字节流上读写RESP帧，默认是TCP连接，也可以是任何实现了AsyncRead + AsyncWrite的流
+pub struct Connection<S = TcpStream> {
+    stream: BufWriter<S>,
+    buffer: BytesMut,
+    // 缓冲区里还不完整的帧已经检查到哪了，大帧分很多次读到时不用每次从头检查
+    progress: Progress,
+    output: BytesMut,
+    protocol: Protocol,
+    // 缓冲区里未完成的帧最多这么大，防止对端声明一个超长的bulk string把内存耗尽
+    max_frame_size: usize,
+}
+
+/// 默认的帧大小上限，和Redis的client-query-buffer-limit默认值一致
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024 * 1024;
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(stream: S) -> Connection<S> {
+        Connection {
+            stream: BufWriter::new(stream),
+            buffer: BytesMut::with_capacity(4 * 1024),
+            progress: Progress::default(),
+            output: BytesMut::with_capacity(4 * 1024),
+            protocol: Protocol::Resp2,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// 切换写出时使用的协议版本，服务端处理HELLO时调用
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    /// 读取一个帧，对端正常关闭时返回None
+    pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
+        self.read_with(parse_with).await
+    }
+
+    /// 读取客户端的请求，除了RESP数组之外还接受内联命令
+    pub async fn read_request(&mut self) -> Result<Option<Frame>> {
+        self.read_with(parse_request_with).await
+    }
+
+    async fn read_with(&mut self, parse: fn(&mut BytesMut, &mut Progress) -> Result<Option<Frame>>) -> Result<Option<Frame>> {
+        loop {
+            if let Some(frame) = parse(&mut self.buffer, &mut self.progress)? {
+                return Ok(Some(frame));
+            }
+            if self.buffer.len() > self.max_frame_size {
+                return Err(Error::Protocol(format!("frame exceeds the limit of {} bytes", self.max_frame_size)));
+            }
+            // 缓冲区里的数据不够一个完整的帧，继续从流里读
+            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+                return if self.buffer.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(Error::Io(io::ErrorKind::ConnectionReset.into()))
+                };
+            }
+        }
+    }
+
+    /// 读取`$<len>\r\n`和紧跟着的len个字节，结尾没有CRLF，主从全量同步时用来传输快照
+    pub async fn read_payload(&mut self) -> Result<Bytes> {
+        let len = loop {
+            if let Some(end) = self.buffer.windows(2).position(|window| window == b"\r\n") {
+                let line = self.buffer.split_to(end + 2);
+                let len = std::str::from_utf8(&line[..end]).ok()
+                    .and_then(|line| line.strip_prefix('$'))
+                    .and_then(|len| len.parse::<usize>().ok());
+                break len.ok_or_else(|| Error::Protocol("invalid payload length".to_string()))?;
+            }
+            if self.buffer.len() > 64 {
+                return Err(Error::Protocol("invalid payload length".to_string()));
+            }
+            self.fill().await?;
+        };
+        if len > self.max_frame_size {
+            return Err(Error::Protocol(format!("frame exceeds the limit of {} bytes", self.max_frame_size)));
+        }
+        while self.buffer.len() < len {
+            self.fill().await?;
+        }
+        Ok(self.buffer.split_to(len).freeze())
+    }
+
+    async fn fill(&mut self) -> Result<()> {
+        if 0 == self.stream.read_buf(&mut self.buffer).await? {
+            return Err(Error::Io(io::ErrorKind::ConnectionReset.into()));
+        }
+        Ok(())
+    }
+
+    pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        self.output.clear();
+        frame.encode(&mut self.output, self.protocol);
+        self.stream.write_all(&self.output).await?;
+        self.stream.flush().await
+    }
+
+    /// 直接写出已经编码好的数据，比如复制积压缓冲区里的命令
+    pub async fn write_raw(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(data).await?;
+        self.stream.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::io::{duplex, AsyncWriteExt};
+    use crate::connection::Connection;
+    use crate::frame::{Frame, Protocol};
+
+    #[tokio::test]
+    async fn test_connection() {
+        let (client, server) = duplex(64);
+        let mut client = Connection::new(client);
+        client.set_protocol(Protocol::Resp3);
+        let frame = Frame::Map(vec![(Frame::Bulk(Bytes::from("big")), Frame::Bulk(Bytes::from(vec![b'x'; 1000])))]);
+        // 帧比管道的容量大，要分几次读完
+        let expected = frame.clone();
+        tokio::spawn(async move { client.write_frame(&frame).await.unwrap() });
+        let mut server = Connection::new(server);
+        assert_eq!(server.read_frame().await.unwrap().unwrap(), expected);
+
+        let (client, server) = duplex(64);
+        let mut client = Connection::new(client);
+        tokio::spawn(async move { client.write_frame(&Frame::Bulk(Bytes::from(vec![b'x'; 1000]))).await });
+        let mut server = Connection::new(server);
+        server.set_max_frame_size(100);
+        assert!(server.read_frame().await.is_err());
+
+        // 对端关闭时，缓冲区里还有不完整的帧算出错
+        let (client, mut peer) = duplex(64);
+        peer.write_all(b"*1\r\n$4\r\nPI").await.unwrap();
+        drop(peer);
+        assert!(Connection::new(client).read_request().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_payload() {
+        let (client, server) = duplex(64);
+        let mut client = Connection::new(client);
+        tokio::spawn(async move {
+            client.write_raw(b"+FULLRESYNC id 0\r\n$100\r\n").await.unwrap();
+            client.write_raw(&[b'x'; 100]).await.unwrap();
+            client.write_raw(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+        });
+        let mut server = Connection::new(server);
+        assert_eq!(server.read_frame().await.unwrap().unwrap(), Frame::Simple("FULLRESYNC id 0".to_string()));
+        assert_eq!(server.read_payload().await.unwrap(), Bytes::from(vec![b'x'; 100]));
+        // 快照后面紧跟着的命令不受影响
+        assert_eq!(server.read_frame().await.unwrap().unwrap(), Frame::Array(vec![Frame::Bulk(Bytes::from("PING"))]));
+    }
+}
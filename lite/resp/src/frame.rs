@@ -0,0 +1,181 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// 连接使用的协议版本，默认是RESP2，客户端发送HELLO 3之后切换到RESP3
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+/// RESP帧。RESP3新增的类型在RESP2连接上会按Redis的规则降级编码，
+/// 所以命令处理函数可以直接返回Map、Set、Double，不用关心客户端用的哪个版本
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Null,
+    Array(Vec<Frame>),
+    Boolean(bool),
+    Double(f64),
+    /// 任意精度的整数，保留原始的十进制文本
+    BigNumber(String),
+    /// 带格式的字符串，比如txt、mkd
+    Verbatim(String, Bytes),
+    Map(Vec<(Frame, Frame)>),
+    Set(Vec<Frame>),
+    /// 服务端主动推送的消息，比如订阅收到的消息
+    Push(Vec<Frame>),
+    /// 附加在下一个帧上的属性
+    Attribute(Vec<(Frame, Frame)>, Box<Frame>),
+}
+
+impl Frame {
+    pub fn ok() -> Frame {
+        Frame::Simple("OK".to_string())
+    }
+
+    pub fn error(msg: impl ToString) -> Frame {
+        Frame::Error(msg.to_string())
+    }
+
+    /// 按协议版本把帧编码后追加到dst
+    pub fn encode(&self, dst: &mut BytesMut, protocol: Protocol) {
+        let resp3 = protocol == Protocol::Resp3;
+        match self {
+            Frame::Simple(val) => line(dst, b'+', val.as_bytes()),
+            // 简单错误里不能有换行，RESP3改用blob error，RESP2和Redis一样把换行替换成空格
+            Frame::Error(val) if val.contains(['\r', '\n']) && resp3 => blob(dst, b'!', val.as_bytes()),
+            Frame::Error(val) if val.contains(['\r', '\n']) => line(dst, b'-', val.replace(['\r', '\n'], " ").as_bytes()),
+            Frame::Error(val) => line(dst, b'-', val.as_bytes()),
+            Frame::Integer(val) => line(dst, b':', val.to_string().as_bytes()),
+            Frame::Bulk(val) => blob(dst, b'$', val),
+            Frame::Null if resp3 => dst.put_slice(b"_\r\n"),
+            Frame::Null => dst.put_slice(b"$-1\r\n"),
+            Frame::Array(items) => aggregate(dst, b'*', items, protocol),
+            Frame::Boolean(val) if resp3 => line(dst, b'#', if *val { b"t" } else { b"f" }),
+            Frame::Boolean(val) => line(dst, b':', if *val { b"1" } else { b"0" }),
+            Frame::Double(val) if resp3 => line(dst, b',', format_double(*val).as_bytes()),
+            Frame::Double(val) => blob(dst, b'$', format_double(*val).as_bytes()),
+            Frame::BigNumber(val) if resp3 => line(dst, b'(', val.as_bytes()),
+            Frame::BigNumber(val) => blob(dst, b'$', val.as_bytes()),
+            Frame::Verbatim(format, val) if resp3 => {
+                let mut data = BytesMut::with_capacity(format.len() + 1 + val.len());
+                data.put_slice(format.as_bytes());
+                data.put_u8(b':');
+                data.put_slice(val);
+                blob(dst, b'=', &data);
+            }
+            Frame::Verbatim(_, val) => blob(dst, b'$', val),
+            Frame::Map(pairs) if resp3 => {
+                line(dst, b'%', pairs.len().to_string().as_bytes());
+                encode_pairs(dst, pairs, protocol);
+            }
+            // RESP2没有map，展开成键值交替的数组
+            Frame::Map(pairs) => {
+                line(dst, b'*', (pairs.len() * 2).to_string().as_bytes());
+                encode_pairs(dst, pairs, protocol);
+            }
+            Frame::Set(items) if resp3 => aggregate(dst, b'~', items, protocol),
+            Frame::Push(items) if resp3 => aggregate(dst, b'>', items, protocol),
+            Frame::Set(items) | Frame::Push(items) => aggregate(dst, b'*', items, protocol),
+            Frame::Attribute(attrs, frame) => {
+                // RESP2客户端不认识属性，直接丢掉
+                if resp3 {
+                    line(dst, b'|', attrs.len().to_string().as_bytes());
+                    encode_pairs(dst, attrs, protocol);
+                }
+                frame.encode(dst, protocol);
+            }
+        }
+    }
+}
+
+/// 和Redis一样，无穷大写成inf、-inf，非数写成nan
+pub fn format_double(val: f64) -> String {
+    if val.is_nan() {
+        "nan".to_string()
+    } else {
+        val.to_string()
+    }
+}
+
+fn line(dst: &mut BytesMut, kind: u8, val: &[u8]) {
+    dst.put_u8(kind);
+    dst.put_slice(val);
+    dst.put_slice(b"\r\n");
+}
+
+fn blob(dst: &mut BytesMut, kind: u8, val: &[u8]) {
+    line(dst, kind, val.len().to_string().as_bytes());
+    dst.put_slice(val);
+    dst.put_slice(b"\r\n");
+}
+
+fn aggregate(dst: &mut BytesMut, kind: u8, items: &[Frame], protocol: Protocol) {
+    line(dst, kind, items.len().to_string().as_bytes());
+    for item in items {
+        item.encode(dst, protocol);
+    }
+}
+
+fn encode_pairs(dst: &mut BytesMut, pairs: &[(Frame, Frame)], protocol: Protocol) {
+    for (key, val) in pairs {
+        key.encode(dst, protocol);
+        val.encode(dst, protocol);
+    }
+}
+
+impl From<Bytes> for Frame {
+    fn from(val: Bytes) -> Self {
+        Frame::Bulk(val)
+    }
+}
+
+impl From<Option<Bytes>> for Frame {
+    fn from(val: Option<Bytes>) -> Self {
+        match val {
+            Some(val) => Frame::Bulk(val),
+            None => Frame::Null,
+        }
+    }
+}
+
+impl From<i64> for Frame {
+    fn from(val: i64) -> Self {
+        Frame::Integer(val)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use crate::frame::{Frame, Protocol};
+
+    fn encode(frame: &Frame, protocol: Protocol) -> String {
+        let mut dst = BytesMut::new();
+        frame.encode(&mut dst, protocol);
+        String::from_utf8(dst.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_downgrade() {
+        let map = Frame::Map(vec![(Frame::Bulk(Bytes::from("a")), Frame::Double(1.5))]);
+        assert_eq!(encode(&map, Protocol::Resp3), "%1\r\n$1\r\na\r\n,1.5\r\n");
+        assert_eq!(encode(&map, Protocol::Resp2), "*2\r\n$1\r\na\r\n$3\r\n1.5\r\n");
+        assert_eq!(encode(&Frame::Null, Protocol::Resp3), "_\r\n");
+        assert_eq!(encode(&Frame::Null, Protocol::Resp2), "$-1\r\n");
+        assert_eq!(encode(&Frame::Boolean(true), Protocol::Resp2), ":1\r\n");
+        assert_eq!(encode(&Frame::Double(f64::NEG_INFINITY), Protocol::Resp3), ",-inf\r\n");
+        let verbatim = Frame::Verbatim("txt".to_string(), Bytes::from("hi"));
+        assert_eq!(encode(&verbatim, Protocol::Resp3), "=6\r\ntxt:hi\r\n");
+        assert_eq!(encode(&verbatim, Protocol::Resp2), "$2\r\nhi\r\n");
+        let push = Frame::Push(vec![Frame::Integer(1)]);
+        assert_eq!(encode(&push, Protocol::Resp2), "*1\r\n:1\r\n");
+        let attr = Frame::Attribute(vec![(Frame::Simple("ttl".to_string()), Frame::Integer(3))], Box::new(Frame::ok()));
+        assert_eq!(encode(&attr, Protocol::Resp3), "|1\r\n+ttl\r\n:3\r\n+OK\r\n");
+        assert_eq!(encode(&attr, Protocol::Resp2), "+OK\r\n");
+    }
+}
@@ -0,0 +1,40 @@
+//! RESP2/RESP3协议的编解码，my-redis-server和my-redis-client共用。
+//! 解析分两步：先确认缓冲区里有一个完整的帧并算出长度，再把这段数据冻结成Bytes，
+//! bulk string直接引用其中的切片，不再复制。
+
+mod connection;
+mod frame;
+mod parse;
+
+use std::fmt;
+use std::io;
+
+pub use connection::{Connection, DEFAULT_MAX_FRAME_SIZE};
+pub use frame::{Frame, Protocol};
+pub use parse::{parse, parse_request};
+
+#[derive(Debug)]
+pub enum Error {
+    /// 对端发来的数据不符合协议
+    Protocol(String),
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
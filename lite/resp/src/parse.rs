@@ -0,0 +1,403 @@
+use bytes::{Bytes, BytesMut};
+use crate::frame::Frame;
+use crate::Error;
+
+/// 嵌套层数上限，防止恶意构造的深层嵌套把栈撑爆
+const MAX_DEPTH: usize = 64;
+/// 内联命令一行的长度上限，和Redis的PROTO_INLINE_MAX_SIZE一致
+const MAX_INLINE: usize = 64 * 1024;
+/// 聚合类型预分配的元素个数上限，长度字段是对端给的，不能直接拿来分配内存
+const MAX_PREALLOC: usize = 1024;
+
+enum ParseError {
+    /// 数据还不够一个完整的帧
+    Incomplete,
+    Invalid(String),
+}
+
+type ParseResult<T> = Result<T, ParseError>;
+
+fn invalid<T>(msg: impl ToString) -> ParseResult<T> {
+    Err(ParseError::Invalid(msg.to_string()))
+}
+
+/// 检查到一半的帧的进度。数据不够一个完整的帧时记下已经检查过的位置，
+/// 读到更多数据之后从这里继续，不用每次都从头把整个缓冲区再检查一遍
+#[derive(Debug, Default)]
+pub(crate) struct Progress {
+    // 已经检查过的完整元素或聚合类型头部结束的位置
+    pos: usize,
+    // 每层还没检查的聚合类型里剩下的元素个数
+    pending: Vec<usize>,
+}
+
+/// 从缓冲区头部解析一个完整的帧并移出缓冲区，数据不够一个帧时返回None
+pub fn parse(buffer: &mut BytesMut) -> crate::Result<Option<Frame>> {
+    parse_with(buffer, &mut Progress::default())
+}
+
+/// 和parse一样，progress保存上次没检查完的进度，调用之间缓冲区只能在尾部追加数据
+pub(crate) fn parse_with(buffer: &mut BytesMut, progress: &mut Progress) -> crate::Result<Option<Frame>> {
+    let len = match check(&buffer[..], progress) {
+        Ok(len) => len,
+        Err(ParseError::Incomplete) => return Ok(None),
+        Err(ParseError::Invalid(msg)) => {
+            *progress = Progress::default();
+            return Err(Error::Protocol(msg));
+        }
+    };
+    // 确认是完整的帧之后再冻结，bulk string引用这段数据，不用复制
+    let src = buffer.split_to(len).freeze();
+    let mut cursor = Cursor { data: &src[..], pos: 0 };
+    match read(&mut cursor, &src) {
+        Ok(frame) => Ok(Some(frame)),
+        Err(ParseError::Invalid(msg)) => Err(Error::Protocol(msg)),
+        Err(ParseError::Incomplete) => unreachable!("frame was checked to be complete"),
+    }
+}
+
+/// 解析客户端发来的请求。和Redis一样，不以`*`开头的是内联命令：
+/// 一行以空白分隔的参数，方便直接用telnet调试。内联命令不支持引号
+pub fn parse_request(buffer: &mut BytesMut) -> crate::Result<Option<Frame>> {
+    parse_request_with(buffer, &mut Progress::default())
+}
+
+pub(crate) fn parse_request_with(buffer: &mut BytesMut, progress: &mut Progress) -> crate::Result<Option<Frame>> {
+    loop {
+        match buffer.first() {
+            None => return Ok(None),
+            Some(b'*') => return parse_with(buffer, progress),
+            Some(_) => {}
+        }
+        let end = match buffer.iter().position(|b| *b == b'\n') {
+            Some(end) => end,
+            None if buffer.len() > MAX_INLINE => return Err(Error::Protocol("too big inline request".to_string())),
+            None => return Ok(None),
+        };
+        let line = buffer.split_to(end + 1).freeze();
+        let mut args = Vec::new();
+        let mut start = None;
+        for (idx, b) in line.iter().enumerate() {
+            match (b.is_ascii_whitespace(), start) {
+                (false, None) => start = Some(idx),
+                (true, Some(from)) => {
+                    args.push(Frame::Bulk(line.slice(from..idx)));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        // 空行直接跳过
+        if !args.is_empty() {
+            return Ok(Some(Frame::Array(args)));
+        }
+    }
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn byte(&mut self) -> ParseResult<u8> {
+        let b = *self.data.get(self.pos).ok_or(ParseError::Incomplete)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    /// 读到\r\n为止，返回不含\r\n的内容
+    fn line(&mut self) -> ParseResult<&'a [u8]> {
+        let rest = &self.data[self.pos..];
+        let end = rest.windows(2).position(|w| w == b"\r\n").ok_or(ParseError::Incomplete)?;
+        self.pos += end + 2;
+        Ok(&rest[..end])
+    }
+
+    fn integer(&mut self) -> ParseResult<i64> {
+        let line = self.line()?;
+        match std::str::from_utf8(line).ok().and_then(|s| s.parse().ok()) {
+            Some(val) => Ok(val),
+            None => invalid(format!("invalid integer {:?}", String::from_utf8_lossy(line))),
+        }
+    }
+
+    /// 读取长度字段，allow_null时-1表示空值，返回None
+    fn length(&mut self, allow_null: bool) -> ParseResult<Option<usize>> {
+        match self.integer()? {
+            -1 if allow_null => Ok(None),
+            len if len < 0 => invalid(format!("invalid length {}", len)),
+            len => Ok(Some(len as usize)),
+        }
+    }
+
+    /// 跳过len字节的数据和结尾的\r\n，返回数据的起止位置
+    fn blob(&mut self, len: usize) -> ParseResult<(usize, usize)> {
+        let start = self.pos;
+        let end = start.checked_add(len).ok_or(ParseError::Incomplete)?;
+        if self.data.len().saturating_sub(2) < end {
+            return Err(ParseError::Incomplete);
+        }
+        if &self.data[end..end + 2] != b"\r\n" {
+            return invalid("bulk string is not terminated by CRLF");
+        }
+        self.pos = end + 2;
+        Ok((start, end))
+    }
+}
+
+/// 第一遍：只检查是不是完整、合法的帧，不构造帧，返回帧的长度。
+/// 不用递归，每层剩下的元素个数记在progress里，数据不完整时可以从上次的位置继续
+fn check(data: &[u8], progress: &mut Progress) -> ParseResult<usize> {
+    let mut cursor = Cursor { data, pos: progress.pos };
+    loop {
+        if progress.pending.len() > MAX_DEPTH {
+            return invalid("too many nested aggregates");
+        }
+        let children = header(&mut cursor)?;
+        progress.pos = cursor.pos;
+        if children > 0 {
+            progress.pending.push(children);
+            continue;
+        }
+        // 一个元素完整了，所在的聚合类型如果也完整了，继续往上一层算
+        loop {
+            match progress.pending.last_mut() {
+                None => return Ok(std::mem::take(progress).pos),
+                Some(1) => {
+                    progress.pending.pop();
+                }
+                Some(left) => {
+                    *left -= 1;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// 检查一个元素，聚合类型只检查头部，返回后面跟着的子元素个数
+fn header(cursor: &mut Cursor) -> ParseResult<usize> {
+    Ok(match cursor.byte()? {
+        b'+' | b'-' | b':' | b'_' | b'#' | b',' | b'(' => {
+            cursor.line()?;
+            0
+        }
+        b'$' => {
+            if let Some(len) = cursor.length(true)? {
+                cursor.blob(len)?;
+            }
+            0
+        }
+        b'=' | b'!' => {
+            let len = cursor.length(false)?.unwrap();
+            cursor.blob(len)?;
+            0
+        }
+        b'*' => cursor.length(true)?.unwrap_or(0),
+        b'~' | b'>' => cursor.length(false)?.unwrap(),
+        b'%' => cursor.length(false)?.unwrap().saturating_mul(2),
+        // 属性后面紧跟着它所修饰的帧
+        b'|' => cursor.length(false)?.unwrap().saturating_mul(2).saturating_add(1),
+        b => return invalid(format!("invalid frame type byte '{}'", b.escape_ascii())),
+    })
+}
+
+/// 第二遍：构造帧，src是冻结后的完整数据，bulk string直接切片引用
+fn read(cursor: &mut Cursor, src: &Bytes) -> ParseResult<Frame> {
+    Ok(match cursor.byte()? {
+        b'+' => Frame::Simple(utf8(cursor.line()?)?),
+        b'-' => Frame::Error(utf8(cursor.line()?)?),
+        b':' => Frame::Integer(cursor.integer()?),
+        b'_' => {
+            cursor.line()?;
+            Frame::Null
+        }
+        b'#' => match cursor.line()? {
+            b"t" => Frame::Boolean(true),
+            b"f" => Frame::Boolean(false),
+            other => return invalid(format!("invalid boolean {:?}", String::from_utf8_lossy(other))),
+        },
+        b',' => {
+            let line = utf8(cursor.line()?)?;
+            match line.parse() {
+                Ok(val) => Frame::Double(val),
+                Err(_) => return invalid(format!("invalid double {:?}", line)),
+            }
+        }
+        b'(' => {
+            let line = utf8(cursor.line()?)?;
+            let digits = line.strip_prefix('-').unwrap_or(&line);
+            if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return invalid(format!("invalid big number {:?}", line));
+            }
+            Frame::BigNumber(line)
+        }
+        b'$' => match cursor.length(true)? {
+            Some(len) => {
+                let (start, end) = cursor.blob(len)?;
+                Frame::Bulk(src.slice(start..end))
+            }
+            None => Frame::Null,
+        },
+        b'!' => {
+            let len = cursor.length(false)?.unwrap();
+            let (start, end) = cursor.blob(len)?;
+            Frame::Error(utf8(&src[start..end])?)
+        }
+        b'=' => {
+            let len = cursor.length(false)?.unwrap();
+            let (start, end) = cursor.blob(len)?;
+            if end - start < 4 || src[start + 3] != b':' {
+                return invalid("verbatim string without format");
+            }
+            Frame::Verbatim(utf8(&src[start..start + 3])?, src.slice(start + 4..end))
+        }
+        b'*' => match cursor.length(true)? {
+            Some(len) => Frame::Array(items(cursor, src, len)?),
+            None => Frame::Null,
+        },
+        b'~' => {
+            let len = cursor.length(false)?.unwrap();
+            Frame::Set(items(cursor, src, len)?)
+        }
+        b'>' => {
+            let len = cursor.length(false)?.unwrap();
+            Frame::Push(items(cursor, src, len)?)
+        }
+        b'%' => {
+            let len = cursor.length(false)?.unwrap();
+            Frame::Map(pairs(cursor, src, len)?)
+        }
+        b'|' => {
+            let len = cursor.length(false)?.unwrap();
+            let attrs = pairs(cursor, src, len)?;
+            Frame::Attribute(attrs, Box::new(read(cursor, src)?))
+        }
+        b => return invalid(format!("invalid frame type byte '{}'", b.escape_ascii())),
+    })
+}
+
+fn items(cursor: &mut Cursor, src: &Bytes, len: usize) -> ParseResult<Vec<Frame>> {
+    let mut items = Vec::with_capacity(len.min(MAX_PREALLOC));
+    for _ in 0..len {
+        items.push(read(cursor, src)?);
+    }
+    Ok(items)
+}
+
+fn pairs(cursor: &mut Cursor, src: &Bytes, len: usize) -> ParseResult<Vec<(Frame, Frame)>> {
+    let mut pairs = Vec::with_capacity(len.min(MAX_PREALLOC));
+    for _ in 0..len {
+        pairs.push((read(cursor, src)?, read(cursor, src)?));
+    }
+    Ok(pairs)
+}
+
+fn utf8(data: &[u8]) -> ParseResult<String> {
+    match std::str::from_utf8(data) {
+        Ok(s) => Ok(s.to_string()),
+        Err(_) => invalid("invalid utf-8 in simple string"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use crate::frame::{Frame, Protocol};
+    use crate::parse::{parse, parse_request, parse_with, Progress};
+
+    fn bulk(s: &str) -> Frame {
+        Frame::Bulk(Bytes::from(s.to_string()))
+    }
+
+    #[test]
+    fn test_resp3() {
+        let data = "%2\r\n+first\r\n:1\r\n$6\r\nsecond\r\n~2\r\n,3.25\r\n#f\r\n\
+            >2\r\n(12345678901234567890\r\n_\r\n\
+            |1\r\n+ttl\r\n,inf\r\n=7\r\nmkd:abc\r\n\
+            !5\r\nERR x\r\n*-1\r\n";
+        let mut buffer = BytesMut::from(data);
+        assert_eq!(parse(&mut buffer).unwrap().unwrap(), Frame::Map(vec![
+            (Frame::Simple("first".to_string()), Frame::Integer(1)),
+            (bulk("second"), Frame::Set(vec![Frame::Double(3.25), Frame::Boolean(false)])),
+        ]));
+        assert_eq!(parse(&mut buffer).unwrap().unwrap(), Frame::Push(vec![
+            Frame::BigNumber("12345678901234567890".to_string()), Frame::Null,
+        ]));
+        assert_eq!(parse(&mut buffer).unwrap().unwrap(), Frame::Attribute(
+            vec![(Frame::Simple("ttl".to_string()), Frame::Double(f64::INFINITY))],
+            Box::new(Frame::Verbatim("mkd".to_string(), Bytes::from("abc"))),
+        ));
+        assert_eq!(parse(&mut buffer).unwrap().unwrap(), Frame::error("ERR x"));
+        assert_eq!(parse(&mut buffer).unwrap().unwrap(), Frame::Null);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let frame = Frame::Array(vec![bulk("SET"), bulk("key"), Frame::Map(vec![(Frame::Integer(-1), Frame::Double(0.5))])]);
+        let mut data = BytesMut::new();
+        frame.encode(&mut data, Protocol::Resp3);
+        // 每个前缀都应该是不完整的，而不是解析错误
+        for len in 0..data.len() {
+            let mut buffer = BytesMut::from(&data[..len]);
+            assert!(parse(&mut buffer).unwrap().is_none());
+            assert_eq!(buffer.len(), len);
+        }
+        assert_eq!(parse(&mut data).unwrap().unwrap(), frame);
+    }
+
+    #[test]
+    fn test_resume() {
+        let frame = Frame::Array((0..100).map(|i| Frame::Array(vec![bulk("item"), Frame::Integer(i)])).collect());
+        let mut data = BytesMut::new();
+        frame.encode(&mut data, Protocol::Resp3);
+        // 一个字节一个字节地追加，已经检查过的元素不再重新检查
+        let mut buffer = BytesMut::new();
+        let mut progress = Progress::default();
+        for (idx, b) in data.iter().enumerate() {
+            assert!(progress.pos <= buffer.len());
+            buffer.extend_from_slice(&[*b]);
+            if idx + 1 < data.len() {
+                assert!(parse_with(&mut buffer, &mut progress).unwrap().is_none());
+                assert_eq!(buffer.len(), idx + 1);
+            }
+        }
+        assert!(progress.pos > data.len() / 2);
+        assert_eq!(parse_with(&mut buffer, &mut progress).unwrap().unwrap(), frame);
+        assert!(buffer.is_empty());
+        assert_eq!(progress.pos, 0);
+        assert!(progress.pending.is_empty());
+
+        // 接着到的数据不合法时照样报错
+        let mut buffer = BytesMut::from("*2\r\n:1\r\n");
+        assert!(parse_with(&mut buffer, &mut progress).unwrap().is_none());
+        assert_eq!(progress.pending, vec![1]);
+        buffer.extend_from_slice(b"?\r\n");
+        assert!(parse_with(&mut buffer, &mut progress).is_err());
+    }
+
+    #[test]
+    fn test_invalid() {
+        for data in ["?\r\n", ":abc\r\n", "$3\r\nabcd\r\n", "$-2\r\n", "#x\r\n", "(12a\r\n", "=2\r\nab\r\n", "~-1\r\n"] {
+            assert!(parse(&mut BytesMut::from(data)).is_err(), "{:?}", data);
+        }
+        let nested = "*1\r\n".repeat(100) + ":1\r\n";
+        assert!(parse(&mut BytesMut::from(nested.as_str())).is_err());
+        // 长度字段很大时只是不完整，不会按这个长度分配内存
+        assert!(parse(&mut BytesMut::from("*4294967295\r\n:1\r\n")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_inline() {
+        let mut buffer = BytesMut::from("\r\nSET  key value\r\nPING\n*1\r\n$4\r\nPING\r\nGET");
+        assert_eq!(parse_request(&mut buffer).unwrap().unwrap(), Frame::Array(vec![bulk("SET"), bulk("key"), bulk("value")]));
+        assert_eq!(parse_request(&mut buffer).unwrap().unwrap(), Frame::Array(vec![bulk("PING")]));
+        assert_eq!(parse_request(&mut buffer).unwrap().unwrap(), Frame::Array(vec![bulk("PING")]));
+        assert!(parse_request(&mut buffer).unwrap().is_none());
+        assert_eq!(&buffer[..], b"GET");
+        let mut buffer = BytesMut::from(&[b'x'; 65 * 1024][..]);
+        assert!(parse_request(&mut buffer).is_err());
+    }
+}